@@ -0,0 +1,61 @@
+//! AbuseIPDB reputation lookups for `cloak lookup`.
+
+use std::{env, fs, net::IpAddr, path::Path};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+const CHECK_URL: &str = "https://api.abuseipdb.com/api/v2/check";
+
+/// Environment variable read when no key file is given
+pub const KEY_VAR: &str = "ABUSEIPDB_API_KEY";
+
+/// Reports older than this many days are not counted
+const MAX_AGE_DAYS: u32 = 90;
+
+#[derive(Deserialize)]
+struct CheckResponse {
+    data: Report,
+}
+
+/// What AbuseIPDB knows about an address
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    /// 0-100, how sure AbuseIPDB is that the address is abusive
+    pub abuse_confidence_score: u8,
+    pub total_reports: u64,
+}
+
+/// The API key from `key_file`, else from `$ABUSEIPDB_API_KEY`; `None`
+/// if neither is set.
+pub fn api_key(key_file: Option<&Path>) -> Result<Option<String>> {
+    let key = match key_file {
+        Some(path) => fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?,
+        None => env::var(KEY_VAR).unwrap_or_default(),
+    };
+    let key = key.trim();
+    if key.is_empty() {
+        if let Some(path) = key_file {
+            bail!("{} is empty", path.display());
+        }
+        return Ok(None);
+    }
+    Ok(Some(key.to_string()))
+}
+
+pub async fn check(client: &reqwest::Client, key: &str, addr: IpAddr) -> Result<Report> {
+    let response: CheckResponse = client
+        .get(CHECK_URL)
+        .query(&[("ipAddress", addr.to_string()), ("maxAgeInDays", MAX_AGE_DAYS.to_string())])
+        .header("Key", key)
+        .header("Accept", "application/json")
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("check {} with AbuseIPDB", addr))?
+        .json()
+        .await
+        .with_context(|| format!("parse the AbuseIPDB report for {}", addr))?;
+    Ok(response.data)
+}
@@ -0,0 +1,193 @@
+//! `cloak analyze`: traffic by country in a packet capture, to see what a
+//! list would actually block before choosing one.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::BufReader,
+    net::IpAddr,
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{
+    fetch,
+    groups::{self, ListChoice},
+    index::Index,
+    pcap, state,
+    ui::info,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct AnalyzeArgs {
+    /// Capture file (pcap or pcapng)
+    pub capture: PathBuf,
+
+    /// JSON maps, or one index from `cloak compile`, to classify addresses
+    /// with (default: every country in the download cache)
+    #[arg(long, value_name = "FILE")]
+    pub map: Vec<PathBuf>,
+
+    /// Where earlier fetches left their downloads
+    #[arg(long, value_name = "DIR", default_value_os_t = state::default_cache_dir())]
+    pub cache_dir: PathBuf,
+
+    /// How many countries to list
+    #[arg(long, value_name = "N", default_value_t = 20)]
+    pub top: usize,
+}
+
+/// Packets and bytes seen
+#[derive(Clone, Copy, Default, Debug, Serialize)]
+pub struct Traffic {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl Traffic {
+    fn add(&mut self, packets: u64, bytes: u64) {
+        self.packets += packets;
+        self.bytes += bytes;
+    }
+}
+
+/// Traffic attributed to the countries of its endpoints. A flow between
+/// two countries counts for both, so the per-country shares can add up to
+/// more than 100%.
+#[derive(Default, Serialize)]
+pub struct CountryTraffic {
+    pub countries: BTreeMap<String, Traffic>,
+    /// Traffic with no endpoint in any known country (e.g. LAN only)
+    pub unmatched: Traffic,
+    pub total: Traffic,
+}
+
+impl CountryTraffic {
+    pub fn record(&mut self, index: &Index, src: IpAddr, dst: IpAddr, packets: u64, bytes: u64) {
+        self.total.add(packets, bytes);
+        let from = index.lookup(src);
+        let to = index.lookup(dst).filter(|cc| Some(*cc) != from);
+        if from.is_none() && to.is_none() {
+            self.unmatched.add(packets, bytes);
+        }
+        for cc in from.into_iter().chain(to) {
+            self.countries.entry(cc.to_string()).or_default().add(packets, bytes);
+        }
+    }
+
+    /// Countries by bytes, busiest first
+    pub fn ranked(&self) -> Vec<(&String, &Traffic)> {
+        let mut ranked: Vec<(&String, &Traffic)> = self.countries.iter().collect();
+        ranked.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then(a.0.cmp(b.0)));
+        ranked
+    }
+
+    fn share(&self, bytes: u64) -> f64 {
+        bytes as f64 * 100.0 / self.total.bytes.max(1) as f64
+    }
+
+    /// Print the busiest `top` countries as a table
+    pub fn print_table(&self, top: usize) {
+        println!("{:<4} {:<24} {:>12} {:>12} {:>7}", "CC", "Country", "Packets", "Bytes", "Share");
+        for (cc, traffic) in self.ranked().into_iter().take(top) {
+            let name = groups::country_name(cc).unwrap_or("");
+            println!(
+                "{:<4} {:<24} {:>12} {:>12} {:>6.1}%",
+                cc.to_uppercase(),
+                name,
+                traffic.packets,
+                format_bytes(traffic.bytes),
+                self.share(traffic.bytes)
+            );
+        }
+        println!(
+            "{:<4} {:<24} {:>12} {:>12} {:>6.1}%",
+            "-",
+            "(no known country)",
+            self.unmatched.packets,
+            format_bytes(self.unmatched.bytes),
+            self.share(self.unmatched.bytes)
+        );
+    }
+
+    /// Print the built-in lists that would touch the most traffic, with the
+    /// observed countries they contain
+    pub fn print_suggestions(&self) {
+        let mut lists: Vec<(ListChoice, u64, Vec<String>)> = ListChoice::value_variants()
+            .iter()
+            .map(|&list| {
+                let members = list.countries();
+                let mut bytes = 0;
+                let mut seen = Vec::new();
+                for (cc, traffic) in self.ranked() {
+                    if members.iter().any(|(member, _)| member == cc) {
+                        bytes += traffic.bytes;
+                        seen.push(cc.to_uppercase());
+                    }
+                }
+                (list, bytes, seen)
+            })
+            .filter(|(_, bytes, _)| *bytes > 0)
+            .collect();
+        if lists.is_empty() {
+            return;
+        }
+        lists.sort_by_key(|(_, bytes, _)| std::cmp::Reverse(*bytes));
+        println!();
+        println!("Lists covering the most traffic (allow or block accordingly):");
+        for (list, bytes, seen) in lists.iter().take(5) {
+            let mut countries = seen.iter().take(6).cloned().collect::<Vec<_>>().join(", ");
+            if seen.len() > 6 {
+                countries.push_str(&format!(", +{}", seen.len() - 6));
+            }
+            println!("  {:<14} {:>6.1}%  ({})", list.to_string(), self.share(*bytes).min(100.0), countries);
+        }
+    }
+}
+
+pub fn run(args: &AnalyzeArgs) -> Result<()> {
+    let index = if args.map.is_empty() {
+        let map = fetch::cached_countries(&args.cache_dir)?;
+        if map.is_empty() {
+            bail!("no country data in {}; pass --map or fetch a list first", args.cache_dir.display());
+        }
+        info!("Using {} countries from {}", map.len(), args.cache_dir.display());
+        Index::build(&map)
+    } else {
+        Index::load(&args.map)?
+    };
+
+    let file = File::open(&args.capture).with_context(|| format!("open {}", args.capture.display()))?;
+    let mut reader = pcap::Reader::new(BufReader::new(file)).with_context(|| format!("read {}", args.capture.display()))?;
+    let mut traffic = CountryTraffic::default();
+    while let Some(packet) = reader.next_packet().with_context(|| format!("read {}", args.capture.display()))? {
+        traffic.record(&index, packet.src, packet.dst, 1, u64::from(packet.len));
+    }
+    info!(
+        "{} IP packets ({}), {} other frames skipped",
+        traffic.total.packets,
+        format_bytes(traffic.total.bytes),
+        reader.skipped
+    );
+    traffic.print_table(args.top);
+    traffic.print_suggestions();
+    Ok(())
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
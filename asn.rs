@@ -0,0 +1,44 @@
+//! Prefixes announced by an autonomous system, as seen by RIPEstat.
+
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use ipnetwork::IpNetwork;
+use serde::Deserialize;
+
+use crate::{download, ui::info};
+
+const RIPESTAT: &str = "https://stat.ripe.net/data/announced-prefixes/data.json";
+
+#[derive(Deserialize)]
+struct Response {
+    data: Data,
+}
+
+#[derive(Deserialize)]
+struct Data {
+    prefixes: Vec<Announced>,
+}
+
+#[derive(Deserialize)]
+struct Announced {
+    prefix: String,
+}
+
+/// Parse `13335` or `AS13335` for `--except-asn`.
+pub fn parse_asn(text: &str) -> Result<u32, String> {
+    let digits = text.trim();
+    let digits = digits.strip_prefix("AS").or_else(|| digits.strip_prefix("as")).unwrap_or(digits);
+    digits.parse().map_err(|_| format!("invalid AS number `{}`", text))
+}
+
+/// Prefixes currently announced by `asn`
+pub async fn announced_prefixes(client: &reqwest::Client, asn: u32, cache_dir: &Path) -> Result<Vec<IpNetwork>> {
+    let url = format!("{}?resource=AS{}", RIPESTAT, asn);
+    let path = download::fetch_to_cache(client, &url, cache_dir).await?;
+    let body = fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+    let response: Response = serde_json::from_slice(&body).with_context(|| format!("parse RIPEstat answer for AS{}", asn))?;
+    let prefixes: Vec<IpNetwork> = response.data.prefixes.iter().filter_map(|p| p.prefix.parse().ok()).collect();
+    info!("AS{} announces {} prefixes", asn, prefixes.len());
+    Ok(prefixes)
+}
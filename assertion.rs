@@ -0,0 +1,141 @@
+//! Policy expectations declared in the config file, checked against
+//! generated rules before they are loaded:
+//!
+//! ```yaml
+//! assertions:
+//!   - allow 203.0.113.7:22          # the office can still SSH in
+//!   - block 198.51.100.1            # port 22 when none is given
+//!   - allow 192.0.2.10:443 on wan0  # arriving on a particular interface
+//!   - "block [2001:db8::1]:443"     # quote IPv6 addresses
+//! ```
+//!
+//! An assertion holds when a new TCP connection from the address gets the
+//! expected verdict from the input chain, worked out as in the pre-flight
+//! report; rate limited counts as allowed. `cloak test` checks a rule file,
+//! and `cloak apply` and the daemon refuse rules that break one.
+
+use std::{
+    fmt, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    exit::{Code, WithCode},
+    preflight::{self, Model, Packet},
+    ui::{info, success, warning},
+    yaml,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct TestArgs {
+    /// Rule file to check, e.g. one just written by cloak
+    rules: PathBuf,
+
+    /// Config file whose `assertions:` the rules must meet
+    #[arg(long, value_name = "FILE")]
+    config: PathBuf,
+}
+
+/// One `allow|block ADDR[:PORT] [on IFACE]` line
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Assertion {
+    allowed: bool,
+    from: SocketAddr,
+    interface: Option<String>,
+}
+
+impl TryFrom<String> for Assertion {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Assertion, String> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let words = words.strip_prefix(&["assert"]).unwrap_or(&words);
+        let (expect, target, interface) = match words {
+            [expect, target] => (*expect, *target, None),
+            [expect, target, "on", interface] => (*expect, *target, Some(interface.to_string())),
+            _ => return Err(format!("expected `allow|block ADDR[:PORT] [on IFACE]`, not `{}`", text)),
+        };
+        let allowed = match expect {
+            "allow" => true,
+            "block" => false,
+            other => return Err(format!("`{}`: expected allow or block, not `{}`", text, other)),
+        };
+        let from = preflight::parse_reachable(target).map_err(|e| format!("`{}`: {}", text, e))?;
+        Ok(Assertion { allowed, from, interface })
+    }
+}
+
+impl fmt::Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", if self.allowed { "allow" } else { "block" }, self.from)?;
+        if let Some(interface) = &self.interface {
+            write!(f, " on {}", interface)?;
+        }
+        Ok(())
+    }
+}
+
+/// The `assertions:` section of the config file at `path`, empty if it has
+/// none
+pub fn from_config_file(path: &Path) -> Result<Vec<Assertion>> {
+    let text = fs::read_to_string(path).with_context(|| format!("read config {}", path.display()))?;
+    let doc = yaml::parse(&text).with_context(|| format!("parse {}", path.display()))?;
+    match doc.get("assertions").cloned().unwrap_or(Value::Null) {
+        Value::Null => Ok(Vec::new()),
+        section => serde_json::from_value(section).with_context(|| format!("invalid assertions section in {}", path.display())),
+    }
+}
+
+/// Fail unless `ruleset` meets every assertion
+pub fn check(ruleset: &str, assertions: &[Assertion]) -> Result<()> {
+    if assertions.is_empty() {
+        return Ok(());
+    }
+    let model = Model::new(ruleset);
+    let mut failed = 0;
+    for assertion in assertions {
+        let packet = Packet {
+            from: assertion.from.ip().to_canonical(),
+            to: None,
+            port: assertion.from.port(),
+            protocol: "tcp",
+            interface: assertion.interface.as_deref(),
+        };
+        let (fate, path) = model.trace(&packet, false);
+        if (fate != "dropped") == assertion.allowed {
+            info!("ok    {} ({})", assertion, fate);
+        } else {
+            failed += 1;
+            warning!("FAIL  {}: {} by {}", assertion, fate, path.last().map_or("no rule", String::as_str));
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} of {} assertions failed", failed, assertions.len())).code(Code::Validation);
+    }
+    Ok(())
+}
+
+/// Check the rule file at `path`
+pub fn check_file(path: &Path, assertions: &[Assertion]) -> Result<()> {
+    if assertions.is_empty() {
+        return Ok(());
+    }
+    let ruleset = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    check(&ruleset, assertions).with_context(|| format!("{} breaks the policy's assertions", path.display()))
+}
+
+pub fn run(args: &TestArgs) -> Result<()> {
+    let assertions = from_config_file(&args.config).code(Code::Validation)?;
+    if assertions.is_empty() {
+        return Err(anyhow!("{} has no `assertions:` section", args.config.display())).code(Code::Validation);
+    }
+    check_file(&args.rules, &assertions)?;
+    success!("{} meets all {} assertions.", args.rules.display(), assertions.len());
+    Ok(())
+}
@@ -0,0 +1,359 @@
+//! Attaching cloak's rules to a chain of an existing nftables table.
+//!
+//! nftables cannot jump between tables, so instead of loading its own
+//! table cloak copies its sets and chains into the target table with a
+//! `cloak_` prefix and inserts a single `jump` into the target chain. The
+//! handle of that jump is recorded in the state directory, so later
+//! applies leave it where it is and `cloak remove` can take it out again.
+//!
+//! Attached rules only ever drop: what cloak would accept returns to the
+//! target chain, whose remaining rules then decide.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{nft, privilege, ui::info};
+
+/// Prefix of every object cloak creates in a foreign table
+const PREFIX: &str = "cloak_";
+
+/// Where the jump into cloak's chain goes in the target chain
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Position {
+    Top,
+    Bottom,
+    /// Before the rule currently at this 0-based index
+    Index(u32),
+    /// Right after the rule with this handle
+    AfterHandle(u64),
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Position::Top => write!(f, "top"),
+            Position::Bottom => write!(f, "bottom"),
+            Position::Index(n) => write!(f, "index {}", n),
+            Position::AfterHandle(h) => write!(f, "after-handle {}", h),
+        }
+    }
+}
+
+/// Parse `top`, `bottom`, `index N` or `after-handle H` for `--insert-at`.
+pub fn parse_position(text: &str) -> Result<Position, String> {
+    let mut words = text.split(|c: char| c.is_whitespace() || c == '=' || c == ':').filter(|w| !w.is_empty());
+    let invalid = || format!("invalid position `{}` (use top, bottom, index N or after-handle H)", text);
+    let position = match (words.next(), words.next()) {
+        (Some("top"), None) => Position::Top,
+        (Some("bottom"), None) => Position::Bottom,
+        (Some("index"), Some(n)) => Position::Index(n.parse().map_err(|_| invalid())?),
+        (Some("after-handle"), Some(h)) => Position::AfterHandle(h.parse().map_err(|_| invalid())?),
+        _ => return Err(invalid()),
+    };
+    if words.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(position)
+}
+
+/// An `inet` table and one of its chains, written `TABLE/CHAIN`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub table: String,
+    pub chain: String,
+}
+
+pub fn parse_target(text: &str) -> Result<Target, String> {
+    let (table, chain) = text.split_once('/').ok_or("expected TABLE/CHAIN, e.g. filter/input")?;
+    for name in [table, chain] {
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-') {
+            return Err(format!("`{}` is not a valid nft object name", name));
+        }
+    }
+    if table == nft::TABLE {
+        return Err(format!("`{}` is cloak's own table; leave out --attach to load into it", table));
+    }
+    Ok(Target { table: table.to_string(), chain: chain.to_string() })
+}
+
+/// The jump cloak placed, as recorded in the state directory
+struct Attachment {
+    target: Target,
+    handle: u64,
+    position: Position,
+}
+
+fn record_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("attached")
+}
+
+fn read_record(state_dir: &Path) -> Option<Attachment> {
+    let text = fs::read_to_string(record_path(state_dir)).ok()?;
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let [table, chain, handle, position @ ..] = words.as_slice() else {
+        return None;
+    };
+    Some(Attachment {
+        target: Target { table: table.to_string(), chain: chain.to_string() },
+        handle: handle.parse().ok()?,
+        position: parse_position(&position.join(" ")).ok()?,
+    })
+}
+
+fn write_record(state_dir: &Path, attachment: &Attachment) -> Result<()> {
+    let path = record_path(state_dir);
+    let line = format!(
+        "{} {} {} {}\n",
+        attachment.target.table, attachment.target.chain, attachment.handle, attachment.position
+    );
+    fs::write(&path, line).with_context(|| format!("write {}", path.display()))
+}
+
+/// Table and name prefix of cloak's objects: those in the attached table
+/// if the rules were last loaded with `--attach`, else cloak's own table
+pub fn location(state_dir: &Path) -> (String, &'static str) {
+    match read_record(state_dir) {
+        Some(attachment) => (attachment.target.table, PREFIX),
+        None => (nft::TABLE.to_string(), ""),
+    }
+}
+
+/// Load the cloak rule file `ruleset` into `target` and make sure the
+/// target chain jumps to it at `position`.
+pub fn attach(ruleset: &str, target: &Target, position: Position, state_dir: &Path) -> Result<()> {
+    let (script, entry) = rewrite(ruleset, &target.table)?;
+    nft::run_script(&script).with_context(|| format!("load rules into table inet {}", target.table))?;
+
+    let previous = read_record(state_dir);
+    if let Some(previous) = &previous {
+        let same_place = previous.target == *target && previous.position == position;
+        if same_place && jump_exists(&previous.target, previous.handle, &entry) {
+            info!("Jump into {} is still in place (handle {}).", entry, previous.handle);
+            return Ok(());
+        }
+        if jump_exists(&previous.target, previous.handle, &entry) {
+            delete_rule(&previous.target, previous.handle)?;
+        }
+    }
+
+    let handle = insert_jump(target, position, &entry)?;
+    info!(
+        "Inserted jump to {} into inet {} {} at {} (handle {}).",
+        entry, target.table, target.chain, position, handle
+    );
+    write_record(state_dir, &Attachment { target: target.clone(), handle, position })
+}
+
+/// Take out the jump, chains and sets a previous [`attach`] created.
+/// Returns whether there was anything to remove.
+pub fn detach(state_dir: &Path) -> Result<bool> {
+    let Some(attachment) = read_record(state_dir) else {
+        return Ok(false);
+    };
+    let target = &attachment.target;
+    let listing = list(&["table", "inet", &target.table]).unwrap_or_default();
+    let mut script = String::new();
+    if listing.lines().any(|line| line.trim_end().ends_with(&format!("# handle {}", attachment.handle))) {
+        script.push_str(&format!("delete rule inet {} {} handle {}\n", target.table, target.chain, attachment.handle));
+    }
+    let chains = own_objects(&listing, "chain");
+    let sets = own_objects(&listing, "set");
+    // Chains may jump to each other, so empty all of them before deleting
+    for chain in &chains {
+        script.push_str(&format!("flush chain inet {} {}\n", target.table, chain));
+    }
+    for chain in &chains {
+        script.push_str(&format!("delete chain inet {} {}\n", target.table, chain));
+    }
+    for set in &sets {
+        script.push_str(&format!("delete set inet {} {}\n", target.table, set));
+    }
+    if !script.is_empty() {
+        nft::run_script(&script).with_context(|| format!("remove cloak's objects from table inet {}", target.table))?;
+    }
+    let path = record_path(state_dir);
+    fs::remove_file(&path).with_context(|| format!("remove {}", path.display()))?;
+    Ok(true)
+}
+
+/// Turn a cloak rule file into a script that replaces cloak's objects in
+/// `table` in one transaction. Returns the script and the name of the chain
+/// to jump to.
+pub fn rewrite(ruleset: &str, table: &str) -> Result<(String, String)> {
+    nft::check_own_ruleset(ruleset)?;
+    let open = format!("table inet {} {{", nft::TABLE);
+    let body: Vec<&str> = ruleset
+        .lines()
+        .skip_while(|line| line.trim() != open)
+        .skip(1)
+        .collect();
+    // Everything up to the table's closing brace
+    let end = body.iter().rposition(|line| line.trim() == "}").context("rule file has no closing brace")?;
+    let body = &body[..end];
+
+    let mut entry = None;
+    let mut sets = Vec::new();
+    let mut chains = Vec::new();
+    let mut renamed = String::new();
+    let mut current_chain = None;
+    for line in body {
+        let trimmed = line.trim_start();
+        if let Some(rest) = trimmed.strip_prefix("chain ") {
+            let name = rest.split_whitespace().next().unwrap_or_default().to_string();
+            chains.push(format!("{}{}", PREFIX, name));
+            current_chain = Some(name);
+        }
+        if let Some(rest) = trimmed.strip_prefix("set ") {
+            let mut words = rest.split_whitespace();
+            let name = words.next().unwrap_or_default();
+            let field = |key: &str| rest.split(key).nth(1).and_then(|t| t.split(';').next()).map(str::to_string);
+            let kind = field("type ").unwrap_or_default();
+            let flags = field("flags ").unwrap_or_else(|| "interval".to_string());
+            sets.push((format!("{}{}", PREFIX, name), kind, flags));
+        }
+        if trimmed.starts_with("type filter hook ") {
+            if entry.is_some() {
+                bail!("rule files with more than one base chain (e.g. --direction both, router zones) cannot be attached");
+            }
+            entry = current_chain.clone().map(|name| format!("{}{}", PREFIX, name));
+            continue;
+        }
+        renamed.push_str(&to_return(&prefix_names(line)));
+        renamed.push('\n');
+    }
+    let entry = entry.context("rule file has no base chain")?;
+
+    let mut script = String::new();
+    script.push_str(&format!("table inet {} {{\n", table));
+    for (set, kind, flags) in &sets {
+        script.push_str(&format!("  set {} {{ type {}; flags {}; }}\n", set, kind, flags));
+    }
+    for chain in &chains {
+        script.push_str(&format!("  chain {} {{ }}\n", chain));
+    }
+    script.push_str("}\n");
+    for chain in &chains {
+        script.push_str(&format!("flush chain inet {} {}\n", table, chain));
+    }
+    for (set, ..) in &sets {
+        script.push_str(&format!("flush set inet {} {}\n", table, set));
+    }
+    script.push_str(&format!("table inet {} {{\n{}}}\n", table, renamed));
+    Ok((script, entry))
+}
+
+/// Prefix the set and chain names a line declares or refers to
+fn prefix_names(line: &str) -> String {
+    let mut out = String::with_capacity(line.len() + 16);
+    let mut words = line.split(' ').peekable();
+    while let Some(word) = words.next() {
+        if let Some(name) = word.strip_prefix('@') {
+            out.push('@');
+            out.push_str(PREFIX);
+            out.push_str(name);
+        } else {
+            out.push_str(word);
+            if matches!(word, "set" | "chain" | "jump") {
+                if let Some(name) = words.next() {
+                    out.push(' ');
+                    out.push_str(PREFIX);
+                    out.push_str(name);
+                }
+            }
+        }
+        if words.peek().is_some() {
+            out.push(' ');
+        }
+    }
+    out
+}
+
+/// Traffic cloak lets through goes back to the target chain, whose own
+/// rules then decide, so `accept` verdicts become `return`
+fn to_return(line: &str) -> String {
+    line.split(' ')
+        .map(|word| match word {
+            "accept" => "return",
+            "accept;" => "return;",
+            other => other,
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Names of the `kind` objects (set or chain) cloak created in a listing
+fn own_objects(listing: &str, kind: &str) -> Vec<String> {
+    let keyword = format!("{} {}", kind, PREFIX);
+    listing
+        .lines()
+        .filter_map(|line| line.trim_start().strip_prefix(&keyword))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .map(|name| format!("{}{}", PREFIX, name))
+        .collect()
+}
+
+fn jump_exists(target: &Target, handle: u64, entry: &str) -> bool {
+    let Ok(listing) = list(&["chain", "inet", &target.table, &target.chain]) else {
+        return false;
+    };
+    let jump = format!("jump {}", entry);
+    let suffix = format!("# handle {}", handle);
+    listing.lines().any(|line| line.contains(&jump) && line.trim_end().ends_with(&suffix))
+}
+
+fn insert_jump(target: &Target, position: Position, entry: &str) -> Result<u64> {
+    let (verb, place) = match position {
+        Position::Top => ("insert", String::new()),
+        Position::Bottom => ("add", String::new()),
+        Position::Index(n) => ("insert", format!(" index {}", n)),
+        Position::AfterHandle(h) => ("add", format!(" handle {}", h)),
+    };
+    let rule = format!(
+        "{} rule inet {} {}{} jump {} comment \"cloak\"",
+        verb, target.table, target.chain, place, entry
+    );
+    let output = privilege::command("nft")
+        .args(["--echo", "--handle"])
+        .args(rule.split(' '))
+        .output()
+        .context("failed to execute nft command")?;
+    if !output.status.success() {
+        bail!("nft refused `{}`: {}", rule, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let echoed = String::from_utf8_lossy(&output.stdout);
+    echoed
+        .rsplit("# handle ")
+        .next()
+        .and_then(|h| h.trim().parse().ok())
+        .context("nft did not report the handle of the inserted jump")
+}
+
+fn delete_rule(target: &Target, handle: u64) -> Result<()> {
+    let handle = handle.to_string();
+    let status = privilege::command("nft")
+        .args(["delete", "rule", "inet", &target.table, &target.chain, "handle", &handle])
+        .status()
+        .context("failed to execute nft command")?;
+    if !status.success() {
+        bail!("nft could not delete rule {} in inet {} {}", handle, target.table, target.chain);
+    }
+    Ok(())
+}
+
+fn list(what: &[&str]) -> Result<String> {
+    let output = privilege::command("nft")
+        .args(["-a", "list"])
+        .args(what)
+        .stderr(Stdio::null())
+        .output()
+        .context("failed to execute nft command")?;
+    if !output.status.success() {
+        bail!("nft list {} failed", what.join(" "));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,83 @@
+//! `cloak lookup --batch`: the country and covering prefix of every
+//! address in a file or on stdin, as `ip,country,cidr` lines.
+//!
+//! Input is read in chunks that are split across one thread per core and
+//! written back in their original order, so the output lines up with the
+//! input however many threads ran.
+
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::IpAddr,
+    path::Path,
+    thread,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{index::Index, ui::warning};
+
+/// Lines handed to the threads at a time
+const CHUNK: usize = 1 << 16;
+
+/// Look up every address in `input` (`-` for stdin) and write the results
+/// to stdout. Blank lines and `#` comments are skipped; addresses that do
+/// not parse are counted and reported at the end.
+pub fn run(index: &Index, input: &Path) -> Result<()> {
+    let reader: Box<dyn BufRead> = if input == Path::new("-") {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(input).with_context(|| format!("open {}", input.display()))?))
+    };
+    let threads = thread::available_parallelism().map_or(1, usize::from);
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut lines = reader.lines();
+    let mut invalid = 0;
+    let mut first_invalid = None;
+    loop {
+        let chunk = lines.by_ref().take(CHUNK).collect::<io::Result<Vec<String>>>().with_context(|| format!("read {}", input.display()))?;
+        if chunk.is_empty() {
+            break;
+        }
+        let parts: Vec<(String, usize, Option<String>)> = thread::scope(|scope| {
+            let workers: Vec<_> =
+                chunk.chunks(chunk.len().div_ceil(threads)).map(|lines| scope.spawn(move || lookup_lines(index, lines))).collect();
+            workers.into_iter().map(|worker| worker.join().expect("lookup thread panicked")).collect()
+        });
+        for (text, bad, first) in parts {
+            out.write_all(text.as_bytes()).context("write results")?;
+            invalid += bad;
+            first_invalid = first_invalid.or(first);
+        }
+    }
+    out.flush().context("write results")?;
+    if let Some(first) = first_invalid {
+        warning!("Skipped {} line(s) that are not addresses, starting with `{}`", invalid, first);
+    }
+    Ok(())
+}
+
+/// The output for `lines`, the number that did not parse and the first of
+/// those
+fn lookup_lines(index: &Index, lines: &[String]) -> (String, usize, Option<String>) {
+    let mut text = String::new();
+    let (mut invalid, mut first_invalid) = (0, None);
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Ok(addr) = line.parse::<IpAddr>() else {
+            invalid += 1;
+            first_invalid.get_or_insert_with(|| line.to_string());
+            continue;
+        };
+        // Writing to a String cannot fail
+        let _ = match index.lookup_net(addr) {
+            Some((cc, net)) => writeln!(text, "{},{},{}", addr, cc.to_uppercase(), net),
+            None => writeln!(text, "{},,", addr),
+        };
+    }
+    (text, invalid, first_invalid)
+}
@@ -0,0 +1,94 @@
+//! `cloak bench`: how fast and how big the lookup index is on this machine.
+
+use std::{
+    hint::black_box,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    time::Instant,
+};
+
+use anyhow::Result;
+use ipnetwork::IpNetwork;
+
+use crate::{index::Index, read_map, ui::info};
+
+#[derive(clap::Args, Debug)]
+pub struct BenchArgs {
+    /// JSON map written by an earlier run (nested layout)
+    pub map: PathBuf,
+
+    /// Random lookups to time per address family
+    #[arg(long, value_name = "N", default_value_t = 1_000_000)]
+    pub lookups: u64,
+}
+
+pub fn run(args: &BenchArgs) -> Result<()> {
+    let map = read_map(&args.map)?;
+    let prefixes: usize = map.values().map(|nets| nets.ipv4.len() + nets.ipv6.len()).sum();
+
+    let started = Instant::now();
+    let index = Index::build(&map);
+    let build = started.elapsed();
+    let (v4_ranges, v6_ranges) = index.ranges();
+    info!(
+        "Indexed {} prefixes from {} countries in {:.1} ms ({} IPv4 and {} IPv6 ranges)",
+        prefixes,
+        map.len(),
+        build.as_secs_f64() * 1000.0,
+        v4_ranges,
+        v6_ranges
+    );
+    info!("Index memory: {:.2} MiB", index.heap_bytes() as f64 / (1024.0 * 1024.0));
+
+    // Half the addresses come from inside the dataset and half are uniformly
+    // random, so both hits and misses are timed
+    let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+    for (family, v6) in [("IPv4", false), ("IPv6", true)] {
+        let nets: Vec<IpNetwork> = map
+            .values()
+            .flat_map(|nets| if v6 { &nets.ipv6 } else { &nets.ipv4 })
+            .map(|net| net.0)
+            .collect();
+        let addrs: Vec<IpAddr> = (0..args.lookups).map(|_| sample(&mut rng, &nets, v6)).collect();
+        let started = Instant::now();
+        let hits = addrs.iter().filter(|addr| black_box(index.lookup(**addr)).is_some()).count();
+        let elapsed = started.elapsed().as_secs_f64();
+        info!(
+            "{}: {:.2} M lookups/s ({:.0} ns each), {:.1}% matched a country",
+            family,
+            args.lookups as f64 / elapsed / 1e6,
+            elapsed * 1e9 / args.lookups as f64,
+            hits as f64 * 100.0 / args.lookups as f64
+        );
+    }
+    Ok(())
+}
+
+fn sample(rng: &mut XorShift, nets: &[IpNetwork], v6: bool) -> IpAddr {
+    let bits = u128::from(rng.next()) << 64 | u128::from(rng.next());
+    let inside = (rng.next() & 1 == 0).then(|| nets.get(rng.next() as usize % nets.len().max(1))).flatten();
+    match (inside, v6) {
+        (Some(IpNetwork::V4(net)), _) => {
+            let host = (bits as u32).checked_shr(u32::from(net.prefix())).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(net.network()) | host))
+        }
+        (Some(IpNetwork::V6(net)), _) => {
+            let host = bits.checked_shr(u32::from(net.prefix())).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(net.network()) | host))
+        }
+        (None, false) => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+        (None, true) => IpAddr::V6(Ipv6Addr::from(bits)),
+    }
+}
+
+/// Deterministic generator so runs are comparable between machines
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
@@ -0,0 +1,267 @@
+//! `cloak bundle`: a generated ruleset, its JSON map and a description of
+//! both packed into one `.tar.zst`, optionally signed with an SSH key, to
+//! copy to other hosts and load there with `cloak apply`.
+//!
+//! The archive is written and read here; only the compression and the
+//! signature are left to the `zstd` and `ssh-keygen` tools.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    naming, nft,
+    ui::{info, success, warning},
+    unix_now,
+};
+
+/// The SSH signature namespace, so a key's signatures for other purposes
+/// cannot pass for a bundle's
+const NAMESPACE: &str = "cloak-bundle";
+
+const RULES: &str = "rules.nft";
+const MAP: &str = "map.json";
+const METADATA: &str = "metadata.json";
+const SIGNATURE: &str = "signature";
+
+#[derive(clap::Args, Debug)]
+pub struct BundleArgs {
+    /// Rule file generated by cloak (e.g. brics_block.nft)
+    rules: PathBuf,
+
+    /// JSON map to include (default: <list>_ip_map.json next to the rules,
+    /// if there is one)
+    #[arg(long, value_name = "FILE")]
+    map: Option<PathBuf>,
+
+    /// Where to write the bundle (default: the rule file's name with
+    /// .tar.zst)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// SSH private key to sign the bundle with; hosts check it with
+    /// `cloak apply --allowed-signers`
+    #[arg(long, value_name = "KEY")]
+    sign_key: Option<PathBuf>,
+}
+
+/// `metadata.json`: where the bundle came from
+#[derive(Serialize, Deserialize, Debug)]
+struct Metadata {
+    cloak_version: String,
+    created_at: u64,
+    host: String,
+    /// Name of the rule file the bundle was made from
+    source: String,
+    fingerprint: String,
+}
+
+pub fn run(args: &BundleArgs) -> Result<()> {
+    let ruleset = fs::read(&args.rules).with_context(|| format!("read {}", args.rules.display()))?;
+    let fingerprint = nft::check_own_ruleset(&String::from_utf8_lossy(&ruleset))
+        .with_context(|| format!("refusing to bundle {}", args.rules.display()))?;
+    let map_path = args.map.clone().or_else(|| {
+        let stem = args.rules.file_stem()?.to_string_lossy().into_owned();
+        let (list, _) = stem.rsplit_once('_')?;
+        Some(args.rules.with_file_name(format!("{}_ip_map.json", list))).filter(|path| path.exists())
+    });
+    let metadata = Metadata {
+        cloak_version: env!("CARGO_PKG_VERSION").to_string(),
+        created_at: unix_now(),
+        host: naming::hostname(),
+        source: args.rules.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        fingerprint,
+    };
+
+    let mut files = vec![(RULES, ruleset)];
+    if let Some(path) = &map_path {
+        files.push((MAP, fs::read(path).with_context(|| format!("read {}", path.display()))?));
+    }
+    files.push((METADATA, serde_json::to_vec_pretty(&metadata)?));
+    if let Some(key) = &args.sign_key {
+        files.push((SIGNATURE, sign(key, &signed_payload(&files))?));
+    }
+
+    let output = args.output.clone().unwrap_or_else(|| args.rules.with_extension("tar.zst"));
+    let archive = zstd(&["-q", "-c"], &tar(&files))?;
+    fs::write(&output, archive).with_context(|| format!("write {}", output.display()))?;
+    let names: Vec<&str> = files.iter().map(|(name, _)| *name).collect();
+    success!("Wrote {} ({}).", output.display(), names.join(", "));
+    info!("To load it on another host, run: cloak apply {}", output.display());
+    Ok(())
+}
+
+/// Whether `path` names a bundle rather than a rule file
+pub fn is_bundle(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(".tar.zst")
+}
+
+/// How `cloak apply` checks a bundle's signature
+pub struct Verify<'a> {
+    pub allowed_signers: Option<&'a Path>,
+    /// The principal to check against; found from the signature if unset
+    pub signer: Option<&'a str>,
+}
+
+/// Unpack the rule file of `bundle` into the state directory, checking its
+/// signature and fingerprint, and return its path
+pub fn extract(bundle: &Path, verify: &Verify, state_dir: &Path) -> Result<PathBuf> {
+    let archive = fs::read(bundle).with_context(|| format!("read {}", bundle.display()))?;
+    let files = untar(&zstd(&["-d", "-q", "-c"], &archive)?).with_context(|| format!("unpack {}", bundle.display()))?;
+    let file = |name: &str| files.iter().find(|(n, _)| n == name).map(|(_, data)| data.as_slice());
+    let ruleset = file(RULES).with_context(|| format!("{} has no {}", bundle.display(), RULES))?;
+    let metadata: Metadata = serde_json::from_slice(file(METADATA).with_context(|| format!("{} has no {}", bundle.display(), METADATA))?)
+        .with_context(|| format!("parse {} in {}", METADATA, bundle.display()))?;
+
+    match (file(SIGNATURE), verify.allowed_signers) {
+        (Some(signature), Some(allowed)) => {
+            let signed: Vec<(&str, Vec<u8>)> = [RULES, MAP, METADATA]
+                .into_iter()
+                .filter_map(|name| file(name).map(|data| (name, data.to_vec())))
+                .collect();
+            let signer = check_signature(&signed_payload(&signed), signature, allowed, verify.signer, state_dir)
+                .with_context(|| format!("bad signature on {}", bundle.display()))?;
+            info!("{} is signed by {}.", bundle.display(), signer);
+        }
+        (None, Some(_)) => bail!("{} is not signed", bundle.display()),
+        (Some(_), None) => warning!("{} is signed, but without --allowed-signers the signature is not checked", bundle.display()),
+        (None, None) => {}
+    }
+
+    let fingerprint = nft::check_own_ruleset(&String::from_utf8_lossy(ruleset))
+        .with_context(|| format!("refusing to load {}", bundle.display()))?;
+    if fingerprint != metadata.fingerprint {
+        bail!("{} in {} does not match its metadata", RULES, bundle.display());
+    }
+    info!("Bundle of {} made on {} by cloak {}.", metadata.source, metadata.host, metadata.cloak_version);
+    fs::create_dir_all(state_dir).with_context(|| format!("create {}", state_dir.display()))?;
+    let path = state_dir.join("bundle.nft");
+    fs::write(&path, ruleset).with_context(|| format!("write {}", path.display()))?;
+    Ok(path)
+}
+
+/// What the signature covers: every other file, with its name and length
+fn signed_payload(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for (name, data) in files.iter().filter(|(name, _)| *name != SIGNATURE) {
+        payload.extend_from_slice(format!("{} {}\n", name, data.len()).as_bytes());
+        payload.extend_from_slice(data);
+    }
+    payload
+}
+
+fn sign(key: &Path, payload: &[u8]) -> Result<Vec<u8>> {
+    let key = key.to_string_lossy();
+    pipe("ssh-keygen", &["-q", "-Y", "sign", "-n", NAMESPACE, "-f", &key], payload)
+}
+
+/// Check `signature` with `ssh-keygen -Y verify`, returning the signer
+fn check_signature(payload: &[u8], signature: &[u8], allowed: &Path, signer: Option<&str>, state_dir: &Path) -> Result<String> {
+    fs::create_dir_all(state_dir).with_context(|| format!("create {}", state_dir.display()))?;
+    let sig_path = state_dir.join("bundle.sig");
+    fs::write(&sig_path, signature).with_context(|| format!("write {}", sig_path.display()))?;
+    let (sig, allowed) = (sig_path.to_string_lossy(), allowed.to_string_lossy());
+    let result = (|| {
+        let signer = match signer {
+            Some(signer) => signer.to_string(),
+            None => {
+                let found = pipe("ssh-keygen", &["-Y", "find-principals", "-s", &sig, "-f", &allowed], &[])
+                    .context("the signing key is not in the allowed signers")?;
+                let found = String::from_utf8_lossy(&found);
+                found.lines().next().context("the signing key is not in the allowed signers")?.to_string()
+            }
+        };
+        pipe("ssh-keygen", &["-Y", "verify", "-n", NAMESPACE, "-f", &allowed, "-I", &signer, "-s", &sig], payload)?;
+        Ok(signer)
+    })();
+    let _ = fs::remove_file(&sig_path);
+    result
+}
+
+fn zstd(args: &[&str], input: &[u8]) -> Result<Vec<u8>> {
+    pipe("zstd", args, input)
+}
+
+/// Run `program` with `input` on stdin and return its stdout
+fn pipe(program: &str, args: &[&str], input: &[u8]) -> Result<Vec<u8>> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to execute {} (is it installed?)", program))?;
+    let mut stdin = child.stdin.take().context("stdin")?;
+    let input = input.to_vec();
+    // Written from another thread so a full stdout pipe cannot stall both
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()?;
+    let _ = writer.join();
+    if !output.status.success() {
+        let message = if output.stderr.is_empty() { &output.stdout } else { &output.stderr };
+        bail!("{} failed: {}", program, String::from_utf8_lossy(message).trim());
+    }
+    Ok(output.stdout)
+}
+
+/// A ustar archive of regular files
+fn tar(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mtime = unix_now();
+    let mut out = Vec::new();
+    for (name, data) in files {
+        let mut header = [0u8; 512];
+        let mut field = |offset: usize, value: &[u8]| header[offset..offset + value.len()].copy_from_slice(value);
+        field(0, name.as_bytes());
+        field(100, b"0000644\0");
+        field(108, b"0000000\0");
+        field(116, b"0000000\0");
+        field(124, format!("{:011o}\0", data.len()).as_bytes());
+        field(136, format!("{:011o}\0", mtime).as_bytes());
+        field(148, b"        ");
+        field(156, b"0");
+        field(257, b"ustar\x0000");
+        let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+        out.extend_from_slice(&header);
+        out.extend_from_slice(data);
+        out.resize(out.len().div_ceil(512) * 512, 0);
+    }
+    out.resize(out.len() + 1024, 0);
+    out
+}
+
+/// The regular files in a tar archive
+fn untar(archive: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut files = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = archive.get(offset..offset + 512) {
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let text = |range: std::ops::Range<usize>| {
+            let field = &header[range];
+            let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..end]).trim().to_string()
+        };
+        let checksum: u32 = header.iter().enumerate().map(|(i, &b)| if (148..156).contains(&i) { 32 } else { u32::from(b) }).sum();
+        if u32::from_str_radix(&text(148..156), 8).ok() != Some(checksum) {
+            bail!("corrupt archive header at byte {}", offset);
+        }
+        let name = text(0..100);
+        let size = usize::from_str_radix(&text(124..136), 8).with_context(|| format!("bad size for {}", name))?;
+        let data = archive
+            .get(offset + 512..offset + 512 + size)
+            .with_context(|| format!("archive ends inside {}", name))?;
+        if matches!(header[156], b'0' | 0) {
+            files.push((name.trim_start_matches("./").to_string(), data.to_vec()));
+        }
+        offset += 512 + size.div_ceil(512) * 512;
+    }
+    Ok(files)
+}
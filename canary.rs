@@ -0,0 +1,120 @@
+//! `cloak apply --canary`: load new rules on probation and put the previous
+//! ones back unless someone confirms them in time, so an aggressive allow
+//! policy cannot lock out the only person who could undo it.
+//!
+//! The previous rules are the listing of cloak's table taken just before
+//! the load. With `--health-url` the URL is fetched throughout the window
+//! instead: the rules stay if it answers the whole time and are reverted
+//! the moment it does not. Typing `keep` ends the window early either way,
+//! and interrupting the command reverts.
+
+use std::{
+    io::{self, IsTerminal},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::{
+    daemon, fetch, format_duration, nft, parse_duration,
+    ui::{info, warning},
+};
+
+/// How often `--health-url` is fetched
+const HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Give up on one health check after this long
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(clap::Args, Debug)]
+pub struct CanaryArgs {
+    /// Revert to the rules loaded before unless the new ones are confirmed
+    /// within this long (e.g. 10m)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, conflicts_with = "attach")]
+    pub canary: Option<Duration>,
+
+    /// During --canary, keep the rules if this URL answers with a success
+    /// status throughout instead of asking, and revert as soon as it does
+    /// not
+    #[arg(long, value_name = "URL", requires = "canary")]
+    pub health_url: Option<String>,
+}
+
+/// Fail early when nobody could confirm the rules
+pub fn check(args: &CanaryArgs) -> Result<()> {
+    if args.canary.is_some() && args.health_url.is_none() && !(cfg!(feature = "interactive") && io::stdin().is_terminal()) {
+        bail!("--canary needs a terminal to confirm the rules on, or a --health-url");
+    }
+    Ok(())
+}
+
+/// The listing of cloak's table to go back to, `None` when none is loaded
+pub fn snapshot() -> Option<String> {
+    nft::list_table(nft::TABLE).ok()
+}
+
+/// Wait out the window after the new rules were loaded; whether they are
+/// to stay
+pub async fn confirmed(args: &CanaryArgs) -> Result<bool> {
+    let Some(window) = args.canary else { return Ok(true) };
+    let client = match &args.health_url {
+        Some(_) => Some(fetch::client_builder()?.timeout(HEALTH_TIMEOUT).build().context("build HTTP client")?),
+        None => None,
+    };
+    match &args.health_url {
+        Some(url) => info!("Keeping the new rules while {} answers, reverting otherwise; type `keep` to keep them now.", url),
+        None => info!(
+            "Type `keep` within {} to keep the new rules; otherwise the previous ones are put back.",
+            format_duration(window)
+        ),
+    }
+    let deadline = tokio::time::sleep(window);
+    tokio::pin!(deadline);
+    let mut health = tokio::time::interval(HEALTH_INTERVAL);
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut reading = io::stdin().is_terminal();
+    let shutdown = daemon::shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => {
+                if client.is_none() {
+                    warning!("The new rules were not confirmed within {}.", format_duration(window));
+                }
+                return Ok(client.is_some());
+            }
+            _ = &mut shutdown => {
+                warning!("Interrupted before the new rules were confirmed.");
+                return Ok(false);
+            }
+            line = lines.next_line(), if reading => match line {
+                Ok(Some(line)) if line.trim() == "keep" => return Ok(true),
+                Ok(Some(_)) => info!("Type `keep` to keep the new rules."),
+                _ => reading = false,
+            },
+            _ = health.tick(), if client.is_some() => {
+                let (Some(client), Some(url)) = (&client, &args.health_url) else { continue };
+                let failure = match client.get(url).send().await {
+                    Ok(response) if response.status().is_success() => None,
+                    Ok(response) => Some(format!("HTTP {}", response.status())),
+                    Err(e) => Some(e.to_string()),
+                };
+                if let Some(failure) = failure {
+                    warning!("{} stopped answering with the new rules loaded: {}", url, failure);
+                    return Ok(false);
+                }
+            }
+        }
+    }
+}
+
+/// Put back the table listed by [`snapshot`], or remove cloak's table if
+/// there was none
+pub fn revert(previous: Option<&str>) -> Result<()> {
+    let script = match previous {
+        Some(listing) => format!("table inet {table}\ndelete table inet {table}\n{}", listing, table = nft::TABLE),
+        None => format!("delete table inet {}\n", nft::TABLE),
+    };
+    nft::run_script(&script).context("put back the previous rules")
+}
@@ -0,0 +1,102 @@
+//! Address space of CDNs and anycast networks, which shows up under
+//! whatever country their POPs are registered in.
+//!
+//! The lists are snapshots of what the operators publish (Cloudflare and
+//! Fastly) or announce (Akamai's AS20940); check them against the
+//! operator's current list when updating.
+
+use clap::ValueEnum;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum CdnList {
+    Cloudflare,
+    Akamai,
+    Fastly,
+}
+
+impl CdnList {
+    /// Prefixes of the network, IPv4 first
+    pub fn prefixes(self) -> &'static [&'static str] {
+        match self {
+            CdnList::Cloudflare => CLOUDFLARE,
+            CdnList::Akamai => AKAMAI,
+            CdnList::Fastly => FASTLY,
+        }
+    }
+}
+
+/// https://www.cloudflare.com/ips/
+const CLOUDFLARE: &[&str] = &[
+    "103.21.244.0/22",
+    "103.22.200.0/22",
+    "103.31.4.0/22",
+    "104.16.0.0/13",
+    "104.24.0.0/14",
+    "108.162.192.0/18",
+    "131.0.72.0/22",
+    "141.101.64.0/18",
+    "162.158.0.0/15",
+    "172.64.0.0/13",
+    "173.245.48.0/20",
+    "188.114.96.0/20",
+    "190.93.240.0/20",
+    "197.234.240.0/22",
+    "198.41.128.0/17",
+    "2400:cb00::/32",
+    "2405:8100::/32",
+    "2405:b500::/32",
+    "2606:4700::/32",
+    "2803:f800::/32",
+    "2a06:98c0::/29",
+    "2c0f:f248::/32",
+];
+
+/// Main aggregates announced by AS20940
+const AKAMAI: &[&str] = &[
+    "2.16.0.0/13",
+    "23.0.0.0/12",
+    "23.32.0.0/11",
+    "23.64.0.0/14",
+    "23.72.0.0/13",
+    "23.192.0.0/11",
+    "69.192.0.0/16",
+    "72.246.0.0/15",
+    "88.221.0.0/16",
+    "92.122.0.0/15",
+    "95.100.0.0/15",
+    "96.6.0.0/15",
+    "96.16.0.0/15",
+    "104.64.0.0/10",
+    "118.214.0.0/16",
+    "173.222.0.0/15",
+    "184.24.0.0/13",
+    "184.50.0.0/15",
+    "184.84.0.0/14",
+    "2600:1400::/24",
+    "2a02:26f0::/29",
+];
+
+/// https://api.fastly.com/public-ip-list
+const FASTLY: &[&str] = &[
+    "23.235.32.0/20",
+    "43.249.72.0/22",
+    "103.244.50.0/24",
+    "103.245.222.0/23",
+    "103.245.224.0/24",
+    "104.156.80.0/20",
+    "140.248.64.0/18",
+    "140.248.128.0/17",
+    "146.75.0.0/17",
+    "151.101.0.0/16",
+    "157.52.64.0/18",
+    "167.82.0.0/17",
+    "167.82.128.0/20",
+    "167.82.160.0/20",
+    "167.82.224.0/20",
+    "172.111.64.0/18",
+    "185.31.16.0/22",
+    "199.27.72.0/21",
+    "199.232.0.0/16",
+    "2a04:4e40::/32",
+    "2a04:4e42::/32",
+];
@@ -0,0 +1,194 @@
+//! Prefix arithmetic: taking one set of prefixes out of another, merging
+//! prefixes into the fewest that cover the same space.
+//!
+//! The prefixes to remove are merged into sorted, disjoint address ranges
+//! once, so subtracting them from many prefixes costs a binary search per
+//! prefix plus the size of the result.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnetwork::IpNetwork;
+
+/// Address space to take out of other prefixes
+pub struct Exclusions {
+    ipv4: Vec<(u128, u128)>,
+    ipv6: Vec<(u128, u128)>,
+}
+
+impl Exclusions {
+    pub fn new<'a>(nets: impl IntoIterator<Item = &'a IpNetwork>) -> Self {
+        let (mut ipv4, mut ipv6) = (Vec::new(), Vec::new());
+        for net in nets {
+            if net.is_ipv4() { &mut ipv4 } else { &mut ipv6 }.push(bounds(net));
+        }
+        Exclusions { ipv4: merge(ipv4), ipv6: merge(ipv6) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ipv4.is_empty() && self.ipv6.is_empty()
+    }
+
+    /// Whether any excluded address lies within `net`
+    pub fn overlaps(&self, net: &IpNetwork) -> bool {
+        let (start, end) = bounds(net);
+        let ranges = self.family(net);
+        let i = ranges.partition_point(|&(_, e)| e < start);
+        ranges.get(i).is_some_and(|&(s, _)| s <= end)
+    }
+
+    /// Whether `net` lies entirely within the excluded space
+    pub fn covers(&self, net: &IpNetwork) -> bool {
+        let (start, end) = bounds(net);
+        let ranges = self.family(net);
+        let i = ranges.partition_point(|&(_, e)| e < start);
+        ranges.get(i).is_some_and(|&(s, e)| s <= start && end <= e)
+    }
+
+    /// Append what is left of `net` after removing the excluded space, as
+    /// the fewest prefixes, in address order.
+    pub fn subtract(&self, net: &IpNetwork, out: &mut Vec<IpNetwork>) {
+        let (start, end) = bounds(net);
+        let ranges = self.family(net);
+        let mut cursor = Some(start);
+        for &(s, e) in &ranges[ranges.partition_point(|&(_, e)| e < start)..] {
+            let Some(from) = cursor else { break };
+            if s > end {
+                break;
+            }
+            if s > from {
+                push_range(from, s - 1, net.is_ipv6(), out);
+            }
+            cursor = e.checked_add(1).filter(|&next| next <= end);
+        }
+        if let Some(from) = cursor {
+            push_range(from, end, net.is_ipv6(), out);
+        }
+    }
+
+    fn family(&self, net: &IpNetwork) -> &[(u128, u128)] {
+        if net.is_ipv4() {
+            &self.ipv4
+        } else {
+            &self.ipv6
+        }
+    }
+}
+
+/// The fewest prefixes covering exactly the space of `nets`, IPv4 first,
+/// in address order
+pub fn aggregate<'a>(nets: impl IntoIterator<Item = &'a IpNetwork>) -> Vec<IpNetwork> {
+    let space = Exclusions::new(nets);
+    let mut out = Vec::new();
+    for &(start, end) in &space.ipv4 {
+        push_range(start, end, false, &mut out);
+    }
+    for &(start, end) in &space.ipv6 {
+        push_range(start, end, true, &mut out);
+    }
+    out
+}
+
+/// The fewest prefixes covering `first` to `last`, which must be of the
+/// same family
+pub fn range(first: IpAddr, last: IpAddr) -> Vec<IpNetwork> {
+    let (start, end) = (bounds(&IpNetwork::from(first)).0, bounds(&IpNetwork::from(last)).0);
+    let mut out = Vec::new();
+    if first.is_ipv4() == last.is_ipv4() && start <= end {
+        push_range(start, end, first.is_ipv6(), &mut out);
+    }
+    out
+}
+
+/// `net` cut into the prefixes of length `prefix` it contains, in address
+/// order; `net` itself, without host bits, if it is already that long or
+/// longer
+pub fn split(net: IpNetwork, prefix: u8) -> Vec<IpNetwork> {
+    if net.prefix() >= prefix {
+        return vec![IpNetwork::new(net.network(), net.prefix()).expect("prefix length of a valid network")];
+    }
+    let (start, _) = bounds(&net);
+    let width = if net.is_ipv4() { 32 } else { 128 };
+    let step = 1u128 << (width - prefix);
+    (0..1u128 << (prefix - net.prefix()))
+        .map(|i| {
+            let first = start + i * step;
+            let addr = if net.is_ipv4() { IpAddr::V4(Ipv4Addr::from(first as u32)) } else { IpAddr::V6(Ipv6Addr::from(first)) };
+            IpNetwork::new(addr, prefix).expect("prefix length within the family")
+        })
+        .collect()
+}
+
+/// The first and last address of a range as feeds write it:
+/// `first - last`, `first-last`, or the first two fields of a CSV line
+/// (`"16777216","16777471","AU",...` as in IP2Location's files), each an
+/// address or its decimal number
+pub fn parse_range(text: &str) -> Option<(IpAddr, IpAddr)> {
+    let (first, last) = match text.split_once(',') {
+        Some((first, rest)) => (first, rest.split(',').next()?),
+        None => text.split_once('-')?,
+    };
+    let (first, last) = (range_bound(first)?, range_bound(last)?);
+    // Decimal IPv4 fits either family; follow the other bound
+    match (first, last) {
+        (IpAddr::V6(a), IpAddr::V6(b)) if (a.to_ipv4_mapped().is_some()) != (b.to_ipv4_mapped().is_some()) => None,
+        (a, b) => Some((a.to_canonical(), b.to_canonical())),
+    }
+}
+
+fn range_bound(text: &str) -> Option<IpAddr> {
+    let text = text.trim().trim_matches('"');
+    if let Ok(addr) = text.parse::<IpAddr>() {
+        return Some(addr);
+    }
+    let number: u128 = text.parse().ok()?;
+    Some(match u32::try_from(number) {
+        Ok(v4) => IpAddr::V4(Ipv4Addr::from(v4)),
+        Err(_) => IpAddr::V6(Ipv6Addr::from(number)),
+    })
+}
+
+/// First and last address of `net`
+pub fn bounds(net: &IpNetwork) -> (u128, u128) {
+    let (start, host_bits) = match net {
+        IpNetwork::V4(n) => (u128::from(u32::from(n.network())), 32 - u32::from(n.prefix())),
+        IpNetwork::V6(n) => (u128::from(n.network()), 128 - u32::from(n.prefix())),
+    };
+    let span = if host_bits == 128 { u128::MAX } else { (1u128 << host_bits) - 1 };
+    (start, start + span)
+}
+
+/// Sort ranges and join the overlapping or adjacent ones
+fn merge(mut ranges: Vec<(u128, u128)>) -> Vec<(u128, u128)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if last.1.checked_add(1).is_none_or(|next| start <= next) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Cover `[start, end]` with the fewest aligned prefixes
+fn push_range(mut start: u128, end: u128, v6: bool, out: &mut Vec<IpNetwork>) {
+    let width = if v6 { 128 } else { 32 };
+    loop {
+        // Largest block aligned at `start` that does not run past `end`
+        let mut bits = start.trailing_zeros().min(width);
+        while bits > 0 && (bits == 128 || start + ((1u128 << bits) - 1) > end) {
+            bits -= 1;
+        }
+        let addr = if v6 {
+            IpAddr::V6(Ipv6Addr::from(start))
+        } else {
+            IpAddr::V4(Ipv4Addr::from(start as u32))
+        };
+        out.push(IpNetwork::new(addr, (width - bits) as u8).expect("prefix length within the family"));
+        let last = if bits == 0 { start } else { start + ((1u128 << bits) - 1) };
+        match last.checked_add(1) {
+            Some(next) if next <= end => start = next,
+            _ => return,
+        }
+    }
+}
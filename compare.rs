@@ -0,0 +1,258 @@
+//! `cloak compare-live`: what differs between a generated rule file and the
+//! rules in the kernel, to audit drift before deciding to re-apply.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use anyhow::{bail, Context, Result};
+use ipnetwork::IpNetwork;
+
+use crate::{
+    attach,
+    cidr::{self, Exclusions},
+    lint::{self, Stmt},
+    privilege,
+    ui::success,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct CompareLiveArgs {
+    /// Rule file generated by cloak (e.g. by `generate` or the daemon)
+    rules: PathBuf,
+
+    /// How many differing elements to list per set
+    #[arg(long, value_name = "N", default_value_t = 20)]
+    max_elements: usize,
+}
+
+/// A set's declaration and the prefixes in it
+struct Set {
+    /// `type` and `flags` statements
+    definition: Vec<String>,
+    /// Sets with timeouts change on their own and are not compared
+    dynamic: bool,
+    elements: Vec<IpNetwork>,
+}
+
+/// cloak's sets and chains in a ruleset
+#[derive(Default)]
+struct Objects {
+    sets: BTreeMap<String, Set>,
+    chains: BTreeMap<String, Vec<String>>,
+}
+
+pub fn run(args: &CompareLiveArgs, state_dir: &Path) -> Result<()> {
+    let ruleset = fs::read_to_string(&args.rules).with_context(|| format!("read {}", args.rules.display()))?;
+    let (table, prefix) = attach::location(state_dir);
+    // Attached rules are loaded renamed, with `return` instead of `accept`
+    let expected = if prefix.is_empty() { ruleset } else { attach::rewrite(&ruleset, &table)?.0 };
+    let output = privilege::command("nft")
+        .args(["list", "table", "inet", &table])
+        .stderr(Stdio::null())
+        .output()
+        .context("failed to execute nft command")?;
+    if !output.status.success() {
+        bail!("table inet {} is not loaded", table);
+    }
+    let expected = objects(&lint::parse_nft(&expected), prefix);
+    let live = objects(&lint::parse_nft(&String::from_utf8_lossy(&output.stdout)), prefix);
+
+    let mut differences = 0;
+    for (name, want) in &expected.sets {
+        let Some(have) = live.sets.get(name) else {
+            println!("set {}: not loaded", name);
+            differences += 1;
+            continue;
+        };
+        if want.definition != have.definition {
+            println!("set {}: changed from `{}` to `{}`", name, want.definition.join("; "), have.definition.join("; "));
+            differences += 1;
+        }
+        if want.dynamic {
+            continue;
+        }
+        let missing = subtract(&want.elements, &have.elements);
+        let extra = subtract(&have.elements, &want.elements);
+        if missing.is_empty() && extra.is_empty() {
+            continue;
+        }
+        differences += missing.len() + extra.len();
+        println!("set {}: {} prefixes missing from the kernel, {} extra", name, missing.len(), extra.len());
+        for (sign, nets) in [("-", &missing), ("+", &extra)] {
+            for net in nets.iter().take(args.max_elements) {
+                println!("  {} {}", sign, net);
+            }
+            if nets.len() > args.max_elements {
+                println!("  {} ... and {} more", sign, nets.len() - args.max_elements);
+            }
+        }
+    }
+    for name in live.sets.keys().filter(|name| !expected.sets.contains_key(*name)) {
+        println!("set {}: loaded but not in {}", name, args.rules.display());
+        differences += 1;
+    }
+
+    for (name, want) in &expected.chains {
+        let Some(have) = live.chains.get(name) else {
+            println!("chain {}: not loaded", name);
+            differences += 1;
+            continue;
+        };
+        let edits = diff(want, have);
+        if edits.is_empty() {
+            continue;
+        }
+        println!("chain {}:", name);
+        for edit in &edits {
+            match edit {
+                Edit::Removed(rule) => println!("  - {}", rule),
+                Edit::Added(rule) => println!("  + {}", rule),
+                Edit::Changed(was, now) => println!("  ~ {}\n    now: {}", was, now),
+            }
+        }
+        differences += edits.len();
+    }
+    for name in live.chains.keys().filter(|name| !expected.chains.contains_key(*name)) {
+        println!("chain {}: loaded but not in {}", name, args.rules.display());
+        differences += 1;
+    }
+
+    if differences > 0 {
+        bail!("{} differences between {} and table inet {}", differences, args.rules.display(), table);
+    }
+    success!("Table inet {} matches {}.", table, args.rules.display());
+    Ok(())
+}
+
+/// The sets and chains named with `prefix` in the last table of `stmts`
+fn objects(stmts: &[Stmt], prefix: &str) -> Objects {
+    let mut objects = Objects::default();
+    let Some(body) = stmts.iter().rev().find(|s| s.words.first().is_some_and(|w| w == "table")).and_then(|s| s.body.as_ref()) else {
+        return objects;
+    };
+    for stmt in body {
+        let (Some(kind), Some(name), Some(inner)) = (stmt.words.first(), stmt.words.get(1), &stmt.body) else {
+            continue;
+        };
+        if !name.starts_with(prefix) {
+            continue;
+        }
+        match kind.as_str() {
+            "set" => {
+                let mut set = Set { definition: Vec::new(), dynamic: false, elements: Vec::new() };
+                for part in inner {
+                    match part.words[0].as_str() {
+                        "type" | "flags" => {
+                            set.dynamic |= part.words.iter().any(|w| w == "timeout");
+                            set.definition.push(part.words.join(" "));
+                        }
+                        "elements" => set.elements = prefixes(&part.elements),
+                        _ => {}
+                    }
+                }
+                objects.sets.insert(name.clone(), set);
+            }
+            "chain" => {
+                let rules = inner.iter().filter_map(rule).collect();
+                objects.chains.insert(name.clone(), rules);
+            }
+            _ => {}
+        }
+    }
+    objects
+}
+
+/// A rule as text that compares equal between a rule file and a listing
+fn rule(stmt: &Stmt) -> Option<String> {
+    let words = &stmt.words;
+    match words[0].as_str() {
+        // The hook is what matters; nft lists priorities by name
+        "type" => Some(words.iter().take(4).cloned().collect::<Vec<_>>().join(" ")),
+        "policy" if words.get(1).is_some_and(|p| p == "accept") => None,
+        _ => {
+            let mut out = Vec::new();
+            let mut rest = words.iter().peekable();
+            while let Some(word) = rest.next() {
+                out.push(word.as_str());
+                if word == "counter" {
+                    while rest.next_if(|w| matches!(w.as_str(), "packets" | "bytes") || w.parse::<u64>().is_ok()).is_some() {}
+                }
+            }
+            Some(out.join(" "))
+        }
+    }
+}
+
+/// Set elements as prefixes, ranges split up
+pub fn prefixes(elements: &[(String, usize)]) -> Vec<IpNetwork> {
+    let mut nets = Vec::new();
+    for (text, _) in elements {
+        if let Ok(net) = text.parse::<IpNetwork>() {
+            nets.push(net);
+        } else if let Some((first, last)) = text.split_once('-') {
+            if let (Ok(first), Ok(last)) = (first.parse::<IpAddr>(), last.parse::<IpAddr>()) {
+                nets.extend(cidr::range(first, last));
+            }
+        }
+    }
+    nets
+}
+
+/// The space of `nets` not in `minus`, as the fewest prefixes
+fn subtract(nets: &[IpNetwork], minus: &[IpNetwork]) -> Vec<IpNetwork> {
+    let minus = Exclusions::new(minus);
+    let mut left = Vec::new();
+    for net in nets {
+        minus.subtract(net, &mut left);
+    }
+    cidr::aggregate(&left)
+}
+
+enum Edit<'a> {
+    Removed(&'a str),
+    Added(&'a str),
+    Changed(&'a str, &'a str),
+}
+
+/// How to get from the rules in `want` to those in `have`; a removal
+/// followed by an addition at the same place counts as a change
+fn diff<'a>(want: &'a [String], have: &'a [String]) -> Vec<Edit<'a>> {
+    // Longest common subsequence; chains are short
+    let (n, m) = (want.len(), have.len());
+    let mut common = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = if want[i] == have[j] { common[i + 1][j + 1] + 1 } else { common[i + 1][j].max(common[i][j + 1]) };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    let mut edits = Vec::new();
+    let flush = |removed: &mut Vec<&'a str>, added: &mut Vec<&'a str>, edits: &mut Vec<Edit<'a>>| {
+        let paired = removed.len().min(added.len());
+        edits.extend(removed.iter().zip(added.iter()).map(|(was, now)| Edit::Changed(was, now)));
+        edits.extend(removed.drain(..).skip(paired).map(Edit::Removed));
+        edits.extend(added.drain(..).skip(paired).map(Edit::Added));
+    };
+    while i < n || j < m {
+        if i < n && j < m && want[i] == have[j] {
+            flush(&mut removed, &mut added, &mut edits);
+            i += 1;
+            j += 1;
+        } else if j == m || i < n && common[i + 1][j] >= common[i][j + 1] {
+            removed.push(want[i].as_str());
+            i += 1;
+        } else {
+            added.push(have[j].as_str());
+            j += 1;
+        }
+    }
+    flush(&mut removed, &mut added, &mut edits);
+    edits
+}
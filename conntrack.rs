@@ -0,0 +1,65 @@
+//! Flushing of tracked connections after a block policy is loaded.
+
+use std::{collections::{BTreeSet, HashMap}, net::IpAddr};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{privilege, CountryNets};
+
+/// Delete tracked connections whose peer lies in one of the blocked sets.
+///
+/// nftables rules only see new packets, so sessions established before the
+/// block was loaded would otherwise keep running. Returns the number of
+/// distinct peer addresses whose flows were deleted.
+pub fn flush_conntrack(map: &HashMap<String, CountryNets>) -> Result<usize> {
+    let output = privilege::command("conntrack")
+        .arg("-L")
+        .output()
+        .context("failed to execute conntrack (is conntrack-tools installed?)")?;
+    if !output.status.success() {
+        bail!(
+            "conntrack -L failed: {}{}",
+            String::from_utf8_lossy(&output.stderr).trim(),
+            privilege::hint()
+        );
+    }
+
+    // Each entry lists the original direction first, so the first src=/dst=
+    // pair identifies the two endpoints regardless of who initiated it.
+    let listing = String::from_utf8_lossy(&output.stdout);
+    let mut peers = BTreeSet::new();
+    for line in listing.lines() {
+        for key in ["src=", "dst="] {
+            let addr = line
+                .split_whitespace()
+                .find_map(|field| field.strip_prefix(key))
+                .and_then(|value| value.parse::<IpAddr>().ok());
+            if let Some(addr) = addr {
+                if is_blocked(map, addr) {
+                    peers.insert(addr);
+                }
+            }
+        }
+    }
+
+    for peer in &peers {
+        // conntrack exits non-zero when nothing matched, which is expected
+        // for the direction the flow wasn't initiated in.
+        for flag in ["-s", "-d"] {
+            privilege::command("conntrack")
+                .arg("-D")
+                .arg(flag)
+                .arg(peer.to_string())
+                .output()
+                .context("failed to execute conntrack")?;
+        }
+    }
+    Ok(peers.len())
+}
+
+fn is_blocked(map: &HashMap<String, CountryNets>, addr: IpAddr) -> bool {
+    map.values().any(|nets| {
+        let family = if addr.is_ipv4() { &nets.ipv4 } else { &nets.ipv6 };
+        family.iter().any(|net| net.0.contains(addr))
+    })
+}
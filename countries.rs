@@ -0,0 +1,73 @@
+//! `cloak countries`: every country code cloak knows, its name and the
+//! built-in groups it belongs to.
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{
+    groups::ListChoice,
+    iso3166::{self, COUNTRIES},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct CountriesArgs {
+    /// Only countries whose code or name contains this (accents and case
+    /// do not matter)
+    filter: Option<String>,
+
+    /// Only members of this built-in group
+    #[arg(long, value_enum)]
+    group: Option<ListChoice>,
+
+    /// Print a JSON array instead of a table
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    code: &'static str,
+    name: &'static str,
+    groups: Vec<String>,
+}
+
+pub fn run(args: &CountriesArgs) -> Result<()> {
+    let filter = args.filter.as_deref().map(iso3166::normalize);
+    // Named as --group takes them
+    let lists: Vec<(ListChoice, String, Vec<String>)> = ListChoice::value_variants()
+        .iter()
+        .filter_map(|&list| {
+            let codes = list.members().into_iter().map(|(cc, _)| cc).collect();
+            Some((list, list.to_possible_value()?.get_name().to_string(), codes))
+        })
+        .collect();
+    let mut entries = Vec::new();
+    for &(code, name) in COUNTRIES {
+        if filter.as_ref().is_some_and(|filter| *filter != code && !iso3166::normalize(name).contains(filter.as_str())) {
+            continue;
+        }
+        let member_of: Vec<&(ListChoice, String, _)> =
+            lists.iter().filter(|(_, _, codes)| codes.iter().any(|cc| cc == code)).collect();
+        if args.group.is_some_and(|group| !member_of.iter().any(|(list, _, _)| *list == group)) {
+            continue;
+        }
+        entries.push(Entry { code, name, groups: member_of.iter().map(|(_, name, _)| name.clone()).collect() });
+    }
+
+    if entries.is_empty() {
+        bail!("no country matches {}", args.filter.as_deref().unwrap_or("the filter"));
+    }
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+    let width = entries.iter().map(|entry| entry.name.chars().count()).max().unwrap_or(0);
+    println!("{:<4} {:<width$} Groups", "CC", "Country", width = width);
+    for entry in &entries {
+        let padding = width - entry.name.chars().count();
+        let line = format!("{:<4} {}{} {}", entry.code.to_uppercase(), entry.name, " ".repeat(padding), entry.groups.join(", "));
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
@@ -0,0 +1,210 @@
+//! CrowdSec bouncer: keeps the community's ban decisions in cloak's
+//! `crowdsec_` sets, next to the country sets.
+//!
+//! Register cloak with the Local API first and keep the key it prints:
+//!
+//! ```text
+//! cscli bouncers add cloak > /etc/cloak/crowdsec.key
+//! cloak crowdsec --api-key-file /etc/cloak/crowdsec.key
+//! ```
+//!
+//! The bouncer pulls the decision stream, adds `ban` decisions on IP and
+//! range scope with their remaining duration as timeout and takes deleted
+//! ones out again. The rules that drop those sets come with every ruleset
+//! cloak generates, so load one (`cloak apply`, `cloak daemon`) as usual.
+
+use std::{
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use ipnetwork::IpNetwork;
+use serde::Deserialize;
+
+use crate::{
+    daemon, fetch, parse_duration,
+    state::RunLock,
+    temp::{self, Block},
+    ui::{info, warning},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct CrowdSecArgs {
+    /// Address of the CrowdSec Local API
+    #[arg(long, value_name = "URL", default_value = "http://127.0.0.1:8080")]
+    lapi_url: String,
+
+    /// File holding the bouncer's API key (from `cscli bouncers add`)
+    #[arg(long, value_name = "FILE")]
+    api_key_file: PathBuf,
+
+    /// How often to pull new and deleted decisions
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    interval: Duration,
+
+    #[command(flatten)]
+    http: fetch::HttpArgs,
+}
+
+/// One answer of `/v1/decisions/stream`; either list may be `null`
+#[derive(Deserialize)]
+struct Stream {
+    #[serde(default)]
+    new: Option<Vec<Decision>>,
+    #[serde(default)]
+    deleted: Option<Vec<Decision>>,
+}
+
+#[derive(Deserialize)]
+struct Decision {
+    scope: String,
+    value: String,
+    #[serde(rename = "type")]
+    kind: String,
+    duration: String,
+}
+
+impl Decision {
+    /// The prefix this decision bans, for the scopes and types cloak acts on
+    fn net(&self) -> Option<IpNetwork> {
+        if !self.kind.eq_ignore_ascii_case("ban") {
+            return None;
+        }
+        match self.scope.to_ascii_lowercase().as_str() {
+            "ip" => self.value.parse::<IpAddr>().ok().map(IpNetwork::from),
+            "range" => self.value.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+pub async fn run(args: &CrowdSecArgs, state_dir: &Path) -> Result<()> {
+    if !cfg!(target_os = "linux") {
+        bail!("the CrowdSec bouncer needs nftables and is only supported on Linux");
+    }
+    let key = fs::read_to_string(&args.api_key_file)
+        .with_context(|| format!("read {}", args.api_key_file.display()))?;
+    let key = key.trim();
+    if key.is_empty() {
+        bail!("{} is empty", args.api_key_file.display());
+    }
+    let client = fetch::client(&args.http)?;
+
+    // The startup pull returns every active decision, so it replaces
+    // whatever an earlier run left behind
+    let stream = pull(&client, &args.lapi_url, key, true).await?;
+    let mut blocks = Vec::new();
+    apply(&mut blocks, stream, true, state_dir)?;
+    info!(
+        "Enforcing {} CrowdSec decisions (polling {} every {})",
+        blocks.len(),
+        args.lapi_url,
+        crate::format_duration(args.interval)
+    );
+
+    let mut tick = tokio::time::interval(args.interval);
+    tick.tick().await;
+    let shutdown = daemon::shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {
+                let result = match pull(&client, &args.lapi_url, key, false).await {
+                    Ok(stream) => apply(&mut blocks, stream, false, state_dir),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    warning!("CrowdSec update failed, keeping the current decisions: {:#}", e);
+                }
+            }
+            _ = &mut shutdown => {
+                info!("Shutting down; decisions stay loaded until they expire");
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn pull(client: &reqwest::Client, lapi_url: &str, key: &str, startup: bool) -> Result<Stream> {
+    let url = format!("{}/v1/decisions/stream?startup={}", lapi_url.trim_end_matches('/'), startup);
+    client
+        .get(&url)
+        .header("X-Api-Key", key)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("GET {}", url))?
+        .json()
+        .await
+        .with_context(|| format!("parse the decisions from {}", url))
+}
+
+/// Fold a stream answer into `blocks` and update the sets if anything
+/// changed, or regardless when `startup` is set.
+fn apply(blocks: &mut Vec<Block>, stream: Stream, startup: bool, state_dir: &Path) -> Result<()> {
+    let before = blocks.len();
+    let deleted: Vec<IpNetwork> = stream.deleted.iter().flatten().filter_map(Decision::net).collect();
+    let now = crate::unix_now();
+    blocks.retain(|block| block.expires > now && !deleted.iter().any(|net| same(net, &block.net)));
+    let mut changed = blocks.len() != before;
+
+    for decision in stream.new.iter().flatten() {
+        let (Some(net), Some(duration)) = (decision.net(), parse_go_duration(&decision.duration)) else {
+            continue;
+        };
+        let block = Block::lasting(net, duration);
+        if block.expires <= now {
+            continue;
+        }
+        match blocks.iter_mut().find(|known| known.net == block.net) {
+            Some(known) if known.expires >= block.expires => continue,
+            Some(known) => known.expires = block.expires,
+            None => blocks.push(block),
+        }
+        changed = true;
+    }
+    if changed || startup {
+        let _lock = RunLock::acquire(state_dir, true)?;
+        temp::CROWDSEC.replace(blocks, state_dir)?;
+    }
+    if changed && !startup {
+        info!("CrowdSec: {} active decisions", blocks.len());
+    }
+    Ok(())
+}
+
+/// Whether two prefixes are the same network, ignoring host bits
+fn same(a: &IpNetwork, b: &IpNetwork) -> bool {
+    a.network() == b.network() && a.prefix() == b.prefix()
+}
+
+/// Parse Go's duration format, which the LAPI uses (`3h59m58.51s`).
+/// Negative durations are decisions that already ran out.
+fn parse_go_duration(text: &str) -> Option<Duration> {
+    if text.starts_with('-') {
+        return Some(Duration::ZERO);
+    }
+    let mut seconds = 0f64;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let (number, tail) = rest.split_at(split);
+        let unit_len = tail.find(|c: char| c.is_ascii_digit()).unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+        let scale = match unit {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 1e-3,
+            "us" | "µs" => 1e-6,
+            "ns" => 1e-9,
+            _ => return None,
+        };
+        seconds += number.parse::<f64>().ok()? * scale;
+        rest = tail;
+    }
+    Some(Duration::from_secs_f64(seconds))
+}
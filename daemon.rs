@@ -0,0 +1,683 @@
+//! Long-running mode: refresh the data on a schedule and keep the loaded
+//! ruleset in line with what cloak generated.
+//!
+//! The policy comes either from the command line or from a `--config` file,
+//! which is re-read whenever it changes:
+//!
+//! ```yaml
+//! list: brics            # or an explicit `countries: [ru, cn, ir]`
+//! action: block
+//! whitelist:             # sources accepted before any country rule
+//!   - 203.0.113.7/32
+//!   - 2001:db8::/48
+//! notify:                # see the notify module
+//!   email: { from: cloak@example.com, to: [ops@example.com], smtp: "mail:25" }
+//! assertions:            # see the assertion module
+//!   - allow 203.0.113.7:22
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use ipnetwork::IpNetwork;
+use serde::Deserialize;
+
+use crate::{
+    assertion::{self, Assertion},
+    exit::{Code, WithCode},
+    fetch, filter,
+    filter::FilterArgs,
+    groups::{self, Group},
+    guard,
+    haproxy, keep, kv,
+    metrics, nft,
+    notify::{self, Event, EventKind, NotifyConfig},
+    parse_duration, persist,
+    state::RunLock,
+    sync, temp, trend,
+    ui::{info, success, warning, FamilyCounts},
+    write_json, yaml, Action, Format, Layout, RuleArgs, SerIpNet,
+};
+
+/// How often the config file is checked for changes
+const CONFIG_POLL: Duration = Duration::from_secs(2);
+
+#[derive(clap::Args, Debug)]
+pub struct DaemonArgs {
+    /// Which country group to enforce: a built-in list or a group defined
+    /// in --group-file
+    #[arg(required_unless_present_any = ["config", "kv"], conflicts_with_all = ["config", "kv"])]
+    pub list: Option<String>,
+
+    /// Whether to allow or block the list
+    #[arg(value_enum, required_unless_present_any = ["config", "kv"], conflicts_with_all = ["config", "kv"])]
+    pub action: Option<Action>,
+
+    /// YAML policy file (list or countries, action, whitelist); edits are
+    /// applied without restarting the daemon
+    #[arg(long, value_name = "FILE", conflicts_with = "kv")]
+    pub config: Option<PathBuf>,
+
+    /// Read the policy (the YAML of a --config file) from a key in etcd or
+    /// Consul and follow its changes: etcd://HOST:PORT/KEY or
+    /// consul://HOST:PORT/KEY (+https after the scheme for TLS)
+    #[arg(long, value_name = "URL", value_parser = kv::parse_url)]
+    pub kv: Option<kv::Store>,
+
+    /// File holding the token for --kv (Consul ACL token, or etcd auth
+    /// token)
+    #[arg(long, value_name = "FILE", requires = "kv")]
+    pub kv_token_file: Option<PathBuf>,
+
+    /// YAML file defining additional named groups (name -> country codes)
+    #[arg(long, value_name = "FILE")]
+    pub group_file: Option<PathBuf>,
+
+    /// How often to refetch the country data and regenerate the rules
+    #[arg(long, value_parser = parse_duration, default_value = "24h")]
+    pub refresh: Duration,
+
+    /// How often to check that the loaded tables still match the generated
+    /// rules (re-applying them if not)
+    #[arg(long, value_parser = parse_duration, default_value = "1m")]
+    pub verify_interval: Duration,
+
+    /// Serve Prometheus metrics (refreshes, drift, drops) on this address;
+    /// the country rules then count what they drop
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Commit the rules and JSON map of every refresh to this git
+    /// repository (see `cloak sync`)
+    #[arg(long, value_name = "URL")]
+    pub sync_repo: Option<String>,
+
+    /// Branch for --sync-repo
+    #[arg(long, value_name = "BRANCH", default_value = "main", requires = "sync_repo")]
+    pub sync_branch: String,
+
+    /// After every refresh, replace a map in the running HAProxy through
+    /// its runtime API at this socket path or HOST:PORT
+    #[arg(long, value_name = "SOCKET", value_parser = haproxy::parse_socket, requires = "haproxy_map")]
+    pub haproxy_socket: Option<haproxy::Socket>,
+
+    /// Map file for --haproxy-socket, as named in haproxy.cfg; it is
+    /// rewritten too, so a restart loads the current data
+    #[arg(long, value_name = "FILE", requires = "haproxy_socket")]
+    pub haproxy_map: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub rules: RuleArgs,
+
+    #[command(flatten)]
+    pub guard: guard::GuardArgs,
+
+    #[command(flatten)]
+    pub filters: FilterArgs,
+
+    #[command(flatten)]
+    pub http: fetch::HttpArgs,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    list: Option<String>,
+    #[serde(default)]
+    countries: Vec<String>,
+    action: Action,
+    #[serde(default)]
+    whitelist: Vec<SerIpNet>,
+    #[serde(default)]
+    notify: NotifyConfig,
+    #[serde(default)]
+    assertions: Vec<Assertion>,
+}
+
+/// What the daemon enforces
+#[derive(Debug, PartialEq, Eq)]
+struct Policy {
+    group: Group,
+    action: Action,
+    whitelist: Vec<IpNetwork>,
+    /// Refreshed rules that break one of these are not loaded
+    assertions: Vec<Assertion>,
+}
+
+impl Policy {
+    fn rules_path(&self, state_dir: &Path) -> PathBuf {
+        state_dir.join(format!("{}_{}.nft", self.group.name, self.action))
+    }
+}
+
+/// Result of a successful refresh
+struct Refreshed {
+    /// Fingerprint of the rules now expected in the kernel
+    fingerprint: String,
+    counts: BTreeMap<String, FamilyCounts>,
+    reloaded: bool,
+}
+
+/// What the daemon has done so far, served with --metrics-addr
+#[derive(Clone, Default)]
+struct Stats {
+    list: String,
+    action: String,
+    refreshes: u64,
+    refresh_failures: u64,
+    /// Unix time of the last successful refresh
+    last_refresh: u64,
+    drift_corrected: u64,
+    restore_failures: u64,
+    counts: BTreeMap<String, FamilyCounts>,
+}
+
+impl Stats {
+    fn refreshed(&mut self, policy: &Policy, result: &Result<Refreshed>) {
+        self.list = policy.group.name.clone();
+        self.action = policy.action.to_string();
+        self.refreshes += 1;
+        match result {
+            Ok(refreshed) => {
+                self.last_refresh = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                self.counts = refreshed.counts.clone();
+            }
+            Err(_) => self.refresh_failures += 1,
+        }
+    }
+
+    /// The Prometheus text format, with the drop counters of the loaded
+    /// table read at the time of the scrape
+    fn render(&self) -> String {
+        let mut out = String::new();
+        metrics::header(&mut out, "cloak_policy_info", "gauge", "The list and action being enforced");
+        let _ = writeln!(out, "cloak_policy_info{{list=\"{}\",action=\"{}\"}} 1", self.list, self.action);
+        let counters = [
+            ("cloak_refreshes_total", "Refreshes attempted", self.refreshes),
+            ("cloak_refresh_failures_total", "Refreshes that failed", self.refresh_failures),
+            ("cloak_drift_corrected_total", "Times the loaded table was found modified and re-applied", self.drift_corrected),
+            ("cloak_restore_failures_total", "Times re-applying a modified table failed", self.restore_failures),
+        ];
+        for (name, help, value) in counters {
+            metrics::header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        metrics::header(&mut out, "cloak_last_refresh_timestamp_seconds", "gauge", "Unix time of the last successful refresh");
+        let _ = writeln!(out, "cloak_last_refresh_timestamp_seconds {}", self.last_refresh);
+        metrics::header(&mut out, "cloak_prefixes", "gauge", "Prefixes loaded per country and address family");
+        for (cc, counts) in &self.counts {
+            let _ = writeln!(out, "cloak_prefixes{{country=\"{}\",family=\"ipv4\"}} {}", cc, counts.ipv4);
+            let _ = writeln!(out, "cloak_prefixes{{country=\"{}\",family=\"ipv6\"}} {}", cc, counts.ipv6);
+        }
+        // Counters start over whenever the table is reloaded, which
+        // Prometheus treats as a counter reset
+        if let Some((packets, bytes)) = nft::drop_counters() {
+            metrics::header(&mut out, "cloak_dropped_packets_total", "counter", "Packets dropped by the country rules");
+            let _ = writeln!(out, "cloak_dropped_packets_total {}", packets);
+            metrics::header(&mut out, "cloak_dropped_bytes_total", "counter", "Bytes dropped by the country rules");
+            let _ = writeln!(out, "cloak_dropped_bytes_total {}", bytes);
+        }
+        if let Some(counters) = nft::country_counters().filter(|counters| !counters.is_empty()) {
+            metrics::header(&mut out, "cloak_country_dropped_packets_total", "counter", "Packets dropped per country and address family (--country-counters)");
+            for (cc, family, packets, _) in &counters {
+                let _ = writeln!(out, "cloak_country_dropped_packets_total{{country=\"{}\",family=\"{}\"}} {}", cc, family, packets);
+            }
+            metrics::header(&mut out, "cloak_country_dropped_bytes_total", "counter", "Bytes dropped per country and address family (--country-counters)");
+            for (cc, family, _, bytes) in &counters {
+                let _ = writeln!(out, "cloak_country_dropped_bytes_total{{country=\"{}\",family=\"{}\"}} {}", cc, family, bytes);
+            }
+        }
+        out
+    }
+}
+
+pub async fn run(args: &DaemonArgs, state_dir: &Path) -> Result<()> {
+    if !cfg!(target_os = "linux") {
+        bail!("daemon mode needs nftables and is only supported on Linux");
+    }
+    if args.rules.sets_only {
+        bail!("--sets-only writes no rules to enforce; the daemon cannot run with it");
+    }
+    args.filters.check()?;
+    let (mut watch, (mut policy, mut notify)) = Watch::open(args).await?;
+    check_policy(args, &policy)?;
+    let mut counts = None;
+    let stats = Arc::new(Mutex::new(Stats::default()));
+    if let Some(addr) = args.metrics_addr {
+        let listener = tokio::net::TcpListener::bind(addr).await.with_context(|| format!("listen on {}", addr))?;
+        let shared = stats.clone();
+        tokio::spawn(metrics::serve(listener, move || {
+            // Copied out so the lock is not held while nft lists the counters
+            let stats = shared.lock().expect("stats lock").clone();
+            stats.render()
+        }));
+        info!("Serving Prometheus metrics on http://{}/metrics", addr);
+    }
+
+    // The first refresh has to work; later failures keep the last good rules
+    let first = refresh(args, &policy, state_dir).await;
+    stats.lock().expect("stats lock").refreshed(&policy, &first);
+    report(&notify, &policy, &first, &mut counts).await;
+    let mut expected = first?.fingerprint;
+
+    let mut refresh_tick = tokio::time::interval(args.refresh);
+    let mut verify_tick = tokio::time::interval(args.verify_interval);
+    let mut config_tick = tokio::time::interval(CONFIG_POLL);
+    refresh_tick.tick().await;
+    verify_tick.tick().await;
+    config_tick.tick().await;
+
+    info!(
+        "Enforcing {} {} (refresh every {}, verify every {})",
+        policy.action,
+        policy.group.name,
+        crate::format_duration(args.refresh),
+        crate::format_duration(args.verify_interval)
+    );
+    if let Some(watched) = watch.describe(args) {
+        info!("Watching {} for changes", watched);
+    }
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = refresh_tick.tick() => {
+                let result = refresh(args, &policy, state_dir).await;
+                stats.lock().expect("stats lock").refreshed(&policy, &result);
+                report(&notify, &policy, &result, &mut counts).await;
+                match result {
+                    Ok(refreshed) => expected = refreshed.fingerprint,
+                    Err(e) => warning!("refresh failed, keeping the current rules: {:#}", e),
+                }
+            }
+            _ = verify_tick.tick() => {
+                let rules = policy.rules_path(state_dir);
+                match reconcile(&expected, &rules, state_dir) {
+                    Ok(None) => {}
+                    Ok(Some(drift)) => {
+                        stats.lock().expect("stats lock").drift_corrected += 1;
+                        let body = format!("Drift detected ({}); re-applied {}.\n", drift, rules.display());
+                        let event = Event::new(EventKind::DriftCorrected, "cloak: drift corrected", body)
+                            .field("drift", drift)
+                            .field("fingerprint", &expected);
+                        notify::send(&notify, &event).await;
+                    }
+                    Err(e) => {
+                        stats.lock().expect("stats lock").restore_failures += 1;
+                        warning!("could not restore the ruleset: {:#}", e);
+                        let body = format!("Drift detected but re-applying {} failed:\n\n{:#}\n", rules.display(), e);
+                        let event = Event::new(EventKind::RestoreFailed, "cloak: could not restore the ruleset", body)
+                            .field("error", format!("{:#}", e))
+                            .field("fingerprint", &expected);
+                        notify::send(&notify, &event).await;
+                    }
+                }
+            }
+            _ = config_tick.tick(), if !matches!(watch, Watch::Nothing) => {
+                let Some(loaded) = watch.poll(args).await else {
+                    continue;
+                };
+                let (updated, updated_notify) = match loaded.and_then(|loaded| check_policy(args, &loaded.0).map(|()| loaded)) {
+                    Ok(loaded) => loaded,
+                    Err(e) => {
+                        warning!("ignoring config change, keeping the current policy: {:#}", e);
+                        continue;
+                    }
+                };
+                notify = updated_notify;
+                if updated == policy {
+                    continue;
+                }
+                info!("Config changed: now enforcing {} {}", updated.action, updated.group.name);
+                let result = refresh(args, &updated, state_dir).await;
+                stats.lock().expect("stats lock").refreshed(&updated, &result);
+                report(&notify, &updated, &result, &mut counts).await;
+                match result {
+                    Ok(refreshed) => {
+                        expected = refreshed.fingerprint;
+                        policy = updated;
+                        refresh_tick.reset();
+                    }
+                    Err(e) => warning!("could not apply the new config, keeping the current rules: {:#}", e),
+                }
+            }
+            _ = &mut shutdown => {
+                info!("Shutting down; the loaded rules stay in place.");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Where the policy comes from, if it can change, and what was last read
+enum Watch {
+    Nothing,
+    /// `--config`, with the hash of what it last held
+    File(Option<String>),
+    /// `--kv`, with the last revision read and whether the last read failed
+    Kv { reader: kv::Reader, revision: u64, failing: bool },
+}
+
+impl Watch {
+    /// Read the policy for the first time
+    async fn open(args: &DaemonArgs) -> Result<(Watch, (Policy, NotifyConfig))> {
+        if let Some(store) = &args.kv {
+            let reader = kv::Reader::new(store, args.kv_token_file.as_deref())?;
+            let (revision, text) = reader.read().await?.with_context(|| format!("{} does not exist", store.describe()))?;
+            let loaded = parse_config(&text, &store.describe(), args)?;
+            return Ok((Watch::Kv { reader, revision, failing: false }, loaded));
+        }
+        let watch = match &args.config {
+            Some(path) => Watch::File(stamp(path)),
+            None => Watch::Nothing,
+        };
+        Ok((watch, load_config(args)?))
+    }
+
+    fn describe(&self, args: &DaemonArgs) -> Option<String> {
+        match self {
+            Watch::Nothing => None,
+            Watch::File(_) => args.config.as_ref().map(|path| path.display().to_string()),
+            Watch::Kv { reader, .. } => Some(reader.store().describe()),
+        }
+    }
+
+    /// The policy again if it changed since it was last read. A store that
+    /// cannot be reached, or a deleted key, keeps the current policy; that
+    /// is reported once rather than on every poll.
+    async fn poll(&mut self, args: &DaemonArgs) -> Option<Result<(Policy, NotifyConfig)>> {
+        match self {
+            Watch::Nothing => None,
+            Watch::File(last) => {
+                let current = args.config.as_deref().and_then(stamp);
+                if current == *last {
+                    return None;
+                }
+                *last = current;
+                Some(load_config(args))
+            }
+            Watch::Kv { reader, revision, failing } => {
+                let read = reader.read().await;
+                let problem = match &read {
+                    Ok(Some(_)) => None,
+                    Ok(None) => Some(format!("{} was deleted", reader.store().describe())),
+                    Err(e) => Some(format!("{:#}", e)),
+                };
+                if let Some(problem) = problem {
+                    if !*failing {
+                        warning!("{}; keeping the current policy", problem);
+                    }
+                    *failing = true;
+                    return None;
+                }
+                if std::mem::take(failing) {
+                    info!("{} is readable again", reader.store().describe());
+                }
+                let (current, text) = read.ok().flatten()?;
+                if current == *revision {
+                    return None;
+                }
+                *revision = current;
+                Some(parse_config(&text, &reader.store().describe(), args))
+            }
+        }
+    }
+}
+
+/// Hash of the file's contents. Modification times are too coarse to
+/// notice an edit that keeps the size right after the last one.
+fn stamp(path: &Path) -> Option<String> {
+    fs::read(path).ok().map(|contents| nft::hash_hex(&contents))
+}
+
+fn load_config(args: &DaemonArgs) -> Result<(Policy, NotifyConfig)> {
+    let Some(path) = &args.config else {
+        let file_groups = match &args.group_file {
+            Some(path) => groups::load_group_file(path)?,
+            None => HashMap::new(),
+        };
+        let list = args.list.as_deref().context("a LIST is required")?;
+        let policy = Policy {
+            group: groups::resolve(list, &file_groups)?,
+            action: args.action.context("an ACTION is required")?,
+            whitelist: Vec::new(),
+            assertions: Vec::new(),
+        };
+        return Ok((policy, NotifyConfig::default()));
+    };
+
+    let text = fs::read_to_string(path).with_context(|| format!("read config {}", path.display()))?;
+    parse_config(&text, &path.display().to_string(), args)
+}
+
+/// The policy in the YAML `text` of a config file or KV key, `origin` in
+/// messages
+fn parse_config(text: &str, origin: &str, args: &DaemonArgs) -> Result<(Policy, NotifyConfig)> {
+    let file_groups = match &args.group_file {
+        Some(path) => groups::load_group_file(path)?,
+        None => HashMap::new(),
+    };
+    let doc = yaml::parse(text).with_context(|| format!("parse {}", origin))?;
+    let config: ConfigFile = serde_json::from_value(doc).with_context(|| format!("invalid config {}", origin))?;
+    let group = match (config.list, config.countries.is_empty()) {
+        (Some(list), true) => groups::resolve(&list, &file_groups)?,
+        (None, false) => groups::from_codes("custom", &config.countries).with_context(|| origin.to_string())?,
+        _ => bail!("{}: set exactly one of `list` or `countries`", origin),
+    };
+    config.notify.validate().with_context(|| format!("invalid config {}", origin))?;
+    let policy = Policy {
+        group,
+        action: config.action,
+        whitelist: config.whitelist.into_iter().map(|net| net.0).collect(),
+        assertions: config.assertions,
+    };
+    Ok((policy, config.notify))
+}
+
+/// The group and action `args` make the daemon enforce
+pub fn load_policy(args: &DaemonArgs) -> Result<(Group, Action)> {
+    if let Some(store) = &args.kv {
+        bail!("the policy in {} is only read by the running daemon; give LIST and ACTION instead", store.describe());
+    }
+    let (policy, _) = load_config(args)?;
+    Ok((policy.group, policy.action))
+}
+
+/// Refetch, regenerate and (if anything changed) reload.
+/// Refuse the rule options that do not go with `policy`, as `cloak` itself
+/// does before generating anything
+fn check_policy(args: &DaemonArgs, policy: &Policy) -> Result<()> {
+    args.rules.check_format(Format::Nft, policy.action, !policy.whitelist.is_empty()).code(Code::Validation)
+}
+
+async fn refresh(args: &DaemonArgs, policy: &Policy, state_dir: &Path) -> Result<Refreshed> {
+    let mut map = fetch::fetch_countries(&policy.group.countries, &args.http).await?;
+    filter::apply(&mut map, &[], &args.filters, &args.http).await?;
+    // Resolved on every refresh so mirrors and NTP servers can move
+    let mut whitelist = policy.whitelist.clone();
+    whitelist.extend(keep::resolve(&args.filters.keep).await?);
+    let counts = map
+        .iter()
+        .map(|(cc, nets)| (cc.clone(), FamilyCounts { ipv4: nets.ipv4.len(), ipv6: nets.ipv6.len() }))
+        .collect();
+
+    // Hold the run lock only while touching shared files and the kernel so
+    // manual runs can still go ahead between refreshes.
+    let _lock = RunLock::acquire(state_dir, true)?;
+    let json = state_dir.join(format!("{}_ip_map.json", policy.group.name));
+    guard::check(&json, &map, &policy.group.countries, args.guard)?;
+    write_json(&map, Layout::Nested, &json.to_string_lossy())?;
+    let rules = policy.rules_path(state_dir);
+    let mut rule_args = args.rules;
+    rule_args.counters |= args.metrics_addr.is_some();
+    // Written aside first so rules breaking an assertion never replace the
+    // file drift correction re-applies
+    let candidate = rules.with_extension("nft.new");
+    let fingerprint = nft::generate_nftables(&map, policy.action, &whitelist, rule_args, &candidate.to_string_lossy())?;
+    assertion::check_file(&candidate, &policy.assertions)?;
+    fs::rename(&candidate, &rules).with_context(|| format!("rename {} to {}", candidate.display(), rules.display()))?;
+
+    let ruleset = fs::read_to_string(&rules).with_context(|| format!("read {}", rules.display()))?;
+    let reloaded = nft::live_fingerprint(&ruleset).as_deref() != Some(fingerprint.as_str());
+    if reloaded {
+        apply(&rules, state_dir, &fingerprint)?;
+        success!("Loaded refreshed rules ({}).", fingerprint);
+    } else {
+        info!("Rules are up to date.");
+    }
+    if let (Some(socket), Some(path)) = (&args.haproxy_socket, &args.haproxy_map) {
+        match haproxy::feed(socket, path, &map).await {
+            Ok(entries) => info!("Updated {} in HAProxy ({} entries).", path.display(), entries),
+            Err(e) => warning!("could not update HAProxy: {:#}", e),
+        }
+    }
+    if let Err(e) = trend::record(state_dir, &counts) {
+        warning!("could not record the prefix counts: {:#}", e);
+    }
+    let refreshed = Refreshed { fingerprint, counts, reloaded };
+    if let Some(url) = &args.sync_repo {
+        if let Err(e) = commit_refresh(url, args, policy, state_dir, &[rules, json], &refreshed) {
+            warning!("could not commit the refresh to {}: {:#}", url, e);
+        }
+    }
+    Ok(refreshed)
+}
+
+/// Commit the files of a refresh to the sync repository
+fn commit_refresh(url: &str, args: &DaemonArgs, policy: &Policy, state_dir: &Path, files: &[PathBuf], refreshed: &Refreshed) -> Result<()> {
+    let repo = sync::Repo::open(url, &args.sync_branch, state_dir)?;
+    let host = crate::naming::hostname();
+    let mut stored = Vec::new();
+    for file in files {
+        stored.push(repo.store(file, &Path::new("hosts").join(&host))?);
+    }
+    let counts = &refreshed.counts;
+    let (ipv4, ipv6) = counts.values().fold((0, 0), |(v4, v6), c| (v4 + c.ipv4, v6 + c.ipv6));
+    let target = format!("{} {}", policy.action, policy.group.name);
+    let trailers = [
+        ("Host", host.clone()),
+        ("Command", "daemon refresh".to_string()),
+        ("Policy", target.clone()),
+        ("Fingerprint", refreshed.fingerprint.clone()),
+        ("Countries", counts.len().to_string()),
+        ("Prefixes", format!("{} IPv4, {} IPv6", ipv4, ipv6)),
+        ("Reloaded", if refreshed.reloaded { "yes" } else { "no" }.to_string()),
+    ];
+    if let Some(commit) = repo.commit(&format!("cloak: refresh {} on {}", target, host), &trailers, &stored)? {
+        info!("Committed the refresh to {} ({}).", url, commit);
+    }
+    Ok(())
+}
+
+/// Send the outcome of a refresh to the configured notification channels,
+/// with prefix counts compared to the previous successful refresh.
+async fn report(
+    notify: &NotifyConfig,
+    policy: &Policy,
+    result: &Result<Refreshed>,
+    previous: &mut Option<BTreeMap<String, FamilyCounts>>,
+) {
+    let target = format!("{} {}", policy.action, policy.group.name);
+    let event = match result {
+        Err(e) => Event::new(
+            EventKind::RefreshFailed,
+            format!("cloak: refresh of {} failed", target),
+            format!("Refreshing {} failed; the previously loaded rules stay in place.\n\n{:#}\n", target, e),
+        )
+        .field("error", format!("{:#}", e)),
+        Ok(refreshed) => {
+            let mut body = format!(
+                "Refreshed {}: {}.\nFingerprint: {}\n\n",
+                target,
+                if refreshed.reloaded { "rules reloaded" } else { "rules unchanged" },
+                refreshed.fingerprint
+            );
+            let empty = BTreeMap::new();
+            let before = previous.as_ref().unwrap_or(&empty);
+            for (cc, now) in &refreshed.counts {
+                let was = before.get(cc).copied().unwrap_or_default();
+                body.push_str(&format!(
+                    "{}  IPv4 {} ({:+})  IPv6 {} ({:+})\n",
+                    cc.to_uppercase(),
+                    now.ipv4,
+                    now.ipv4 as i64 - was.ipv4 as i64,
+                    now.ipv6,
+                    now.ipv6 as i64 - was.ipv6 as i64
+                ));
+            }
+            let totals = refreshed.counts.values().fold((0, 0), |(v4, v6), c| (v4 + c.ipv4, v6 + c.ipv6));
+            Event::new(EventKind::Refreshed, format!("cloak: refreshed {}", target), body)
+                .field("fingerprint", &refreshed.fingerprint)
+                .field("reloaded", refreshed.reloaded)
+                .field("ipv4_prefixes", totals.0)
+                .field("ipv6_prefixes", totals.1)
+        }
+    };
+    let event = event.field("list", &policy.group.name).field("action", policy.action);
+    if let Ok(refreshed) = result {
+        *previous = Some(refreshed.counts.clone());
+    }
+    notify::send(notify, &event).await;
+}
+
+/// Re-apply the generated rules if the kernel no longer has them. Returns
+/// a description of the drift if there was any.
+fn reconcile(expected: &str, rules: &Path, state_dir: &Path) -> Result<Option<String>> {
+    let ruleset = fs::read_to_string(rules).with_context(|| format!("read {}", rules.display()))?;
+    let live = nft::live_fingerprint(&ruleset);
+    if live.as_deref() == Some(expected) {
+        return Ok(None);
+    }
+    let drift = match &live {
+        None => format!("table inet {} or a table loaded with it is missing or was modified", nft::TABLE),
+        Some(other) => format!("loaded ruleset {} differs from expected {}", other, expected),
+    };
+    warning!("drift detected: {}", drift);
+    let _lock = RunLock::acquire(state_dir, true)?;
+    apply(rules, state_dir, expected)?;
+    success!("Drift corrected: re-applied {}.", rules.display());
+    Ok(Some(drift))
+}
+
+fn apply(rules: &Path, state_dir: &Path, fingerprint: &str) -> Result<()> {
+    if !nft::load(&rules.to_string_lossy())? {
+        bail!("nft rejected {}{}", rules.display(), crate::privilege::hint());
+    }
+    nft::record_applied_hash(state_dir, fingerprint)?;
+    temp::restore(state_dir);
+    // The rules are loaded either way; a stale copy only matters at boot
+    if let Err(e) = persist::update(rules, state_dir) {
+        warning!("Could not update the nftables service's copy of the rules: {:#}", e);
+    }
+    Ok(())
+}
+
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = term.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
@@ -0,0 +1,181 @@
+//! `cloak dashboard`: a Grafana dashboard and Prometheus alerting rules for
+//! the metrics `cloak daemon --metrics-addr` serves, tailored to the policy
+//! the daemon enforces.
+//!
+//! Import `cloak-dashboard.json` into Grafana and add `cloak-alerts.yml`
+//! to Prometheus' `rule_files`.
+
+use std::{fmt::Write, fs, path::PathBuf};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use crate::{
+    daemon::{self, DaemonArgs},
+    ui::{info, success},
+};
+
+/// Drop rates below this many packets per second never count as a spike
+const SPIKE_FLOOR: f64 = 1.0;
+
+#[derive(clap::Args, Debug)]
+pub struct DashboardArgs {
+    /// Directory to write cloak-dashboard.json and cloak-alerts.yml to
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    out_dir: PathBuf,
+
+    /// Alert when drops per second exceed this multiple of the daily
+    /// average
+    #[arg(long, value_name = "FACTOR", default_value_t = 3.0)]
+    spike_factor: f64,
+
+    /// The arguments `cloak daemon` runs with
+    #[command(flatten)]
+    daemon: DaemonArgs,
+}
+
+pub fn run(args: &DashboardArgs) -> Result<()> {
+    let (group, action) = daemon::load_policy(&args.daemon)?;
+    let countries = group.countries.iter().map(|(cc, _)| cc.as_str()).collect::<Vec<_>>().join("|");
+    let title = format!("cloak: {} {}", action, group.name);
+    let refresh_secs = args.daemon.refresh.as_secs();
+
+    fs::create_dir_all(&args.out_dir).with_context(|| format!("create {}", args.out_dir.display()))?;
+    let dashboard_path = args.out_dir.join("cloak-dashboard.json");
+    let dashboard = dashboard(&title, &countries);
+    fs::write(&dashboard_path, serde_json::to_string_pretty(&dashboard)?)
+        .with_context(|| format!("write {}", dashboard_path.display()))?;
+
+    let alerts_path = args.out_dir.join("cloak-alerts.yml");
+    fs::write(&alerts_path, alert_rules(&group.name, &countries, refresh_secs, args.spike_factor))
+        .with_context(|| format!("write {}", alerts_path.display()))?;
+
+    success!("Wrote {} and {}.", dashboard_path.display(), alerts_path.display());
+    if args.daemon.metrics_addr.is_none() {
+        info!("Run the daemon with --metrics-addr so Prometheus has something to scrape.");
+    }
+    Ok(())
+}
+
+fn panel(id: u32, kind: &str, title: &str, grid: (u32, u32, u32, u32), targets: &[(&str, &str)], unit: &str) -> Value {
+    let (x, y, w, h) = grid;
+    let targets: Vec<Value> = targets
+        .iter()
+        .enumerate()
+        .map(|(i, (expr, legend))| {
+            json!({
+                "refId": ((b'A' + i as u8) as char).to_string(),
+                "datasource": { "type": "prometheus", "uid": "${datasource}" },
+                "expr": expr,
+                "legendFormat": legend,
+            })
+        })
+        .collect();
+    json!({
+        "id": id,
+        "type": kind,
+        "title": title,
+        "gridPos": { "x": x, "y": y, "w": w, "h": h },
+        "datasource": { "type": "prometheus", "uid": "${datasource}" },
+        "fieldConfig": { "defaults": { "unit": unit }, "overrides": [] },
+        "targets": targets,
+    })
+}
+
+fn dashboard(title: &str, countries: &str) -> Value {
+    let prefixes = format!("sum by (country) (cloak_prefixes{{country=~\"{}\"}})", countries);
+    let panels = vec![
+        panel(1, "stat", "Since last refresh", (0, 0, 6, 4), &[("time() - cloak_last_refresh_timestamp_seconds", "")], "s"),
+        panel(2, "stat", "Refresh failures (24h)", (6, 0, 6, 4), &[("increase(cloak_refresh_failures_total[24h])", "")], "short"),
+        panel(3, "stat", "Drift corrections (24h)", (12, 0, 6, 4), &[("increase(cloak_drift_corrected_total[24h])", "")], "short"),
+        panel(4, "stat", "Restore failures (24h)", (18, 0, 6, 4), &[("increase(cloak_restore_failures_total[24h])", "")], "short"),
+        panel(
+            5,
+            "timeseries",
+            "Dropped packets",
+            (0, 4, 12, 8),
+            &[("rate(cloak_dropped_packets_total[5m])", "packets/s")],
+            "pps",
+        ),
+        panel(6, "timeseries", "Dropped bytes", (12, 4, 12, 8), &[("rate(cloak_dropped_bytes_total[5m])", "bytes/s")], "Bps"),
+        panel(7, "bargauge", "Prefixes by country", (0, 12, 24, 10), &[(prefixes.as_str(), "{{country}}")], "short"),
+    ];
+    json!({
+        "title": title,
+        "uid": "cloak",
+        "tags": ["cloak", "firewall"],
+        "timezone": "browser",
+        "refresh": "1m",
+        "time": { "from": "now-24h", "to": "now" },
+        "schemaVersion": 39,
+        "templating": {
+            "list": [{
+                "name": "datasource",
+                "label": "Data source",
+                "type": "datasource",
+                "query": "prometheus",
+            }]
+        },
+        "panels": panels,
+    })
+}
+
+fn alert_rules(list: &str, countries: &str, refresh_secs: u64, spike_factor: f64) -> String {
+    // (name, expression, for, severity, summary)
+    let rules = [
+        (
+            "CloakRefreshFailed",
+            "increase(cloak_refresh_failures_total[1h]) > 0".to_string(),
+            "0m",
+            "warning",
+            format!("Refreshing the {} country data failed; the previous rules stay loaded", list),
+        ),
+        (
+            "CloakRefreshStale",
+            format!("time() - cloak_last_refresh_timestamp_seconds > {}", 2 * refresh_secs),
+            "10m",
+            "warning",
+            format!("The {} country data has not been refreshed for two refresh intervals", list),
+        ),
+        (
+            "CloakDriftDetected",
+            "increase(cloak_drift_corrected_total[15m]) > 0".to_string(),
+            "0m",
+            "warning",
+            "The loaded ruleset was modified or removed and has been re-applied".to_string(),
+        ),
+        (
+            "CloakRestoreFailed",
+            "increase(cloak_restore_failures_total[15m]) > 0".to_string(),
+            "0m",
+            "critical",
+            "The loaded ruleset was modified and re-applying it failed".to_string(),
+        ),
+        (
+            "CloakDropSpike",
+            format!(
+                "rate(cloak_dropped_packets_total[5m]) > {} * rate(cloak_dropped_packets_total[1d]) and rate(cloak_dropped_packets_total[5m]) > {}",
+                spike_factor, SPIKE_FLOOR
+            ),
+            "10m",
+            "info",
+            format!("Drops by the {} rules are well above their daily average", list),
+        ),
+        (
+            "CloakCountryEmpty",
+            format!("sum by (country) (cloak_prefixes{{country=~\"{}\"}}) == 0", countries),
+            "1h",
+            "warning",
+            "No prefixes are loaded for {{ $labels.country }}".to_string(),
+        ),
+    ];
+    let mut out = String::from("# Generated by cloak dashboard\ngroups:\n  - name: cloak\n    rules:\n");
+    for (name, expr, wait, severity, summary) in rules {
+        let _ = writeln!(out, "      - alert: {}", name);
+        let _ = writeln!(out, "        expr: {}", expr);
+        let _ = writeln!(out, "        for: {}", wait);
+        let _ = writeln!(out, "        labels:\n          severity: {}", severity);
+        let _ = writeln!(out, "        annotations:\n          summary: '{}'", summary.replace('\'', "''"));
+    }
+    out
+}
@@ -0,0 +1,126 @@
+//! Downloads into the on-disk cache, resuming interrupted transfers.
+//!
+//! A transfer is written to `<file>.part` as it arrives. If it breaks off,
+//! the next attempt asks only for the missing bytes (`Range`), guarded by
+//! `If-Range` with the validator (ETag or Last-Modified) of the first
+//! response so a file that changed upstream is downloaded afresh instead
+//! of being spliced together from two versions.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use reqwest::{
+    header::{ACCEPT_ENCODING, ETAG, IF_RANGE, LAST_MODIFIED, RANGE},
+    StatusCode,
+};
+
+use crate::ui::{info, warning};
+
+/// Attempts per file before giving up; each one resumes the last
+const ATTEMPTS: usize = 3;
+
+/// The server has no such file (HTTP 404), which retrying will not change
+#[derive(Debug)]
+pub struct NotFound;
+
+impl std::fmt::Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP 404 Not Found")
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// Download `url` into `cache_dir` and return the path of the complete file.
+pub async fn fetch_to_cache(client: &reqwest::Client, url: &str, cache_dir: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(cache_dir).with_context(|| format!("create cache directory {}", cache_dir.display()))?;
+    let dest = cache_dir.join(cache_name(url));
+    let part = with_suffix(&dest, ".part");
+    let validator = with_suffix(&dest, ".part.validator");
+
+    let mut last_error = None;
+    for attempt in 1..=ATTEMPTS {
+        match transfer(client, url, &part, &validator).await {
+            Ok(()) => {
+                fs::rename(&part, &dest).with_context(|| format!("rename {} into place", part.display()))?;
+                let _ = fs::remove_file(&validator);
+                return Ok(dest);
+            }
+            Err(e) if e.is::<NotFound>() => {
+                last_error = Some(e);
+                break;
+            }
+            Err(e) => {
+                if attempt < ATTEMPTS {
+                    warning!("download of {} interrupted ({:#}); resuming", url, e);
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.expect("at least one attempt")).with_context(|| format!("GET {}", url))
+}
+
+async fn transfer(client: &reqwest::Client, url: &str, part: &Path, validator: &Path) -> Result<()> {
+    // Ranges refer to the bytes on the wire, so ask for them unencoded
+    let mut request = client.get(url).header(ACCEPT_ENCODING, "identity");
+    let have = fs::metadata(part).map(|m| m.len()).unwrap_or(0);
+    let known = fs::read_to_string(validator).ok().filter(|v| !v.is_empty());
+    if let (true, Some(known)) = (have > 0, &known) {
+        request = request.header(RANGE, format!("bytes={}-", have)).header(IF_RANGE, known.as_str());
+    }
+
+    let mut response = request.send().await?;
+    let append = match response.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            info!("Resuming {} at {} bytes", url, have);
+            true
+        }
+        StatusCode::RANGE_NOT_SATISFIABLE => {
+            // The partial file is bogus (e.g. longer than the new upstream
+            // file); throw it away and start over on the next attempt
+            let _ = fs::remove_file(part);
+            let _ = fs::remove_file(validator);
+            bail!("server rejected resuming at {} bytes", have);
+        }
+        status if status.is_success() => false,
+        StatusCode::NOT_FOUND => return Err(NotFound.into()),
+        status => bail!("HTTP {}", status),
+    };
+    if !append {
+        let tag = response.headers().get(ETAG).or_else(|| response.headers().get(LAST_MODIFIED));
+        let tag = tag.and_then(|v| v.to_str().ok()).unwrap_or_default();
+        fs::write(validator, tag).with_context(|| format!("write {}", validator.display()))?;
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(part)
+        .with_context(|| format!("open {}", part.display()))?;
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).with_context(|| format!("write {}", part.display()))?;
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Flatten a URL into a file name: `www.ipdeny.com_ipblocks_..._ca-aggregated.zone`
+pub fn cache_name(url: &str) -> String {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    rest.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '-') { c } else { '_' })
+        .collect()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
@@ -0,0 +1,73 @@
+//! Exit codes, so wrapper scripts and monitoring can tell outcomes apart
+//! without parsing messages.
+//!
+//! - 0: success
+//! - 1: any other error
+//! - 2: invalid command line
+//! - 3: downloading country data failed
+//! - 4: validation failed: invalid or stale input data, an option that
+//!   does not fit the format, lint errors, an anomalous refresh, a rule
+//!   file or bundle that did not pass its checks
+//! - 5: loading the rules into the kernel failed
+//! - 6: lockout protection refused to load rules that would cut off this
+//!   session or a `--reachable` address without confirmation, or put the
+//!   previous rules back because a `--canary` load was not confirmed
+//! - 7: nothing to do, the rules were already loaded (only with
+//!   `--detailed-exit-codes`; 0 otherwise)
+//!
+//! Errors are tagged with their code where they arise; context added on
+//! the way up keeps the tag.
+
+use std::{error::Error, fmt};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code {
+    Fetch = 3,
+    Validation = 4,
+    Apply = 5,
+    Lockout = 6,
+    NothingToDo = 7,
+}
+
+/// An error and its exit code; shows as the error itself
+#[derive(Debug)]
+struct Tagged {
+    code: Code,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Tagged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&*self.error, f)
+    }
+}
+
+impl Error for Tagged {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// `error`, exiting with `code` unless it already has one
+pub fn tag(error: anyhow::Error, code: Code) -> anyhow::Error {
+    if error.downcast_ref::<Tagged>().is_some() {
+        return error;
+    }
+    anyhow::Error::new(Tagged { code, error })
+}
+
+/// Tag the error of a result with `code`
+pub trait WithCode<T> {
+    fn code(self, code: Code) -> anyhow::Result<T>;
+}
+
+impl<T> WithCode<T> for anyhow::Result<T> {
+    fn code(self, code: Code) -> anyhow::Result<T> {
+        self.map_err(|error| tag(error, code))
+    }
+}
+
+/// The exit code for a failed run
+pub fn of(error: &anyhow::Error) -> u8 {
+    error.downcast_ref::<Tagged>().map_or(1, |tagged| tagged.code as u8)
+}
@@ -0,0 +1,381 @@
+//! Downloading and parsing of IPdeny zone files.
+
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use ipnetwork::IpNetwork;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{
+    cidr, download,
+    exit::{Code, WithCode},
+    geoip::{self, Place},
+    net, parse_duration, state,
+    ui::{info, warning},
+    unix_now, CountryNets, SerIpNet,
+};
+
+/// IPv4 and IPv6 base URLs from IPdeny
+const IPV4_BASE: &str = "https://www.ipdeny.com/ipblocks/data/aggregated";
+const IPV6_BASE: &str = "https://www.ipdeny.com/ipv6/ipaddresses/aggregated";
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct HttpArgs {
+    /// Give up connecting to a server after this long
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "10s")]
+    pub connect_timeout: Duration,
+
+    /// Give up on a download that receives nothing for this long
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "60s")]
+    pub read_timeout: Duration,
+
+    /// User-Agent sent with every request
+    #[arg(long, value_name = "UA", default_value = concat!("cloak/", env!("CARGO_PKG_VERSION")))]
+    pub user_agent: String,
+
+    /// Extra request header, e.g. for an authenticated mirror (repeatable)
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    pub headers: Vec<(String, String)>,
+
+    /// How many countries to download at the same time
+    #[arg(long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    pub jobs: u16,
+
+    /// Where downloads are kept; interrupted ones resume from here
+    #[arg(long, value_name = "DIR", default_value_os_t = state::default_cache_dir())]
+    pub cache_dir: PathBuf,
+
+    /// What to do when a country's download fails: stop the run, leave
+    /// the country out, or use the copy an earlier fetch left in the cache
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = OnFailure::Fail)]
+    pub on_failure: OnFailure,
+
+    /// Treat a line of a zone file that is not a prefix as a failed
+    /// download, listing every such line (otherwise they are counted and
+    /// skipped)
+    #[arg(long)]
+    pub strict: bool,
+}
+
+/// `--on-failure`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnFailure {
+    Fail,
+    Skip,
+    Cache,
+}
+
+fn parse_header(text: &str) -> Result<(String, String), String> {
+    let (name, value) = text.split_once(':').ok_or("expected `Name: value`")?;
+    let name = name.trim();
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name `{}`", name))?;
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// Fetch both address families for every country, reporting progress.
+///
+/// Countries are downloaded `--jobs` at a time; each one is parsed and
+/// merged into the result as soon as it arrives, so parsing overlaps with
+/// the remaining downloads and raw file contents never pile up in memory.
+pub async fn fetch_countries(countries: &[(String, String)], http: &HttpArgs) -> Result<HashMap<String, CountryNets>> {
+    let client = client(http)?;
+    let slots = Arc::new(Semaphore::new(usize::from(http.jobs)));
+    let mut pending = JoinSet::new();
+    let (on_failure, strict) = (http.on_failure, http.strict);
+    for (cc, name) in countries {
+        let (client, slots, cache_dir) = (client.clone(), slots.clone(), http.cache_dir.clone());
+        let (cc, name) = (cc.clone(), name.clone());
+        pending.spawn(async move {
+            let _slot = slots.acquire_owned().await?;
+            let ipv4_url = format!("{}/{}-aggregated.zone", IPV4_BASE, cc);
+            let ipv6_url = format!("{}/{}-aggregated.zone", IPV6_BASE, cc);
+            let fetched = if on_failure == OnFailure::Cache {
+                let (ipv4, ipv6) = tokio::join!(
+                    fetch_cidrs(&client, &ipv4_url, &cache_dir, strict),
+                    fetch_ipv6(&client, &ipv6_url, &cache_dir, strict),
+                );
+                let (ipv4, ipv4_at) = or_cached(ipv4, &ipv4_url, &cache_dir, strict)?;
+                let (ipv6, ipv6_at) = or_cached(ipv6, &ipv6_url, &cache_dir, strict)?;
+                Ok((ipv4, ipv6, ipv4_at.min(ipv6_at)))
+            } else {
+                tokio::try_join!(
+                    fetch_cidrs(&client, &ipv4_url, &cache_dir, strict),
+                    fetch_ipv6(&client, &ipv6_url, &cache_dir, strict),
+                )
+                .map(|(ipv4, ipv6)| (ipv4, ipv6, unix_now()))
+            };
+            match fetched {
+                Ok((ipv4, ipv6, fetched_at)) => anyhow::Ok(Some((cc, name, ipv4, ipv6, fetched_at))),
+                Err(e) if on_failure == OnFailure::Skip => {
+                    warning!("leaving out {} ({}): {:#}", name, cc.to_uppercase(), e);
+                    Ok(None)
+                }
+                Err(e) => Err(e),
+            }
+        });
+    }
+
+    // Dropping `pending` on the first error aborts the downloads still running
+    let mut map: HashMap<String, CountryNets> = HashMap::new();
+    while let Some(joined) = pending.join_next().await {
+        let fetched = joined.context("download task failed").and_then(|fetched| fetched).code(Code::Fetch)?;
+        let Some((cc, name, ipv4, ipv6, fetched_at)) = fetched else {
+            continue;
+        };
+        info!(
+            "{} ({}) -> {} IPv4 blocks, {} IPv6 blocks",
+            name,
+            cc.to_uppercase(),
+            ipv4.len(),
+            ipv6.len()
+        );
+        let ipv4 = ipv4.into_iter().map(SerIpNet).collect();
+        let ipv6 = ipv6.into_iter().map(SerIpNet).collect();
+        map.insert(cc, CountryNets { ipv4, ipv6, fetched_at: Some(fetched_at) });
+    }
+    if map.is_empty() && !countries.is_empty() {
+        return Err(anyhow::anyhow!("every country's download failed")).code(Code::Fetch);
+    }
+    if map.len() < countries.len() {
+        warning!("{} of {} countries were left out after failed downloads", countries.len() - map.len(), countries.len());
+    }
+    Ok(map)
+}
+
+/// The prefixes of a download, or of the complete copy an earlier fetch
+/// left in the cache if it failed, with the time they were fetched
+fn or_cached(fetched: Result<Vec<IpNetwork>>, url: &str, cache_dir: &Path, strict: bool) -> Result<(Vec<IpNetwork>, u64)> {
+    let e = match fetched {
+        Ok(nets) => return Ok((nets, unix_now())),
+        Err(e) => e,
+    };
+    let path = cache_dir.join(download::cache_name(url));
+    let Ok(modified) = fs::metadata(&path).and_then(|meta| meta.modified()) else {
+        return Err(e.context("no cached copy to fall back to"));
+    };
+    let fetched_at = modified.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    warning!("{:#}; using the cached copy from {} ago", e, crate::format_duration(Duration::from_secs(unix_now().saturating_sub(fetched_at))));
+    Ok((parse_zone(&path, strict)?, fetched_at))
+}
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("enable a TLS backend: the `native-tls` or `rustls` feature");
+
+/// HTTP client shared by all downloads of a run
+pub fn client(http: &HttpArgs) -> Result<reqwest::Client> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &http.headers {
+        let value = HeaderValue::from_str(value).with_context(|| format!("invalid value for header {}", name))?;
+        headers.append(HeaderName::from_bytes(name.as_bytes())?, value);
+    }
+    client_builder()?
+        .connect_timeout(http.connect_timeout)
+        .read_timeout(http.read_timeout)
+        .user_agent(&http.user_agent)
+        .default_headers(headers)
+        .build()
+        .context("build HTTP client")
+}
+
+/// Client builder with the TLS backend set up, for any HTTP cloak does
+pub fn client_builder() -> Result<reqwest::ClientBuilder> {
+    let builder = reqwest::Client::builder();
+    #[cfg(feature = "rustls")]
+    let builder = with_system_roots(builder.use_rustls_tls())?;
+    Ok(builder)
+}
+
+/// CA bundles of the common distributions, after `$SSL_CERT_FILE`
+#[cfg(feature = "rustls")]
+const CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+/// rustls ships no trust roots of its own; use the system's bundle so
+/// builds stay free of a baked-in (and aging) root list.
+#[cfg(feature = "rustls")]
+fn with_system_roots(builder: reqwest::ClientBuilder) -> Result<reqwest::ClientBuilder> {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let from_env = std::env::var("SSL_CERT_FILE").ok();
+    let path = from_env
+        .as_deref()
+        .into_iter()
+        .chain(CA_BUNDLES.iter().copied())
+        .find(|path| std::path::Path::new(path).is_file())
+        .context("no CA certificate bundle found; point SSL_CERT_FILE at one")?;
+    let pem = std::fs::read(path).with_context(|| format!("read {}", path))?;
+    let certs = reqwest::Certificate::from_pem_bundle(&pem).with_context(|| format!("parse {}", path))?;
+    Ok(certs
+        .into_iter()
+        .fold(builder.tls_built_in_root_certs(false), |builder, cert| builder.add_root_certificate(cert)))
+}
+
+// Prefixes to use like countries, from outside the country lists
+#[derive(clap::Args, Debug, Clone)]
+pub struct SourceArgs {
+    /// A plain CIDR list to use like a country: file:PATH, or - for stdin
+    /// (repeatable; one prefix or `first - last` range per line, `#`
+    /// starts a comment)
+    #[arg(long, value_name = "SOURCE", value_parser = parse_source)]
+    pub source: Vec<Source>,
+
+    /// A region to use like a country, as an ISO 3166-2 code such as
+    /// us-tx (repeatable; needs --geoip-db)
+    #[arg(long, value_name = "REGION", value_parser = geoip::parse_region, requires = "geoip_db")]
+    pub region: Vec<Place>,
+
+    /// A city to use like a country, by its English name, e.g. amsterdam
+    /// or nl:amsterdam (repeatable; needs --geoip-db)
+    #[arg(long, value_name = "CITY", value_parser = geoip::parse_city, requires = "geoip_db")]
+    pub city: Vec<Place>,
+
+    /// GeoLite2 City database (.mmdb) to look up --region and --city in
+    #[arg(long, value_name = "FILE")]
+    pub geoip_db: Option<PathBuf>,
+}
+
+/// A plain list of prefixes: `file:PATH`, or `-` for stdin
+#[derive(Clone, Debug)]
+pub struct Source(PathBuf);
+
+/// `value_parser` for `--source`
+pub fn parse_source(text: &str) -> Result<Source, String> {
+    match text.strip_prefix("file:") {
+        _ if text == "-" => Ok(Source(PathBuf::from("-"))),
+        Some(path) if !path.is_empty() => Ok(Source(PathBuf::from(path))),
+        _ => Err("expected file:PATH, or - for stdin".to_string()),
+    }
+}
+
+impl Source {
+    /// The key of the source's prefixes in the map: the file's stem, or
+    /// `stdin`
+    pub fn label(&self) -> String {
+        if self.0.as_os_str() == "-" {
+            return "stdin".to_string();
+        }
+        self.0.file_stem().unwrap_or_default().to_string_lossy().into_owned()
+    }
+}
+
+impl SourceArgs {
+    pub fn is_empty(&self) -> bool {
+        self.source.is_empty() && self.region.is_empty() && self.city.is_empty()
+    }
+
+    /// The map keys the sources' prefixes go under, in order
+    pub fn labels(&self) -> Vec<String> {
+        let places = self.region.iter().chain(&self.city).map(Place::label);
+        self.source.iter().map(Source::label).chain(places).collect()
+    }
+
+    /// The map keys of the `--source` lists alone, whose contents are the
+    /// user's own rather than fetched
+    pub fn own_labels(&self) -> Vec<String> {
+        self.source.iter().map(Source::label).collect()
+    }
+
+    /// Add the prefixes of every source to `map`, aggregated so overlapping
+    /// lines from external feeds still load
+    pub fn read(&self, map: &mut HashMap<String, CountryNets>) -> Result<()> {
+        let mut lists = Vec::new();
+        for source in &self.source {
+            lists.push(net::read_prefixes(std::slice::from_ref(&source.0))?);
+        }
+        let places: Vec<Place> = self.region.iter().chain(&self.city).cloned().collect();
+        if let Some(db) = self.geoip_db.as_deref().filter(|_| !places.is_empty()) {
+            lists.extend(geoip::lookup(db, &places)?);
+        }
+        for (label, nets) in self.labels().into_iter().zip(lists) {
+            if map.contains_key(&label) {
+                bail!("source {} clashes with a country or another source of that name", label);
+            }
+            let nets = cidr::aggregate(&nets);
+            if nets.is_empty() {
+                warning!("{} matched no prefixes", label);
+            }
+            let (ipv4, ipv6): (Vec<_>, Vec<_>) = nets.into_iter().map(SerIpNet).partition(|net| net.0.is_ipv4());
+            info!("{} -> {} IPv4 blocks, {} IPv6 blocks", label, ipv4.len(), ipv6.len());
+            map.insert(label, CountryNets { ipv4, ipv6, fetched_at: Some(unix_now()) });
+        }
+        Ok(())
+    }
+}
+
+/// Every country with data in the download cache, left there by earlier
+/// fetches of any list. Each country's timestamp is that of its files.
+pub fn cached_countries(cache_dir: &Path) -> Result<HashMap<String, CountryNets>> {
+    let prefixes = [IPV4_BASE, IPV6_BASE].map(|base| download::cache_name(&format!("{}/", base)));
+    let entries = fs::read_dir(cache_dir).with_context(|| format!("read cache directory {}", cache_dir.display()))?;
+    let mut map: HashMap<String, CountryNets> = HashMap::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let cc = prefixes
+            .iter()
+            .find_map(|prefix| name.strip_prefix(prefix.as_str()))
+            .and_then(|rest| rest.strip_suffix("-aggregated.zone"));
+        let Some(cc) = cc.filter(|cc| cc.len() == 2) else {
+            continue;
+        };
+        let modified = entry.metadata().and_then(|meta| meta.modified()).ok();
+        let fetched_at = modified.and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok()).map(|d| d.as_secs());
+        let country = map
+            .entry(cc.to_string())
+            .or_insert(CountryNets { ipv4: Vec::new(), ipv6: Vec::new(), fetched_at });
+        for net in parse_zone(&entry.path(), false)? {
+            if net.is_ipv4() { &mut country.ipv4 } else { &mut country.ipv6 }.push(SerIpNet(net));
+        }
+        country.fetched_at = country.fetched_at.min(fetched_at);
+    }
+    Ok(map)
+}
+
+async fn fetch_cidrs(client: &reqwest::Client, url: &str, cache_dir: &Path, strict: bool) -> Result<Vec<IpNetwork>> {
+    let path = download::fetch_to_cache(client, url, cache_dir).await?;
+    tokio::task::spawn_blocking(move || parse_zone(&path, strict))
+        .await
+        .context("parser task failed")?
+}
+
+/// Like [`fetch_cidrs`], but IPdeny has no IPv6 zone at all for some small
+/// territories, which is not an error
+async fn fetch_ipv6(client: &reqwest::Client, url: &str, cache_dir: &Path, strict: bool) -> Result<Vec<IpNetwork>> {
+    match fetch_cidrs(client, url, cache_dir, strict).await {
+        Err(e) if e.downcast_ref::<download::NotFound>().is_some() => {
+            info!("No IPv6 zone at {}; taking it as no IPv6 prefixes", url);
+            Ok(Vec::new())
+        }
+        fetched => fetched,
+    }
+}
+
+/// The prefixes in a zone file. Lines that are not prefixes mean a
+/// corrupted download: `strict` fails on them, listing each one, and
+/// otherwise they are counted in a warning.
+fn parse_zone(path: &Path, strict: bool) -> Result<Vec<IpNetwork>> {
+    let body = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+
+    let mut nets = Vec::new();
+    let mut rejected = Vec::new();
+    for (number, line) in body.lines().enumerate() {
+        let token = line.trim();
+        if token.is_empty() || token.starts_with('#') {
+            continue;
+        }
+        match token.parse::<IpNetwork>() {
+            Ok(net) => nets.push(net),
+            Err(_) => rejected.push(format!("{}:{}: `{}`", path.display(), number + 1, token)),
+        }
+    }
+    if !rejected.is_empty() {
+        if strict {
+            bail!("{} lines are not prefixes:\n  {}", rejected.len(), rejected.join("\n  "));
+        }
+        warning!("skipped {} lines of {} that are not prefixes (--strict lists them)", rejected.len(), path.display());
+    }
+    Ok(nets)
+}
@@ -0,0 +1,246 @@
+//! Sanity filters applied to fetched data before rules are generated.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use ipnetwork::IpNetwork;
+
+use crate::{
+    asn,
+    cdn::CdnList,
+    cidr::{self, Exclusions},
+    exit::{Code, WithCode},
+    ui::{info, warning},
+    fetch::{self, HttpArgs},
+    keep,
+    CountryNets, SerIpNet,
+};
+
+/// Special-purpose space (RFC 1918, loopback, link-local, documentation,
+/// multicast, ...) that never belongs to a country
+const RESERVED: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.88.99.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "::ffff:0:0/96",
+    "64:ff9b:1::/48",
+    "100::/64",
+    "2001::/23",
+    "2001:db8::/32",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct FilterArgs {
+    /// Drop IPv4 prefixes broader than /LEN (e.g. 8 rejects a bogus /0)
+    #[arg(long, value_name = "LEN", value_parser = clap::value_parser!(u8).range(0..=32))]
+    pub min_prefix_len: Option<u8>,
+
+    /// Drop IPv4 prefixes more specific than /LEN
+    #[arg(long, value_name = "LEN", value_parser = clap::value_parser!(u8).range(0..=32))]
+    pub max_prefix_len: Option<u8>,
+
+    /// Drop IPv6 prefixes broader than /LEN
+    #[arg(long, value_name = "LEN", value_parser = clap::value_parser!(u8).range(0..=128))]
+    pub min_prefix_len_v6: Option<u8>,
+
+    /// Drop IPv6 prefixes more specific than /LEN
+    #[arg(long, value_name = "LEN", value_parser = clap::value_parser!(u8).range(0..=128))]
+    pub max_prefix_len_v6: Option<u8>,
+
+    /// Widen IPv6 prefixes more specific than /LEN to /LEN (e.g. 64 or
+    /// 48) and merge the result, for smaller sets; this only ever covers
+    /// more addresses, never fewer
+    #[arg(long, value_name = "LEN", value_parser = clap::value_parser!(u8).range(0..=128))]
+    pub widen_v6: Option<u8>,
+
+    /// Keep private and reserved ranges (RFC 1918, loopback, ...) if a
+    /// feed happens to include them; they are stripped by default, except
+    /// from --source lists
+    #[arg(long)]
+    pub keep_reserved: bool,
+
+    /// Carve these networks' ranges out of the country data, so blocking
+    /// a country does not also block CDN POPs located there
+    /// (comma-separated: cloudflare, akamai, fastly)
+    #[arg(long, value_enum, value_name = "LIST", value_delimiter = ',')]
+    pub except_list: Vec<CdnList>,
+
+    /// Carve the prefixes these autonomous systems announce out of the
+    /// country data (comma-separated, e.g. 13335,15169; looked up on RIPEstat)
+    #[arg(long, value_name = "ASN", value_delimiter = ',', value_parser = asn::parse_asn)]
+    pub except_asn: Vec<u32>,
+
+    /// Always accept these, whatever the countries' rules say
+    /// (comma-separated: dns-roots, ntp-pool, package-mirrors; nft rules
+    /// only)
+    #[arg(long, value_enum, value_name = "PRESETS", value_delimiter = ',')]
+    pub keep: Vec<keep::Preset>,
+}
+
+impl FilterArgs {
+    /// Fail on prefix length bounds no prefix can meet
+    pub fn check(&self) -> Result<()> {
+        let bounds = [("", self.min_prefix_len, self.max_prefix_len), ("-v6", self.min_prefix_len_v6, self.max_prefix_len_v6)];
+        for (suffix, min, max) in bounds {
+            if let (Some(min), Some(max)) = (min, max) {
+                if min > max {
+                    return Err(anyhow!(
+                        "--min-prefix-len{0} {1} is above --max-prefix-len{0} {2}, which would drop every prefix",
+                        suffix,
+                        min,
+                        max
+                    ))
+                    .code(Code::Validation);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Apply all configured filters in place, reporting what was dropped. Only
+/// `--except-asn` needs the network. The keys in `own` are the user's own
+/// `--source` lists, which keep any reserved space they list on purpose.
+pub async fn apply(map: &mut HashMap<String, CountryNets>, own: &[String], args: &FilterArgs, http: &HttpArgs) -> Result<()> {
+    let reserved: Vec<IpNetwork> = RESERVED
+        .iter()
+        .map(|net| net.parse().expect("valid reserved range"))
+        .collect();
+    let reserved = Exclusions::new(&reserved);
+
+    let mut excepted: Vec<IpNetwork> = args
+        .except_list
+        .iter()
+        .flat_map(|list| list.prefixes())
+        .map(|net| net.parse().expect("valid CDN range"))
+        .collect();
+    if !args.except_asn.is_empty() {
+        let client = fetch::client(http)?;
+        for asn in &args.except_asn {
+            excepted.extend(asn::announced_prefixes(&client, *asn, &http.cache_dir).await?);
+        }
+    }
+    let excepted = Exclusions::new(&excepted);
+
+    let mut codes: Vec<String> = map.keys().cloned().collect();
+    codes.sort();
+    for cc in codes {
+        let nets = map.get_mut(&cc).expect("code taken from the map");
+        // Before the carving, so that widening cannot cover the carved
+        // space again
+        if let Some(len) = args.widen_v6 {
+            let before = nets.ipv6.len();
+            let widened = widen(&mut nets.ipv6, len);
+            if widened > 0 {
+                info!(
+                    "{}: widened {} IPv6 prefixes to /{} ({} -> {} prefixes)",
+                    cc.to_uppercase(),
+                    widened,
+                    len,
+                    before,
+                    nets.ipv6.len()
+                );
+            }
+        }
+        // Before the stripping and carving too, whose remainders are
+        // often more specific than the feed's prefixes and would otherwise
+        // leave holes
+        let v4 = retain_prefix_len(&mut nets.ipv4, args.min_prefix_len, args.max_prefix_len);
+        let v6 = retain_prefix_len(&mut nets.ipv6, args.min_prefix_len_v6, args.max_prefix_len_v6);
+        if v4 + v6 > 0 {
+            info!(
+                "{}: dropped {} IPv4 and {} IPv6 prefixes outside the allowed prefix lengths",
+                cc.to_uppercase(),
+                v4,
+                v6
+            );
+        }
+        if !args.keep_reserved && !own.contains(&cc) {
+            let stripped = subtract(&mut nets.ipv4, &reserved) + subtract(&mut nets.ipv6, &reserved);
+            if stripped > 0 {
+                warning!(
+                    "{}: removed reserved or private space from {} prefixes (--keep-reserved keeps it)",
+                    cc.to_uppercase(),
+                    stripped
+                );
+            }
+        }
+        if !excepted.is_empty() {
+            let carved = subtract(&mut nets.ipv4, &excepted) + subtract(&mut nets.ipv6, &excepted);
+            if carved > 0 {
+                info!("{}: carved excepted networks out of {} prefixes", cc.to_uppercase(), carved);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keep prefixes whose length lies within `[min, max]`; returns how many
+/// were removed.
+fn retain_prefix_len(nets: &mut Vec<SerIpNet>, min: Option<u8>, max: Option<u8>) -> usize {
+    let before = nets.len();
+    nets.retain(|net| {
+        let len = net.0.prefix();
+        min.is_none_or(|min| len >= min) && max.is_none_or(|max| len <= max)
+    });
+    before - nets.len()
+}
+
+/// Round prefixes more specific than /`len` out to /`len` and merge what
+/// overlaps; returns how many were widened.
+fn widen(nets: &mut Vec<SerIpNet>, len: u8) -> usize {
+    let mut widened = 0;
+    let wide: Vec<IpNetwork> = nets
+        .iter()
+        .map(|net| {
+            if net.0.prefix() <= len {
+                return net.0;
+            }
+            widened += 1;
+            let rounded = IpNetwork::new(net.0.network(), len).expect("shorter than the prefix it widens");
+            IpNetwork::new(rounded.network(), len).expect("shorter than the prefix it widens")
+        })
+        .collect();
+    if widened > 0 {
+        *nets = cidr::aggregate(&wide).into_iter().map(SerIpNet).collect();
+    }
+    widened
+}
+
+/// Remove the space covered by `excluded` from `nets`. Prefixes inside an
+/// excluded block are dropped; broader prefixes containing one are split so
+/// only the excluded part goes. Returns the number of input prefixes
+/// affected.
+fn subtract(nets: &mut Vec<SerIpNet>, excluded: &Exclusions) -> usize {
+    let mut affected = 0;
+    let mut kept = Vec::with_capacity(nets.len());
+    let mut parts = Vec::new();
+    for net in nets.drain(..) {
+        if excluded.overlaps(&net.0) {
+            affected += 1;
+            excluded.subtract(&net.0, &mut parts);
+            kept.extend(parts.drain(..).map(SerIpNet));
+        } else {
+            kept.push(net);
+        }
+    }
+    *nets = kept;
+    affected
+}
@@ -0,0 +1,298 @@
+//! Regions and cities from a MaxMind GeoLite2 City (or GeoIP2 City)
+//! database, for policies finer than a whole country.
+//!
+//! The `.mmdb` file is read here directly: its search tree is walked once
+//! and every network whose record names a selected region or city is kept.
+
+use std::{collections::HashMap, fs, net::{Ipv4Addr, Ipv6Addr}, path::Path};
+
+use anyhow::{bail, Context, Result};
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+use serde_json::{Map, Value};
+
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// A place to select: `us-tx` is the ISO 3166-2 subdivision TX of the
+/// United States, a city is matched by its English name and optionally
+/// its country (`nl:amsterdam`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Place {
+    Region { country: String, subdivision: String },
+    City { country: Option<String>, name: String },
+}
+
+/// `value_parser` for `--region`
+pub fn parse_region(text: &str) -> Result<Place, String> {
+    let (country, subdivision) = text.split_once('-').ok_or("expected COUNTRY-SUBDIVISION, e.g. us-tx")?;
+    if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) || subdivision.is_empty() {
+        return Err("expected COUNTRY-SUBDIVISION, e.g. us-tx".to_string());
+    }
+    Ok(Place::Region { country: country.to_ascii_uppercase(), subdivision: subdivision.to_ascii_uppercase() })
+}
+
+/// `value_parser` for `--city`
+pub fn parse_city(text: &str) -> Result<Place, String> {
+    let (country, name) = match text.split_once(':') {
+        Some((country, name)) if country.len() == 2 => (Some(country.to_ascii_uppercase()), name),
+        Some(_) => return Err("expected CITY or COUNTRY:CITY, e.g. nl:amsterdam".to_string()),
+        None => (None, text),
+    };
+    if name.trim().is_empty() {
+        return Err("the city name is empty".to_string());
+    }
+    Ok(Place::City { country, name: name.trim().to_lowercase() })
+}
+
+impl Place {
+    /// The key of the place's prefixes in the map
+    pub fn label(&self) -> String {
+        match self {
+            Place::Region { country, subdivision } => format!("{}-{}", country, subdivision).to_lowercase(),
+            Place::City { country: Some(country), name } => format!("{}-{}", country.to_lowercase(), name.replace(' ', "_")),
+            Place::City { country: None, name } => name.replace(' ', "_"),
+        }
+    }
+
+    fn matches(&self, record: &Value) -> bool {
+        let country = record["country"]["iso_code"].as_str();
+        match self {
+            Place::Region { country: cc, subdivision } => {
+                country == Some(cc.as_str())
+                    && record["subdivisions"]
+                        .as_array()
+                        .is_some_and(|subs| subs.iter().any(|sub| sub["iso_code"].as_str() == Some(subdivision.as_str())))
+            }
+            Place::City { country: cc, name } => {
+                cc.as_deref().is_none_or(|cc| country == Some(cc))
+                    && record["city"]["names"]["en"].as_str().is_some_and(|city| city.to_lowercase() == *name)
+            }
+        }
+    }
+}
+
+/// The prefixes of every place in `places`, in order, from the database
+/// at `path`
+pub fn lookup(path: &Path, places: &[Place]) -> Result<Vec<Vec<IpNetwork>>> {
+    let bytes = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+    let db = Database::open(&bytes).with_context(|| format!("{} is not a MaxMind database", path.display()))?;
+    if !db.database_type.contains("City") {
+        bail!("{} is a {} database; regions and cities need a City database", path.display(), db.database_type);
+    }
+    let mut found = vec![Vec::new(); places.len()];
+    // Records are shared by many networks; decode and match each once
+    let mut matched: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut failure = None;
+    db.walk(|net, offset| {
+        if failure.is_some() {
+            return;
+        }
+        let hits = match matched.get(&offset) {
+            Some(hits) => hits,
+            None => {
+                let hits = match db.decode(offset) {
+                    Ok((record, _)) => places.iter().enumerate().filter(|(_, place)| place.matches(&record)).map(|(i, _)| i).collect(),
+                    Err(e) => {
+                        failure = Some(e);
+                        return;
+                    }
+                };
+                matched.entry(offset).or_insert(hits)
+            }
+        };
+        for &i in hits {
+            found[i].push(net);
+        }
+    })?;
+    if let Some(e) = failure {
+        return Err(e.context(format!("bad record in {}", path.display())));
+    }
+    Ok(found)
+}
+
+struct Database<'a> {
+    tree: &'a [u8],
+    data: &'a [u8],
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    database_type: String,
+}
+
+impl<'a> Database<'a> {
+    fn open(bytes: &'a [u8]) -> Result<Self> {
+        let start = bytes
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .context("no metadata")?
+            + METADATA_MARKER.len();
+        let metadata = Database { tree: &[], data: &bytes[start..], node_count: 0, record_size: 0, ip_version: 0, database_type: String::new() };
+        let (metadata, _) = metadata.decode(0)?;
+        let field = |name: &str| metadata[name].as_u64().with_context(|| format!("metadata has no {}", name));
+        let (node_count, record_size) = (field("node_count")? as usize, field("record_size")? as usize);
+        if !matches!(record_size, 24 | 28 | 32) {
+            bail!("unsupported record size {}", record_size);
+        }
+        let tree_size = node_count * record_size / 4;
+        if tree_size + 16 > start {
+            bail!("search tree is larger than the file");
+        }
+        Ok(Database {
+            tree: &bytes[..tree_size],
+            data: &bytes[tree_size + 16..start - METADATA_MARKER.len()],
+            node_count,
+            record_size,
+            ip_version: field("ip_version")?,
+            database_type: metadata["database_type"].as_str().unwrap_or_default().to_string(),
+        })
+    }
+
+    /// The left or right record of `node`
+    fn record(&self, node: usize, right: bool) -> usize {
+        let width = self.record_size / 4;
+        let b = &self.tree[node * width..(node + 1) * width];
+        let read = |bytes: &[u8]| bytes.iter().fold(0usize, |n, &b| n << 8 | usize::from(b));
+        match (self.record_size, right) {
+            (24, false) => read(&b[..3]),
+            (24, true) => read(&b[3..]),
+            (28, false) => usize::from(b[3] >> 4) << 24 | read(&b[..3]),
+            (28, true) => usize::from(b[3] & 0x0f) << 24 | read(&b[4..]),
+            (_, false) => read(&b[..4]),
+            (_, true) => read(&b[4..]),
+        }
+    }
+
+    /// Call `visit` with every network in the tree and the offset of its
+    /// record in the data section. IPv4 networks are reported once, not
+    /// again under the IPv6 ranges that alias them.
+    fn walk(&self, mut visit: impl FnMut(IpNetwork, usize)) -> Result<()> {
+        let bits: u8 = if self.ip_version == 6 { 128 } else { 32 };
+        // In IPv6 databases IPv4 lives under ::/96
+        let mut ipv4_node = None;
+        if bits == 128 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= self.node_count {
+                    break;
+                }
+                node = self.record(node, false);
+            }
+            ipv4_node = Some(node).filter(|&node| node < self.node_count);
+        }
+        let mut stack = vec![(0usize, 0u128, 0u8)];
+        while let Some((node, addr, depth)) = stack.pop() {
+            // Anywhere but ::/96 itself, IPv4 is an alias (::ffff:0:0/96,
+            // 2002::/16)
+            if ipv4_node == Some(node) && (depth, addr) != (96, 0) {
+                continue;
+            }
+            for right in [true, false] {
+                let addr = if right { addr | 1 << (bits - 1 - depth) } else { addr };
+                let depth = depth + 1;
+                let next = self.record(node, right);
+                if next < self.node_count {
+                    if depth >= bits {
+                        bail!("search tree is deeper than the addresses");
+                    }
+                    stack.push((next, addr, depth));
+                } else if next > self.node_count {
+                    let offset = next - self.node_count - 16;
+                    if offset >= self.data.len() {
+                        bail!("record points past the data section");
+                    }
+                    visit(network(addr, depth, bits), offset);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The value at `offset` of the data section and the offset after it
+    fn decode(&self, offset: usize) -> Result<(Value, usize)> {
+        let byte = |at: usize| self.data.get(at).copied().context("value runs past the data section");
+        let control = byte(offset)?;
+        let mut at = offset + 1;
+        let mut kind = control >> 5;
+        if kind == 0 {
+            kind = 7 + byte(at)?;
+            at += 1;
+        }
+        if kind == 1 {
+            let size = usize::from(control & 0x1f);
+            let (length, base) = match size >> 3 {
+                0 => (1, 0),
+                1 => (2, 2048),
+                2 => (3, 526_336),
+                _ => (4, 0),
+            };
+            let mut pointer = if length == 4 { 0 } else { size & 0x7 };
+            for i in 0..length {
+                pointer = pointer << 8 | usize::from(byte(at + i)?);
+            }
+            let (value, _) = self.decode(pointer + base)?;
+            return Ok((value, at + length));
+        }
+        let mut size = usize::from(control & 0x1f);
+        let extra = |at: usize, n: usize| -> Result<usize> { (0..n).try_fold(0, |s, i| Ok(s << 8 | usize::from(byte(at + i)?))) };
+        match size {
+            29 => {
+                size = 29 + extra(at, 1)?;
+                at += 1;
+            }
+            30 => {
+                size = 285 + extra(at, 2)?;
+                at += 2;
+            }
+            31 => {
+                size = 65_821 + extra(at, 3)?;
+                at += 3;
+            }
+            _ => {}
+        }
+        let bytes = |at: usize, n: usize| self.data.get(at..at + n).context("value runs past the data section");
+        let uint = |at: usize, n: usize| -> Result<u128> { Ok(bytes(at, n)?.iter().fold(0u128, |v, &b| v << 8 | u128::from(b))) };
+        Ok(match kind {
+            2 => (Value::String(String::from_utf8_lossy(bytes(at, size)?).into_owned()), at + size),
+            3 => (serde_json::json!(f64::from_be_bytes(bytes(at, 8)?.try_into()?)), at + 8),
+            4 => (Value::Null, at + size),
+            5 | 6 | 9 | 10 => {
+                let value = uint(at, size)?;
+                (u64::try_from(value).map(Value::from).unwrap_or(Value::String(value.to_string())), at + size)
+            }
+            8 => (Value::from(uint(at, size)? as u32 as i32), at + size),
+            7 => {
+                let mut map = Map::new();
+                for _ in 0..size {
+                    let (key, next) = self.decode(at)?;
+                    let (value, next) = self.decode(next)?;
+                    map.insert(key.as_str().unwrap_or_default().to_string(), value);
+                    at = next;
+                }
+                (Value::Object(map), at)
+            }
+            11 => {
+                let mut items = Vec::with_capacity(size.min(1024));
+                for _ in 0..size {
+                    let (value, next) = self.decode(at)?;
+                    items.push(value);
+                    at = next;
+                }
+                (Value::Array(items), at)
+            }
+            14 => (Value::Bool(size != 0), at),
+            15 => (serde_json::json!(f32::from_be_bytes(bytes(at, 4)?.try_into()?)), at + 4),
+            other => bail!("unknown data type {} at offset {}", other, offset),
+        })
+    }
+}
+
+/// The network of the first `depth` bits of `addr`; in IPv6 databases
+/// those under ::/96 are IPv4
+fn network(addr: u128, depth: u8, bits: u8) -> IpNetwork {
+    if bits == 32 {
+        return IpNetwork::V4(Ipv4Network::new(Ipv4Addr::from(addr as u32), depth).expect("valid prefix"));
+    }
+    if depth >= 96 && addr >> 32 == 0 {
+        return IpNetwork::V4(Ipv4Network::new(Ipv4Addr::from(addr as u32), depth - 96).expect("valid prefix"));
+    }
+    IpNetwork::V6(Ipv6Network::new(Ipv6Addr::from(addr), depth).expect("valid prefix"))
+}
@@ -0,0 +1,590 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde_json::Value;
+use std::{collections::HashMap, fmt, fs, path::Path};
+
+use crate::{iso3166, ui::info, yaml};
+
+/// ISO 3166-1 alpha-2 code (as used by IPdeny) and display name
+pub type Country = (&'static str, &'static str);
+
+/// Codes that stand for several countries and have no data of their own,
+/// with the countries they expand to
+const PSEUDO_CODES: &[(&str, &[Country])] = &[("eu", EU)];
+
+/// A resolved selection: the name used for output files and its members
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub name: String,
+    pub countries: Vec<(String, String)>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum ListChoice {
+    Brics,
+    Nato,
+    Eu,
+    Asean,
+    G7,
+    G20,
+    Opec,
+    Africa,
+    FiveEyes,
+    NineEyes,
+    FourteenEyes,
+    Schengen,
+    Gcc,
+    Mercosur,
+    Sco,
+    Sanctioned,
+}
+
+impl ListChoice {
+    /// Member countries of the group
+    pub fn countries(self) -> &'static [Country] {
+        match self {
+            ListChoice::Brics => BRICS,
+            ListChoice::Nato => NATO,
+            ListChoice::Eu => EU,
+            ListChoice::Asean => ASEAN,
+            ListChoice::G7 => G7,
+            ListChoice::G20 => G20,
+            ListChoice::Opec => OPEC,
+            ListChoice::Africa => AFRICAN_UNION,
+            ListChoice::FiveEyes => FIVE_EYES,
+            ListChoice::NineEyes => NINE_EYES,
+            ListChoice::FourteenEyes => FOURTEEN_EYES,
+            ListChoice::Schengen => SCHENGEN,
+            ListChoice::Gcc => GCC,
+            ListChoice::Mercosur => MERCOSUR,
+            ListChoice::Sco => SCO,
+            ListChoice::Sanctioned => SANCTIONED,
+        }
+    }
+
+    /// Member countries with pseudo codes such as `eu` expanded, as the
+    /// group resolves
+    pub fn members(self) -> Vec<(String, String)> {
+        expand(self.countries().iter().map(|(cc, name)| (cc.to_string(), name.to_string())).collect())
+    }
+}
+
+/// Load named groups from a YAML file mapping each group name to a list of
+/// country codes:
+///
+/// ```yaml
+/// partners: [de, fr, nl]
+/// edge:
+///   - ru
+///   - cn
+/// ```
+pub fn load_group_file(path: &Path) -> Result<HashMap<String, Vec<String>>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("read group file {}", path.display()))?;
+    let doc = yaml::parse(&text).with_context(|| format!("parse {}", path.display()))?;
+    let Value::Object(entries) = doc else {
+        bail!("{}: expected a mapping of group name to country codes", path.display());
+    };
+
+    let mut groups = HashMap::new();
+    for (name, members) in entries {
+        let Value::Array(members) = members else {
+            bail!("{}: group `{}` must be a list of country codes", path.display(), name);
+        };
+        let mut codes = Vec::new();
+        for member in members {
+            let code = match member {
+                Value::String(code) => code.to_ascii_lowercase(),
+                other => bail!("{}: group `{}`: invalid country code {}", path.display(), name, other),
+            };
+            if code.len() != 2 || !code.bytes().all(|b| b.is_ascii_lowercase()) {
+                bail!("{}: group `{}`: `{}` is not a two-letter country code", path.display(), name, code);
+            }
+            codes.push(code);
+        }
+        groups.insert(name, codes);
+    }
+    Ok(groups)
+}
+
+/// Resolve a list name against the group file first, then the built-ins.
+/// Groups from the file may shadow a built-in of the same name. Several
+/// names joined with `+` (e.g. `nato+g7`) resolve to every country in any
+/// of them, each once.
+pub fn resolve(name: &str, file_groups: &HashMap<String, Vec<String>>) -> Result<Group> {
+    if !name.contains('+') {
+        return resolve_one(name, file_groups);
+    }
+    let mut names = Vec::new();
+    let mut countries = Vec::new();
+    for part in name.split('+') {
+        let group = resolve_one(part, file_groups)?;
+        names.push(group.name);
+        countries.extend(group.countries);
+    }
+    let listed = countries.len();
+    let countries = expand(countries);
+    let name = names.join("+");
+    let codes: Vec<String> = countries.iter().map(|(cc, _)| cc.to_uppercase()).collect();
+    info!("{} resolves to {} countries: {}", name, codes.len(), codes.join(", "));
+    if listed > countries.len() {
+        info!("{} countries in more than one of the groups are fetched once.", listed - countries.len());
+    }
+    Ok(Group { name, countries })
+}
+
+fn resolve_one(name: &str, file_groups: &HashMap<String, Vec<String>>) -> Result<Group> {
+    if let Some(codes) = file_groups.get(name) {
+        let countries = codes
+            .iter()
+            .map(|cc| (cc.clone(), country_name(cc).unwrap_or(&cc.to_uppercase()).to_string()))
+            .collect();
+        return Ok(Group { name: name.to_string(), countries: expand(countries) });
+    }
+
+    match ListChoice::from_str(name, true) {
+        Ok(list) => Ok(Group { name: list.to_string(), countries: list.members() }),
+        Err(_) => {
+            let builtin: Vec<String> = ListChoice::value_variants()
+                .iter()
+                .filter_map(|v| v.to_possible_value().map(|p| p.get_name().to_string()))
+                .collect();
+            bail!(
+                "unknown list `{}` (built-in lists: {}; custom groups can be defined with --group-file)",
+                name,
+                builtin.join(", ")
+            )
+        }
+    }
+}
+
+/// Replace pseudo codes such as `eu` by their member countries, keeping the
+/// first mention of each country
+fn expand(countries: Vec<(String, String)>) -> Vec<(String, String)> {
+    let mut expanded: Vec<(String, String)> = Vec::new();
+    for (cc, name) in countries {
+        let members = match PSEUDO_CODES.iter().find(|(code, _)| *code == cc) {
+            Some((_, members)) => members.iter().map(|(cc, name)| (cc.to_string(), name.to_string())).collect(),
+            None => vec![(cc, name)],
+        };
+        for member in members {
+            if !expanded.iter().any(|(cc, _)| *cc == member.0) {
+                expanded.push(member);
+            }
+        }
+    }
+    expanded
+}
+
+/// Group named `name` made of explicitly listed countries: codes, names
+/// or aliases (see [`iso3166::resolve`])
+pub fn from_codes(name: &str, codes: &[String]) -> Result<Group> {
+    let mut resolved = Vec::new();
+    for text in codes.iter().map(|text| text.trim()).filter(|text| !text.is_empty()) {
+        let code = text.to_ascii_lowercase();
+        let known = iso3166::name(&code).is_some() || PSEUDO_CODES.iter().any(|(cc, _)| *cc == code);
+        // Two letters are still taken as a code IPdeny may know
+        let plain_code = code.len() == 2 && code.bytes().all(|b| b.is_ascii_lowercase());
+        match iso3166::resolve(text) {
+            _ if known => resolved.push(code),
+            Ok(cc) => {
+                let name = iso3166::name(cc).unwrap_or(cc);
+                if name.to_lowercase() != text.to_lowercase() {
+                    info!("`{}` is {} ({})", text, name, cc.to_uppercase());
+                }
+                resolved.push(cc.to_string());
+            }
+            Err(_) if plain_code => resolved.push(code),
+            Err(e) => bail!(e),
+        }
+    }
+    resolve(name, &HashMap::from([(name.to_string(), resolved)]))
+}
+
+/// Display name of a country
+pub fn country_name(code: &str) -> Option<&'static str> {
+    iso3166::name(code)
+}
+
+// --- Implement Display for filename formatting ---
+impl fmt::Display for ListChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListChoice::Brics => write!(f, "brics"),
+            ListChoice::Nato => write!(f, "nato"),
+            ListChoice::Eu => write!(f, "eu"),
+            ListChoice::Asean => write!(f, "asean"),
+            ListChoice::G7 => write!(f, "g7"),
+            ListChoice::G20 => write!(f, "g20"),
+            ListChoice::Opec => write!(f, "opec"),
+            ListChoice::Africa => write!(f, "african_union"),
+            ListChoice::FiveEyes => write!(f, "five_eyes"),
+            ListChoice::NineEyes => write!(f, "nine_eyes"),
+            ListChoice::FourteenEyes => write!(f, "fourteen_eyes"),
+            ListChoice::Schengen => write!(f, "schengen"),
+            ListChoice::Gcc => write!(f, "gcc"),
+            ListChoice::Mercosur => write!(f, "mercosur"),
+            ListChoice::Sco => write!(f, "sco"),
+            ListChoice::Sanctioned => write!(f, "sanctioned"),
+        }
+    }
+}
+
+const BRICS: &[Country] = &[
+    ("br", "Brazil"),
+    ("ru", "Russia"),
+    ("in", "India"),
+    ("cn", "China"),
+    ("za", "South Africa"),
+];
+
+const NATO: &[Country] = &[
+    ("al", "Albania"),
+    ("be", "Belgium"),
+    ("bg", "Bulgaria"),
+    ("ca", "Canada"),
+    ("hr", "Croatia"),
+    ("cz", "Czechia"),
+    ("dk", "Denmark"),
+    ("ee", "Estonia"),
+    ("fi", "Finland"),
+    ("fr", "France"),
+    ("de", "Germany"),
+    ("gr", "Greece"),
+    ("hu", "Hungary"),
+    ("is", "Iceland"),
+    ("it", "Italy"),
+    ("lv", "Latvia"),
+    ("lt", "Lithuania"),
+    ("lu", "Luxembourg"),
+    ("mt", "Malta"),
+    ("nl", "Netherlands"),
+    ("no", "Norway"),
+    ("pl", "Poland"),
+    ("pt", "Portugal"),
+    ("ro", "Romania"),
+    ("sk", "Slovakia"),
+    ("si", "Slovenia"),
+    ("es", "Spain"),
+    ("se", "Sweden"),
+    ("tr", "Türkiye"),
+    ("gb", "United Kingdom"),
+    ("us", "United States"),
+];
+
+const EU: &[Country] = &[
+    ("at", "Austria"),
+    ("be", "Belgium"),
+    ("bg", "Bulgaria"),
+    ("hr", "Croatia"),
+    ("cy", "Cyprus"),
+    ("cz", "Czechia"),
+    ("dk", "Denmark"),
+    ("ee", "Estonia"),
+    ("fi", "Finland"),
+    ("fr", "France"),
+    ("de", "Germany"),
+    ("gr", "Greece"),
+    ("hu", "Hungary"),
+    ("ie", "Ireland"),
+    ("it", "Italy"),
+    ("lv", "Latvia"),
+    ("lt", "Lithuania"),
+    ("lu", "Luxembourg"),
+    ("mt", "Malta"),
+    ("nl", "Netherlands"),
+    ("pl", "Poland"),
+    ("pt", "Portugal"),
+    ("ro", "Romania"),
+    ("sk", "Slovakia"),
+    ("si", "Slovenia"),
+    ("es", "Spain"),
+    ("se", "Sweden"),
+];
+
+const ASEAN: &[Country] = &[
+    ("id", "Indonesia"),
+    ("my", "Malaysia"),
+    ("ph", "Philippines"),
+    ("sg", "Singapore"),
+    ("th", "Thailand"),
+    ("vn", "Vietnam"),
+    ("mm", "Myanmar"),
+    ("kh", "Cambodia"),
+    ("la", "Laos"),
+    ("bn", "Brunei"),
+];
+
+const G7: &[Country] = &[
+    ("ca", "Canada"),
+    ("fr", "France"),
+    ("de", "Germany"),
+    ("it", "Italy"),
+    ("jp", "Japan"),
+    ("gb", "United Kingdom"),
+    ("us", "United States"),
+];
+
+const G20: &[Country] = &[
+    ("ar", "Argentina"),
+    ("au", "Australia"),
+    ("br", "Brazil"),
+    ("ca", "Canada"),
+    ("cn", "China"),
+    ("fr", "France"),
+    ("de", "Germany"),
+    ("in", "India"),
+    ("id", "Indonesia"),
+    ("it", "Italy"),
+    ("jp", "Japan"),
+    ("mx", "Mexico"),
+    ("ru", "Russia"),
+    ("sa", "Saudi Arabia"),
+    ("za", "South Africa"),
+    ("kr", "South Korea"),
+    ("tr", "Türkiye"),
+    ("gb", "United Kingdom"),
+    ("us", "United States"),
+    // Expanded to the EU's members when the group is resolved
+    ("eu", "European Union"),
+];
+
+const OPEC: &[Country] = &[
+    ("dz", "Algeria"),
+    ("ao", "Angola"),
+    ("cd", "Congo"),
+    ("gq", "Equatorial Guinea"),
+    ("ga", "Gabon"),
+    ("iq", "Iraq"),
+    ("kw", "Kuwait"),
+    ("ly", "Libya"),
+    ("ng", "Nigeria"),
+    ("sa", "Saudi Arabia"),
+    ("ae", "United Arab Emirates"),
+    ("ve", "Venezuela"),
+];
+
+const AFRICAN_UNION: &[Country] = &[
+    ("dz", "Algeria"),
+    ("ao", "Angola"),
+    ("bj", "Benin"),
+    ("bw", "Botswana"),
+    ("bf", "Burkina Faso"),
+    ("bi", "Burundi"),
+    ("cm", "Cameroon"),
+    ("cv", "Cape Verde"),
+    ("cf", "Central African Republic"),
+    ("td", "Chad"),
+    ("km", "Comoros"),
+    ("cg", "Congo"),
+    ("cd", "Democratic Republic of the Congo"),
+    ("ci", "Côte d'Ivoire"),
+    ("dj", "Djibouti"),
+    ("eg", "Egypt"),
+    ("gq", "Equatorial Guinea"),
+    ("er", "Eritrea"),
+    ("sz", "Eswatini"),
+    ("et", "Ethiopia"),
+    ("ga", "Gabon"),
+    ("gm", "Gambia"),
+    ("gh", "Ghana"),
+    ("gn", "Guinea"),
+    ("gw", "Guinea-Bissau"),
+    ("ke", "Kenya"),
+    ("ls", "Lesotho"),
+    ("lr", "Liberia"),
+    ("ly", "Libya"),
+    ("mg", "Madagascar"),
+    ("mw", "Malawi"),
+    ("ml", "Mali"),
+    ("mr", "Mauritania"),
+    ("mu", "Mauritius"),
+    ("ma", "Morocco"),
+    ("mz", "Mozambique"),
+    ("na", "Namibia"),
+    ("ne", "Niger"),
+    ("ng", "Nigeria"),
+    ("rw", "Rwanda"),
+    ("st", "São Tomé and Príncipe"),
+    ("sn", "Senegal"),
+    ("sc", "Seychelles"),
+    ("sl", "Sierra Leone"),
+    ("so", "Somalia"),
+    ("za", "South Africa"),
+    ("ss", "South Sudan"),
+    ("sd", "Sudan"),
+    ("tz", "Tanzania"),
+    ("tg", "Togo"),
+    ("tn", "Tunisia"),
+    ("ug", "Uganda"),
+    ("zm", "Zambia"),
+    ("zw", "Zimbabwe"),
+];
+
+/// UKUSA signals-intelligence alliance
+const FIVE_EYES: &[Country] = &[
+    ("au", "Australia"),
+    ("ca", "Canada"),
+    ("nz", "New Zealand"),
+    ("gb", "United Kingdom"),
+    ("us", "United States"),
+];
+
+/// Five Eyes plus Denmark, France, the Netherlands and Norway
+const NINE_EYES: &[Country] = &[
+    ("au", "Australia"),
+    ("ca", "Canada"),
+    ("nz", "New Zealand"),
+    ("gb", "United Kingdom"),
+    ("us", "United States"),
+    ("dk", "Denmark"),
+    ("fr", "France"),
+    ("nl", "Netherlands"),
+    ("no", "Norway"),
+];
+
+/// Nine Eyes plus Germany, Belgium, Italy, Spain and Sweden
+const FOURTEEN_EYES: &[Country] = &[
+    ("au", "Australia"),
+    ("ca", "Canada"),
+    ("nz", "New Zealand"),
+    ("gb", "United Kingdom"),
+    ("us", "United States"),
+    ("dk", "Denmark"),
+    ("fr", "France"),
+    ("nl", "Netherlands"),
+    ("no", "Norway"),
+    ("de", "Germany"),
+    ("be", "Belgium"),
+    ("it", "Italy"),
+    ("es", "Spain"),
+    ("se", "Sweden"),
+];
+
+/// Schengen travel area; differs from the EU (includes Iceland,
+/// Liechtenstein, Norway and Switzerland, excludes Cyprus and Ireland)
+const SCHENGEN: &[Country] = &[
+    ("at", "Austria"),
+    ("be", "Belgium"),
+    ("bg", "Bulgaria"),
+    ("hr", "Croatia"),
+    ("cz", "Czechia"),
+    ("dk", "Denmark"),
+    ("ee", "Estonia"),
+    ("fi", "Finland"),
+    ("fr", "France"),
+    ("de", "Germany"),
+    ("gr", "Greece"),
+    ("hu", "Hungary"),
+    ("is", "Iceland"),
+    ("it", "Italy"),
+    ("lv", "Latvia"),
+    ("li", "Liechtenstein"),
+    ("lt", "Lithuania"),
+    ("lu", "Luxembourg"),
+    ("mt", "Malta"),
+    ("nl", "Netherlands"),
+    ("no", "Norway"),
+    ("pl", "Poland"),
+    ("pt", "Portugal"),
+    ("ro", "Romania"),
+    ("sk", "Slovakia"),
+    ("si", "Slovenia"),
+    ("es", "Spain"),
+    ("se", "Sweden"),
+    ("ch", "Switzerland"),
+];
+
+/// Gulf Cooperation Council
+const GCC: &[Country] = &[
+    ("bh", "Bahrain"),
+    ("kw", "Kuwait"),
+    ("om", "Oman"),
+    ("qa", "Qatar"),
+    ("sa", "Saudi Arabia"),
+    ("ae", "United Arab Emirates"),
+];
+
+/// Mercosur full members (Venezuela is suspended and not included)
+const MERCOSUR: &[Country] = &[
+    ("ar", "Argentina"),
+    ("bo", "Bolivia"),
+    ("br", "Brazil"),
+    ("py", "Paraguay"),
+    ("uy", "Uruguay"),
+];
+
+/// Shanghai Cooperation Organisation member states
+const SCO: &[Country] = &[
+    ("by", "Belarus"),
+    ("cn", "China"),
+    ("in", "India"),
+    ("ir", "Iran"),
+    ("kz", "Kazakhstan"),
+    ("kg", "Kyrgyzstan"),
+    ("pk", "Pakistan"),
+    ("ru", "Russia"),
+    ("tj", "Tajikistan"),
+    ("uz", "Uzbekistan"),
+];
+
+/// Jurisdictions under comprehensive sanctions programmes. Occupied
+/// regions (Crimea, Donetsk, Luhansk) have no country code of their own in
+/// the IPdeny data and cannot be represented here.
+const SANCTIONED: &[Country] = &[
+    ("cu", "Cuba"),
+    ("ir", "Iran"),
+    ("kp", "North Korea"),
+    ("sy", "Syria"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn members_are_known_countries() {
+        for list in ListChoice::value_variants() {
+            for &(cc, _) in list.countries() {
+                if PSEUDO_CODES.iter().any(|&(code, _)| code == cc) {
+                    continue;
+                }
+                assert_eq!(iso3166::resolve(cc), Ok(cc), "{} in {}", cc, list);
+            }
+        }
+    }
+
+    fn codes(group: &Group) -> Vec<&str> {
+        group.countries.iter().map(|(cc, _)| cc.as_str()).collect()
+    }
+
+    #[test]
+    fn g20_includes_the_eu_members() {
+        let g20 = resolve("g20", &HashMap::new()).unwrap();
+        let codes = codes(&g20);
+        assert!(!codes.contains(&"eu"));
+        for (cc, _) in EU {
+            assert!(codes.contains(cc), "{} missing from g20", cc);
+        }
+    }
+
+    #[test]
+    fn expand_keeps_the_first_mention() {
+        let countries = [("fr", "France"), ("eu", "European Union"), ("de", "Germany"), ("fr", "France")];
+        let expanded = expand(countries.iter().map(|(cc, name)| (cc.to_string(), name.to_string())).collect());
+        assert_eq!(expanded.len(), EU.len());
+        assert_eq!(expanded[0].0, "fr");
+        assert_eq!(expanded.iter().filter(|(cc, _)| cc == "fr" || cc == "de").count(), 2);
+    }
+
+    #[test]
+    fn joined_lists_resolve_to_each_country_once() {
+        let file_groups = HashMap::from([("edge".to_string(), vec!["ru".to_string(), "de".to_string()])]);
+        let joined = resolve("g7+edge", &file_groups).unwrap();
+        assert_eq!(joined.name, "g7+edge");
+        let codes = codes(&joined);
+        assert_eq!(codes.len(), G7.len() + 1);
+        assert_eq!(codes.iter().filter(|&&cc| cc == "de").count(), 1);
+        assert!(codes.contains(&"ru"));
+    }
+}
@@ -0,0 +1,75 @@
+//! Refusing refreshes whose data changed implausibly since the last one.
+//!
+//! A country that loses most of its prefixes overnight almost always means
+//! a truncated download or a provider outage, and loading that would open
+//! a block policy (or lock people out of an allow policy). Each refresh is
+//! compared with the JSON map the previous one left behind before that map
+//! is overwritten.
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    exit::{Code, WithCode},
+    read_map,
+    ui::warning,
+    CountryNets,
+};
+
+/// Families with fewer prefixes than this last time are not checked: a
+/// small country going from 4 prefixes to 1 is ordinary
+const MIN_PREFIXES: usize = 16;
+
+#[derive(clap::Args, Clone, Copy, Debug)]
+pub struct GuardArgs {
+    /// Refuse a refresh in which a country lost more than this percentage
+    /// of its IPv4 or IPv6 prefixes since the last one (100 turns the
+    /// check off)
+    #[arg(long, value_name = "PERCENT", default_value_t = 50.0)]
+    pub max_shrink: f64,
+
+    /// Also refuse one in which a country grew by more than this
+    /// percentage
+    #[arg(long, value_name = "PERCENT")]
+    pub max_growth: Option<f64>,
+}
+
+/// Fail if any of `countries` changed beyond the limits between the map at
+/// `previous` (if there is one) and `map`
+pub fn check(previous: &Path, map: &HashMap<String, CountryNets>, countries: &[(String, String)], limits: GuardArgs) -> Result<()> {
+    if !previous.exists() {
+        return Ok(());
+    }
+    let before = match read_map(previous) {
+        Ok(before) => before,
+        Err(e) => {
+            warning!("not comparing the refresh with {}: {:#}", previous.display(), e);
+            return Ok(());
+        }
+    };
+    let mut anomalies = Vec::new();
+    for (cc, name) in countries {
+        // Countries left out by --on-failure skip were not refreshed at all
+        let (Some(old), Some(new)) = (before.get(cc), map.get(cc)) else { continue };
+        let families = [("IPv4", old.ipv4.len(), new.ipv4.len()), ("IPv6", old.ipv6.len(), new.ipv6.len())];
+        for (family, old, new) in families {
+            if old < MIN_PREFIXES {
+                continue;
+            }
+            let change = (new as f64 - old as f64) * 100.0 / old as f64;
+            if -change > limits.max_shrink || limits.max_growth.is_some_and(|limit| change > limit) {
+                anomalies.push(format!("{} ({}) {} {} -> {} ({:+.0}%)", name, cc.to_uppercase(), family, old, new, change));
+            }
+        }
+    }
+    if !anomalies.is_empty() {
+        return Err(anyhow!(
+            "refusing the refresh, the data changed too much since the last one, which usually means a truncated download \
+             or a provider outage: {}. Raise --max-shrink or --max-growth if the change is real",
+            anomalies.join(", ")
+        ))
+        .code(Code::Validation);
+    }
+    Ok(())
+}
@@ -0,0 +1,122 @@
+//! HAProxy map files of prefix -> country, for `map_ip()` and ACLs such
+//! as `acl geo src,map_ip(/etc/haproxy/cloak.map) -m found`.
+//!
+//! The daemon can also feed a running HAProxy through its runtime API, so
+//! the proxy follows every refresh without a reload: a new version of the
+//! map is prepared, filled in one `add map` payload and committed, which
+//! swaps it in atomically (HAProxy 2.4 or later).
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Write as _},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::CountryNets;
+
+/// The `<cidr> <CC>` lines of a map file for `map`
+fn entries(map: &HashMap<String, CountryNets>) -> String {
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    let mut text = String::new();
+    for cc in codes {
+        for net in map[cc].ipv4.iter().chain(&map[cc].ipv6) {
+            // Writing to a String cannot fail
+            let _ = writeln!(text, "{} {}", net.0, cc.to_uppercase());
+        }
+    }
+    text
+}
+
+/// Write a map file for `map` to `filename`
+pub fn generate_haproxy(map: &HashMap<String, CountryNets>, filename: &str) -> Result<()> {
+    let text = format!("# Generated by cloak. Use with: src,map_ip({})\n{}", filename, entries(map));
+    fs::write(filename, text).with_context(|| format!("write {}", filename))
+}
+
+/// HAProxy's runtime API (a `stats socket`)
+#[derive(Clone, Debug)]
+pub enum Socket {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+impl fmt::Display for Socket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Socket::Unix(path) => write!(f, "{}", path.display()),
+            Socket::Tcp(addr) => f.write_str(addr),
+        }
+    }
+}
+
+/// `value_parser` for `--haproxy-socket`: a socket path, or HOST:PORT
+pub fn parse_socket(text: &str) -> Result<Socket, String> {
+    if text.contains('/') {
+        if !cfg!(unix) {
+            return Err("Unix sockets are not supported on this platform; give HOST:PORT".to_string());
+        }
+        Ok(Socket::Unix(PathBuf::from(text)))
+    } else if text.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) {
+        Ok(Socket::Tcp(text.to_string()))
+    } else {
+        Err(format!("expected a socket path or HOST:PORT, not `{}`", text))
+    }
+}
+
+impl Socket {
+    /// Send one command and return HAProxy's reply
+    async fn command(&self, command: &str) -> Result<String> {
+        match self {
+            #[cfg(unix)]
+            Socket::Unix(path) => {
+                let stream = tokio::net::UnixStream::connect(path).await.with_context(|| format!("connect to {}", self))?;
+                exchange(stream, command).await
+            }
+            #[cfg(not(unix))]
+            Socket::Unix(_) => unreachable!("rejected by parse_socket"),
+            Socket::Tcp(addr) => {
+                let stream = tokio::net::TcpStream::connect(addr).await.with_context(|| format!("connect to {}", self))?;
+                exchange(stream, command).await
+            }
+        }
+        .with_context(|| format!("HAProxy at {}", self))
+    }
+}
+
+/// Without `prompt` mode HAProxy answers one command and closes
+async fn exchange(mut stream: impl AsyncRead + AsyncWrite + Unpin, command: &str) -> Result<String> {
+    stream.write_all(command.as_bytes()).await.context("send command")?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await.context("read reply")?;
+    Ok(reply)
+}
+
+/// Rewrite `path` with `map` (so a restart finds it current) and replace
+/// the running HAProxy's copy of it, returning the number of entries
+pub async fn feed(socket: &Socket, path: &Path, map: &HashMap<String, CountryNets>) -> Result<usize> {
+    let filename = path.to_string_lossy();
+    let partial = path.with_extension("map.partial");
+    generate_haproxy(map, &partial.to_string_lossy())?;
+    fs::rename(&partial, path).with_context(|| format!("replace {}", path.display()))?;
+
+    let reply = socket.command(&format!("prepare map {}\n", filename)).await?;
+    let Some(version) = reply.trim().strip_prefix("New version created:").map(str::trim) else {
+        bail!("HAProxy did not prepare {}: {}", filename, reply.trim());
+    };
+    let entries = entries(map);
+    // The payload ends at the first empty line
+    let reply = socket.command(&format!("add map @{} {} <<\n{}\n", version, filename, entries)).await?;
+    if !reply.trim().is_empty() {
+        bail!("HAProxy refused entries for {}: {}", filename, reply.trim());
+    }
+    let reply = socket.command(&format!("commit map @{} {}\n", version, filename)).await?;
+    if !reply.trim().is_empty() {
+        bail!("HAProxy did not commit {}: {}", filename, reply.trim());
+    }
+    Ok(entries.lines().count())
+}
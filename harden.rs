@@ -0,0 +1,169 @@
+//! `cloak harden`: a complete input policy for a server in one reviewable
+//! rule file, instead of a country list bolted onto an open host.
+//!
+//! Loopback and established traffic pass, SSH is accepted only from the
+//! given addresses, prefixes on public drop lists are refused, new
+//! connections from the home countries are accepted and everything else is
+//! rate limited (or dropped). The file is only written; load it with
+//! `cloak apply` once it has been read.
+
+use std::fs;
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use ipnetwork::IpNetwork;
+
+use crate::{
+    download, fetch,
+    filter::{self, FilterArgs},
+    groups,
+    nft::{self, Hardened},
+    record_counts,
+    ui::{info, Summary},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct HardenArgs {
+    /// Countries whose new connections are accepted (comma-separated
+    /// codes, e.g. ca or ca,us)
+    #[arg(long, value_name = "CC", value_delimiter = ',', required = true)]
+    home: Vec<String>,
+
+    /// Addresses or prefixes SSH is accepted from (comma-separated); SSH
+    /// from anywhere else is dropped
+    #[arg(long, value_name = "CIDR", value_delimiter = ',', required = true)]
+    ssh_from: Vec<IpNetwork>,
+
+    /// Port sshd listens on
+    #[arg(long, value_name = "PORT", default_value_t = 22)]
+    ssh_port: u16,
+
+    /// How many new connections per second, minute or hour from outside
+    /// the home countries are still let in (e.g. 25/second); 0 drops them
+    #[arg(long, value_name = "RATE", default_value = "25/second", value_parser = parse_rate)]
+    rest_rate: String,
+
+    /// Drop lists of known-bad prefixes to refuse (comma-separated)
+    #[arg(long, value_enum, value_name = "FEEDS", value_delimiter = ',', default_values_t = [Feed::Spamhaus, Feed::Dshield])]
+    feeds: Vec<Feed>,
+
+    /// Leave out the drop lists
+    #[arg(long, conflicts_with = "feeds")]
+    no_feeds: bool,
+
+    /// Where to write the ruleset
+    #[arg(short, long, default_value = "harden.nft")]
+    output: String,
+
+    #[command(flatten)]
+    http: fetch::HttpArgs,
+}
+
+/// Public lists of networks run by or for spammers and attackers
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum Feed {
+    /// Spamhaus DROP, IPv4 and IPv6
+    Spamhaus,
+    /// DShield's most active attacking /24s
+    Dshield,
+}
+
+impl Feed {
+    fn urls(self) -> &'static [&'static str] {
+        match self {
+            Feed::Spamhaus => &["https://www.spamhaus.org/drop/drop.txt", "https://www.spamhaus.org/drop/dropv6.txt"],
+            Feed::Dshield => &["https://feeds.dshield.org/block.txt"],
+        }
+    }
+
+    /// The prefixes in one downloaded list
+    fn parse(self, text: &str) -> Vec<IpNetwork> {
+        let mut nets = Vec::new();
+        for line in text.lines() {
+            let line = line.split([';', '#']).next().unwrap_or("").trim();
+            let net: Option<IpNetwork> = match self {
+                // `1.10.16.0/20 ; SBL256894`
+                Feed::Spamhaus => line.parse().ok(),
+                // `start<TAB>end<TAB>prefix length<TAB>...`
+                Feed::Dshield => {
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    match fields.as_slice() {
+                        [start, _, length, ..] => format!("{}/{}", start, length).parse().ok(),
+                        _ => None,
+                    }
+                }
+            };
+            nets.extend(net);
+        }
+        nets
+    }
+}
+
+/// `value_parser` for `--rest-rate`: `0`, or N per second, minute or hour
+fn parse_rate(text: &str) -> Result<String, String> {
+    if text == "0" {
+        return Ok(text.to_string());
+    }
+    let invalid = || format!("invalid rate `{}` (expected e.g. 25/second, 100/minute or 0)", text);
+    let (count, unit) = text.split_once('/').ok_or_else(invalid)?;
+    let count: u32 = count.parse().map_err(|_| invalid())?;
+    if count == 0 || !matches!(unit, "second" | "minute" | "hour") {
+        return Err(invalid());
+    }
+    Ok(text.to_string())
+}
+
+pub async fn run(args: &HardenArgs, summary: &mut Summary) -> Result<()> {
+    let home = groups::from_codes("home", &args.home)?;
+    summary.list = Some(home.countries.iter().map(|(cc, _)| cc.as_str()).collect::<Vec<_>>().join(","));
+    summary.action = Some("harden".to_string());
+
+    let mut map = fetch::fetch_countries(&home.countries, &args.http).await?;
+    filter::apply(&mut map, &[], &FilterArgs::default(), &args.http).await?;
+    record_counts(summary, &map);
+    let home_nets: Vec<IpNetwork> = map.values().flat_map(|nets| nets.ipv4.iter().chain(&nets.ipv6)).map(|net| net.0).collect();
+    if home_nets.is_empty() {
+        bail!("no prefixes found for {}", args.home.join(", "));
+    }
+
+    let mut bad = Vec::new();
+    if !args.no_feeds {
+        let client = fetch::client(&args.http)?;
+        for &feed in &args.feeds {
+            let before = bad.len();
+            for url in feed.urls() {
+                let path = download::fetch_to_cache(&client, url, &args.http.cache_dir).await?;
+                let text = fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+                bad.extend(feed.parse(&text));
+            }
+            let name = feed.to_possible_value().map(|value| value.get_name().to_string()).unwrap_or_default();
+            info!("{} -> {} known-bad prefixes", name, bad.len() - before);
+        }
+    }
+
+    let policy = Hardened {
+        home: home_nets,
+        bad,
+        ssh_from: args.ssh_from.clone(),
+        ssh_port: args.ssh_port,
+        rest_rate: (args.rest_rate != "0").then(|| args.rest_rate.clone()),
+    };
+    nft::generate_hardened(&policy, &args.output)?;
+    summary.wrote(&args.output);
+
+    let from: Vec<String> = args.ssh_from.iter().map(IpNetwork::to_string).collect();
+    info!("The ruleset in {}:", args.output);
+    info!("   accepts loopback and established connections");
+    info!("   accepts SSH (port {}) only from {}", args.ssh_port, from.join(", "));
+    if !policy.bad.is_empty() {
+        info!("   drops {} prefixes on the drop lists", policy.bad.len());
+    }
+    info!("   accepts new connections from {} ({} prefixes)", args.home.join(", "), policy.home.len());
+    match &policy.rest_rate {
+        Some(rate) => info!("   lets in up to {} other new connections and drops the rest", rate),
+        None => info!("   drops all other new connections"),
+    }
+    info!("Review it, then load it with: cloak apply {}", args.output);
+    summary.print_human();
+    Ok(())
+}
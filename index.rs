@@ -0,0 +1,269 @@
+//! Longest-prefix-match index answering "which country is this address in?".
+//!
+//! Prefixes are flattened into sorted, non-overlapping address ranges, each
+//! labelled with the country of the most specific prefix covering it, so a
+//! lookup is a single binary search.
+//!
+//! `cloak compile` writes the flattened ranges to a file, which loads with
+//! one read and no parsing: the magic, then the country codes, then the
+//! IPv4 and IPv6 ranges as fixed-size little-endian records.
+
+use std::{
+    collections::HashMap,
+    fs,
+    mem::size_of,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use ipnetwork::IpNetwork;
+
+use crate::{read_maps, CountryNets};
+
+/// Start of a compiled index; the last byte is the format version
+const MAGIC: &[u8; 8] = b"CLOAKIX\x01";
+
+#[derive(Clone, Copy)]
+struct Range<T> {
+    start: T,
+    end: T,
+    country: u16,
+}
+
+pub struct Index {
+    countries: Vec<String>,
+    ipv4: Vec<Range<u32>>,
+    ipv6: Vec<Range<u128>>,
+}
+
+impl Index {
+    pub fn build(map: &HashMap<String, CountryNets>) -> Self {
+        let mut countries: Vec<String> = map.keys().cloned().collect();
+        countries.sort();
+
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for (i, cc) in countries.iter().enumerate() {
+            let nets = &map[cc];
+            v4.extend(nets.ipv4.iter().map(|net| bounds(&net.0, i as u16)));
+            v6.extend(nets.ipv6.iter().map(|net| bounds(&net.0, i as u16)));
+        }
+        let ipv4 = flatten(v4)
+            .into_iter()
+            .map(|r| Range { start: r.start as u32, end: r.end as u32, country: r.country })
+            .collect();
+        Index { countries, ipv4, ipv6: flatten(v6) }
+    }
+
+    /// Country code of the most specific prefix containing `addr`; an
+    /// IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) is looked up as IPv4
+    pub fn lookup(&self, addr: IpAddr) -> Option<&str> {
+        let country = match addr.to_canonical() {
+            IpAddr::V4(a) => find(&self.ipv4, u32::from(a)),
+            IpAddr::V6(a) => find(&self.ipv6, u128::from(a)),
+        }?;
+        Some(&self.countries[usize::from(country)])
+    }
+
+    /// Like [`Index::lookup`], also returning the largest prefix around
+    /// `addr` that has the same country: the map's own prefix, or the part
+    /// of it left over between more specific ones
+    pub fn lookup_net(&self, addr: IpAddr) -> Option<(&str, IpNetwork)> {
+        let addr = addr.to_canonical();
+        let (country, bits) = match addr {
+            IpAddr::V4(a) => {
+                let range = find_range(&self.ipv4, u32::from(a))?;
+                (range.country, block(u128::from(u32::from(a)), u128::from(range.start), u128::from(range.end), 32))
+            }
+            IpAddr::V6(a) => {
+                let range = find_range(&self.ipv6, u128::from(a))?;
+                (range.country, block(u128::from(a), range.start, range.end, 128))
+            }
+        };
+        let net = IpNetwork::new(addr, bits).ok()?;
+        Some((&self.countries[usize::from(country)], IpNetwork::new(net.network(), bits).ok()?))
+    }
+
+    /// Number of (IPv4, IPv6) ranges after flattening
+    pub fn ranges(&self) -> (usize, usize) {
+        (self.ipv4.len(), self.ipv6.len())
+    }
+
+    /// The index of a compiled file, or of the merged JSON maps in `paths`
+    pub fn load(paths: &[PathBuf]) -> Result<Self> {
+        match paths {
+            [path] if is_compiled(path) => Index::read(path),
+            _ => {
+                if let Some(path) = paths.iter().find(|path| is_compiled(path)) {
+                    bail!("{} is a compiled index, which cannot be combined with other maps", path.display());
+                }
+                Ok(Index::build(&read_maps(paths)?))
+            }
+        }
+    }
+
+    /// Write the index to `path` for [`Index::read`]
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut out = MAGIC.to_vec();
+        out.extend((self.countries.len() as u32).to_le_bytes());
+        for cc in &self.countries {
+            out.push(cc.len() as u8);
+            out.extend(cc.as_bytes());
+        }
+        out.extend((self.ipv4.len() as u64).to_le_bytes());
+        for range in &self.ipv4 {
+            out.extend(range.start.to_le_bytes());
+            out.extend(range.end.to_le_bytes());
+            out.extend(range.country.to_le_bytes());
+        }
+        out.extend((self.ipv6.len() as u64).to_le_bytes());
+        for range in &self.ipv6 {
+            out.extend(range.start.to_le_bytes());
+            out.extend(range.end.to_le_bytes());
+            out.extend(range.country.to_le_bytes());
+        }
+        fs::write(path, out).with_context(|| format!("write {}", path.display()))
+    }
+
+    /// Load an index written by [`Index::write`]
+    pub fn read(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+        let corrupt = || format!("{} is not a complete cloak index (compile it again)", path.display());
+        let mut reader = Bytes(bytes.strip_prefix(MAGIC).with_context(corrupt)?);
+        let count = u32::from_le_bytes(reader.take().with_context(corrupt)?);
+        let mut countries = Vec::new();
+        for _ in 0..count {
+            let [len] = reader.take().with_context(corrupt)?;
+            let code = reader.slice(usize::from(len)).with_context(corrupt)?;
+            countries.push(String::from_utf8(code.to_vec()).ok().with_context(corrupt)?);
+        }
+        let mut ipv4 = Vec::new();
+        for _ in 0..u64::from_le_bytes(reader.take().with_context(corrupt)?) {
+            let start = u32::from_le_bytes(reader.take().with_context(corrupt)?);
+            let end = u32::from_le_bytes(reader.take().with_context(corrupt)?);
+            let country = u16::from_le_bytes(reader.take().with_context(corrupt)?);
+            ipv4.push(Range { start, end, country });
+        }
+        let mut ipv6 = Vec::new();
+        for _ in 0..u64::from_le_bytes(reader.take().with_context(corrupt)?) {
+            let start = u128::from_le_bytes(reader.take().with_context(corrupt)?);
+            let end = u128::from_le_bytes(reader.take().with_context(corrupt)?);
+            let country = u16::from_le_bytes(reader.take().with_context(corrupt)?);
+            ipv6.push(Range { start, end, country });
+        }
+        let known = |country: u16| usize::from(country) < countries.len();
+        if !reader.0.is_empty() || !ipv4.iter().all(|r| known(r.country)) || !ipv6.iter().all(|r| known(r.country)) {
+            bail!(corrupt());
+        }
+        Ok(Index { countries, ipv4, ipv6 })
+    }
+
+    /// Approximate heap memory held by the index, in bytes
+    pub fn heap_bytes(&self) -> usize {
+        self.ipv4.capacity() * size_of::<Range<u32>>()
+            + self.ipv6.capacity() * size_of::<Range<u128>>()
+            + self.countries.iter().map(|c| size_of::<String>() + c.capacity()).sum::<usize>()
+    }
+}
+
+/// Whether `path` holds a compiled index rather than a JSON map
+pub fn is_compiled(path: &Path) -> bool {
+    let mut magic = [0; MAGIC.len()];
+    fs::File::open(path).and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic)).is_ok() && magic == *MAGIC
+}
+
+/// The unread rest of a compiled index
+struct Bytes<'a>(&'a [u8]);
+
+impl<'a> Bytes<'a> {
+    fn slice(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.slice(N)?.try_into().ok()
+    }
+}
+
+fn find<T: Copy + Ord>(ranges: &[Range<T>], addr: T) -> Option<u16> {
+    find_range(ranges, addr).map(|range| range.country)
+}
+
+fn find_range<T: Copy + Ord>(ranges: &[Range<T>], addr: T) -> Option<Range<T>> {
+    let i = ranges.partition_point(|r| r.start <= addr).checked_sub(1)?;
+    (addr <= ranges[i].end).then_some(ranges[i])
+}
+
+/// Prefix length of the largest aligned block holding `addr` that fits
+/// inside `start..=end`
+fn block(addr: u128, start: u128, end: u128, width: u8) -> u8 {
+    (0..width)
+        .find(|&bits| {
+            let host_bits = u32::from(width - bits);
+            let span = if host_bits == 128 { u128::MAX } else { (1u128 << host_bits) - 1 };
+            let first = addr & !span;
+            first >= start && first | span <= end
+        })
+        .unwrap_or(width)
+}
+
+fn bounds(net: &IpNetwork, country: u16) -> (u128, u8, u128, u16) {
+    let (start, bits, width) = match net {
+        IpNetwork::V4(n) => (u128::from(u32::from(n.network())), n.prefix(), 32),
+        IpNetwork::V6(n) => (u128::from(n.network()), n.prefix(), 128),
+    };
+    let host_bits = u32::from(width - bits);
+    let span = if host_bits == 128 { u128::MAX } else { (1u128 << host_bits) - 1 };
+    (start, bits, start + span, country)
+}
+
+/// Turn possibly nested prefixes into disjoint ranges where the innermost
+/// prefix wins. Prefixes are either nested or disjoint, so a stack of the
+/// currently open ones is enough.
+fn flatten(mut prefixes: Vec<(u128, u8, u128, u16)>) -> Vec<Range<u128>> {
+    // Broader prefixes first when they share a start address
+    prefixes.sort_by_key(|&(start, bits, _, _)| (start, bits));
+
+    let mut out: Vec<Range<u128>> = Vec::new();
+    let mut open: Vec<(u128, u16)> = Vec::new();
+    // Next address not yet emitted; `None` once the top of the space is done
+    let mut cursor: Option<u128> = Some(0);
+
+    fn emit(out: &mut Vec<Range<u128>>, cursor: &mut Option<u128>, end: u128, country: u16) {
+        if let Some(start) = *cursor {
+            if start <= end {
+                out.push(Range { start, end, country });
+                *cursor = end.checked_add(1);
+            }
+        }
+    }
+
+    for (start, _, end, country) in prefixes {
+        while let Some(&(top_end, top_country)) = open.last() {
+            if top_end >= start {
+                break;
+            }
+            emit(&mut out, &mut cursor, top_end, top_country);
+            open.pop();
+        }
+        if let Some(&(_, top_country)) = open.last() {
+            if start > 0 {
+                emit(&mut out, &mut cursor, start - 1, top_country);
+            }
+        }
+        if cursor.is_some_and(|c| c < start) || open.is_empty() {
+            cursor = Some(start);
+        }
+        open.push((end, country));
+    }
+    while let Some((end, country)) = open.pop() {
+        emit(&mut out, &mut cursor, end, country);
+    }
+    out
+}
@@ -0,0 +1,61 @@
+//! ipset restore files, for iptables-based firewalls that match the sets
+//! with `-m set --match-set`.
+
+use std::{collections::HashMap, fmt::Write, fs};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{Action, CountryNets, Direction};
+
+/// ipset refuses longer set names
+const MAX_NAME: usize = 31;
+
+/// ipset's default limit on the number of elements of a set
+const DEFAULT_MAXELEM: usize = 65536;
+
+/// Write an `ipset restore` file for `map` to `filename`, with sets named
+/// `cloak_<name>_v4` and `cloak_<name>_v6`. The iptables rules that use
+/// them are left to the firewall's own configuration; the file's header
+/// shows them.
+pub fn generate_ipset(
+    map: &HashMap<String, CountryNets>,
+    action: Action,
+    direction: Direction,
+    name: &str,
+    filename: &str,
+) -> Result<()> {
+    let sets = [(format!("cloak_{}_v4", name), "inet", "iptables"), (format!("cloak_{}_v6", name), "inet6", "ip6tables")];
+    if sets[0].0.len() > MAX_NAME {
+        bail!("set name {} is longer than ipset allows; pick a shorter --name", sets[0].0);
+    }
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    let ipv4: Vec<String> = codes.iter().flat_map(|cc| &map[*cc].ipv4).map(|ip| ip.0.to_string()).collect();
+    let ipv6: Vec<String> = codes.iter().flat_map(|cc| &map[*cc].ipv6).map(|ip| ip.0.to_string()).collect();
+
+    let mut file = String::new();
+    writeln!(file, "# Generated by cloak. Load with: ipset restore -exist < {}", filename)?;
+    writeln!(file, "# and match the sets from iptables, e.g.:")?;
+    let mut sides = Vec::new();
+    if direction != Direction::Out {
+        sides.push(("INPUT", "src"));
+    }
+    if direction != Direction::In {
+        sides.push(("OUTPUT", "dst"));
+    }
+    for (set, _, tool) in &sets {
+        for (chain, side) in &sides {
+            let negate = if action == Action::Allow { "! " } else { "" };
+            writeln!(file, "#   {} -I {} -m set {}--match-set {} {} -j DROP", tool, chain, negate, set, side)?;
+        }
+    }
+    for ((set, family, _), nets) in sets.iter().zip([&ipv4, &ipv6]) {
+        let maxelem = nets.len().max(DEFAULT_MAXELEM);
+        writeln!(file, "create {} hash:net family {} maxelem {}", set, family, maxelem)?;
+        writeln!(file, "flush {}", set)?;
+        for net in nets {
+            writeln!(file, "add {} {}", set, net)?;
+        }
+    }
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
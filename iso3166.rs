@@ -0,0 +1,431 @@
+//! Every ISO 3166-1 country and common aliases for them, so selections can
+//! name countries (`United Kingdom`, `USA`, `Türkiye`) instead of codes.
+//!
+//! Names are compared case-insensitively with accents, punctuation and
+//! filler words ("the", "of", "and") ignored. A name that is not exactly
+//! known may still be the start of a single country's name or a small typo
+//! away from one; anything matching more than one country is an error
+//! listing the candidates.
+
+use crate::groups::Country;
+
+/// Alpha-2 code (lowercase, as IPdeny names its zones) and short name
+pub const COUNTRIES: &[Country] = &[
+    ("ad", "Andorra"),
+    ("ae", "United Arab Emirates"),
+    ("af", "Afghanistan"),
+    ("ag", "Antigua and Barbuda"),
+    ("ai", "Anguilla"),
+    ("al", "Albania"),
+    ("am", "Armenia"),
+    ("ao", "Angola"),
+    ("aq", "Antarctica"),
+    ("ar", "Argentina"),
+    ("as", "American Samoa"),
+    ("at", "Austria"),
+    ("au", "Australia"),
+    ("aw", "Aruba"),
+    ("ax", "Åland Islands"),
+    ("az", "Azerbaijan"),
+    ("ba", "Bosnia and Herzegovina"),
+    ("bb", "Barbados"),
+    ("bd", "Bangladesh"),
+    ("be", "Belgium"),
+    ("bf", "Burkina Faso"),
+    ("bg", "Bulgaria"),
+    ("bh", "Bahrain"),
+    ("bi", "Burundi"),
+    ("bj", "Benin"),
+    ("bl", "Saint Barthélemy"),
+    ("bm", "Bermuda"),
+    ("bn", "Brunei"),
+    ("bo", "Bolivia"),
+    ("bq", "Caribbean Netherlands"),
+    ("br", "Brazil"),
+    ("bs", "Bahamas"),
+    ("bt", "Bhutan"),
+    ("bv", "Bouvet Island"),
+    ("bw", "Botswana"),
+    ("by", "Belarus"),
+    ("bz", "Belize"),
+    ("ca", "Canada"),
+    ("cc", "Cocos (Keeling) Islands"),
+    ("cd", "Democratic Republic of the Congo"),
+    ("cf", "Central African Republic"),
+    ("cg", "Republic of the Congo"),
+    ("ch", "Switzerland"),
+    ("ci", "Côte d'Ivoire"),
+    ("ck", "Cook Islands"),
+    ("cl", "Chile"),
+    ("cm", "Cameroon"),
+    ("cn", "China"),
+    ("co", "Colombia"),
+    ("cr", "Costa Rica"),
+    ("cu", "Cuba"),
+    ("cv", "Cabo Verde"),
+    ("cw", "Curaçao"),
+    ("cx", "Christmas Island"),
+    ("cy", "Cyprus"),
+    ("cz", "Czechia"),
+    ("de", "Germany"),
+    ("dj", "Djibouti"),
+    ("dk", "Denmark"),
+    ("dm", "Dominica"),
+    ("do", "Dominican Republic"),
+    ("dz", "Algeria"),
+    ("ec", "Ecuador"),
+    ("ee", "Estonia"),
+    ("eg", "Egypt"),
+    ("eh", "Western Sahara"),
+    ("er", "Eritrea"),
+    ("es", "Spain"),
+    ("et", "Ethiopia"),
+    ("fi", "Finland"),
+    ("fj", "Fiji"),
+    ("fk", "Falkland Islands"),
+    ("fm", "Micronesia"),
+    ("fo", "Faroe Islands"),
+    ("fr", "France"),
+    ("ga", "Gabon"),
+    ("gb", "United Kingdom"),
+    ("gd", "Grenada"),
+    ("ge", "Georgia"),
+    ("gf", "French Guiana"),
+    ("gg", "Guernsey"),
+    ("gh", "Ghana"),
+    ("gi", "Gibraltar"),
+    ("gl", "Greenland"),
+    ("gm", "Gambia"),
+    ("gn", "Guinea"),
+    ("gp", "Guadeloupe"),
+    ("gq", "Equatorial Guinea"),
+    ("gr", "Greece"),
+    ("gs", "South Georgia and the South Sandwich Islands"),
+    ("gt", "Guatemala"),
+    ("gu", "Guam"),
+    ("gw", "Guinea-Bissau"),
+    ("gy", "Guyana"),
+    ("hk", "Hong Kong"),
+    ("hm", "Heard Island and McDonald Islands"),
+    ("hn", "Honduras"),
+    ("hr", "Croatia"),
+    ("ht", "Haiti"),
+    ("hu", "Hungary"),
+    ("id", "Indonesia"),
+    ("ie", "Ireland"),
+    ("il", "Israel"),
+    ("im", "Isle of Man"),
+    ("in", "India"),
+    ("io", "British Indian Ocean Territory"),
+    ("iq", "Iraq"),
+    ("ir", "Iran"),
+    ("is", "Iceland"),
+    ("it", "Italy"),
+    ("je", "Jersey"),
+    ("jm", "Jamaica"),
+    ("jo", "Jordan"),
+    ("jp", "Japan"),
+    ("ke", "Kenya"),
+    ("kg", "Kyrgyzstan"),
+    ("kh", "Cambodia"),
+    ("ki", "Kiribati"),
+    ("km", "Comoros"),
+    ("kn", "Saint Kitts and Nevis"),
+    ("kp", "North Korea"),
+    ("kr", "South Korea"),
+    ("kw", "Kuwait"),
+    ("ky", "Cayman Islands"),
+    ("kz", "Kazakhstan"),
+    ("la", "Laos"),
+    ("lb", "Lebanon"),
+    ("lc", "Saint Lucia"),
+    ("li", "Liechtenstein"),
+    ("lk", "Sri Lanka"),
+    ("lr", "Liberia"),
+    ("ls", "Lesotho"),
+    ("lt", "Lithuania"),
+    ("lu", "Luxembourg"),
+    ("lv", "Latvia"),
+    ("ly", "Libya"),
+    ("ma", "Morocco"),
+    ("mc", "Monaco"),
+    ("md", "Moldova"),
+    ("me", "Montenegro"),
+    ("mf", "Saint Martin"),
+    ("mg", "Madagascar"),
+    ("mh", "Marshall Islands"),
+    ("mk", "North Macedonia"),
+    ("ml", "Mali"),
+    ("mm", "Myanmar"),
+    ("mn", "Mongolia"),
+    ("mo", "Macao"),
+    ("mp", "Northern Mariana Islands"),
+    ("mq", "Martinique"),
+    ("mr", "Mauritania"),
+    ("ms", "Montserrat"),
+    ("mt", "Malta"),
+    ("mu", "Mauritius"),
+    ("mv", "Maldives"),
+    ("mw", "Malawi"),
+    ("mx", "Mexico"),
+    ("my", "Malaysia"),
+    ("mz", "Mozambique"),
+    ("na", "Namibia"),
+    ("nc", "New Caledonia"),
+    ("ne", "Niger"),
+    ("nf", "Norfolk Island"),
+    ("ng", "Nigeria"),
+    ("ni", "Nicaragua"),
+    ("nl", "Netherlands"),
+    ("no", "Norway"),
+    ("np", "Nepal"),
+    ("nr", "Nauru"),
+    ("nu", "Niue"),
+    ("nz", "New Zealand"),
+    ("om", "Oman"),
+    ("pa", "Panama"),
+    ("pe", "Peru"),
+    ("pf", "French Polynesia"),
+    ("pg", "Papua New Guinea"),
+    ("ph", "Philippines"),
+    ("pk", "Pakistan"),
+    ("pl", "Poland"),
+    ("pm", "Saint Pierre and Miquelon"),
+    ("pn", "Pitcairn Islands"),
+    ("pr", "Puerto Rico"),
+    ("ps", "Palestine"),
+    ("pt", "Portugal"),
+    ("pw", "Palau"),
+    ("py", "Paraguay"),
+    ("qa", "Qatar"),
+    ("re", "Réunion"),
+    ("ro", "Romania"),
+    ("rs", "Serbia"),
+    ("ru", "Russia"),
+    ("rw", "Rwanda"),
+    ("sa", "Saudi Arabia"),
+    ("sb", "Solomon Islands"),
+    ("sc", "Seychelles"),
+    ("sd", "Sudan"),
+    ("se", "Sweden"),
+    ("sg", "Singapore"),
+    ("sh", "Saint Helena, Ascension and Tristan da Cunha"),
+    ("si", "Slovenia"),
+    ("sj", "Svalbard and Jan Mayen"),
+    ("sk", "Slovakia"),
+    ("sl", "Sierra Leone"),
+    ("sm", "San Marino"),
+    ("sn", "Senegal"),
+    ("so", "Somalia"),
+    ("sr", "Suriname"),
+    ("ss", "South Sudan"),
+    ("st", "São Tomé and Príncipe"),
+    ("sv", "El Salvador"),
+    ("sx", "Sint Maarten"),
+    ("sy", "Syria"),
+    ("sz", "Eswatini"),
+    ("tc", "Turks and Caicos Islands"),
+    ("td", "Chad"),
+    ("tf", "French Southern Territories"),
+    ("tg", "Togo"),
+    ("th", "Thailand"),
+    ("tj", "Tajikistan"),
+    ("tk", "Tokelau"),
+    ("tl", "Timor-Leste"),
+    ("tm", "Turkmenistan"),
+    ("tn", "Tunisia"),
+    ("to", "Tonga"),
+    ("tr", "Türkiye"),
+    ("tt", "Trinidad and Tobago"),
+    ("tv", "Tuvalu"),
+    ("tw", "Taiwan"),
+    ("tz", "Tanzania"),
+    ("ua", "Ukraine"),
+    ("ug", "Uganda"),
+    ("um", "United States Minor Outlying Islands"),
+    ("us", "United States"),
+    ("uy", "Uruguay"),
+    ("uz", "Uzbekistan"),
+    ("va", "Vatican City"),
+    ("vc", "Saint Vincent and the Grenadines"),
+    ("ve", "Venezuela"),
+    ("vg", "British Virgin Islands"),
+    ("vi", "U.S. Virgin Islands"),
+    ("vn", "Vietnam"),
+    ("vu", "Vanuatu"),
+    ("wf", "Wallis and Futuna"),
+    ("ws", "Samoa"),
+    ("ye", "Yemen"),
+    ("yt", "Mayotte"),
+    ("za", "South Africa"),
+    ("zm", "Zambia"),
+    ("zw", "Zimbabwe"),
+];
+
+/// Other names in use, including official long forms, former names and
+/// abbreviations
+const ALIASES: &[(&str, &str)] = &[
+    ("usa", "us"),
+    ("america", "us"),
+    ("united states of america", "us"),
+    ("uk", "gb"),
+    ("great britain", "gb"),
+    ("britain", "gb"),
+    ("england", "gb"),
+    ("scotland", "gb"),
+    ("wales", "gb"),
+    ("northern ireland", "gb"),
+    ("turkey", "tr"),
+    ("republic of korea", "kr"),
+    ("dprk", "kp"),
+    ("democratic peoples republic of korea", "kp"),
+    ("russian federation", "ru"),
+    ("peoples republic of china", "cn"),
+    ("prc", "cn"),
+    ("republic of china", "tw"),
+    ("uae", "ae"),
+    ("emirates", "ae"),
+    ("czech republic", "cz"),
+    ("holland", "nl"),
+    ("burma", "mm"),
+    ("macedonia", "mk"),
+    ("swaziland", "sz"),
+    ("cape verde", "cv"),
+    ("ivory coast", "ci"),
+    ("east timor", "tl"),
+    ("vatican", "va"),
+    ("holy see", "va"),
+    ("drc", "cd"),
+    ("congo kinshasa", "cd"),
+    ("zaire", "cd"),
+    ("congo brazzaville", "cg"),
+    ("viet nam", "vn"),
+    ("lao", "la"),
+    ("iran islamic republic", "ir"),
+    ("persia", "ir"),
+    ("syrian arab republic", "sy"),
+    ("moldova republic", "md"),
+    ("brunei darussalam", "bn"),
+    ("macau", "mo"),
+    ("palestinian territories", "ps"),
+    ("state of palestine", "ps"),
+    ("bosnia", "ba"),
+    ("st kitts", "kn"),
+    ("st lucia", "lc"),
+    ("st vincent", "vc"),
+    ("trinidad", "tt"),
+    ("federated states of micronesia", "fm"),
+    ("ksa", "sa"),
+    ("deutschland", "de"),
+    ("espana", "es"),
+    ("suisse", "ch"),
+    ("schweiz", "ch"),
+    ("osterreich", "at"),
+    ("nippon", "jp"),
+];
+
+/// Words that never decide between countries
+const FILLER: &[&str] = &["the", "of", "and"];
+
+/// The code of the country `text` names: an alpha-2 code, a name or an
+/// alias, possibly abbreviated or slightly misspelled
+pub fn resolve(text: &str) -> Result<&'static str, String> {
+    let query = normalize(text);
+    if query.is_empty() {
+        return Err("an empty country name".to_string());
+    }
+    if let Some((cc, _)) = COUNTRIES.iter().find(|(cc, _)| *cc == query) {
+        return Ok(cc);
+    }
+    let names = || {
+        COUNTRIES
+            .iter()
+            .map(|&(cc, name)| (cc, normalize(name)))
+            .chain(ALIASES.iter().map(|&(alias, cc)| (cc, normalize(alias))))
+    };
+    if let Some((cc, _)) = names().find(|(_, name)| *name == query) {
+        return Ok(cc);
+    }
+
+    // A name starting with the query, or containing it as whole words
+    let words = |name: &str| format!(" {} ", name).contains(&format!(" {} ", query));
+    let mut partial: Vec<&str> = names().filter(|(_, name)| name.starts_with(&query) || words(name)).map(|(cc, _)| cc).collect();
+    partial.sort();
+    partial.dedup();
+    match partial.as_slice() {
+        [cc] => return Ok(cc),
+        [] => {}
+        many => return Err(ambiguous(text, many)),
+    }
+
+    // One or two typos, only for names long enough to tell apart
+    let allowed = match query.chars().count() {
+        0..=4 => 0,
+        5..=8 => 1,
+        _ => 2,
+    };
+    let mut close: Vec<(usize, &str)> = names()
+        .map(|(cc, name)| (distance(&query, &name), cc))
+        .filter(|&(distance, _)| distance <= allowed)
+        .collect();
+    close.sort();
+    close.dedup_by_key(|(_, cc)| *cc);
+    match close.as_slice() {
+        [] => Err(format!("`{}` is not a country code or a known country name", text)),
+        [(_, cc)] => Ok(cc),
+        [(best, cc), (next, _), ..] if best < next => Ok(cc),
+        many => Err(ambiguous(text, &many.iter().map(|(_, cc)| *cc).collect::<Vec<_>>())),
+    }
+}
+
+/// Short name of the country with alpha-2 code `code`
+pub fn name(code: &str) -> Option<&'static str> {
+    COUNTRIES.iter().find(|(cc, _)| *cc == code).map(|(_, name)| *name)
+}
+
+fn ambiguous(text: &str, codes: &[&str]) -> String {
+    let candidates: Vec<String> = codes
+        .iter()
+        .map(|cc| format!("{} ({})", name(cc).unwrap_or(cc), cc.to_uppercase()))
+        .collect();
+    format!("`{}` could be any of {}; use the code or the full name", text, candidates.join(", "))
+}
+
+/// Lowercase ASCII words with accents, punctuation and filler words removed
+pub fn normalize(text: &str) -> String {
+    let mut plain = String::new();
+    for c in text.to_lowercase().chars() {
+        let c = match c {
+            'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' => 'a',
+            'ç' => 'c',
+            'è' | 'é' | 'ê' | 'ë' => 'e',
+            'ì' | 'í' | 'î' | 'ï' => 'i',
+            'ñ' => 'n',
+            'ò' | 'ó' | 'ô' | 'õ' | 'ö' => 'o',
+            'ù' | 'ú' | 'û' | 'ü' => 'u',
+            'ý' | 'ÿ' => 'y',
+            // `Côte d'Ivoire`, `U.S.` and `People's` read the same without
+            '\'' | '’' | '.' => continue,
+            c if c.is_ascii_alphanumeric() => c,
+            _ => ' ',
+        };
+        plain.push(c);
+    }
+    plain.split_whitespace().filter(|word| !FILLER.contains(word)).collect::<Vec<_>>().join(" ")
+}
+
+/// Levenshtein distance
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substituted = previous + usize::from(ca != cb);
+            previous = row[j + 1];
+            row[j + 1] = substituted.min(previous + 1).min(row[j] + 1);
+        }
+    }
+    row[b.len()]
+}
@@ -0,0 +1,170 @@
+//! `--keep`: infrastructure a host needs whatever the country policy,
+//! accepted before any country rule like the daemon's whitelist.
+//!
+//! Only the DNS roots are fixed addresses. NTP servers and package mirrors
+//! are read from the host's own configuration and resolved when the rules
+//! are generated, so they follow the addresses those names have then.
+
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use ipnetwork::IpNetwork;
+use tokio::{net::lookup_host, task::JoinSet, time::timeout};
+
+use crate::ui::{info, warning};
+
+/// Give up resolving one name after this long
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Used when no NTP client configuration names any server
+const DEFAULT_NTP: &[&str] = &["0.pool.ntp.org", "1.pool.ntp.org", "2.pool.ntp.org", "3.pool.ntp.org"];
+
+/// The 13 root name servers, a to m
+const DNS_ROOTS: &[&str] = &[
+    "198.41.0.4", "2001:503:ba3e::2:30",
+    "170.247.170.2", "2801:1b8:10::b",
+    "192.33.4.12", "2001:500:2::c",
+    "199.7.91.13", "2001:500:2d::d",
+    "192.203.230.10", "2001:500:a8::e",
+    "192.5.5.241", "2001:500:2f::f",
+    "192.112.36.4", "2001:500:12::d0d",
+    "198.97.190.53", "2001:500:1::53",
+    "192.36.148.17", "2001:7fe::53",
+    "192.58.128.30", "2001:503:c27::2:30",
+    "193.0.14.129", "2001:7fd::1",
+    "199.7.83.42", "2001:500:9f::42",
+    "202.12.27.33", "2001:dc3::35",
+];
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum Preset {
+    /// The root DNS servers
+    DnsRoots,
+    /// The NTP servers chrony, ntpd or systemd-timesyncd is configured
+    /// with (pool.ntp.org if none)
+    NtpPool,
+    /// The mirrors in the apt, dnf/yum, pacman or apk configuration
+    PackageMirrors,
+}
+
+/// The addresses of every preset in `presets`
+pub async fn resolve(presets: &[Preset]) -> Result<Vec<IpNetwork>> {
+    let mut kept = Vec::new();
+    for preset in presets {
+        let before = kept.len();
+        match preset {
+            Preset::DnsRoots => kept.extend(DNS_ROOTS.iter().map(|addr| addr.parse::<IpNetwork>().expect("valid root server address"))),
+            Preset::NtpPool => {
+                let mut hosts = ntp_servers();
+                if hosts.is_empty() {
+                    hosts = DEFAULT_NTP.iter().map(|host| host.to_string()).collect();
+                }
+                kept.extend(lookup_all(hosts).await);
+            }
+            Preset::PackageMirrors => {
+                let hosts = mirror_hosts();
+                if hosts.is_empty() {
+                    warning!("--keep package-mirrors: no package manager configuration found");
+                }
+                kept.extend(lookup_all(hosts).await);
+            }
+        }
+        let name = preset.to_possible_value().map(|value| value.get_name().to_string()).unwrap_or_default();
+        info!("Keeping {} addresses of {} reachable.", kept.len() - before, name);
+    }
+    Ok(kept)
+}
+
+/// Servers named in the NTP clients' configuration files
+fn ntp_servers() -> Vec<String> {
+    let mut hosts = Vec::new();
+    let files = ["/etc/chrony.conf", "/etc/chrony/chrony.conf", "/etc/ntp.conf", "/etc/ntpsec/ntp.conf"];
+    let dirs = ["/etc/chrony/sources.d", "/etc/chrony.d"];
+    for text in read_all(&files, &dirs) {
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+            if let (Some("server" | "pool"), Some(host)) = (words.next(), words.next()) {
+                hosts.push(host.to_string());
+            }
+        }
+    }
+    for text in read_all(&["/etc/systemd/timesyncd.conf"], &["/etc/systemd/timesyncd.conf.d"]) {
+        for line in text.lines() {
+            if let Some(("NTP" | "FallbackNTP", servers)) = line.split_once('=').map(|(key, value)| (key.trim(), value)) {
+                hosts.extend(servers.split_whitespace().map(str::to_string));
+            }
+        }
+    }
+    hosts.sort();
+    hosts.dedup();
+    hosts
+}
+
+/// Hosts of the repository URLs in the package managers' configuration
+fn mirror_hosts() -> Vec<String> {
+    let files = ["/etc/apt/sources.list", "/etc/pacman.d/mirrorlist", "/etc/apk/repositories"];
+    let dirs = ["/etc/apt/sources.list.d", "/etc/yum.repos.d"];
+    let mut hosts: Vec<String> = read_all(&files, &dirs)
+        .iter()
+        .flat_map(|text| text.lines())
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(|line| line.split_whitespace().flat_map(|word| word.split('=')))
+        .filter_map(url_host)
+        .collect();
+    hosts.sort();
+    hosts.dedup();
+    hosts
+}
+
+/// The host of an http(s) or ftp URL, unless it is templated
+fn url_host(word: &str) -> Option<String> {
+    let (scheme, rest) = word.split_once("://")?;
+    if !matches!(scheme, "http" | "https" | "ftp") {
+        return None;
+    }
+    let authority = rest.split('/').next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+    (!host.is_empty() && !host.contains('$')).then(|| host.to_string())
+}
+
+/// Contents of the files that exist among `files` and in `dirs`
+fn read_all(files: &[&str], dirs: &[&str]) -> Vec<String> {
+    let mut paths: Vec<_> = files.iter().map(|file| Path::new(file).to_path_buf()).collect();
+    for dir in dirs {
+        if let Ok(entries) = fs::read_dir(dir) {
+            let mut found: Vec<_> = entries.flatten().map(|entry| entry.path()).collect();
+            found.sort();
+            paths.extend(found);
+        }
+    }
+    paths.iter().filter_map(|path| fs::read_to_string(path).ok()).collect()
+}
+
+/// Every address `hosts` resolve to; names that do not resolve are
+/// reported and skipped
+async fn lookup_all(hosts: Vec<String>) -> Vec<IpNetwork> {
+    let mut pending = JoinSet::new();
+    for host in hosts {
+        pending.spawn(async move {
+            let found = timeout(LOOKUP_TIMEOUT, lookup_host((host.as_str(), 0))).await;
+            let found = found.map(|found| found.map(|addrs| addrs.collect::<Vec<_>>()));
+            (host, found)
+        });
+    }
+    let mut addrs = Vec::new();
+    while let Some(Ok((host, found))) = pending.join_next().await {
+        match found {
+            Ok(Ok(found)) => addrs.extend(found.iter().map(|addr| IpNetwork::from(addr.ip()))),
+            Ok(Err(e)) => warning!("--keep: could not resolve {}: {}", host, e),
+            Err(_) => warning!("--keep: resolving {} timed out", host),
+        }
+    }
+    addrs.sort_by_key(|net| (net.is_ipv6(), net.network()));
+    addrs.dedup();
+    addrs
+}
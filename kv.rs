@@ -0,0 +1,166 @@
+//! The daemon's policy read from a key in etcd or Consul, so a fleet of
+//! daemons follows one definition and picks up edits to it within a few
+//! seconds.
+//!
+//! The key holds the same YAML as a `--config` file. It is read over the
+//! HTTP APIs (etcd's v3 JSON gateway, Consul's KV endpoint) and polled for
+//! a new revision, which keeps the daemon free of long-lived watch streams
+//! that would need their own reconnect logic.
+
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::fetch;
+
+/// Give up on one read after this long
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Etcd,
+    Consul,
+}
+
+/// A key in a KV store, from `--kv`
+#[derive(Clone, Debug)]
+pub struct Store {
+    kind: Kind,
+    /// `http(s)://host:port`
+    base: String,
+    key: String,
+}
+
+/// `value_parser` for `--kv`: `etcd://HOST:PORT/KEY` or
+/// `consul://HOST:PORT/KEY`, with `+https` after the scheme for TLS
+pub fn parse_url(text: &str) -> Result<Store, String> {
+    let invalid = || format!("expected etcd://HOST:PORT/KEY or consul://HOST:PORT/KEY, not `{}`", text);
+    let (scheme, rest) = text.split_once("://").ok_or_else(invalid)?;
+    let (kind, tls) = match scheme {
+        "etcd" => (Kind::Etcd, false),
+        "etcd+https" => (Kind::Etcd, true),
+        "consul" => (Kind::Consul, false),
+        "consul+https" => (Kind::Consul, true),
+        _ => return Err(invalid()),
+    };
+    let (authority, key) = rest.split_once('/').ok_or_else(invalid)?;
+    if authority.is_empty() || key.is_empty() {
+        return Err(invalid());
+    }
+    let base = format!("{}://{}", if tls { "https" } else { "http" }, authority);
+    Ok(Store { kind, base, key: key.to_string() })
+}
+
+impl Store {
+    /// How the key is shown in messages
+    pub fn describe(&self) -> String {
+        let kind = match self.kind {
+            Kind::Etcd => "etcd",
+            Kind::Consul => "Consul",
+        };
+        format!("{} key {} on {}", kind, self.key, self.base)
+    }
+}
+
+/// Reads one key, authenticated with the token from `--kv-token-file`
+pub struct Reader {
+    store: Store,
+    client: reqwest::Client,
+    token: Option<String>,
+}
+
+impl Reader {
+    pub fn new(store: &Store, token_file: Option<&Path>) -> Result<Reader> {
+        let token = match token_file {
+            Some(path) => Some(fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?.trim().to_string()),
+            None => None,
+        };
+        let client = fetch::client_builder()?.timeout(TIMEOUT).build().context("build HTTP client")?;
+        Ok(Reader { store: store.clone(), client, token })
+    }
+
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    /// The key's value and revision, or `None` if the key does not exist
+    pub async fn read(&self) -> Result<Option<(u64, String)>> {
+        let store = &self.store;
+        let request = match store.kind {
+            Kind::Etcd => {
+                let body = serde_json::json!({ "key": base64_encode(store.key.as_bytes()) });
+                let request = self.client.post(format!("{}/v3/kv/range", store.base)).json(&body);
+                match &self.token {
+                    Some(token) => request.header("Authorization", token),
+                    None => request,
+                }
+            }
+            Kind::Consul => {
+                let request = self.client.get(format!("{}/v1/kv/{}", store.base, store.key));
+                match &self.token {
+                    Some(token) => request.header("X-Consul-Token", token),
+                    None => request,
+                }
+            }
+        };
+        let response = request.send().await.with_context(|| format!("read {}", store.describe()))?;
+        if store.kind == Kind::Consul && response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            bail!("read {}: HTTP {}", store.describe(), response.status());
+        }
+        let body: Value = response.json().await.with_context(|| format!("read {}", store.describe()))?;
+        // etcd encodes 64-bit numbers as strings and both encode values as
+        // base64
+        let (entry, revision, value) = match store.kind {
+            Kind::Etcd => (&body["kvs"][0], "mod_revision", "value"),
+            Kind::Consul => (&body[0], "ModifyIndex", "Value"),
+        };
+        if entry.is_null() {
+            return Ok(None);
+        }
+        let revision = match &entry[revision] {
+            Value::String(text) => text.parse().ok(),
+            number => number.as_u64(),
+        }
+        .with_context(|| format!("{} has no revision", store.describe()))?;
+        let value = base64_decode(entry[value].as_str().unwrap_or_default())
+            .with_context(|| format!("{} is not valid base64", store.describe()))?;
+        let text = String::from_utf8(value).with_context(|| format!("{} is not UTF-8 text", store.describe()))?;
+        Ok(Some((revision, text)))
+    }
+}
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(char::from(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize]));
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.bytes().filter(|&c| c != b'=') {
+        let value = ALPHABET.iter().position(|&a| a == c)? as u32;
+        bits = (bits << 6 | value) & 0xff_ffff;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
@@ -0,0 +1,105 @@
+//! The data model of cloak's JSON country maps (`<list>_ip_map.json` in
+//! the nested layout), for tools that read or write them.
+//!
+//! A map is an object of country codes (or `--source` labels) to their
+//! prefixes, plus a `schema_version` key. The field names below are part
+//! of the format and only change together with [`SCHEMA_VERSION`]; maps
+//! written before the key existed are version 1.
+//!
+//! ```no_run
+//! let text = std::fs::read_to_string("brics_ip_map.json")?;
+//! let map: cloak::IpMap = serde_json::from_str(&text)?;
+//! map.check_version()?;
+//! for (cc, nets) in &map.countries {
+//!     println!("{}: {} IPv4 prefixes", cc, nets.ipv4.len());
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::collections::HashMap;
+
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+
+/// Version of the map format this build reads and writes
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A prefix, serialized as its CIDR string (`192.0.2.0/24`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SerIpNet(pub IpNetwork);
+
+impl Serialize for SerIpNet {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.0.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for SerIpNet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map(SerIpNet).map_err(serde::de::Error::custom)
+    }
+}
+
+/// The prefixes of one country or source
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CountryNets {
+    pub ipv4: Vec<SerIpNet>,
+    pub ipv6: Vec<SerIpNet>,
+    /// Unix time of the last successful fetch; absent in maps written
+    /// before this was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetched_at: Option<u64>,
+}
+
+/// A whole map file
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpMap {
+    #[serde(default = "unversioned")]
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub countries: HashMap<String, CountryNets>,
+}
+
+fn unversioned() -> u32 {
+    1
+}
+
+impl IpMap {
+    /// A map of `countries` in the current format
+    pub fn new(countries: HashMap<String, CountryNets>) -> Self {
+        IpMap { schema_version: SCHEMA_VERSION, countries }
+    }
+
+    /// Fail for maps written by a newer cloak, whose fields may no longer
+    /// mean what this build expects
+    pub fn check_version(&self) -> Result<(), String> {
+        if self.schema_version > SCHEMA_VERSION {
+            return Err(format!(
+                "schema version {} is newer than the {} this build understands; upgrade cloak",
+                self.schema_version, SCHEMA_VERSION
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Borrowed form of [`IpMap`] for writing maps held elsewhere
+#[derive(Serialize)]
+pub struct IpMapRef<'a> {
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub countries: &'a HashMap<String, CountryNets>,
+}
+
+impl<'a> IpMapRef<'a> {
+    pub fn new(countries: &'a HashMap<String, CountryNets>) -> Self {
+        IpMapRef { schema_version: SCHEMA_VERSION, countries }
+    }
+}
@@ -0,0 +1,885 @@
+//! `cloak lint`: checks rule files, whether cloak wrote them or someone
+//! edited them since, before they are loaded.
+//!
+//! nftables rulesets, pf anchors, `ipset restore` files and
+//! `iptables-save` output are understood well enough to find structural
+//! mistakes, set elements that are invalid, duplicated or overlapping, and
+//! rules that can never match because an earlier one already decided.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use ipnetwork::IpNetwork;
+
+use crate::{
+    cidr,
+    exit::{Code, WithCode},
+    ui::{success, warning},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct LintArgs {
+    /// Rule files to check
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// What the files are (default: guessed from the extension, else the
+    /// contents)
+    #[arg(long, value_enum)]
+    format: Option<LintFormat>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+enum LintFormat {
+    /// nftables ruleset, as loaded with `nft -f`
+    Nft,
+    /// pf rules or anchor
+    Pf,
+    /// `ipset save` / `ipset restore` file
+    Ipset,
+    /// `iptables-save` / `iptables-restore` file
+    Iptables,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Severity {
+    Error,
+    Warning,
+}
+
+struct Diagnostic {
+    line: usize,
+    severity: Severity,
+    message: String,
+}
+
+/// Findings for one file
+#[derive(Default)]
+struct Report {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Report {
+    fn error(&mut self, line: usize, message: impl fmt::Display) {
+        self.diagnostics.push(Diagnostic { line, severity: Severity::Error, message: message.to_string() });
+    }
+
+    fn warn(&mut self, line: usize, message: impl fmt::Display) {
+        self.diagnostics.push(Diagnostic { line, severity: Severity::Warning, message: message.to_string() });
+    }
+
+    fn push(&mut self, severity: Severity, line: usize, message: impl fmt::Display) {
+        match severity {
+            Severity::Error => self.error(line, message),
+            Severity::Warning => self.warn(line, message),
+        }
+    }
+}
+
+pub fn run(args: &LintArgs) -> Result<()> {
+    let (mut errors, mut warnings) = (0, 0);
+    for path in &args.files {
+        let text = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let format = args.format.unwrap_or_else(|| guess_format(path, &text));
+        let mut report = Report::default();
+        match format {
+            LintFormat::Nft => nft(&text, &mut report),
+            LintFormat::Pf => pf(&text, &mut report),
+            LintFormat::Ipset => ipset(&text, &mut report),
+            LintFormat::Iptables => iptables(&text, &mut report),
+        }
+        report.diagnostics.sort_by_key(|d| d.line);
+        for d in &report.diagnostics {
+            let label = match d.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            println!("{}:{}: {}: {}", path.display(), d.line, label, d.message);
+        }
+        errors += report.diagnostics.iter().filter(|d| d.severity == Severity::Error).count();
+        warnings += report.diagnostics.iter().filter(|d| d.severity == Severity::Warning).count();
+    }
+    if errors > 0 {
+        return Err(anyhow!("{} errors and {} warnings", errors, warnings)).code(Code::Validation);
+    }
+    if warnings > 0 {
+        warning!("{} warnings", warnings);
+    } else {
+        success!("No problems found in {} files.", args.files.len());
+    }
+    Ok(())
+}
+
+fn guess_format(path: &Path, text: &str) -> LintFormat {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("nft") => return LintFormat::Nft,
+        Some("pf") => return LintFormat::Pf,
+        Some("ipset") => return LintFormat::Ipset,
+        Some("iptables" | "ip6tables") => return LintFormat::Iptables,
+        _ => {}
+    }
+    let first = text
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .unwrap_or("");
+    if first.starts_with('*') || first.starts_with(':') || first.starts_with("-A ") {
+        LintFormat::Iptables
+    } else if first.starts_with("create ") || first.starts_with("add ") && !first.contains('{') {
+        LintFormat::Ipset
+    } else if first.starts_with("table <") || first.starts_with("block ") || first.starts_with("pass ") {
+        LintFormat::Pf
+    } else {
+        LintFormat::Nft
+    }
+}
+
+/// One address element of a set or table
+struct Element {
+    text: String,
+    line: usize,
+    v6: bool,
+    start: u128,
+    end: u128,
+}
+
+/// Parse an address, prefix or `first-last` range. Prefixes with host bits
+/// set are reported and taken as their network.
+fn element(text: &str, line: usize, report: &mut Report) -> Option<Element> {
+    let text = text.trim_start_matches('!');
+    if let Some((first, last)) = text.split_once('-') {
+        let (first, last) = (first.parse::<IpAddr>().ok()?, last.parse::<IpAddr>().ok()?);
+        if first.is_ipv4() != last.is_ipv4() {
+            return None;
+        }
+        let (start, end) = (cidr::bounds(&IpNetwork::from(first)).0, cidr::bounds(&IpNetwork::from(last)).0);
+        if start > end {
+            report.error(line, format!("range {} ends before it starts", text));
+            return None;
+        }
+        return Some(Element { text: text.to_string(), line, v6: first.is_ipv6(), start, end });
+    }
+    let net = text.parse::<IpNetwork>().ok()?;
+    if net.ip() != net.network() {
+        report.warn(line, format!("{} has host bits set; it means {}/{}", text, net.network(), net.prefix()));
+    }
+    let (start, end) = cidr::bounds(&net);
+    Some(Element { text: text.to_string(), line, v6: net.is_ipv6(), start, end })
+}
+
+/// Report repeated and overlapping elements of one set
+fn check_elements(set: &str, elements: &mut [Element], duplicate: Severity, overlap: Severity, report: &mut Report) {
+    elements.sort_by_key(|e| (e.v6, e.start, std::cmp::Reverse(e.end), e.line));
+    let mut widest: Option<&Element> = None;
+    let mut last: Option<&Element> = None;
+    for element in elements.iter() {
+        match (last, widest) {
+            (Some(prev), _) if (prev.v6, prev.start, prev.end) == (element.v6, element.start, element.end) => {
+                report.push(duplicate, element.line, format!("{}: {} is listed again (first on line {})", set, element.text, prev.line));
+                continue;
+            }
+            (_, Some(prev)) if prev.v6 == element.v6 && element.start <= prev.end => {
+                if element.end <= prev.end {
+                    report.push(overlap, element.line, format!("{}: {} is already covered by {} (line {})", set, element.text, prev.text, prev.line));
+                } else {
+                    report.push(overlap, element.line, format!("{}: {} overlaps {} (line {})", set, element.text, prev.text, prev.line));
+                    widest = Some(element);
+                }
+            }
+            _ => widest = Some(element),
+        }
+        last = Some(element);
+    }
+}
+
+/// Tracks the rules of one chain to find the ones earlier rules shadow
+#[derive(Default)]
+struct Reachability {
+    /// Line and verdict of a rule that decides every packet
+    ended: Option<(usize, String)>,
+    /// Matches of earlier deciding rules, with their line and verdict
+    seen: HashMap<String, (usize, String)>,
+}
+
+impl Reachability {
+    /// Record a rule; `matches` is empty for a rule that matches everything
+    /// and `verdict` is set if the rule decides what happens to the packet
+    fn rule(&mut self, line: usize, matches: &[String], verdict: Option<&str>, report: &mut Report) {
+        if let Some((at, decided)) = &self.ended {
+            report.warn(line, format!("unreachable: the rule on line {} already {}s every packet", at, decided));
+            return;
+        }
+        let Some(verdict) = verdict else { return };
+        if matches.is_empty() {
+            self.ended = Some((line, verdict.to_string()));
+            return;
+        }
+        let key = matches.join(" ");
+        match self.seen.get(&key) {
+            Some((at, earlier)) if earlier != verdict => report.warn(
+                line,
+                format!("never matches: the rule on line {} already {}s these packets, so this {} has no effect", at, earlier, verdict),
+            ),
+            Some((at, _)) => report.warn(line, format!("redundant: same as the rule on line {}", at)),
+            None => {
+                self.seen.insert(key, (line, verdict.to_string()));
+            }
+        }
+    }
+}
+
+// nftables
+
+#[derive(Debug, PartialEq)]
+enum Tok {
+    Word(String),
+    Open,
+    Close,
+    /// `;` or the end of a line
+    End,
+}
+
+fn tokenize(text: &str, report: &mut Report) -> Vec<(Tok, usize)> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    let mut word = String::new();
+    let flush = |word: &mut String, tokens: &mut Vec<(Tok, usize)>, line: usize| {
+        if !word.is_empty() {
+            tokens.push((Tok::Word(std::mem::take(word)), line));
+        }
+    };
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                flush(&mut word, &mut tokens, line);
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+            }
+            '"' => {
+                word.push('"');
+                loop {
+                    match chars.next() {
+                        Some('"') => {
+                            word.push('"');
+                            break;
+                        }
+                        Some('\n') | None => {
+                            report.error(line, "unterminated string");
+                            line += 1;
+                            break;
+                        }
+                        Some(c) => word.push(c),
+                    }
+                }
+            }
+            '\\' if chars.peek() == Some(&'\n') => {
+                chars.next();
+                line += 1;
+            }
+            '{' | '}' | ';' | '\n' => {
+                flush(&mut word, &mut tokens, line);
+                tokens.push((
+                    match c {
+                        '{' => Tok::Open,
+                        '}' => Tok::Close,
+                        _ => Tok::End,
+                    },
+                    line,
+                ));
+                if c == '\n' {
+                    line += 1;
+                }
+            }
+            ',' => flush(&mut word, &mut tokens, line),
+            '=' => {
+                flush(&mut word, &mut tokens, line);
+                tokens.push((Tok::Word("=".to_string()), line));
+            }
+            c if c.is_whitespace() => flush(&mut word, &mut tokens, line),
+            c => word.push(c),
+        }
+    }
+    flush(&mut word, &mut tokens, line);
+    tokens
+}
+
+/// A statement, with its block (`table`, `chain`, `set`, ...) or the
+/// elements of a `{ ... }` literal in it
+#[derive(Default)]
+pub struct Stmt {
+    pub line: usize,
+    pub words: Vec<String>,
+    pub body: Option<Vec<Stmt>>,
+    pub elements: Vec<(String, usize)>,
+}
+
+/// The statements of an nft ruleset or listing, for other commands that
+/// need to look inside one; mistakes in it are ignored
+pub fn parse_nft(text: &str) -> Vec<Stmt> {
+    let mut ignored = Report::default();
+    let tokens = tokenize(text, &mut ignored);
+    parse_block(&mut tokens.into_iter(), None, &mut ignored)
+}
+
+const BLOCKS: [&str; 7] = ["table", "chain", "set", "map", "flowtable", "counter", "quota"];
+
+fn parse_block(tokens: &mut std::vec::IntoIter<(Tok, usize)>, opened: Option<usize>, report: &mut Report) -> Vec<Stmt> {
+    let mut stmts = Vec::new();
+    let mut current = Stmt::default();
+    let finish = |current: &mut Stmt, stmts: &mut Vec<Stmt>| {
+        if !current.words.is_empty() {
+            stmts.push(std::mem::take(current));
+        }
+    };
+    loop {
+        let Some((tok, line)) = tokens.next() else {
+            if let Some(opened) = opened {
+                report.error(opened, "`{` is never closed");
+            }
+            finish(&mut current, &mut stmts);
+            return stmts;
+        };
+        match tok {
+            Tok::Word(word) => {
+                if current.words.is_empty() {
+                    current.line = line;
+                }
+                current.words.push(word);
+            }
+            Tok::End => finish(&mut current, &mut stmts),
+            Tok::Close => {
+                if opened.is_some() {
+                    finish(&mut current, &mut stmts);
+                    return stmts;
+                }
+                report.error(line, "`}` without a matching `{`");
+            }
+            Tok::Open => {
+                let words = &current.words;
+                let block = words.last().is_some_and(|w| w != "=")
+                    && (BLOCKS.contains(&words[0].as_str())
+                        || matches!(words[0].as_str(), "add" | "create") && words.get(1).is_some_and(|w| BLOCKS.contains(&w.as_str())));
+                if block {
+                    current.body = Some(parse_block(tokens, Some(line), report));
+                    finish(&mut current, &mut stmts);
+                    continue;
+                }
+                // An anonymous set or the elements of a named one
+                if current.words.is_empty() {
+                    current.line = line;
+                }
+                let mut depth = 1;
+                let mut literal = Vec::new();
+                while depth > 0 {
+                    match tokens.next() {
+                        Some((Tok::Word(word), at)) => {
+                            literal.push(word.clone());
+                            current.elements.push((word, at));
+                        }
+                        Some((Tok::Open, _)) => depth += 1,
+                        Some((Tok::Close, _)) => depth -= 1,
+                        Some((Tok::End, _)) => {}
+                        None => {
+                            report.error(line, "`{` is never closed");
+                            break;
+                        }
+                    }
+                }
+                current.words.push(format!("{{ {} }}", literal.join(", ")));
+            }
+        }
+    }
+}
+
+struct SetInfo {
+    line: usize,
+    used: bool,
+}
+
+fn nft(text: &str, report: &mut Report) {
+    let tokens = tokenize(text, report);
+    let stmts = parse_block(&mut tokens.into_iter(), None, report);
+    for stmt in &stmts {
+        let words: Vec<&str> = stmt.words.iter().map(String::as_str).collect();
+        match (words.as_slice(), &stmt.body) {
+            (["table" | "add" | "create", ..], Some(body)) => nft_table(body, report),
+            (["flush" | "delete" | "add" | "create" | "destroy" | "define" | "list" | "table" | "insert" | "replace", ..], None) => {}
+            (["include", ..], _) => report.warn(stmt.line, "includes are not checked"),
+            _ => report.error(stmt.line, format!("unexpected `{}` outside a table", words[0])),
+        }
+    }
+}
+
+fn nft_table(body: &[Stmt], report: &mut Report) {
+    let mut sets: HashMap<&str, SetInfo> = HashMap::new();
+    let mut chains = HashSet::new();
+    for stmt in body {
+        match (stmt.words.first().map(String::as_str), stmt.words.get(1), &stmt.body) {
+            (Some("set" | "map"), Some(name), Some(set)) => {
+                if sets.insert(name, SetInfo { line: stmt.line, used: false }).is_some() {
+                    report.error(stmt.line, format!("set {} is declared twice", name));
+                }
+                nft_set(name, set, report);
+            }
+            (Some("chain"), Some(name), Some(_)) => {
+                if !chains.insert(name.as_str()) {
+                    report.error(stmt.line, format!("chain {} is declared twice", name));
+                }
+            }
+            (Some("flags" | "comment"), _, None) => {}
+            // Named stateful objects, referenced as `counter name "..."`
+            (Some("counter" | "quota"), Some(_), Some(_)) => {}
+            (Some(word), _, _) => report.error(stmt.line, format!("unexpected `{}` in a table", word)),
+            (None, _, _) => {}
+        }
+    }
+    for stmt in body {
+        if let (Some("chain"), Some(name), Some(rules)) = (stmt.words.first().map(String::as_str), stmt.words.get(1), &stmt.body) {
+            nft_chain(name, rules, &mut sets, &chains, report);
+        }
+    }
+    let mut unused: Vec<(&&str, &SetInfo)> = sets.iter().filter(|(_, set)| !set.used).collect();
+    unused.sort_by_key(|(_, set)| set.line);
+    for (name, set) in unused {
+        report.warn(set.line, format!("set {} is never used", name));
+    }
+}
+
+fn nft_set(name: &str, body: &[Stmt], report: &mut Report) {
+    let mut family = None;
+    let mut typed = false;
+    let mut interval = false;
+    let mut auto_merge = false;
+    let mut elements = None;
+    for stmt in body {
+        match stmt.words[0].as_str() {
+            "type" | "typeof" => {
+                typed = true;
+                // Only plain address sets; concatenations are not checked
+                family = match stmt.words[1..].iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+                    ["ipv4_addr"] | ["ip", "saddr" | "daddr"] => Some(false),
+                    ["ipv6_addr"] | ["ip6", "saddr" | "daddr"] => Some(true),
+                    _ => None,
+                };
+            }
+            "flags" => interval = stmt.words.iter().any(|w| w == "interval"),
+            "auto-merge" => auto_merge = true,
+            "elements" => elements = Some(stmt),
+            "timeout" | "gc-interval" | "size" | "policy" | "comment" | "counter" => {}
+            other => report.error(stmt.line, format!("unexpected `{}` in set {}", other, name)),
+        }
+    }
+    if !typed {
+        report.error(body.first().map_or(0, |s| s.line), format!("set {} has no type", name));
+    }
+    let (Some(v6), Some(elements)) = (family, elements) else {
+        return;
+    };
+    let mut parsed = Vec::new();
+    let mut items = elements.elements.iter();
+    while let Some((text, line)) = items.next() {
+        // Per-element options such as `timeout 1h` or `comment "..."`
+        match text.as_str() {
+            "timeout" | "expires" | "comment" => {
+                items.next();
+                continue;
+            }
+            "counter" | "packets" | "bytes" => continue,
+            _ if text.parse::<u64>().is_ok() => continue,
+            _ => {}
+        }
+        let Some(element) = element(text, *line, report) else {
+            report.error(*line, format!("{}: `{}` is not an address, prefix or range", name, text));
+            continue;
+        };
+        if element.v6 != v6 {
+            let family = if v6 { "IPv6" } else { "IPv4" };
+            report.error(*line, format!("{}: {} does not belong in an {} set", name, text, family));
+            continue;
+        }
+        if !interval && element.start != element.end {
+            report.error(*line, format!("{}: {} needs `flags interval` on the set", name, text));
+        }
+        parsed.push(element);
+    }
+    let overlap = if interval && !auto_merge { Severity::Error } else { Severity::Warning };
+    check_elements(name, &mut parsed, overlap, overlap, report);
+}
+
+/// Statements that do not decide anything nor restrict what a rule matches
+pub fn nft_matches(words: &[String]) -> Vec<String> {
+    let mut matches = Vec::new();
+    let mut words = words.iter().peekable();
+    while let Some(word) = words.next() {
+        match word.as_str() {
+            "counter" => {
+                while words.next_if(|w| matches!(w.as_str(), "packets" | "bytes") || w.parse::<u64>().is_ok()).is_some() {}
+            }
+            "log" => {
+                while words.next_if(|w| matches!(w.as_str(), "prefix" | "level" | "group" | "flags") || !w.starts_with(char::is_alphabetic) || matches!(w.as_str(), "all" | "warn" | "info" | "notice" | "debug" | "err" | "crit" | "alert" | "emerg")).is_some() {}
+            }
+            "comment" => {
+                words.next();
+            }
+            _ => matches.push(word.clone()),
+        }
+    }
+    matches
+}
+
+fn nft_chain(name: &str, rules: &[Stmt], sets: &mut HashMap<&str, SetInfo>, chains: &HashSet<&str>, report: &mut Report) {
+    let mut reach = Reachability::default();
+    for rule in rules {
+        if matches!(rule.words[0].as_str(), "type" | "policy" | "comment" | "devices") {
+            continue;
+        }
+        for word in &rule.words {
+            if let Some(set) = word.strip_prefix('@') {
+                match sets.get_mut(set) {
+                    Some(info) => info.used = true,
+                    None => report.error(rule.line, format!("chain {}: set @{} is not declared in this table", name, set)),
+                }
+            }
+        }
+        let verdict_at = rule
+            .words
+            .iter()
+            .position(|w| matches!(w.as_str(), "accept" | "drop" | "reject" | "return" | "goto" | "jump" | "queue" | "continue"));
+        let (matches, verdict) = match verdict_at {
+            Some(at) => {
+                let verdict = rule.words[at].as_str();
+                if matches!(verdict, "goto" | "jump") {
+                    match rule.words.get(at + 1) {
+                        Some(target) if chains.contains(target.as_str()) => {}
+                        Some(target) => report.error(rule.line, format!("chain {}: {} to undeclared chain {}", name, verdict, target)),
+                        None => report.error(rule.line, format!("chain {}: {} without a target", name, verdict)),
+                    }
+                }
+                let decides = matches!(verdict, "accept" | "drop" | "reject" | "return" | "goto");
+                (nft_matches(&rule.words[..at]), decides.then_some(verdict))
+            }
+            None => (nft_matches(&rule.words), None),
+        };
+        reach.rule(rule.line, &matches, verdict, report);
+    }
+}
+
+// pf
+
+fn pf(text: &str, report: &mut Report) {
+    let mut tables = HashMap::new();
+    let mut rules = Vec::new();
+    let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line.split('#').next().unwrap_or("").trim()));
+    while let Some((number, line)) = lines.next() {
+        if line.is_empty() {
+            continue;
+        }
+        let mut statement = line.to_string();
+        while statement.ends_with('\\') {
+            statement.pop();
+            match lines.next() {
+                Some((_, next)) => statement.push_str(&format!(" {}", next)),
+                None => break,
+            }
+        }
+        if statement.starts_with("table") && statement.contains('{') {
+            let mut elements = Vec::new();
+            let mut rest = statement.split_once('{').map(|(_, rest)| rest.to_string()).unwrap_or_default();
+            let mut at = number;
+            loop {
+                let (inside, closed) = match rest.split_once('}') {
+                    Some((inside, _)) => (inside.to_string(), true),
+                    None => (rest.clone(), false),
+                };
+                elements.extend(inside.split([' ', ',', '\t']).filter(|e| !e.is_empty()).map(|e| (e.to_string(), at)));
+                if closed {
+                    break;
+                }
+                match lines.next() {
+                    Some((next_number, next)) => {
+                        at = next_number;
+                        rest = next.to_string();
+                    }
+                    None => {
+                        report.error(number, "`{` is never closed");
+                        break;
+                    }
+                }
+            }
+            pf_table(&statement, number, elements, &mut tables, report);
+            continue;
+        }
+        if statement.starts_with("table") {
+            pf_table(&statement, number, Vec::new(), &mut tables, report);
+            continue;
+        }
+        rules.push((number, statement));
+    }
+
+    let mut reach: HashMap<&str, Reachability> = HashMap::new();
+    for (number, rule) in &rules {
+        let words: Vec<&str> = rule.split_whitespace().collect();
+        match words[0] {
+            "block" | "pass" => {}
+            "match" | "anchor" | "load" | "set" | "scrub" | "antispoof" | "nat" | "rdr" | "binat" | "nat-anchor" | "rdr-anchor" => continue,
+            _ if words.get(1) == Some(&"=") => continue,
+            other => {
+                report.error(*number, format!("unknown statement `{}`", other));
+                continue;
+            }
+        }
+        for word in &words {
+            if let Some(name) = word.trim_start_matches('!').strip_prefix('<').and_then(|w| w.strip_suffix('>')) {
+                if !tables.contains_key(name) {
+                    report.error(*number, format!("table <{}> is not declared in this file", name));
+                }
+            }
+        }
+        // pf applies the last matching rule unless one is `quick`, so only
+        // quick rules can shadow later ones
+        if !words.contains(&"quick") {
+            continue;
+        }
+        let verdict = if words[0] == "pass" { "pass" } else { "block" };
+        let matches: Vec<String> = words[1..]
+            .iter()
+            .filter(|w| !matches!(**w, "drop" | "return" | "quick" | "log" | "in" | "out" | "all"))
+            .map(|w| w.to_string())
+            .collect();
+        let directions: &[&str] = if words.contains(&"in") {
+            &["in"]
+        } else if words.contains(&"out") {
+            &["out"]
+        } else {
+            &["in", "out"]
+        };
+        for direction in directions {
+            reach.entry(direction).or_default().rule(*number, &matches, Some(verdict), report);
+        }
+    }
+}
+
+fn pf_table(statement: &str, line: usize, elements: Vec<(String, usize)>, tables: &mut HashMap<String, usize>, report: &mut Report) {
+    let Some(name) = statement
+        .split_whitespace()
+        .nth(1)
+        .and_then(|w| w.strip_prefix('<'))
+        .and_then(|w| w.strip_suffix('>'))
+    else {
+        report.error(line, "table without a <name>");
+        return;
+    };
+    if let Some(first) = tables.insert(name.to_string(), line) {
+        report.error(line, format!("table <{}> is already declared on line {}", name, first));
+    }
+    let mut parsed = Vec::new();
+    for (text, at) in elements {
+        match element(&text, at, report) {
+            Some(element) => parsed.push(element),
+            // Host names and interface groups are looked up by pfctl
+            None if text.starts_with(char::is_alphabetic) => {}
+            None => report.error(at, format!("<{}>: `{}` is not an address or prefix", name, text)),
+        }
+    }
+    check_elements(&format!("<{}>", name), &mut parsed, Severity::Warning, Severity::Warning, report);
+}
+
+// ipset
+
+fn ipset(text: &str, report: &mut Report) {
+    let mut sets: HashMap<String, (usize, String, bool)> = HashMap::new();
+    let mut elements: HashMap<String, Vec<Element>> = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some(&command) = words.first() else { continue };
+        if command.starts_with('#') {
+            continue;
+        }
+        let Some(&name) = words.get(1) else {
+            if !matches!(command, "flush" | "destroy" | "COMMIT") {
+                report.error(number, format!("`{}` needs a set name", command));
+            }
+            continue;
+        };
+        match command {
+            "create" | "-N" | "n" => {
+                let Some(kind) = words.get(2) else {
+                    report.error(number, format!("set {} has no type", name));
+                    continue;
+                };
+                let v6 = words.windows(2).any(|w| w[0] == "family" && w[1] == "inet6");
+                if let Some((first, _, _)) = sets.insert(name.to_string(), (number, kind.to_string(), v6)) {
+                    report.error(number, format!("set {} is already created on line {}", name, first));
+                }
+            }
+            "add" | "-A" | "a" => {
+                let Some((_, kind, v6)) = sets.get(name) else {
+                    report.error(number, format!("set {} is not created before this", name));
+                    continue;
+                };
+                let Some(value) = words.get(2) else {
+                    report.error(number, "`add` without an element");
+                    continue;
+                };
+                if !matches!(kind.as_str(), "hash:net" | "hash:ip" | "bitmap:ip") {
+                    continue;
+                }
+                match element(value, number, report) {
+                    Some(element) if element.v6 != *v6 => {
+                        let family = if *v6 { "inet6" } else { "inet" };
+                        report.error(number, format!("{}: {} does not belong in a family {} set", name, value, family));
+                    }
+                    Some(element) => elements.entry(name.to_string()).or_default().push(element),
+                    None => report.error(number, format!("{}: `{}` is not an address or prefix", name, value)),
+                }
+            }
+            "del" | "-D" | "flush" | "-F" | "destroy" | "-X" | "swap" | "-W" | "rename" | "-E" | "test" | "-T" => {}
+            other => report.error(number, format!("unknown command `{}`", other)),
+        }
+    }
+    let mut elements: Vec<(String, Vec<Element>)> = elements.into_iter().collect();
+    elements.sort_by(|a, b| a.0.cmp(&b.0));
+    for (name, mut set) in elements {
+        // Restoring fails on a repeated element unless run with -exist
+        check_elements(&name, &mut set, Severity::Error, Severity::Warning, report);
+    }
+}
+
+// iptables
+
+const IPTABLES_BUILTIN: [&str; 5] = ["INPUT", "OUTPUT", "FORWARD", "PREROUTING", "POSTROUTING"];
+
+fn iptables(text: &str, report: &mut Report) {
+    let mut table: Option<(String, usize)> = None;
+    let mut chains: HashSet<String> = HashSet::new();
+    let mut rules: Vec<(usize, Vec<String>)> = Vec::new();
+    let finish = |table: &mut Option<(String, usize)>, chains: &mut HashSet<String>, rules: &mut Vec<(usize, Vec<String>)>, report: &mut Report| {
+        iptables_rules(chains, rules, report);
+        *table = None;
+        chains.clear();
+        rules.clear();
+    };
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Counters that iptables-save -c puts in front of rules
+        let line = match line.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+            Some((_, rule)) => rule.trim(),
+            None => line,
+        };
+        if let Some(name) = line.strip_prefix('*') {
+            if let Some((open, at)) = &table {
+                report.error(*at, format!("table {} is not closed with COMMIT", open));
+                finish(&mut table, &mut chains, &mut rules, report);
+            }
+            table = Some((name.to_string(), number));
+            continue;
+        }
+        if table.is_none() {
+            report.error(number, "outside a `*table` section");
+            continue;
+        }
+        if line == "COMMIT" {
+            finish(&mut table, &mut chains, &mut rules, report);
+        } else if let Some(chain) = line.strip_prefix(':') {
+            let name = chain.split_whitespace().next().unwrap_or("");
+            if !chains.insert(name.to_string()) {
+                report.error(number, format!("chain {} is declared twice", name));
+            }
+        } else if line.starts_with("-A ") || line.starts_with("-I ") {
+            rules.push((number, shell_words(line)));
+        } else {
+            report.error(number, format!("unexpected `{}`", line.split_whitespace().next().unwrap_or(line)));
+        }
+    }
+    if let Some((open, at)) = &table {
+        report.error(*at, format!("table {} is not closed with COMMIT", open));
+        finish(&mut table, &mut chains, &mut rules, report);
+    }
+}
+
+fn iptables_rules(chains: &HashSet<String>, rules: &[(usize, Vec<String>)], report: &mut Report) {
+    let known = |chain: &str| chains.contains(chain) || IPTABLES_BUILTIN.contains(&chain);
+    let mut reach: HashMap<&str, Reachability> = HashMap::new();
+    for (number, words) in rules {
+        let Some(chain) = words.get(1) else {
+            report.error(*number, "rule without a chain");
+            continue;
+        };
+        if !known(chain) {
+            report.error(*number, format!("chain {} is not declared", chain));
+        }
+        let mut target = None;
+        let mut matches = Vec::new();
+        let mut rest = words[2..].iter();
+        while let Some(word) = rest.next() {
+            match word.as_str() {
+                "-j" | "--jump" | "-g" | "--goto" => target = rest.next().map(|t| (word.as_str(), t.as_str())),
+                // Comments and counters do not restrict the match
+                "-m" if rest.clone().next().is_some_and(|m| m == "comment") => {
+                    rest.next();
+                }
+                "--comment" => {
+                    rest.next();
+                }
+                "-c" | "--set-counters" => {
+                    rest.next();
+                    rest.next();
+                }
+                _ => matches.push(word.clone()),
+            }
+        }
+        let verdict = match target {
+            Some((_, "ACCEPT" | "DROP" | "REJECT" | "RETURN")) => target.map(|(_, t)| t.to_lowercase()),
+            Some(("-g" | "--goto", name)) => {
+                if !known(name) {
+                    report.error(*number, format!("goto to undeclared chain {}", name));
+                }
+                Some("goto".to_string())
+            }
+            Some((_, name)) => {
+                // Upper case names are target extensions (LOG, MARK, ...)
+                if !known(name) && name.chars().any(|c| c.is_ascii_lowercase()) {
+                    report.error(*number, format!("jump to undeclared chain {}", name));
+                }
+                None
+            }
+            None => None,
+        };
+        reach.entry(chain.as_str()).or_default().rule(*number, &matches, verdict.as_deref(), report);
+    }
+}
+
+/// Split on whitespace, keeping double-quoted strings together
+fn shell_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
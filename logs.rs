@@ -0,0 +1,210 @@
+//! `cloak logs`: which countries and ports the loaded rules are dropping,
+//! from the kernel log lines that rules generated with `--log` emit.
+
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::{
+    fs::File,
+    io::{AsyncBufRead, AsyncBufReadExt, BufReader},
+    process::Command,
+};
+
+use crate::{
+    analyze::CountryTraffic,
+    daemon, fetch,
+    index::Index,
+    nft, parse_duration, state,
+    ui::info,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct LogsArgs {
+    /// Keep reading new drops and print the statistics periodically
+    #[arg(short, long)]
+    follow: bool,
+
+    /// Syslog file to read (e.g. /var/log/kern.log) instead of the journal
+    #[arg(long, value_name = "FILE")]
+    file: Option<PathBuf>,
+
+    /// JSON maps, or one index from `cloak compile`, to classify addresses
+    /// with (default: every country in the download cache)
+    #[arg(long, value_name = "FILE")]
+    map: Vec<PathBuf>,
+
+    /// Where earlier fetches left their downloads
+    #[arg(long, value_name = "DIR", default_value_os_t = state::default_cache_dir())]
+    cache_dir: PathBuf,
+
+    /// How often --follow prints the statistics
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    report_every: Duration,
+
+    /// How many countries and ports to list
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    top: usize,
+}
+
+/// Drops counted so far
+#[derive(Default)]
+struct Drops {
+    countries: CountryTraffic,
+    /// Keyed by protocol and destination port
+    ports: BTreeMap<(String, u16), u64>,
+}
+
+/// One packet a `--log` rule dropped
+pub struct Drop {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub len: u64,
+    /// Protocol and destination port, for TCP and UDP
+    pub port: Option<(String, u16)>,
+}
+
+/// The drop a kernel log line reports, if it is one of cloak's
+pub fn parse_drop(line: &str) -> Option<Drop> {
+    let start = line.find(nft::LOG_PREFIX)?;
+    let mut src = None;
+    let mut dst = None;
+    let mut len = 0;
+    let mut proto = None;
+    let mut port = None;
+    for field in line[start + nft::LOG_PREFIX.len()..].split_whitespace() {
+        let Some((key, value)) = field.split_once('=') else { continue };
+        match key {
+            "SRC" => src = value.parse::<IpAddr>().ok(),
+            "DST" => dst = value.parse::<IpAddr>().ok(),
+            "LEN" => len = value.parse().unwrap_or(0),
+            "PROTO" => proto = Some(value.to_string()),
+            "DPT" => port = value.parse().ok(),
+            _ => {}
+        }
+    }
+    Some(Drop { src: src?, dst: dst?, len, port: proto.zip(port) })
+}
+
+/// A reader of cloak's kernel log lines: the journal, or `file` (a syslog
+/// file) with `tail` when following it. Following starts at the end.
+pub fn log_command(file: Option<&Path>, follow: bool) -> Command {
+    match file {
+        Some(file) => {
+            let mut tail = Command::new("tail");
+            tail.args(["-F", "-n", "0"]).arg(file);
+            tail
+        }
+        None => {
+            let mut journal = Command::new("journalctl");
+            journal.args(["-k", "-o", "cat", "--no-pager", "--grep", nft::LOG_PREFIX.trim_end()]);
+            if follow {
+                journal.args(["-f", "-n", "0"]);
+            }
+            journal
+        }
+    }
+}
+
+impl Drops {
+    /// Count one kernel log line, if it is one of cloak's
+    fn record(&mut self, index: &Index, line: &str) {
+        let Some(drop) = parse_drop(line) else {
+            return;
+        };
+        self.countries.record(index, drop.src, drop.dst, 1, drop.len);
+        if let Some(port) = drop.port {
+            *self.ports.entry(port).or_default() += 1;
+        }
+    }
+
+    fn print(&self, top: usize) {
+        if self.countries.total.packets == 0 {
+            info!("No drops logged yet.");
+            return;
+        }
+        info!("{} drops logged", self.countries.total.packets);
+        self.countries.print_table(top);
+        let mut ports: Vec<(&(String, u16), &u64)> = self.ports.iter().collect();
+        ports.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        println!();
+        println!("{:<12} {:>10}", "Port", "Drops");
+        for ((proto, port), count) in ports.into_iter().take(top) {
+            println!("{:<12} {:>10}", format!("{}/{}", port, proto.to_lowercase()), count);
+        }
+    }
+}
+
+pub async fn run(args: &LogsArgs) -> Result<()> {
+    let index = if args.map.is_empty() {
+        let map = fetch::cached_countries(&args.cache_dir)?;
+        if map.is_empty() {
+            bail!("no country data in {}; pass --map or fetch a list first", args.cache_dir.display());
+        }
+        Index::build(&map)
+    } else {
+        Index::load(&args.map)?
+    };
+
+    // Following only shows drops from now on; otherwise read all there is
+    let mut reader = None;
+    let lines: Box<dyn AsyncBufRead + Unpin> = match &args.file {
+        Some(file) if !args.follow => {
+            let file = File::open(file).await.with_context(|| format!("open {}", file.display()))?;
+            Box::new(BufReader::new(file))
+        }
+        file => {
+            let mut child = log_command(file.as_deref(), args.follow)
+                .stdout(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .context("start reading the kernel log")?;
+            let stdout = child.stdout.take().context("log reader stdout")?;
+            reader = Some(child);
+            Box::new(BufReader::new(stdout))
+        }
+    };
+    let mut lines = lines.lines();
+    if args.follow {
+        info!("Watching for drops by rules generated with --log (Ctrl-C to stop)");
+    }
+
+    let mut drops = Drops::default();
+    let mut tick = tokio::time::interval(args.report_every);
+    tick.tick().await;
+    let shutdown = daemon::shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            line = lines.next_line() => match line.context("read the kernel log")? {
+                Some(line) => drops.record(&index, &line),
+                None => break,
+            },
+            _ = tick.tick(), if args.follow => {
+                println!();
+                drops.print(args.top);
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+    match reader {
+        Some(mut child) if args.follow => {
+            let _ = child.kill().await;
+        }
+        Some(mut child) => {
+            let status = child.wait().await.context("wait for the log reader")?;
+            // journalctl --grep exits with 1 when nothing matched
+            if !status.success() && status.code() != Some(1) {
+                bail!("reading the kernel log failed ({})", status);
+            }
+        }
+        None => {}
+    }
+    drops.print(args.top);
+    Ok(())
+}
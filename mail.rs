@@ -0,0 +1,85 @@
+//! Access lists for mail servers, checked when a client connects: Exim
+//! host lists, Sendmail access map entries and Postfix CIDR tables.
+
+use std::{collections::HashMap, fmt::Write, fs, net::Ipv6Addr};
+
+use anyhow::{bail, Context, Result};
+use ipnetwork::IpNetwork;
+
+use crate::{cidr, Action, CountryNets};
+
+/// Every prefix of `map` with its country, IPv4 first, in country order
+fn prefixes(map: &HashMap<String, CountryNets>) -> Vec<(&str, IpNetwork)> {
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    let ipv4 = codes.iter().flat_map(|cc| map[*cc].ipv4.iter().map(move |net| (cc.as_str(), net.0)));
+    ipv4.chain(codes.iter().flat_map(|cc| map[*cc].ipv6.iter().map(move |net| (cc.as_str(), net.0)))).collect()
+}
+
+/// Write an Exim host list file for `map` to `filename`, one prefix per
+/// line
+pub fn generate_exim(map: &HashMap<String, CountryNets>, action: Action, name: &str, filename: &str) -> Result<()> {
+    let list = format!("cloak_{}", name.replace(|c: char| !c.is_ascii_alphanumeric(), "_"));
+    let negate = if action == Action::Allow { "!" } else { "" };
+    let mut file = String::new();
+    writeln!(file, "# Generated by cloak. In the Exim configuration:")?;
+    writeln!(file, "#   hostlist {} = {}", list, filename)?;
+    writeln!(file, "# and in acl_smtp_connect:")?;
+    writeln!(file, "#   deny {}hosts = +{}", negate, list)?;
+    for (_, net) in prefixes(map) {
+        writeln!(file, "{}", net)?;
+    }
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
+
+/// Write Sendmail access map entries for `map` to `filename`, to merge
+/// into /etc/mail/access. The map matches whole octets (IPv4) and groups
+/// (IPv6) only, so other prefixes are split at the next one.
+pub fn generate_sendmail(map: &HashMap<String, CountryNets>, action: Action, filename: &str) -> Result<()> {
+    if action != Action::Block {
+        bail!("a Sendmail access map cannot reject everything outside a list; use --action block");
+    }
+    let mut file = String::new();
+    writeln!(file, "# Generated by cloak. Merge into /etc/mail/access and rebuild it with makemap")?;
+    for (cc, net) in prefixes(map) {
+        let (step, width) = if net.is_ipv4() { (8, 32) } else { (16, 128) };
+        let boundary = (net.prefix().div_ceil(step) * step).min(width);
+        for part in cidr::split(net, boundary) {
+            let key = match part {
+                IpNetwork::V4(n) => {
+                    let octets = n.network().octets();
+                    octets[..usize::from(boundary / 8)].iter().map(u8::to_string).collect::<Vec<_>>().join(".")
+                }
+                IpNetwork::V6(n) => ipv6_groups(n.network(), boundary),
+            };
+            writeln!(file, "Connect:{} REJECT cloak: {}", key, cc.to_uppercase())?;
+        }
+    }
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
+
+/// The leading `bits / 16` groups of `addr` as Sendmail writes them
+fn ipv6_groups(addr: Ipv6Addr, bits: u8) -> String {
+    let groups: Vec<String> = addr.segments()[..usize::from(bits / 16)].iter().map(|group| format!("{:x}", group)).collect();
+    format!("IPv6:{}", groups.join(":"))
+}
+
+/// Write a Postfix CIDR table for `map` to `filename`, for
+/// `check_client_access cidr:<file>`
+pub fn generate_postfix(map: &HashMap<String, CountryNets>, action: Action, filename: &str) -> Result<()> {
+    let mut file = String::new();
+    writeln!(file, "# Generated by cloak. In main.cf:")?;
+    writeln!(file, "#   smtpd_client_restrictions = check_client_access cidr:{}", filename)?;
+    for (cc, net) in prefixes(map) {
+        match action {
+            Action::Allow => writeln!(file, "{} DUNNO", net)?,
+            _ => writeln!(file, "{} REJECT cloak: {}", net, cc.to_uppercase())?,
+        }
+    }
+    // The first match wins, so the catch-all goes last
+    if action == Action::Allow {
+        writeln!(file, "0.0.0.0/0 REJECT cloak: not an allowed country")?;
+        writeln!(file, "::/0 REJECT cloak: not an allowed country")?;
+    }
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
@@ -1,429 +1,1845 @@
-use std::{collections::HashMap, fs::File, io::BufWriter};
-use anyhow::{Context, Result};
+use std::{collections::{BTreeMap, HashMap}, fs::{self, File}, io::{BufReader, BufWriter}, net::SocketAddr, path::{Path, PathBuf}};
+use anyhow::{bail, Context, Result};
 use ipnetwork::IpNetwork;
-use serde::Serialize;
-use clap::{Parser, ValueEnum};
-use std::process::Command;
+use serde::Deserialize;
+use clap::{error::ErrorKind, parser::ValueSource, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-/// IPv4 and IPv6 base URLs from IPdeny
-const IPV4_BASE: &str = "https://www.ipdeny.com/ipblocks/data/aggregated";
-const IPV6_BASE: &str = "https://www.ipdeny.com/ipv6/ipaddresses/aggregated";
+mod abuseipdb;
+mod analyze;
+mod asn;
+mod assertion;
+mod attach;
+mod batch;
+mod bench;
+mod bundle;
+mod canary;
+mod cdn;
+mod cidr;
+mod compare;
+mod conntrack;
+mod countries;
+mod crowdsec;
+mod daemon;
+mod dashboard;
+mod download;
+mod exit;
+mod fetch;
+mod filter;
+mod geoip;
+mod groups;
+mod guard;
+mod haproxy;
+mod harden;
+mod index;
+mod iso3166;
+mod keep;
+mod kv;
+mod ipset;
+mod lint;
+mod logs;
+mod mail;
+mod mesh;
+mod metrics;
+mod naming;
+mod net;
+mod netflow;
+mod nft;
+mod notify;
+mod pcap;
+mod persist;
+mod pf;
+mod preflight;
+mod privilege;
+mod profile;
+mod proxy;
+mod rdap;
+mod report;
+mod resolver;
+mod rpz;
+mod selftest;
+mod simulate;
+mod state;
+mod suricata;
+mod sync;
+mod tc;
+mod temp;
+mod top;
+mod travel;
+mod trend;
+mod ui;
+mod wazuh;
+mod winfw;
+mod yaml;
+mod zeek;
 
-/// Wrapper to serialize IpNetwork as a string
-#[derive(Debug)]
-struct SerIpNet(IpNetwork);
+use exit::{Code, WithCode};
+use filter::FilterArgs;
+use naming::NameTemplate;
+use nft::generate_nftables;
+use notify::{Event, EventKind, NotifyConfig};
+use state::RunLock;
+use ui::{info, success, warning, ColorChoice, FamilyCounts, LoadResult, Summary};
 
-impl Serialize for SerIpNet {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        serializer.serialize_str(&self.0.to_string())
-    }
+use cloak::{CountryNets, IpMap, IpMapRef, SerIpNet};
+
+/// Data older than this draws a warning when rules are generated from it
+const STALE_WARN_AGE: Duration = Duration::from_secs(7 * 24 * 3600);
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    Allow,
+    Block,
+    /// Set a DSCP class or packet priority (--dscp, --priority) on the
+    /// traffic for QoS to act on, instead of dropping it (nft rules only)
+    Mark,
+}
+
+/// Which traffic the country rules apply to
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Default, Debug)]
+enum Direction {
+    /// Connections from the countries (input hook, source address)
+    #[default]
+    In,
+    /// Connections to the countries (output hook, destination address)
+    Out,
+    /// Both
+    Both,
+}
+
+// How the country rules match, shared by everything that generates them.
+// Not a doc comment: on a flattened struct clap would take it for `about`.
+#[derive(clap::Args, Clone, Copy, Default, Debug)]
+struct RuleArgs {
+    /// Whether to filter traffic from the countries, to them, or both
+    #[arg(long, value_enum, default_value_t = Direction::In)]
+    direction: Direction,
+
+    /// Only enforce the country rules during this daily window, in the
+    /// firewall's local time (e.g. 22:00-06:00; nft rules only)
+    #[arg(long, value_name = "HH:MM-HH:MM", value_parser = nft::parse_schedule)]
+    schedule: Option<nft::Schedule>,
+
+    /// Log every packet the country rules drop, for `cloak logs`, or mark
+    /// with --action mark (nft rules only)
+    #[arg(long)]
+    log: bool,
+
+    /// Count the packets and bytes the country rules drop, for the
+    /// daemon's metrics (nft rules only)
+    #[arg(long)]
+    counters: bool,
+
+    /// Write only the per-country sets, with no table, chain or policy, to
+    /// `include` into a ruleset of your own (nft rules only; never loaded)
+    #[arg(long)]
+    sets_only: bool,
+
+    /// With --sets-only, also write `geoip_ipv4`/`geoip_ipv6` verdict maps
+    /// of every prefix to drop (block) or accept (allow), for `ip saddr
+    /// vmap @geoip_ipv4`
+    #[arg(long, requires = "sets_only")]
+    geoip_map: bool,
+
+    /// DSCP class `--action mark` sets (e.g. le, cs1, af11 or 0-63)
+    #[arg(long, value_name = "CLASS", value_parser = nft::parse_dscp)]
+    dscp: Option<nft::Dscp>,
+
+    /// Packet priority (tc class) `--action mark` sets, e.g. 1:10
+    #[arg(long, value_name = "MAJOR:MINOR", value_parser = nft::parse_priority)]
+    priority: Option<nft::Priority>,
+
+    /// Also match the IPv4 prefixes in their IPv4-mapped IPv6 form
+    /// (::ffff:a.b.c.d), as translators and some stacks pass IPv4 traffic
+    /// (nft rules only)
+    #[arg(long)]
+    mapped_v6: bool,
+
+    /// Write Suricata rules that alert on the traffic instead of dropping
+    /// it, for IDS deployments (suricata rules only)
+    #[arg(long)]
+    alert: bool,
+
+    /// Also drop the prefixes in the NIC of this device, from a netdev
+    /// table with an offloaded ingress chain (nft rules only; the driver
+    /// needs hw-tc-offload)
+    #[arg(long, value_name = "DEVICE", value_parser = nft::parse_device)]
+    offload: Option<nft::Device>,
+
+    /// Give every country its own sets and a named drop counter per
+    /// address family, which the daemon's metrics export (nft block rules
+    /// only)
+    #[arg(long)]
+    country_counters: bool,
+
+    /// Also filter the frames a bridge passes to VMs through this port,
+    /// its uplink (e.g. eno1 in Proxmox's vmbr0), from a bridge-family
+    /// table; bridged traffic never reaches the input chain (nft rules
+    /// only)
+    #[arg(long, value_name = "PORT", value_parser = nft::parse_device)]
+    bridge: Option<nft::Device>,
+
+    /// Also filter connections forwarded to containers' published ports
+    /// (Podman/netavark, Docker), which never pass the input chain (nft
+    /// rules only)
+    #[arg(long)]
+    containers: bool,
 }
 
-#[derive(Debug, Serialize)]
-struct CountryNets {
-    ipv4: Vec<SerIpNet>,
-    ipv6: Vec<SerIpNet>,
+impl RuleArgs {
+    /// Fail if an option is set that `format` cannot express, or that does
+    /// not go with `action`
+    fn check_format(&self, format: Format, action: Action, keep: bool) -> Result<()> {
+        let marks = self.dscp.is_some() || self.priority.is_some();
+        match action {
+            Action::Mark if !marks => bail!("--action mark needs --dscp, --priority or both"),
+            Action::Mark if self.sets_only => bail!("--sets-only writes no rules to mark traffic with"),
+            Action::Allow | Action::Block if marks => bail!("--dscp and --priority only apply to --action mark"),
+            _ => {}
+        }
+        if !matches!(format, Format::Nft | Format::Json | Format::Csv) {
+            if action == Action::Mark {
+                bail!("--action mark is only supported for nft rules");
+            }
+            if keep {
+                bail!("--keep is only supported for nft rules");
+            }
+            if self.schedule.is_some() {
+                bail!("--schedule is only supported for nft rules");
+            }
+            if self.log {
+                bail!("--log is only supported for nft rules");
+            }
+            if self.counters {
+                bail!("--counters is only supported for nft rules");
+            }
+            if self.sets_only {
+                bail!("--sets-only is only supported for nft rules");
+            }
+            if self.mapped_v6 {
+                bail!("--mapped-v6 is only supported for nft rules");
+            }
+            if self.offload.is_some() {
+                bail!("--offload is only supported for nft rules");
+            }
+            if self.country_counters {
+                bail!("--country-counters is only supported for nft rules");
+            }
+            if self.containers {
+                bail!("--containers is only supported for nft rules");
+            }
+            if self.bridge.is_some() {
+                bail!("--bridge is only supported for nft rules");
+            }
+        }
+        let inbound_only = matches!(
+            format,
+            Format::Istio | Format::Envoy | Format::Traefik | Format::TraefikToml | Format::Caddy | Format::Exim | Format::Sendmail | Format::Postfix
+        );
+        if inbound_only && self.direction != Direction::In {
+            bail!("proxy, service-mesh and mail server policies only see inbound connections; use --direction in");
+        }
+        // Offloaded chains take plain address matches and verdicts
+        if self.offload.is_some()
+            && (action == Action::Mark || self.sets_only || self.schedule.is_some() || self.log || self.direction != Direction::In)
+        {
+            bail!("--offload only drops or accepts incoming traffic; it does not go with --action mark, --sets-only, --schedule, --log or --direction");
+        }
+        // What an allow list drops comes from no country of its own
+        if self.country_counters && (action != Action::Block || self.sets_only) {
+            bail!("--country-counters counts what block rules drop; use --action block without --sets-only");
+        }
+        // Marks are set in prerouting, which forwarded traffic passes anyway
+        if self.containers && (action == Action::Mark || self.sets_only || self.direction == Direction::Out) {
+            bail!("--containers filters incoming connections to published ports; it does not go with --action mark, --sets-only or --direction out");
+        }
+        if self.bridge.is_some() && (action == Action::Mark || self.sets_only) {
+            bail!("--bridge drops or accepts bridged traffic; it does not go with --action mark or --sets-only");
+        }
+        if self.alert && format != Format::Suricata {
+            bail!("--alert is only supported for suricata rules");
+        }
+        // The sets are matched by the includer's own rules
+        if self.sets_only && (self.schedule.is_some() || self.log || self.counters || self.direction != Direction::In) {
+            bail!("--sets-only writes no rules, so --direction, --schedule, --log and --counters do not apply");
+        }
+        Ok(())
+    }
 }
 
+/// Shape of the JSON map written next to the rules
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
-enum ListChoice {
-    Brics,
-    Nato,
-    Eu,
-    Asean,
-    G7,
-    G20,
-    Opec,
-    Africa
+enum Layout {
+    /// Country code -> { ipv4: [...], ipv6: [...] }
+    Nested,
+    /// CIDR -> country code, for log-enrichment lookups
+    Reverse,
 }
 
+/// Firewall the generated rules are written for
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
-enum Action {
-    Allow,
-    Block,
+enum Format {
+    /// nftables ruleset (Linux)
+    Nft,
+    /// pf anchor (macOS, FreeBSD, OpenBSD)
+    Pf,
+    /// PowerShell script creating Windows Firewall rules
+    Windows,
+    /// ipset restore file, for iptables to match with `-m set`
+    Ipset,
+    /// The prefixes the rules would match, as a JSON map
+    Json,
+    /// The prefixes the rules would match, as country,cidr lines
+    Csv,
+    /// Suricata rules with an IP reputation category and list for them
+    Suricata,
+    /// Zeek Intelligence Framework file, to tag the countries' connections
+    Zeek,
+    /// Wazuh/OSSEC CDB list of prefix -> country, for alerting rules
+    Wazuh,
+    /// Istio AuthorizationPolicy for the sidecars of a namespace
+    Istio,
+    /// Envoy HTTP RBAC filter
+    Envoy,
+    /// Traefik ipAllowList middleware, as YAML dynamic configuration
+    Traefik,
+    /// Traefik ipAllowList middleware, as TOML dynamic configuration
+    TraefikToml,
+    /// Caddyfile snippet matching the prefixes with remote_ip
+    Caddy,
+    /// HAProxy map file of prefix -> country, for map_ip()
+    Haproxy,
+    /// Exim host list, for `deny hosts = +list` in an ACL
+    Exim,
+    /// Sendmail access map entries rejecting the prefixes
+    Sendmail,
+    /// Postfix CIDR table, for check_client_access
+    Postfix,
+    /// DNS response policy zone refusing answers into the prefixes
+    Rpz,
+    /// Unbound response-ip configuration (respip module)
+    Unbound,
+    /// Blocky denylist of the prefixes
+    Blocky,
+    /// AdGuard Home filter list of the prefixes
+    Adguard,
+    /// Shell script adding tc flower drop filters to a device, for
+    /// hardware offload
+    Tc,
 }
 
-// --- Implement Display for filename formatting ---
-impl fmt::Display for ListChoice {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl Format {
+    /// The firewall of the platform cloak was built for
+    fn native() -> Self {
+        if cfg!(windows) {
+            Format::Windows
+        } else if cfg!(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd")) {
+            Format::Pf
+        } else {
+            Format::Nft
+        }
+    }
+
+    fn extension(self) -> &'static str {
         match self {
-            ListChoice::Brics => write!(f, "brics"),
-            ListChoice::Nato => write!(f, "nato"),
-            ListChoice::Eu => write!(f, "eu"),
-            ListChoice::Asean => write!(f, "asean"),
-            ListChoice::G7 => write!(f, "g7"),
-            ListChoice::G20 => write!(f, "g20"),
-            ListChoice::Opec => write!(f, "opec"),
-            ListChoice::Africa => write!(f, "african_union")
+            Format::Nft => "nft",
+            Format::Pf => "pf",
+            Format::Windows => "ps1",
+            Format::Ipset => "ipset",
+            Format::Json => "json",
+            Format::Csv => "csv",
+            Format::Suricata => "rules",
+            Format::Zeek => "intel",
+            Format::Wazuh => "wazuh",
+            Format::Istio => "istio.yaml",
+            Format::Envoy => "envoy.yaml",
+            Format::Traefik => "traefik.yaml",
+            Format::TraefikToml => "traefik.toml",
+            Format::Caddy => "caddy",
+            Format::Haproxy => "map",
+            Format::Exim => "exim",
+            Format::Sendmail => "access",
+            Format::Postfix => "cidr",
+            Format::Rpz => "rpz",
+            Format::Unbound => "unbound.conf",
+            Format::Blocky => "blocky.txt",
+            Format::Adguard => "adguard.txt",
+            Format::Tc => "tc.sh",
+        }
+    }
+
+    /// Whether cloak can load this format itself on this platform
+    fn loadable(self) -> bool {
+        self == Format::Nft && cfg!(target_os = "linux")
+    }
+
+    /// How to load a file of this format, unless it only holds data
+    fn load_hint(self, filename: &str, rules: RuleArgs) -> Option<String> {
+        match self {
+            Format::Nft if rules.sets_only => Some(format!("nft -f <your ruleset>, with include \"{}\" inside its table", filename)),
+            Format::Nft => Some(format!("cloak apply {}", filename)),
+            Format::Pf => Some(format!("pfctl -a {} -f {}", pf::ANCHOR, filename)),
+            Format::Windows => Some(format!("powershell -ExecutionPolicy Bypass -File {}", filename)),
+            Format::Ipset => Some(format!("ipset restore -exist < {}", filename)),
+            Format::Suricata => Some(format!("suricatasc -c reload-rules, once suricata.yaml lists {}", filename)),
+            Format::Zeek => Some(format!("zeekctl deploy, once local.zeek has redef Intel::read_files += {{ \"{}\" }}", filename)),
+            Format::Wazuh => Some(format!("copy {} to /var/ossec/etc/lists/ and list it under <ruleset> in ossec.conf", filename)),
+            Format::Istio => Some(format!("kubectl apply -n <namespace> -f {}", filename)),
+            Format::Sendmail => Some(format!("cat {} >> /etc/mail/access && makemap hash /etc/mail/access < /etc/mail/access", filename)),
+            Format::Postfix => Some("postfix reload".to_string()),
+            Format::Rpz => Some("rndc reload (BIND), once named.conf loads the zone".to_string()),
+            Format::Unbound => Some("unbound-control reload".to_string()),
+            Format::Tc => Some(format!("sh {} <device>", filename)),
+            Format::Json | Format::Csv | Format::Envoy | Format::Traefik | Format::TraefikToml | Format::Caddy | Format::Haproxy | Format::Exim | Format::Blocky | Format::Adguard => None,
         }
     }
 }
 
+// --- Implement Display for filename formatting ---
 impl fmt::Display for Action {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Action::Allow => write!(f, "allow"),
             Action::Block => write!(f, "block"),
+            Action::Mark => write!(f, "mark"),
         }
     }
 }
 
 #[derive(Parser, Debug)]
-#[command(author, version, about)]
+#[command(author, version, about, subcommand_negates_reqs = true)]
 struct Args {
-    /// Which list to use: brics or nato
-    #[arg(value_enum)]
-    list: ListChoice,
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    /// Only print errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// When to use colors in console output
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto, global = true)]
+    color: ColorChoice,
+
+    /// Print a machine-readable JSON summary of the run on stdout (other
+    /// output moves to stderr)
+    #[arg(long, global = true)]
+    summary_json: bool,
+
+    /// Directory for the run lock and other persistent state
+    #[arg(long, value_name = "DIR", global = true, default_value_os_t = state::default_state_dir())]
+    state_dir: PathBuf,
+
+    /// Command used to run nft and conntrack when cloak is neither root nor
+    /// holding CAP_NET_ADMIN (e.g. "sudo" or "doas -n")
+    #[arg(long, value_name = "CMD", global = true)]
+    escalate: Option<String>,
+
+    /// If another cloak run is in progress, wait for it instead of failing
+    #[arg(long, global = true)]
+    wait: bool,
+
+    /// Exit with 7 instead of 0 when the rules were already loaded and
+    /// nothing was done
+    #[arg(long, global = true)]
+    detailed_exit_codes: bool,
+
+    /// Which country group to use: a built-in list (brics, nato, eu, ...)
+    /// or a group defined in --group-file; join several with + (nato+g7)
+    #[arg(required_unless_present_any = ["source", "region", "city", "countries"])]
+    list: Option<String>,
 
     /// Whether to allow or block the list
-    #[arg(value_enum)]
+    #[arg(value_enum, required_unless_present_any = ["list_members", "source", "region", "city", "countries"])]
+    action: Option<Action>,
+
+    /// Countries to use instead of a LIST, by code or name, comma-separated
+    /// (e.g. "United Kingdom, USA, Türkiye, South Korea")
+    #[arg(long, value_name = "NAMES", value_delimiter = ',')]
+    countries: Vec<String>,
+
+    /// Print the member countries of the list and exit
+    #[arg(long)]
+    list_members: bool,
+
+    #[command(flatten)]
+    sources: fetch::SourceArgs,
+
+    /// YAML file defining additional named groups (name -> country codes)
+    #[arg(long, value_name = "FILE")]
+    group_file: Option<PathBuf>,
+
+    /// Layout of the JSON map
+    #[arg(long, value_enum, default_value_t = Layout::Nested)]
+    layout: Layout,
+
+    /// Firewalls to generate rules for, comma-separated or repeated
+    /// (defaults to this platform's). Only nft rules can be loaded by
+    /// cloak itself
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = [Format::native()])]
+    format: Vec<Format>,
+
+    /// Names of the rule files, from {list}, {action}, {ext}, {date}
+    /// (YYYY-MM-DD), {time} (HHMMSS, both UTC) and {host}
+    #[arg(long, value_name = "TEMPLATE", value_parser = naming::parse_template, default_value = naming::DEFAULT)]
+    name_template: NameTemplate,
+
+    #[command(flatten)]
+    filters: FilterArgs,
+
+    #[command(flatten)]
+    rules: RuleArgs,
+
+    #[command(flatten)]
+    http: fetch::HttpArgs,
+
+    /// Write one JSON map and one rule file per country instead of a
+    /// merged pair for the whole list
+    #[arg(long)]
+    split_by_country: bool,
+
+    /// Load the rules without asking
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Also check that this address can still connect after loading, to
+    /// port 22 or the one given (ADDR or ADDR:PORT, repeatable)
+    #[arg(long, value_name = "ADDR", value_parser = preflight::parse_reachable)]
+    reachable: Vec<SocketAddr>,
+
+    /// Only fetch and generate; load the rules later with `cloak apply`
+    /// (nothing in this step needs root)
+    #[arg(long, conflicts_with_all = ["yes", "force", "flush_conntrack", "persist_dir"])]
+    no_load: bool,
+
+    /// Also keep a copy of the loaded rules in this directory (e.g.
+    /// /etc/nftables.d) for the nftables service to load at boot
+    #[arg(long, value_name = "DIR")]
+    persist_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    guard: guard::GuardArgs,
+
+    /// Reload the rules even if they match what was last applied, and
+    /// write them even if the data changed past --max-shrink or
+    /// --max-growth
+    #[arg(long)]
+    force: bool,
+
+    /// After loading a block policy, kill existing conntrack flows whose
+    /// peer falls in the blocked sets (requires conntrack-tools)
+    #[arg(long)]
+    flush_conntrack: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Download a country list and write it as a JSON map, without
+    /// generating any rules
+    Fetch(FetchArgs),
+
+    /// Generate rule files, in one or more formats, from JSON maps written
+    /// by `cloak fetch`
+    Generate(GenerateArgs),
+
+    /// Union previously generated JSON maps and regenerate rules from the
+    /// result without refetching anything
+    Merge(MergeArgs),
+
+    /// Load a rule file or bundle generated earlier. This is the only step
+    /// that needs root; it also works with CAP_NET_ADMIN granted via setcap
+    Apply(ApplyArgs),
+
+    /// Pack a rule file, its JSON map and their metadata into one signed
+    /// .tar.zst for `cloak apply` on other hosts
+    Bundle(bundle::BundleArgs),
+
+    /// Delete cloak's table from nftables
+    Remove,
+
+    /// Keep the loaded rules across reboots, through the nftables service
+    /// or a systemd unit of their own
+    Persist(persist::PersistArgs),
+
+    /// Check that the loaded rules do what their map says: sampled
+    /// addresses are in the sets and get the expected verdict
+    Selftest(selftest::SelftestArgs),
+
+    /// Follow a packet through a rule file or the loaded rules and show
+    /// which rules decide what happens to it
+    Simulate(simulate::SimulateArgs),
+
+    /// Show how the loaded rules differ from a generated rule file, set
+    /// element by set element and rule by rule
+    CompareLive(compare::CompareLiveArgs),
+
+    /// Commit policy files and generated rules to a git repository, or
+    /// pull the shared policy from it
+    Sync(sync::SyncArgs),
+
+    /// Block addresses, prefixes or whole countries in the loaded rules
+    /// for a limited time
+    BlockTemp(BlockTempArgs),
+
+    /// Accept a country or addresses in the loaded rules for a limited
+    /// time, e.g. while travelling
+    Travel(travel::TravelArgs),
+
+    /// Check nft, pf, ipset or iptables rule files for structural errors,
+    /// bad or overlapping set elements and rules that can never match
+    Lint(lint::LintArgs),
+
+    /// Write a complete server policy: SSH from chosen addresses, the home
+    /// countries accepted, drop lists refused and the rest rate limited
+    Harden(harden::HardenArgs),
+
+    /// Print the country of each address according to a JSON map
+    Lookup(LookupArgs),
+
+    /// Turn JSON maps into a binary index that lookup, analyze, logs and
+    /// collect load without parsing
+    Compile(CompileArgs),
+
+    /// List the country codes cloak knows, their names and the built-in
+    /// groups they belong to
+    Countries(countries::CountriesArgs),
+
+    /// Summarise which countries a policy allows and blocks, as a table,
+    /// GeoJSON or an HTML page for policy reviews
+    Report(report::ReportArgs),
+
+    /// Chart a country's prefix counts over the daemon's refreshes, to
+    /// spot sudden changes in the provider's data
+    Trend(trend::TrendArgs),
+
+    /// Check a rule file against the `assertions:` of a config file, the
+    /// addresses it must still allow or block
+    Test(assertion::TestArgs),
+
+    /// Show traffic by country in a packet capture, with the lists that
+    /// would cover most of it
+    Analyze(analyze::AnalyzeArgs),
+
+    /// Collect NetFlow v5/v9 or IPFIX exports and report traffic by
+    /// country
+    Collect(netflow::CollectArgs),
+
+    /// Show which countries and ports the loaded rules drop, from the
+    /// kernel log lines of rules generated with --log
+    Logs(logs::LogsArgs),
+
+    /// Watch drops per country and port live, refreshed every second,
+    /// from --country-counters and --log rules
+    Top(top::TopArgs),
+
+    /// Prefix arithmetic on CIDR lists: aggregate, subtract, contains,
+    /// split
+    Net(net::NetArgs),
+
+    /// Measure lookup speed and memory use of the country index built from
+    /// a JSON map
+    Bench(bench::BenchArgs),
+
+    /// Keep a policy enforced: refresh it periodically and re-apply it if
+    /// the loaded table disappears or is modified
+    Daemon(daemon::DaemonArgs),
+
+    /// Write a Grafana dashboard and Prometheus alerting rules for the
+    /// metrics of `cloak daemon --metrics-addr`
+    Dashboard(dashboard::DashboardArgs),
+
+    /// Run as a CrowdSec bouncer, keeping the Local API's ban decisions in
+    /// the loaded rules
+    Crowdsec(crowdsec::CrowdSecArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct FetchArgs {
+    /// Which country group to fetch: a built-in list (brics, nato, eu, ...)
+    /// or a group defined in --group-file; join several with + (nato+g7)
+    #[arg(required_unless_present_any = ["source", "region", "city", "countries"], conflicts_with = "countries")]
+    list: Option<String>,
+
+    /// Countries to fetch instead of a LIST, by code or name,
+    /// comma-separated (e.g. "United Kingdom, USA")
+    #[arg(long, value_name = "NAMES", value_delimiter = ',')]
+    countries: Vec<String>,
+
+    #[command(flatten)]
+    sources: fetch::SourceArgs,
+
+    /// YAML file defining additional named groups (name -> country codes)
+    #[arg(long, value_name = "FILE")]
+    group_file: Option<PathBuf>,
+
+    /// Where to write the JSON map (default: <list>_ip_map.json)
+    #[arg(short, long, conflicts_with = "split_by_country")]
+    output: Option<PathBuf>,
+
+    /// Layout of the JSON map
+    #[arg(long, value_enum, default_value_t = Layout::Nested)]
+    layout: Layout,
+
+    /// Write one JSON map per country (<cc>_ip_map.json)
+    #[arg(long)]
+    split_by_country: bool,
+
+    #[command(flatten)]
+    filters: FilterArgs,
+
+    #[command(flatten)]
+    http: fetch::HttpArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct GenerateArgs {
+    /// JSON maps written by `cloak fetch` (nested layout); several are
+    /// combined into one policy
+    #[arg(required_unless_present = "from")]
+    inputs: Vec<PathBuf>,
+
+    /// Another JSON map to generate from, the same as naming it as an
+    /// input (repeatable)
+    #[arg(long, value_name = "FILE")]
+    from: Vec<PathBuf>,
+
+    /// Whether the rules allow or block the countries in the maps
+    #[arg(long, value_enum, default_value_t = Action::Block)]
     action: Action,
+
+    /// Firewalls to generate rules for, comma-separated or repeated
+    /// (defaults to this platform's)
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = [Format::native()])]
+    format: Vec<Format>,
+
+    /// Base name of the rule files (default: taken from the first input,
+    /// so brics_ip_map.json gives brics_block.nft)
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Names of the rule files, from {list}, {action}, {ext}, {date}
+    /// (YYYY-MM-DD), {time} (HHMMSS, both UTC) and {host}
+    #[arg(long, value_name = "TEMPLATE", value_parser = naming::parse_template, default_value = naming::DEFAULT)]
+    name_template: NameTemplate,
+
+    #[command(flatten)]
+    rules: RuleArgs,
+
+    /// Refuse to generate rules if any country's data is older than
+    /// this (e.g. 36h, 7d)
+    #[arg(long, value_parser = parse_duration)]
+    max_age: Option<Duration>,
+
+    #[command(flatten)]
+    filters: FilterArgs,
+
+    // Only used to look up --except-asn
+    #[command(flatten)]
+    http: fetch::HttpArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct BlockTempArgs {
+    /// Addresses, prefixes (CIDR) or two-letter country codes to block
+    #[arg(required = true)]
+    targets: Vec<String>,
+
+    /// How long the block lasts (e.g. 30m, 6h, 2d)
+    #[arg(long = "for", value_name = "DURATION", value_parser = parse_duration)]
+    duration: Duration,
+
+    /// JSON map to take country prefixes from instead of downloading them
+    #[arg(long, value_name = "FILE")]
+    map: Option<PathBuf>,
+
+    #[command(flatten)]
+    http: fetch::HttpArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct LookupArgs {
+    /// JSON map written by `cloak fetch` (nested layout), or an index
+    /// written by `cloak compile`
+    #[arg(long)]
+    map: PathBuf,
+
+    /// Addresses to look up
+    #[arg(required_unless_present = "batch", conflicts_with = "batch")]
+    addrs: Vec<std::net::IpAddr>,
+
+    /// Read one address per line from FILE (`-` for stdin) and print
+    /// `ip,country,cidr` lines, using every core
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["abuseipdb_key_file", "whois"])]
+    batch: Option<PathBuf>,
+
+    /// File holding an AbuseIPDB API key; with a key (here or in
+    /// $ABUSEIPDB_API_KEY) each address also gets its abuse confidence
+    /// score and report count
+    #[arg(long, value_name = "FILE")]
+    abuseipdb_key_file: Option<PathBuf>,
+
+    /// Also show who holds each address and their abuse contact, from RDAP
+    #[arg(long)]
+    whois: bool,
+
+    // Only used for AbuseIPDB and RDAP
+    #[command(flatten)]
+    http: fetch::HttpArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct CompileArgs {
+    /// JSON maps to combine (nested layout)
+    #[arg(required = true)]
+    maps: Vec<PathBuf>,
+
+    /// Where to write the index (default: the first map with an .idx
+    /// extension)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct MergeArgs {
+    /// JSON maps written by earlier runs (nested layout)
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Where to write the combined JSON map
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Drop prefixes that appear more than once for the same country
+    #[arg(long)]
+    dedup: bool,
+
+    /// Whether the regenerated rules allow or block the merged list
+    #[arg(long, value_enum, default_value_t = Action::Block)]
+    action: Action,
+
+    /// Firewalls to generate rules for, comma-separated or repeated
+    /// (defaults to this platform's)
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = [Format::native()])]
+    format: Vec<Format>,
+
+    /// Names of the rule files, from {list}, {action}, {ext}, {date}
+    /// (YYYY-MM-DD), {time} (HHMMSS, both UTC) and {host}
+    #[arg(long, value_name = "TEMPLATE", value_parser = naming::parse_template, default_value = naming::DEFAULT)]
+    name_template: NameTemplate,
+
+    #[command(flatten)]
+    rules: RuleArgs,
+
+    /// Refuse to generate rules if any country's data is older than
+    /// this (e.g. 36h, 7d)
+    #[arg(long, value_parser = parse_duration)]
+    max_age: Option<Duration>,
+
+    #[command(flatten)]
+    filters: FilterArgs,
+
+    // Only used to look up --except-asn
+    #[command(flatten)]
+    http: fetch::HttpArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ApplyArgs {
+    /// Rule file written by cloak (e.g. brics_block.nft), or a bundle
+    /// written by `cloak bundle`
+    #[arg(required_unless_present = "profile", conflicts_with = "profile")]
+    file: Option<PathBuf>,
+
+    /// Only load bundles signed by a key in this ssh-keygen allowed
+    /// signers file
+    #[arg(long, value_name = "FILE")]
+    allowed_signers: Option<PathBuf>,
+
+    /// The principal the bundle must be signed by (default: whichever
+    /// entry of --allowed-signers matches the signing key)
+    #[arg(long, value_name = "IDENTITY", requires = "allowed_signers")]
+    signer: Option<String>,
+
+    /// Fetch and load every layer of this profile from --config as one
+    /// ruleset
+    #[arg(long, value_name = "NAME", requires = "config")]
+    profile: Option<String>,
+
+    /// Config file whose `notify:` channels should hear about the result,
+    /// which defines the `profiles:` and whose `assertions:` the rules
+    /// must meet
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// YAML file defining additional named groups for the profile's layers
+    #[arg(long, value_name = "FILE")]
+    group_file: Option<PathBuf>,
+
+    /// Load into this chain of an existing inet table instead of cloak's
+    /// own table, e.g. filter/input (the rules go in a `cloak_` chain the
+    /// target chain jumps to)
+    #[arg(long, value_name = "TABLE/CHAIN", value_parser = attach::parse_target)]
+    attach: Option<attach::Target>,
+
+    /// Where the jump goes in the --attach chain: top, bottom, index N or
+    /// after-handle H. It stays put on later applies unless this changes
+    #[arg(long, value_name = "POSITION", requires = "attach", default_value = "top", value_parser = attach::parse_position)]
+    insert_at: attach::Position,
+
+    /// Also check that this address can still connect, to port 22 or the
+    /// one given (ADDR or ADDR:PORT, repeatable); the SSH session the
+    /// command runs in is always checked
+    #[arg(long, value_name = "ADDR", value_parser = preflight::parse_reachable)]
+    reachable: Vec<SocketAddr>,
+
+    /// Load allow-mode rules, and rules that cut off a checked address,
+    /// without the typed confirmation
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Also keep a copy of the loaded rules in this directory (e.g.
+    /// /etc/nftables.d) for the nftables service to load at boot; later
+    /// loads keep it current
+    #[arg(long, value_name = "DIR", conflicts_with = "attach")]
+    persist_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    canary: canary::CanaryArgs,
+
+    #[command(flatten)]
+    http: fetch::HttpArgs,
+}
+
+/// Exit codes are listed in [`exit`]
+/// Parse the command line. Global flags may come before a subcommand, which
+/// clap's `args_conflicts_with_subcommands` would refuse, so the options of
+/// the plain `LIST ACTION` form are kept from going with a subcommand here.
+fn parse_args<I, T>(argv: I) -> Result<Args, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let mut command = Args::command();
+    let matches = command.try_get_matches_from_mut(argv)?;
+    if matches.subcommand_name().is_some() {
+        let given = command
+            .get_arguments()
+            .filter(|arg| !arg.is_global_set() && matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine))
+            .map(|arg| match arg.get_long() {
+                Some(long) => format!("--{}", long),
+                None => format!("<{}>", arg.get_id().as_str().to_uppercase()),
+            })
+            .next();
+        if let Some(name) = given {
+            return Err(command.error(ErrorKind::ArgumentConflict, format!("{} cannot be used with a subcommand", name)));
+        }
+    }
+    Args::from_arg_matches(&matches).map_err(|e| e.format(&mut command))
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
-
-    let brics = [
-    ("br", "Brazil"),
-    ("ru", "Russia"),
-    ("in", "India"),
-    ("cn", "China"),
-    ("za", "South Africa"),
-    ];
-
-    let nato = [
-        ("al", "Albania"),
-        ("be", "Belgium"),
-        ("bg", "Bulgaria"),
-        ("ca", "Canada"),
-        ("hr", "Croatia"),
-        ("cz", "Czechia"),
-        ("dk", "Denmark"),
-        ("ee", "Estonia"),
-        ("fi", "Finland"),
-        ("fr", "France"),
-        ("de", "Germany"),
-        ("gr", "Greece"),
-        ("hu", "Hungary"),
-        ("is", "Iceland"),
-        ("it", "Italy"),
-        ("lv", "Latvia"),
-        ("lt", "Lithuania"),
-        ("lu", "Luxembourg"),
-        ("mt", "Malta"),
-        ("nl", "Netherlands"),
-        ("no", "Norway"),
-        ("pl", "Poland"),
-        ("pt", "Portugal"),
-        ("ro", "Romania"),
-        ("sk", "Slovakia"),
-        ("si", "Slovenia"),
-        ("es", "Spain"),
-        ("se", "Sweden"),
-        ("tr", "Türkiye"),
-        ("gb", "United Kingdom"),
-        ("us", "United States"),
-    ];
-
-    let eu = [
-        ("at", "Austria"),
-        ("be", "Belgium"),
-        ("bg", "Bulgaria"),
-        ("hr", "Croatia"),
-        ("cy", "Cyprus"),
-        ("cz", "Czechia"),
-        ("dk", "Denmark"),
-        ("ee", "Estonia"),
-        ("fi", "Finland"),
-        ("fr", "France"),
-        ("de", "Germany"),
-        ("gr", "Greece"),
-        ("hu", "Hungary"),
-        ("ie", "Ireland"),
-        ("it", "Italy"),
-        ("lv", "Latvia"),
-        ("lt", "Lithuania"),
-        ("lu", "Luxembourg"),
-        ("mt", "Malta"),
-        ("nl", "Netherlands"),
-        ("pl", "Poland"),
-        ("pt", "Portugal"),
-        ("ro", "Romania"),
-        ("sk", "Slovakia"),
-        ("si", "Slovenia"),
-        ("es", "Spain"),
-        ("se", "Sweden"),
-    ];
-
-    let asean = [
-        ("id", "Indonesia"),
-        ("my", "Malaysia"),
-        ("ph", "Philippines"),
-        ("sg", "Singapore"),
-        ("th", "Thailand"),
-        ("vn", "Vietnam"),
-        ("mm", "Myanmar"),
-        ("kh", "Cambodia"),
-        ("la", "Laos"),
-        ("bn", "Brunei"),
-    ];
-
-    let g7 = [
-        ("ca", "Canada"),
-        ("fr", "France"),
-        ("de", "Germany"),
-        ("it", "Italy"),
-        ("jp", "Japan"),
-        ("gb", "United Kingdom"),
-        ("us", "United States"),
-    ];
-
-    let g20 = [
-        ("ar", "Argentina"),
-        ("au", "Australia"),
-        ("br", "Brazil"),
-        ("ca", "Canada"),
-        ("cn", "China"),
-        ("fr", "France"),
-        ("de", "Germany"),
-        ("in", "India"),
-        ("id", "Indonesia"),
-        ("it", "Italy"),
-        ("jp", "Japan"),
-        ("mx", "Mexico"),
-        ("ru", "Russia"),
-        ("sa", "Saudi Arabia"),
-        ("za", "South Africa"),
-        ("kr", "South Korea"),
-        ("tr", "Türkiye"),
-        ("gb", "United Kingdom"),
-        ("us", "United States"),
-        ("eu", "European Union"),
-    ];
-
-    let opec = [
-        ("dz", "Algeria"),
-        ("ao", "Angola"),
-        ("cd", "Congo"),
-        ("gq", "Equatorial Guinea"),
-        ("ga", "Gabon"),
-        ("iq", "Iraq"),
-        ("kw", "Kuwait"),
-        ("ly", "Libya"),
-        ("ng", "Nigeria"),
-        ("sa", "Saudi Arabia"),
-        ("ae", "United Arab Emirates"),
-        ("ve", "Venezuela"),
-    ];
-
-    let african_union = [
-        ("dz", "Algeria"),
-        ("ao", "Angola"),
-        ("bj", "Benin"),
-        ("bw", "Botswana"),
-        ("bf", "Burkina Faso"),
-        ("bi", "Burundi"),
-        ("cm", "Cameroon"),
-        ("cv", "Cape Verde"),
-        ("cf", "Central African Republic"),
-        ("td", "Chad"),
-        ("km", "Comoros"),
-        ("cg", "Congo"),
-        ("cd", "Democratic Republic of the Congo"),
-        ("ci", "Côte d'Ivoire"),
-        ("dj", "Djibouti"),
-        ("eg", "Egypt"),
-        ("gq", "Equatorial Guinea"),
-        ("er", "Eritrea"),
-        ("sz", "Eswatini"),
-        ("et", "Ethiopia"),
-        ("ga", "Gabon"),
-        ("gm", "Gambia"),
-        ("gh", "Ghana"),
-        ("gn", "Guinea"),
-        ("gw", "Guinea-Bissau"),
-        ("ke", "Kenya"),
-        ("ls", "Lesotho"),
-        ("lr", "Liberia"),
-        ("ly", "Libya"),
-        ("mg", "Madagascar"),
-        ("mw", "Malawi"),
-        ("ml", "Mali"),
-        ("mr", "Mauritania"),
-        ("mu", "Mauritius"),
-        ("ma", "Morocco"),
-        ("mz", "Mozambique"),
-        ("na", "Namibia"),
-        ("ne", "Niger"),
-        ("ng", "Nigeria"),
-        ("rw", "Rwanda"),
-        ("st", "São Tomé and Príncipe"),
-        ("sn", "Senegal"),
-        ("sc", "Seychelles"),
-        ("sl", "Sierra Leone"),
-        ("so", "Somalia"),
-        ("za", "South Africa"),
-        ("ss", "South Sudan"),
-        ("sd", "Sudan"),
-        ("tz", "Tanzania"),
-        ("tg", "Togo"),
-        ("tn", "Tunisia"),
-        ("ug", "Uganda"),
-        ("zm", "Zambia"),
-        ("zw", "Zimbabwe"),
-    ];
-
-
-    let countries: &[(&str, &str)] = match args.list {
-        ListChoice::Brics => &brics,
-        ListChoice::Nato => &nato,
-        ListChoice::Eu => &eu,
-        ListChoice::Asean => &asean,
-        ListChoice::G7 => &g7,
-        ListChoice::G20 => &g20,
-        ListChoice::Opec => &opec,
-        ListChoice::Africa => &african_union,
+async fn main() -> std::process::ExitCode {
+    let mut args = parse_args(std::env::args_os()).unwrap_or_else(|e| e.exit());
+    ui::init(args.quiet, args.color, args.summary_json);
+    privilege::init(args.escalate.as_deref());
+    let (summary_json, detailed_exit_codes) = (args.summary_json, args.detailed_exit_codes);
+
+    // Listing members touches nothing shared; everything else serializes
+    // against concurrent runs (cron + manual) so nft transactions and
+    // output files never interleave. The daemon and the bouncer lock per
+    // update instead.
+    let lock = if args.list_members || matches!(args.command, Some(Commands::Daemon(_) | Commands::Dashboard(_) | Commands::Crowdsec(_) | Commands::Bench(_) | Commands::Lookup(_) | Commands::Compile(_) | Commands::Countries(_) | Commands::Report(_) | Commands::Trend(_) | Commands::Test(_) | Commands::Analyze(_) | Commands::Collect(_) | Commands::Logs(_) | Commands::Top(_) | Commands::Net(_) | Commands::Lint(_) | Commands::CompareLive(_) | Commands::Selftest(_) | Commands::Simulate(_) | Commands::Bundle(_))) {
+        Ok(None)
+    } else {
+        RunLock::acquire(&args.state_dir, args.wait).map(Some)
+    };
+
+    let (mut summary, result) = match (lock, args.command.take()) {
+        (Err(e), command) => {
+            let name = match command {
+                Some(Commands::Apply(_)) => "apply",
+                Some(Commands::Merge(_)) => "merge",
+                Some(Commands::Fetch(_)) => "fetch",
+                Some(Commands::Generate(_)) => "generate",
+                Some(Commands::Harden(_)) => "harden",
+                Some(Commands::Remove) => "remove",
+                Some(Commands::Persist(_)) => "persist",
+                Some(Commands::BlockTemp(_)) => "block-temp",
+                Some(Commands::Travel(_)) => "travel",
+                Some(Commands::Sync(_)) => "sync",
+                _ => "run",
+            };
+            (Summary::new(name), Err(e))
+        }
+        (Ok(_), Some(Commands::Daemon(daemon_args))) => {
+            let result = daemon::run(&daemon_args, &args.state_dir).await;
+            (Summary::new("daemon"), result)
+        }
+        (Ok(_), Some(Commands::Crowdsec(crowdsec_args))) => {
+            let result = crowdsec::run(&crowdsec_args, &args.state_dir).await;
+            (Summary::new("crowdsec"), result)
+        }
+        (Ok(_), Some(Commands::Dashboard(dashboard_args))) => (Summary::new("dashboard"), dashboard::run(&dashboard_args)),
+        (Ok(_), Some(Commands::Analyze(analyze_args))) => (Summary::new("analyze"), analyze::run(&analyze_args)),
+        (Ok(_), Some(Commands::Collect(collect_args))) => (Summary::new("collect"), netflow::run(&collect_args).await),
+        (Ok(_), Some(Commands::Logs(logs_args))) => (Summary::new("logs"), logs::run(&logs_args).await),
+        (Ok(_), Some(Commands::Top(top_args))) => (Summary::new("top"), top::run(&top_args).await),
+        (Ok(_), Some(Commands::CompareLive(compare_args))) => {
+            (Summary::new("compare-live"), compare::run(&compare_args, &args.state_dir))
+        }
+        (Ok(_), Some(Commands::Selftest(selftest_args))) => {
+            (Summary::new("selftest"), selftest::run(&selftest_args, &args.state_dir).await)
+        }
+        (Ok(_), Some(Commands::Simulate(simulate_args))) => {
+            (Summary::new("simulate"), simulate::run(&simulate_args, &args.state_dir))
+        }
+        (Ok(_), Some(Commands::Bundle(bundle_args))) => (Summary::new("bundle"), bundle::run(&bundle_args)),
+        (Ok(_), Some(Commands::Lint(lint_args))) => (Summary::new("lint"), lint::run(&lint_args)),
+        (Ok(_), Some(Commands::Net(net_args))) => (Summary::new("net"), net::run(&net_args)),
+        (Ok(_), Some(Commands::Bench(bench_args))) => (Summary::new("bench"), bench::run(&bench_args)),
+        (Ok(_), Some(Commands::Lookup(lookup_args))) => (Summary::new("lookup"), lookup(&lookup_args).await),
+        (Ok(_), Some(Commands::Compile(compile_args))) => (Summary::new("compile"), compile(&compile_args)),
+        (Ok(_), Some(Commands::Countries(countries_args))) => (Summary::new("countries"), countries::run(&countries_args)),
+        (Ok(_), Some(Commands::Report(report_args))) => (Summary::new("report"), report::run(&report_args)),
+        (Ok(_), Some(Commands::Trend(trend_args))) => (Summary::new("trend"), trend::run(&trend_args, &args.state_dir)),
+        (Ok(_), Some(Commands::Test(test_args))) => (Summary::new("test"), assertion::run(&test_args)),
+        (Ok(_lock), Some(Commands::Fetch(fetch_args))) => {
+            let mut summary = Summary::new("fetch");
+            let result = fetch(&fetch_args, &mut summary).await;
+            (summary, result)
+        }
+        (Ok(_lock), Some(Commands::Generate(generate_args))) => {
+            let mut summary = Summary::new("generate");
+            let result = generate(&generate_args, &mut summary).await;
+            (summary, result)
+        }
+        (Ok(_lock), Some(Commands::Harden(harden_args))) => {
+            let mut summary = Summary::new("harden");
+            let result = harden::run(&harden_args, &mut summary).await;
+            (summary, result)
+        }
+        (Ok(_lock), Some(Commands::Sync(sync_args))) => (Summary::new("sync"), sync::run(&sync_args, &args.state_dir)),
+        (Ok(_lock), Some(Commands::Remove)) => (Summary::new("remove"), remove(&args.state_dir)),
+        (Ok(_lock), Some(Commands::Persist(persist_args))) => (Summary::new("persist"), persist::run(&persist_args, &args.state_dir)),
+        (Ok(_lock), Some(Commands::BlockTemp(block_args))) => {
+            let mut summary = Summary::new("block-temp");
+            let result = block_temp(&block_args, &args.state_dir, &mut summary).await;
+            (summary, result)
+        }
+        (Ok(_lock), Some(Commands::Travel(travel_args))) => {
+            let mut summary = Summary::new("travel");
+            let result = travel::run(&travel_args, &args.state_dir, &mut summary).await;
+            (summary, result)
+        }
+        (Ok(_lock), Some(Commands::Apply(apply_args))) => {
+            let mut summary = Summary::new("apply");
+            let result = apply(&apply_args, &args.state_dir, &mut summary).await;
+            (summary, result)
+        }
+        (Ok(_lock), Some(Commands::Merge(merge_args))) => {
+            let mut summary = Summary::new("merge");
+            let result = merge(&merge_args, &mut summary).await;
+            (summary, result)
+        }
+        (Ok(_lock), None) => {
+            let mut summary = Summary::new("run");
+            let result = run(args, &mut summary).await;
+            (summary, result)
+        }
+    };
 
+    summary.ok = result.is_ok();
+    if let Err(e) = &result {
+        summary.error = Some(format!("{:#}", e));
+    }
+    if summary_json {
+        summary.print_json();
+    }
+    let code = match &result {
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            exit::of(e)
+        }
+        // Runs report a failed load in the summary rather than as an error
+        Ok(()) if summary.load == LoadResult::Failed => Code::Apply as u8,
+        Ok(()) if summary.load == LoadResult::UpToDate && detailed_exit_codes => Code::NothingToDo as u8,
+        Ok(()) => 0,
     };
+    std::process::ExitCode::from(code)
+}
 
-    let mut map: HashMap<String, CountryNets> = HashMap::new();
+async fn run(args: Args, summary: &mut Summary) -> Result<()> {
+    // With only --source, --region or --city, the one positional given is
+    // the action
+    let (list, action) = match (args.list.as_deref(), args.action) {
+        (Some(word), None) if !args.sources.is_empty() || !args.countries.is_empty() => match Action::from_str(word, true) {
+            Ok(action) => (None, Some(action)),
+            Err(_) => (Some(word), None),
+        },
+        positionals => positionals,
+    };
+    let group = resolve_group(list, &args.countries, args.group_file.as_deref(), &args.sources)?;
+    let countries = &group.countries;
 
-    for (cc, name) in countries {
-        let ipv4_url = format!("{}/{}-aggregated.zone", IPV4_BASE, cc);
-        let ipv6_url = format!("{}/{}-aggregated.zone", IPV6_BASE, cc);
+    if args.list_members {
+        println!("{} ({} countries):", group.name, countries.len());
+        for (cc, name) in countries {
+            println!("  {}  {}", cc.to_uppercase(), name);
+        }
+        return Ok(());
+    }
+    let action = action.context("an ACTION is required")?;
+    args.filters.check()?;
+    for &format in &args.format {
+        args.rules.check_format(format, action, !args.filters.keep.is_empty()).code(Code::Validation)?;
+    }
+    summary.list = Some(group.name.clone());
+    summary.action = Some(action.to_string());
 
-        let ipv4: Vec<SerIpNet> = fetch_cidrs(&ipv4_url).await?
-            .into_iter()
-            .map(SerIpNet)
-            .collect();
+    let mut map = fetch::fetch_countries(countries, &args.http).await?;
+    args.sources.read(&mut map)?;
 
-        let ipv6: Vec<SerIpNet> = fetch_cidrs(&ipv6_url).await?
-            .into_iter()
-            .map(SerIpNet)
-            .collect();
+    filter::apply(&mut map, &args.sources.own_labels(), &args.filters, &args.http).await?;
+    let keep = keep::resolve(&args.filters.keep).await?;
+    record_counts(summary, &map);
 
-        map.insert(cc.to_string(), CountryNets { ipv4, ipv6 });
+    if args.split_by_country {
+        let labels = args.sources.labels();
+        for cc in countries.iter().map(|(cc, _)| cc).chain(&labels) {
+            let Some(nets) = map.remove(cc) else { continue };
+            let single = HashMap::from([(cc.clone(), nets)]);
 
-        if let Some(entry) = map.get(&cc.to_string()) {
-            println!(
-                "{} ({}) -> {} IPv4 blocks, {} IPv6 blocks",
-                name,
-                cc.to_uppercase(),
-                entry.ipv4.len(),
-                entry.ipv6.len()
-            );
+            let filename = format!("{}_ip_map.json", cc);
+            write_json(&single, args.layout, &filename)?;
+            summary.wrote(&filename);
+
+            for &format in &args.format {
+                let rules_filename = args.name_template.render(cc, action, format.extension());
+                write_rules(&single, action, format, args.rules, &keep, cc, &rules_filename)?;
+                summary.wrote(&rules_filename);
+            }
+        }
+        // Each file carries its own complete policy, so loading several of
+        // them together would not combine into anything meaningful.
+        info!("Per-country rule files are not loaded automatically.");
+        for hint in args.format.iter().filter_map(|format| format.load_hint("<file>", args.rules)) {
+            info!("To load one manually, run: {}", hint);
         }
+        summary.print_human();
+        return Ok(());
     }
 
     // --- Dump to JSON file ---
-    let filename = format!("{}_ip_map.json", args.list);
-    let file = File::create(&filename)?;
-    let writer = BufWriter::new(file);
-    serde_json::to_writer_pretty(writer, &map)?;
-    println!("Wrote {}", filename);
+    let filename = format!("{}_ip_map.json", group.name);
+    // The map of the last run is the baseline, so compare before replacing it
+    if !args.force && args.layout == Layout::Nested {
+        guard::check(Path::new(&filename), &map, countries, args.guard)?;
+    }
+    write_json(&map, args.layout, &filename)?;
+    summary.wrote(&filename);
 
-    // --- Generate nftables rules ---
-    let nft_filename = format!("{}_{}.nft", args.list, args.action);
-    generate_nftables(&map, args.action, &nft_filename)?;
-    println!("Wrote {}", nft_filename);
+    // --- Generate firewall rules ---
+    let mut hints = Vec::new();
+    // Sets alone are for the user's own ruleset and not loaded here
+    let load = |format: &Format| format.loadable() && !args.rules.sets_only;
+    for &format in args.format.iter().filter(|format| !load(format)) {
+        let rules_filename = args.name_template.render(&group.name, action, format.extension());
+        write_rules(&map, action, format, args.rules, &keep, &group.name, &rules_filename)?;
+        summary.wrote(&rules_filename);
+        hints.extend(format.load_hint(&rules_filename, args.rules));
+    }
+    if !hints.is_empty() {
+        info!("To load the rules, run (elevated):");
+        for hint in &hints {
+            info!("   {}", hint);
+        }
+    }
+    if !args.format.iter().any(load) {
+        summary.print_human();
+        return Ok(());
+    }
+    let nft_filename = args.name_template.render(&group.name, action, Format::Nft.extension());
+    let fingerprint = generate_nftables(&map, action, &keep, args.rules, &nft_filename)?;
+    summary.wrote(&nft_filename);
 
     // --- Ask user if they want to load rules ---
-    println!("To load the rules manually, run:");
-    println!("   sudo nft -f {}", nft_filename);
-    println!("Do you want to load the rules now? [y/N]");
-
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input)?;
-    if input.trim().eq_ignore_ascii_case("y") {
-        println!("Loading rules into nftables...");
-        let status = Command::new("sudo")
-            .arg("nft")
-            .arg("-f")
-            .arg(&nft_filename)
-            .status()
-            .expect("failed to execute nft command");
-        if status.success() {
-            println!("Rules loaded successfully.");
+    info!("To load the rules manually (as root or with CAP_NET_ADMIN), run:");
+    info!("   cloak apply {}", nft_filename);
+    if args.no_load {
+        summary.print_human();
+        return Ok(());
+    }
+
+    // Frequent scheduled runs mostly regenerate identical rules; skip the
+    // kernel reload when the ruleset matches the last one applied and the
+    // table is still there.
+    let unchanged = nft::last_applied_hash(&args.state_dir).as_deref() == Some(fingerprint.as_str());
+    let ruleset = fs::read_to_string(&nft_filename).with_context(|| format!("read {}", nft_filename))?;
+    if unchanged && !args.force && nft::live_fingerprint(&ruleset).as_deref() == Some(fingerprint.as_str()) {
+        summary.load = LoadResult::UpToDate;
+        success!("Rules are up to date; nothing to reload.");
+        if let Some(dir) = &args.persist_dir {
+            persist_rules(Path::new(&nft_filename), Some(dir), &args.state_dir)?;
+        }
+    } else if confirm_load(&nft_filename, &args.reachable, args.yes)? {
+        info!("Loading rules into nftables...");
+        if nft::load(&nft_filename).code(Code::Apply)? {
+            nft::record_applied_hash(&args.state_dir, &fingerprint)?;
+            temp::restore(&args.state_dir);
+            summary.load = LoadResult::Loaded;
+            success!("Rules loaded successfully.");
+            persist_rules(Path::new(&nft_filename), args.persist_dir.as_deref(), &args.state_dir)?;
+            if args.flush_conntrack && action == Action::Block {
+                let killed = conntrack::flush_conntrack(&map)?;
+                summary.conntrack_peers_flushed = Some(killed);
+                info!("Flushed conntrack entries for {} blocked peers.", killed);
+            }
         } else {
-            println!("Failed to load rules. Try manually: sudo nft -f {}", nft_filename);
+            summary.load = LoadResult::Failed;
+            warning!("Failed to load rules{}. Try manually: cloak apply {}", privilege::hint(), nft_filename);
         }
+    } else {
+        summary.load = LoadResult::Declined;
     }
 
+    summary.print_human();
     Ok(())
 }
 
-async fn fetch_cidrs(url: &str) -> Result<Vec<IpNetwork>> {
-    let body = reqwest::get(url)
-        .await
-        .with_context(|| format!("GET {}", url))?
-        .text()
-        .await
-        .with_context(|| format!("read response body {}", url))?;
+/// Print the pre-flight report for `file` and ask whether to load it:
+/// with a typed confirmation for risky rules, unless `yes`
+fn confirm_load(file: &str, reachable: &[SocketAddr], yes: bool) -> Result<bool> {
+    let report = preflight::assess(&fs::read_to_string(file).with_context(|| format!("read {}", file))?, reachable);
+    report.print(file);
+    Ok(match (yes, report.risky()) {
+        (true, _) => true,
+        // Refusing risky rules is lockout protection, not a change of mind
+        (false, true) if !report.confirm()? => {
+            return Err(anyhow::anyhow!("not loading {} without confirmation", file)).code(Code::Lockout);
+        }
+        (false, true) => true,
+        (false, false) => ui::confirm("Do you want to load the rules now?")?,
+    })
+}
 
-    let mut nets = Vec::new();
-    for line in body.lines() {
-        let token = line.trim();
-        if token.is_empty() {
-            continue;
+fn record_counts(summary: &mut Summary, map: &HashMap<String, CountryNets>) {
+    for (cc, nets) in map {
+        summary.countries.insert(
+            cc.clone(),
+            FamilyCounts { ipv4: nets.ipv4.len(), ipv6: nets.ipv6.len() },
+        );
+    }
+}
+
+async fn merge(args: &MergeArgs, summary: &mut Summary) -> Result<()> {
+    args.filters.check()?;
+    summary.action = Some(args.action.to_string());
+    let mut merged = read_maps(&args.inputs)?;
+
+    if args.dedup {
+        for nets in merged.values_mut() {
+            for family in [&mut nets.ipv4, &mut nets.ipv6] {
+                family.sort_by_key(|net| net.0);
+                family.dedup_by_key(|net| net.0);
+            }
         }
-        if let Ok(net) = token.parse::<IpNetwork>() {
+    }
+
+    filter::apply(&mut merged, &[], &args.filters, &args.http).await?;
+    let keep = keep::resolve(&args.filters.keep).await?;
+    check_freshness(&merged, args.max_age)?;
+    record_counts(summary, &merged);
+
+    let output = &args.output;
+    let action = args.action;
+    let filename = output.to_string_lossy();
+    write_json(&merged, Layout::Nested, &filename)?;
+    summary.wrote(&filename);
+
+    // combined.json -> combined_block.nft, brics_ip_map.json -> brics_block.nft
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let stem = stem.strip_suffix("_ip_map").unwrap_or(&stem);
+    let mut hints = Vec::new();
+    for &format in &args.format {
+        let rules_filename = output.with_file_name(args.name_template.render(stem, action, format.extension()));
+        let rules_filename = rules_filename.to_string_lossy();
+        write_rules(&merged, action, format, args.rules, &keep, stem, &rules_filename)?;
+        summary.wrote(&rules_filename);
+        hints.extend(format.load_hint(&rules_filename, args.rules));
+    }
+    if !hints.is_empty() {
+        info!("To load the rules, run:");
+        for hint in &hints {
+            info!("   {}", hint);
+        }
+    }
+    summary.print_human();
+    Ok(())
+}
+
+/// The group named `list` or made of `countries`, or with only other
+/// sources an empty one named after the first of them
+fn resolve_group(list: Option<&str>, countries: &[String], group_file: Option<&Path>, sources: &fetch::SourceArgs) -> Result<groups::Group> {
+    if !countries.is_empty() {
+        if let Some(list) = list {
+            bail!("give either a LIST ({}) or --countries, not both", list);
+        }
+        return groups::from_codes("custom", countries);
+    }
+    let Some(list) = list else {
+        let name = sources.labels().into_iter().next().context("a LIST or --source is required")?;
+        return Ok(groups::Group { name, countries: Vec::new() });
+    };
+    let file_groups = match group_file {
+        Some(path) => groups::load_group_file(path)?,
+        None => HashMap::new(),
+    };
+    groups::resolve(list, &file_groups)
+}
+
+/// Fetch a list and write only its JSON map.
+async fn fetch(args: &FetchArgs, summary: &mut Summary) -> Result<()> {
+    args.filters.check()?;
+    if !args.filters.keep.is_empty() {
+        bail!("--keep applies to rules, and fetch writes only the JSON map");
+    }
+    let group = resolve_group(args.list.as_deref(), &args.countries, args.group_file.as_deref(), &args.sources)?;
+    summary.list = Some(group.name.clone());
+
+    let mut map = fetch::fetch_countries(&group.countries, &args.http).await?;
+    args.sources.read(&mut map)?;
+    filter::apply(&mut map, &args.sources.own_labels(), &args.filters, &args.http).await?;
+    record_counts(summary, &map);
+
+    if args.split_by_country {
+        for (cc, nets) in map {
+            let filename = format!("{}_ip_map.json", cc);
+            write_json(&HashMap::from([(cc, nets)]), args.layout, &filename)?;
+            summary.wrote(&filename);
+        }
+    } else {
+        let filename = match &args.output {
+            Some(path) => path.to_string_lossy().into_owned(),
+            None => format!("{}_ip_map.json", group.name),
+        };
+        write_json(&map, args.layout, &filename)?;
+        summary.wrote(&filename);
+    }
+    summary.print_human();
+    Ok(())
+}
+
+/// Write rule files for every requested format from previously fetched maps.
+async fn generate(args: &GenerateArgs, summary: &mut Summary) -> Result<()> {
+    args.filters.check()?;
+    summary.action = Some(args.action.to_string());
+    let inputs: Vec<PathBuf> = args.inputs.iter().chain(&args.from).cloned().collect();
+    let mut map = read_maps(&inputs)?;
+    filter::apply(&mut map, &[], &args.filters, &args.http).await?;
+    let keep = keep::resolve(&args.filters.keep).await?;
+    check_freshness(&map, args.max_age)?;
+    record_counts(summary, &map);
+
+    let first = &inputs[0];
+    let name = match &args.name {
+        Some(name) => name.clone(),
+        None => {
+            let stem = first.file_stem().unwrap_or_default().to_string_lossy();
+            stem.strip_suffix("_ip_map").unwrap_or(&stem).to_string()
+        }
+    };
+    for &format in &args.format {
+        let rules_filename = first.with_file_name(args.name_template.render(&name, args.action, format.extension()));
+        let rules_filename = rules_filename.to_string_lossy();
+        write_rules(&map, args.action, format, args.rules, &keep, &name, &rules_filename)?;
+        summary.wrote(&rules_filename);
+        if let Some(hint) = format.load_hint(&rules_filename, args.rules) {
+            info!("To load them, run: {}", hint);
+        }
+    }
+    summary.print_human();
+    Ok(())
+}
+
+/// Take cloak's rules out of the kernel again.
+fn remove(state_dir: &Path) -> Result<()> {
+    if !cfg!(target_os = "linux") {
+        bail!("removing rules is only supported on Linux; use the platform's own tools");
+    }
+    let detached = attach::detach(state_dir)?;
+    if detached {
+        success!("Removed cloak's rules from the attached table.");
+    }
+    let table_loaded = nft::live_fingerprint("").is_some() || (!detached && nft::last_applied_hash(state_dir).is_some());
+    if !table_loaded {
+        if !detached {
+            info!("No cloak rules are loaded.");
+        }
+    } else if !nft::remove()? {
+        bail!("nft could not delete the cloak table{}", privilege::hint());
+    } else {
+        success!("Removed the cloak table.");
+    }
+    if let Some(dropin) = persist::uninstall(state_dir)? {
+        success!("Removed {}, so the nftables service no longer loads the rules.", dropin.display());
+    }
+    nft::forget_applied_hash(state_dir)
+}
+
+/// Add expiring entries for addresses, prefixes and countries to the
+/// loaded rules.
+async fn block_temp(args: &BlockTempArgs, state_dir: &Path, summary: &mut Summary) -> Result<()> {
+    if !cfg!(target_os = "linux") {
+        bail!("temporary blocks are only supported on Linux");
+    }
+    if args.duration.is_zero() {
+        bail!("--for must be longer than 0s");
+    }
+    let nets = target_nets(&args.targets, args.map.as_deref(), &args.http, summary).await?;
+    let blocks: Vec<temp::Block> = nets.iter().map(|&net| temp::Block::lasting(net, args.duration)).collect();
+    temp::TEMP.add(&blocks, state_dir)?;
+    success!("Blocked {} prefixes for {}.", nets.len(), format_duration(args.duration));
+    summary.print_human();
+    Ok(())
+}
+
+/// The prefixes of `targets`: addresses, prefixes and country codes, whose
+/// prefixes come from `map` or are downloaded
+async fn target_nets(targets: &[String], map: Option<&Path>, http: &fetch::HttpArgs, summary: &mut Summary) -> Result<Vec<IpNetwork>> {
+    let mut nets = Vec::new();
+    let mut codes = Vec::new();
+    for target in targets {
+        if let Ok(net) = target.parse::<IpNetwork>() {
             nets.push(net);
+        } else if let Ok(addr) = target.parse::<std::net::IpAddr>() {
+            nets.push(IpNetwork::from(addr));
+        } else {
+            codes.push(target.clone());
+        }
+    }
+    if !codes.is_empty() {
+        let group = groups::from_codes("targets", &codes).context("targets must be addresses, prefixes or country codes")?;
+        let map = match map {
+            Some(path) => read_map(path)?,
+            None => fetch::fetch_countries(&group.countries, http).await?,
+        };
+        for (cc, _) in &group.countries {
+            let country = map.get(cc).with_context(|| format!("no prefixes for {}", cc.to_uppercase()))?;
+            summary.countries.insert(cc.clone(), FamilyCounts { ipv4: country.ipv4.len(), ipv6: country.ipv6.len() });
+            nets.extend(country.ipv4.iter().chain(&country.ipv6).map(|net| net.0));
         }
     }
     Ok(nets)
 }
 
-fn generate_nftables(
+/// Write the ranges of the merged maps to an index file.
+fn compile(args: &CompileArgs) -> Result<()> {
+    let started = Instant::now();
+    let index = index::Index::build(&read_maps(&args.maps)?);
+    let output = args.output.clone().unwrap_or_else(|| args.maps[0].with_extension("idx"));
+    index.write(&output)?;
+    let (ipv4, ipv6) = index.ranges();
+    let size = fs::metadata(&output).with_context(|| format!("stat {}", output.display()))?.len();
+    success!(
+        "Compiled {} IPv4 and {} IPv6 ranges into {} ({} KiB) in {:.1?}",
+        ipv4,
+        ipv6,
+        output.display(),
+        size.div_ceil(1024),
+        started.elapsed()
+    );
+    Ok(())
+}
+
+/// Print `<address> <country>` per address, `-` for no match, followed
+/// by `abuse=<score>% reports=<count>` when an AbuseIPDB key is set and
+/// `holder="<org>" abuse_contact=<email>` with `--whois`. With `--batch`
+/// the addresses come from a file instead and the output is CSV.
+async fn lookup(args: &LookupArgs) -> Result<()> {
+    let index = index::Index::load(std::slice::from_ref(&args.map))?;
+    if let Some(input) = &args.batch {
+        return batch::run(&index, input);
+    }
+    let abuse_key = abuseipdb::api_key(args.abuseipdb_key_file.as_deref())?;
+    let client = if abuse_key.is_some() || args.whois { Some(fetch::client(&args.http)?) } else { None };
+    for addr in &args.addrs {
+        let mut line = format!("{} {}", addr, index.lookup(*addr).map_or("-".to_string(), str::to_uppercase));
+        let addr = &addr.to_canonical();
+        let Some(client) = &client else {
+            println!("{}", line);
+            continue;
+        };
+        if let Some(key) = &abuse_key {
+            match abuseipdb::check(client, key, *addr).await {
+                Ok(report) => line.push_str(&format!(
+                    " abuse={}% reports={}",
+                    report.abuse_confidence_score, report.total_reports
+                )),
+                Err(e) => warning!("{:#}", e),
+            }
+        }
+        if args.whois {
+            match rdap::holder(client, *addr).await {
+                Ok(holder) => line.push_str(&format!(
+                    " holder=\"{}\" abuse_contact={}",
+                    holder.name.as_deref().unwrap_or("?"),
+                    holder.abuse_email.as_deref().unwrap_or("-")
+                )),
+                Err(e) => warning!("{:#}", e),
+            }
+        }
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn write_rules(
     map: &HashMap<String, CountryNets>,
     action: Action,
+    format: Format,
+    rules: RuleArgs,
+    keep: &[IpNetwork],
+    name: &str,
     filename: &str,
 ) -> Result<()> {
-    use std::io::Write;
-    let mut file = File::create(filename)?;
+    rules.check_format(format, action, !keep.is_empty()).code(Code::Validation)?;
+    match format {
+        Format::Nft => generate_nftables(map, action, keep, rules, filename).map(|_| ()),
+        Format::Pf => pf::generate_pf(map, action, rules.direction, filename),
+        Format::Windows => winfw::generate_powershell(map, action, rules.direction, name, filename),
+        Format::Ipset => ipset::generate_ipset(map, action, rules.direction, name, filename),
+        Format::Json => write_json(map, Layout::Nested, filename),
+        Format::Csv => write_csv(map, filename),
+        Format::Suricata => suricata::generate_suricata(map, action, rules.direction, rules.alert, name, filename),
+        Format::Zeek => zeek::generate_zeek(map, action, name, filename),
+        Format::Wazuh => wazuh::generate_wazuh(map, filename),
+        Format::Istio => mesh::generate_istio(map, action, name, filename),
+        Format::Envoy => mesh::generate_envoy(map, action, name, filename),
+        Format::Traefik => proxy::generate_traefik(map, action, false, name, filename),
+        Format::TraefikToml => proxy::generate_traefik(map, action, true, name, filename),
+        Format::Caddy => proxy::generate_caddy(map, action, name, filename),
+        Format::Haproxy => haproxy::generate_haproxy(map, filename),
+        Format::Exim => mail::generate_exim(map, action, name, filename),
+        Format::Sendmail => mail::generate_sendmail(map, action, filename),
+        Format::Postfix => mail::generate_postfix(map, action, filename),
+        Format::Rpz => rpz::generate_rpz(map, action, name, filename),
+        Format::Unbound => resolver::generate_unbound(map, action, filename),
+        Format::Blocky => resolver::generate_list(map, action, false, filename),
+        Format::Adguard => resolver::generate_list(map, action, true, filename),
+        Format::Tc => tc::generate_tc(map, action, rules.direction, filename),
+    }
+}
+
+/// Load a generated rule file with nft.
+async fn apply(args: &ApplyArgs, state_dir: &Path, summary: &mut Summary) -> Result<()> {
+    if !cfg!(target_os = "linux") {
+        bail!("loading rules is only supported on Linux; use the platform's own tools");
+    }
+    canary::check(&args.canary)?;
+    let (notify, assertions) = match &args.config {
+        Some(path) => (NotifyConfig::from_config_file(path)?, assertion::from_config_file(path).code(Code::Validation)?),
+        None => (NotifyConfig::default(), Vec::new()),
+    };
+    let file = match (&args.profile, &args.file) {
+        (Some(name), _) => profile_rules(args, name, state_dir, summary).await,
+        (None, Some(file)) if bundle::is_bundle(file) => {
+            let verify = bundle::Verify { allowed_signers: args.allowed_signers.as_deref(), signer: args.signer.as_deref() };
+            bundle::extract(file, &verify, state_dir).code(Code::Validation)
+        }
+        (None, file) => file.clone().context("a rule FILE is required"),
+    };
+    let attach = args.attach.as_ref().map(|target| (target, args.insert_at));
+    let result = match file {
+        Ok(file) => async {
+            assertion::check_file(&file, &assertions)?;
+            let load = (args.reachable.as_slice(), args.yes);
+            Ok((load_rule_file(&file, attach, load, &args.canary, args.persist_dir.as_deref(), state_dir, summary).await?, file))
+        }
+        .await,
+        Err(e) => Err(e),
+    };
+    let target = match (&args.profile, &args.file) {
+        (Some(name), _) => format!("profile {}", name),
+        (None, file) => file.as_deref().map(|file| file.display().to_string()).unwrap_or_default(),
+    };
+    let event = match &result {
+        Ok((fingerprint, file)) => Event::new(
+            EventKind::Applied,
+            "cloak: rules applied",
+            format!("Loaded {} ({}).\n", target, fingerprint),
+        )
+        .field("fingerprint", fingerprint)
+        .field("file", file.display()),
+        Err(e) => Event::new(
+            EventKind::ApplyFailed,
+            "cloak: apply failed",
+            format!("Loading {} failed:\n\n{:#}\n", target, e),
+        )
+        .field("error", format!("{:#}", e)),
+    };
+    let event = match &args.profile {
+        Some(name) => event.field("profile", name),
+        None => event,
+    };
+    notify::send(&notify, &event).await;
+    result.map(|_| ())
+}
+
+/// Fetch the countries of a profile and write its layered ruleset to the
+/// state directory, returning the file's path.
+async fn profile_rules(args: &ApplyArgs, name: &str, state_dir: &Path, summary: &mut Summary) -> Result<PathBuf> {
+    let config = args.config.as_deref().context("--profile needs --config")?;
+    let file_groups = match &args.group_file {
+        Some(path) => groups::load_group_file(path)?,
+        None => HashMap::new(),
+    };
+    let profile = profile::Profile::load(config, name, &file_groups)?;
+    summary.list = Some(profile.name.clone());
+    let map = fetch::fetch_countries(&profile.countries(), &args.http).await?;
+    record_counts(summary, &map);
+
+    let rules = state_dir.join(format!("profile_{}.nft", profile.name));
+    let filename = rules.to_string_lossy();
+    nft::generate_layered(&profile.zones(&map), &filename)?;
+    summary.wrote(&filename);
+    Ok(rules)
+}
 
-    writeln!(file, "table inet filter {{")?;
+/// Load `file` after printing its pre-flight report. `(reachable, yes)`
+/// are the addresses to check besides the SSH session and whether risky
+/// rules may be loaded without the typed confirmation; with `--canary`
+/// they are only kept once confirmed.
+async fn load_rule_file(
+    file: &Path,
+    attach: Option<(&attach::Target, attach::Position)>,
+    (reachable, yes): (&[SocketAddr], bool),
+    canary: &canary::CanaryArgs,
+    persist_dir: Option<&Path>,
+    state_dir: &Path,
+    summary: &mut Summary,
+) -> Result<String> {
+    let ruleset = fs::read_to_string(file).with_context(|| format!("read {}", file.display()))?;
+    let fingerprint = nft::check_own_ruleset(&ruleset)
+        .with_context(|| format!("refusing to load {}", file.display()))
+        .code(Code::Validation)?;
+    let report = preflight::assess(&ruleset, reachable);
+    report.print(&file.display().to_string());
+    if report.risky() && !yes && !report.confirm()? {
+        return Err(anyhow::anyhow!("not loading {} without confirmation", file.display())).code(Code::Lockout);
+    }
 
-    // IPv4 set
-    writeln!(file, "  set country_ipv4 {{ type ipv4_addr; flags interval; elements = {{")?;
-    for nets in map.values() {
-        for ip in &nets.ipv4 {
-            writeln!(file, "    {},", ip.0)?;
+    if let Some((target, position)) = attach {
+        let attached = attach::attach(&ruleset, target, position, state_dir);
+        summary.load = if attached.is_ok() { LoadResult::Loaded } else { LoadResult::Failed };
+        attached.with_context(|| format!("attach {}", file.display())).code(Code::Apply)?;
+        nft::record_applied_hash(state_dir, &fingerprint)?;
+        temp::restore(state_dir);
+        success!("Loaded {} into inet {} {} ({}).", file.display(), target.table, target.chain, fingerprint);
+        return Ok(fingerprint);
+    }
+    let previous = canary.canary.map(|_| canary::snapshot());
+    if !nft::load(&file.to_string_lossy()).code(Code::Apply)? {
+        summary.load = LoadResult::Failed;
+        return Err(anyhow::anyhow!("nft rejected {}{}", file.display(), privilege::hint())).code(Code::Apply);
+    }
+    summary.load = LoadResult::Loaded;
+    temp::restore(state_dir);
+    if let Some(previous) = previous {
+        if !canary::confirmed(canary).await? {
+            canary::revert(previous.as_deref()).code(Code::Apply)?;
+            summary.load = LoadResult::Failed;
+            warning!("Put back the rules loaded before {}.", file.display());
+            return Err(anyhow::anyhow!("reverted {}: the new rules were not confirmed", file.display())).code(Code::Lockout);
         }
     }
-    writeln!(file, "  }} }}")?;
+    nft::record_applied_hash(state_dir, &fingerprint)?;
+    success!("Loaded {} ({}).", file.display(), fingerprint);
+    persist_rules(file, persist_dir, state_dir)?;
+    Ok(fingerprint)
+}
 
-    // IPv6 set
-    writeln!(file, "  set country_ipv6 {{ type ipv6_addr; flags interval; elements = {{")?;
-    for nets in map.values() {
-        for ip in &nets.ipv6 {
-            writeln!(file, "    {},", ip.0)?;
+/// Start keeping the nftables service's copy of `file` in `dir`, or bring
+/// an existing copy up to date
+fn persist_rules(file: &Path, dir: Option<&Path>, state_dir: &Path) -> Result<()> {
+    match dir {
+        Some(dir) => {
+            let dropin = persist::install(file, dir, state_dir)?;
+            success!("Saved the rules to {} for the nftables service.", dropin.display());
+            Ok(())
         }
+        None => persist::update(file, state_dir),
     }
-    writeln!(file, "  }} }}")?;
+}
 
-    // Chain rules
-    writeln!(file, "  chain input {{")?;
-    writeln!(file, "    type filter hook input priority 0;")?;
+/// Read several nested JSON maps and union them per country
+fn read_maps(inputs: &[PathBuf]) -> Result<HashMap<String, CountryNets>> {
+    let mut merged: HashMap<String, CountryNets> = HashMap::new();
+    for input in inputs {
+        let map = read_map(input)?;
+        info!("Read {} ({} countries)", input.display(), map.len());
 
-    match action {
-        Action::Block => {
-            writeln!(file, "    ip saddr @country_ipv4 drop;")?;
-            writeln!(file, "    ip6 saddr @country_ipv6 drop;")?;
-            writeln!(file, "    accept;")?;
+        for (cc, nets) in map {
+            let entry = merged.entry(cc).or_insert_with(|| CountryNets {
+                ipv4: Vec::new(),
+                ipv6: Vec::new(),
+                fetched_at: nets.fetched_at,
+            });
+            entry.ipv4.extend(nets.ipv4);
+            entry.ipv6.extend(nets.ipv6);
+            // The union is only as fresh as its oldest part
+            entry.fetched_at = match (entry.fetched_at, nets.fetched_at) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                _ => None,
+            };
         }
-        Action::Allow => {
-            writeln!(file, "    ip saddr @country_ipv4 accept;")?;
-            writeln!(file, "    ip6 saddr @country_ipv6 accept;")?;
-            writeln!(file, "    drop;")?;
+    }
+    Ok(merged)
+}
+
+/// Read a JSON map in the nested layout
+fn read_map(path: &Path) -> Result<HashMap<String, CountryNets>> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let map: IpMap = serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("parse {} (expected the nested layout)", path.display()))
+        .code(Code::Validation)?;
+    map.check_version().map_err(anyhow::Error::msg).with_context(|| format!("read {}", path.display())).code(Code::Validation)?;
+    Ok(map.countries)
+}
+
+/// Warn about stale country data, or fail if any of it exceeds `max_age`.
+fn check_freshness(map: &HashMap<String, CountryNets>, max_age: Option<Duration>) -> Result<()> {
+    let now = unix_now();
+    let mut too_old = Vec::new();
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    for cc in codes {
+        let Some(fetched_at) = map[cc].fetched_at else {
+            warning!("{} has no fetch timestamp; its age is unknown", cc.to_uppercase());
+            if max_age.is_some() {
+                too_old.push(cc.to_uppercase());
+            }
+            continue;
+        };
+        let age = Duration::from_secs(now.saturating_sub(fetched_at));
+        if max_age.is_some_and(|max| age > max) {
+            too_old.push(cc.to_uppercase());
+        } else if age > STALE_WARN_AGE {
+            warning!("{} data is {} old", cc.to_uppercase(), format_duration(age));
         }
     }
+    if !too_old.is_empty() {
+        return Err(anyhow::anyhow!(
+            "data for {} is older than --max-age {} or undated; refetch before generating rules",
+            too_old.join(", "),
+            format_duration(max_age.unwrap_or_default())
+        ))
+        .code(Code::Validation);
+    }
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
 
-    writeln!(file, "  }}")?;
-    writeln!(file, "}}")?;
+/// Parse durations like `90s`, `15m`, `36h`, `7d` or `2w`.
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (digits, unit) = text.split_at(split);
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid duration `{}` (expected e.g. 30m, 6h, 7d)", text))?;
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 24 * 3600,
+        "w" => 7 * 24 * 3600,
+        _ => return Err(format!("unknown duration unit `{}` (use s, m, h, d or w)", unit)),
+    };
+    value
+        .checked_mul(scale)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration `{}` is too long", text))
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        s if s >= 24 * 3600 => format!("{}d {}h", s / (24 * 3600), s % (24 * 3600) / 3600),
+        s if s >= 3600 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+/// One `country,cidr` line per prefix, after a header line
+fn write_csv(map: &HashMap<String, CountryNets>, filename: &str) -> Result<()> {
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    let mut csv = String::from("country,cidr\n");
+    for cc in codes {
+        for net in map[cc].ipv4.iter().chain(&map[cc].ipv6) {
+            csv.push_str(&format!("{},{}\n", cc, net.0));
+        }
+    }
+    fs::write(filename, csv).with_context(|| format!("write {}", filename))
+}
+
+fn write_json(map: &HashMap<String, CountryNets>, layout: Layout, filename: &str) -> Result<()> {
+    let file = File::create(filename).with_context(|| format!("create {}", filename))?;
+    let writer = BufWriter::new(file);
+    match layout {
+        Layout::Nested => serde_json::to_writer_pretty(writer, &IpMapRef::new(map))?,
+        Layout::Reverse => {
+            let mut reverse = BTreeMap::new();
+            for (cc, nets) in map {
+                for net in nets.ipv4.iter().chain(&nets.ipv6) {
+                    if let Some(prev) = reverse.insert(net.0.to_string(), cc.as_str()) {
+                        if prev != cc {
+                            warning!("{} listed for both {} and {}, keeping {}", net.0, prev, cc, cc);
+                        }
+                    }
+                }
+            }
+            serde_json::to_writer_pretty(writer, &reverse)?;
+        }
+    }
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn global_flags_go_before_a_subcommand() {
+        let args = parse_args(["cloak", "-q", "--state-dir", "/tmp/cloak", "apply", "f.nft"]).unwrap();
+        assert!(args.quiet);
+        assert_eq!(args.state_dir, PathBuf::from("/tmp/cloak"));
+        assert!(matches!(args.command, Some(Commands::Apply(_))));
+        let args = parse_args(["cloak", "--wait", "countries", "fr"]).unwrap();
+        assert!(args.wait);
+        assert!(matches!(args.command, Some(Commands::Countries(_))));
+    }
+
+    #[test]
+    fn list_and_action_without_a_subcommand() {
+        let args = parse_args(["cloak", "-q", "nato", "block", "--no-load"]).unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.list.as_deref(), Some("nato"));
+        assert_eq!(args.action, Some(Action::Block));
+    }
+
+    #[test]
+    fn list_options_refused_with_a_subcommand() {
+        let error = parse_args(["cloak", "--countries", "de", "countries", "fr"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ArgumentConflict);
+    }
+}
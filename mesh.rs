@@ -0,0 +1,80 @@
+//! Service-mesh policies: an Istio AuthorizationPolicy or the Envoy RBAC
+//! filter it compiles to, matching the client address each sidecar sees
+//! (`remoteIpBlocks`, Envoy's `remote_ip`, which honours the configured
+//! trusted proxies).
+//!
+//! Both only see inbound requests, so they implement `--direction in`.
+//! Allow lists become a DENY of everything outside the prefixes, which
+//! keeps other ALLOW policies of the workload meaningful.
+
+use std::{collections::HashMap, fmt::Write, fs};
+
+use anyhow::{Context, Result};
+
+use crate::{Action, CountryNets, SerIpNet};
+
+/// Every prefix of `map`, IPv4 first, in country order
+fn prefixes(map: &HashMap<String, CountryNets>) -> Vec<&SerIpNet> {
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    let ipv4 = codes.iter().flat_map(|cc| &map[*cc].ipv4);
+    ipv4.chain(codes.iter().flat_map(|cc| &map[*cc].ipv6)).collect()
+}
+
+/// `name` as a Kubernetes object name (lowercase letters, digits and `-`)
+fn object_name(name: &str) -> String {
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' }).collect();
+    format!("cloak-{}", name.trim_matches('-'))
+}
+
+/// Write an Istio AuthorizationPolicy for `map` to `filename`. It has no
+/// selector, so it applies to every workload of the namespace it is
+/// applied in.
+pub fn generate_istio(map: &HashMap<String, CountryNets>, action: Action, name: &str, filename: &str) -> Result<()> {
+    let field = if action == Action::Allow { "notRemoteIpBlocks" } else { "remoteIpBlocks" };
+    let mut file = String::new();
+    writeln!(file, "# Generated by cloak. Apply with: kubectl apply -n <namespace> -f {}", filename)?;
+    writeln!(file, "apiVersion: security.istio.io/v1")?;
+    writeln!(file, "kind: AuthorizationPolicy")?;
+    writeln!(file, "metadata:")?;
+    writeln!(file, "  name: {}", object_name(name))?;
+    writeln!(file, "spec:")?;
+    writeln!(file, "  action: DENY")?;
+    writeln!(file, "  rules:")?;
+    writeln!(file, "  - from:")?;
+    writeln!(file, "    - source:")?;
+    writeln!(file, "        {}:", field)?;
+    for net in prefixes(map) {
+        writeln!(file, "        - \"{}\"", net.0)?;
+    }
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
+
+/// Write an Envoy HTTP RBAC filter for `map` to `filename`, to put in a
+/// listener's `http_filters` before the router
+pub fn generate_envoy(map: &HashMap<String, CountryNets>, action: Action, name: &str, filename: &str) -> Result<()> {
+    let mut file = String::new();
+    writeln!(file, "# Generated by cloak. Add to the http_filters of a listener, before envoy.filters.http.router")?;
+    writeln!(file, "name: envoy.filters.http.rbac")?;
+    writeln!(file, "typed_config:")?;
+    writeln!(file, "  \"@type\": type.googleapis.com/envoy.extensions.filters.http.rbac.v3.RBAC")?;
+    writeln!(file, "  rules:")?;
+    writeln!(file, "    action: DENY")?;
+    writeln!(file, "    policies:")?;
+    writeln!(file, "      {}:", object_name(name))?;
+    writeln!(file, "        permissions:")?;
+    writeln!(file, "        - any: true")?;
+    writeln!(file, "        principals:")?;
+    let indent = if action == Action::Allow {
+        writeln!(file, "        - not_id:")?;
+        writeln!(file, "            or_ids:")?;
+        writeln!(file, "              ids:")?;
+        "              "
+    } else {
+        "        "
+    };
+    for net in prefixes(map) {
+        writeln!(file, "{}- remote_ip: {{ address_prefix: \"{}\", prefix_len: {} }}", indent, net.0.network(), net.0.prefix())?;
+    }
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
@@ -0,0 +1,38 @@
+//! A minimal Prometheus endpoint for the long-running commands.
+
+use std::{fmt::Write, sync::Arc};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Answer every request on `listener` with the text `render` returns
+pub async fn serve(listener: TcpListener, render: impl Fn() -> String + Send + Sync + 'static) {
+    let render = Arc::new(render);
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            continue;
+        };
+        // Every request gets the metrics, whatever its path
+        let mut request = [0u8; 1024];
+        let _ = stream.read(&mut request).await;
+        // Off the runtime, since rendering may wait for nft
+        let render = render.clone();
+        let Ok(body) = tokio::task::spawn_blocking(move || render()).await else {
+            continue;
+        };
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+    }
+}
+
+/// Append the HELP and TYPE lines of metric `name`
+pub fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
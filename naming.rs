@@ -0,0 +1,91 @@
+//! File names for generated rules, from `--name-template`, so scheduled
+//! runs can keep a file per run instead of overwriting the last one.
+
+use std::{
+    env, fs,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::Action;
+
+/// The names cloak has always used
+pub const DEFAULT: &str = "{list}_{action}.{ext}";
+
+const PLACEHOLDERS: [&str; 6] = ["list", "action", "ext", "date", "time", "host"];
+
+/// A parsed `--name-template`, with the time of the run fixed so every file
+/// of one run carries the same date
+#[derive(Clone, Debug)]
+pub struct NameTemplate {
+    template: String,
+    unix: u64,
+}
+
+/// `value_parser` for `--name-template`
+pub fn parse_template(s: &str) -> Result<NameTemplate, String> {
+    let mut rest = s;
+    while let Some(open) = rest.find('{') {
+        let Some(close) = rest[open..].find('}') else {
+            return Err(format!("unclosed {{ in {}", s));
+        };
+        let name = &rest[open + 1..open + close];
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(format!("unknown placeholder {{{}}}; expected one of {{{}}}", name, PLACEHOLDERS.join("}, {")));
+        }
+        rest = &rest[open + close + 1..];
+    }
+    if rest.contains('}') || s.is_empty() {
+        return Err(format!("not a file name template: {}", s));
+    }
+    let unix = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    Ok(NameTemplate { template: s.to_string(), unix })
+}
+
+impl NameTemplate {
+    /// The file name for `list`'s rules in the format with extension `ext`
+    pub fn render(&self, list: &str, action: Action, ext: &str) -> String {
+        let (year, month, day) = civil_date(self.unix);
+        let secs = self.unix % 86_400;
+        let mut name = self.template.clone();
+        for (placeholder, value) in [
+            ("{list}", list.to_string()),
+            ("{action}", action.to_string()),
+            ("{ext}", ext.to_string()),
+            ("{date}", format!("{:04}-{:02}-{:02}", year, month, day)),
+            ("{time}", format!("{:02}{:02}{:02}", secs / 3600, secs / 60 % 60, secs % 60)),
+        ] {
+            name = name.replace(placeholder, &value);
+        }
+        if name.contains("{host}") {
+            name = name.replace("{host}", &hostname());
+        }
+        name
+    }
+}
+
+/// Year, month and day (UTC) of a Unix time
+pub fn civil_date(unix: u64) -> (i64, i64, i64) {
+    let days = (unix / 86_400) as i64;
+    // Civil-from-days (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// This machine's name, safe to put in a file name
+pub fn hostname() -> String {
+    let name = env::var("COMPUTERNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .or_else(|| env::var("HOSTNAME").ok())
+        .unwrap_or_default();
+    let name: String = name.trim().chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' }).collect();
+    if name.is_empty() { "localhost".to_string() } else { name }
+}
@@ -0,0 +1,164 @@
+//! `cloak net`: the prefix arithmetic behind the rules, for lists of CIDRs
+//! read from files or stdin, one per line (`#` starts a comment).
+
+use std::{
+    fs,
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::Subcommand;
+use ipnetwork::IpNetwork;
+
+use crate::cidr::{self, Exclusions};
+
+/// Refuse to split into more subnets than this
+const MAX_SPLIT: u128 = 1 << 20;
+
+#[derive(clap::Args, Debug)]
+pub struct NetArgs {
+    #[command(subcommand)]
+    command: NetCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum NetCommand {
+    /// Merge prefixes into the fewest that cover the same addresses
+    Aggregate {
+        /// Files of prefixes (default: stdin; `-` also reads stdin)
+        files: Vec<PathBuf>,
+    },
+
+    /// Take the prefixes in --minus out of the others
+    Subtract {
+        /// Files of prefixes (default: stdin; `-` also reads stdin)
+        files: Vec<PathBuf>,
+
+        /// Prefixes to remove
+        #[arg(long, value_name = "FILE", required = true)]
+        minus: Vec<PathBuf>,
+    },
+
+    /// Tell whether addresses or prefixes are in a list: `listed`,
+    /// `partly` or `not listed`
+    Contains {
+        /// The list of prefixes to check against
+        #[arg(long, value_name = "FILE")]
+        list: PathBuf,
+
+        /// Addresses or prefixes to check (default: read from stdin)
+        queries: Vec<String>,
+    },
+
+    /// Cut prefixes into subnets of one length
+    Split {
+        /// Files of prefixes (default: stdin; `-` also reads stdin)
+        files: Vec<PathBuf>,
+
+        /// Length of the subnets, e.g. 24
+        #[arg(long, value_name = "LEN")]
+        prefix: u8,
+    },
+}
+
+pub fn run(args: &NetArgs) -> Result<()> {
+    let mut out = BufWriter::new(io::stdout().lock());
+    match &args.command {
+        NetCommand::Aggregate { files } => {
+            for net in cidr::aggregate(&read_prefixes(files)?) {
+                writeln!(out, "{}", net)?;
+            }
+        }
+        NetCommand::Subtract { files, minus } => {
+            let exclusions = Exclusions::new(&read_prefixes(minus)?);
+            let mut left = Vec::new();
+            for net in read_prefixes(files)? {
+                exclusions.subtract(&net, &mut left);
+            }
+            for net in cidr::aggregate(&left) {
+                writeln!(out, "{}", net)?;
+            }
+        }
+        NetCommand::Contains { list, queries } => {
+            let queries = if queries.is_empty() {
+                if list.as_os_str() == "-" {
+                    bail!("--list and the queries cannot both come from stdin");
+                }
+                read_prefixes(&[])?
+            } else {
+                queries
+                    .iter()
+                    .map(|query| query.parse::<IpNetwork>().with_context(|| format!("not an address or prefix: {}", query)))
+                    .collect::<Result<_>>()?
+            };
+            let listed = Exclusions::new(&read_prefixes(std::slice::from_ref(list))?);
+            for query in queries {
+                let verdict = if listed.covers(&query) {
+                    "listed"
+                } else if listed.overlaps(&query) {
+                    "partly"
+                } else {
+                    "not listed"
+                };
+                writeln!(out, "{} {}", query, verdict)?;
+            }
+        }
+        NetCommand::Split { files, prefix } => {
+            let nets = read_prefixes(files)?;
+            if let Some(net) = nets.iter().find(|net| *prefix > if net.is_ipv4() { 32 } else { 128 }) {
+                bail!("/{} is longer than the addresses of {}", prefix, net);
+            }
+            let subnets = nets
+                .iter()
+                .fold(0u128, |total, net| total.saturating_add(1 << prefix.saturating_sub(net.prefix()).min(127)));
+            if subnets > MAX_SPLIT {
+                bail!("splitting into /{} would make {} subnets (at most {})", prefix, subnets, MAX_SPLIT);
+            }
+            for net in nets {
+                for part in cidr::split(net, *prefix) {
+                    writeln!(out, "{}", part)?;
+                }
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Every prefix in `files`, or on stdin if there are none
+pub fn read_prefixes(files: &[PathBuf]) -> Result<Vec<IpNetwork>> {
+    let stdin = [PathBuf::from("-")];
+    let mut nets = Vec::new();
+    for path in if files.is_empty() { &stdin[..] } else { files } {
+        let (name, text) = read_input(path)?;
+        for (number, line) in text.lines().enumerate() {
+            let token = line.split('#').next().unwrap_or("").trim();
+            if token.is_empty() {
+                continue;
+            }
+            if let Ok(net) = token.parse::<IpNetwork>() {
+                nets.push(net);
+                continue;
+            }
+            let (first, last) = cidr::parse_range(token)
+                .with_context(|| format!("{}:{}: not an address, prefix or range: {}", name, number + 1, token))?;
+            let covering = cidr::range(first, last);
+            if covering.is_empty() {
+                bail!("{}:{}: range ends before it starts or mixes families: {}", name, number + 1, token);
+            }
+            nets.extend(covering);
+        }
+    }
+    Ok(nets)
+}
+
+fn read_input(path: &Path) -> Result<(String, String)> {
+    if path.as_os_str() == "-" {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text).context("read stdin")?;
+        return Ok(("stdin".to_string(), text));
+    }
+    let text = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    Ok((path.display().to_string(), text))
+}
@@ -0,0 +1,350 @@
+//! `cloak collect`: a NetFlow v5/v9 and IPFIX collector reporting traffic
+//! by country, to measure what rules would block and, once loaded, what
+//! they did.
+//!
+//! Point the exporter (router, softflowd, pmacct, ...) at `--listen`.
+//! Counts are cumulative since the collector started; v9 and IPFIX flows
+//! are only decoded once the exporter has sent their template.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::{
+    analyze::{CountryTraffic, Traffic},
+    daemon, fetch,
+    index::Index,
+    metrics, parse_duration, state,
+    ui::{info, warning},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct CollectArgs {
+    /// UDP address to receive flow exports on
+    #[arg(long, value_name = "ADDR", default_value = "0.0.0.0:2055")]
+    listen: SocketAddr,
+
+    /// JSON maps, or one index from `cloak compile`, to classify addresses
+    /// with (default: every country in the download cache)
+    #[arg(long, value_name = "FILE")]
+    map: Vec<PathBuf>,
+
+    /// Where earlier fetches left their downloads
+    #[arg(long, value_name = "DIR", default_value_os_t = state::default_cache_dir())]
+    cache_dir: PathBuf,
+
+    /// How to print the running totals
+    #[arg(long, value_enum, default_value_t = Report::Table)]
+    report: Report,
+
+    /// How often to print them
+    #[arg(long, value_parser = parse_duration, default_value = "1m")]
+    report_every: Duration,
+
+    /// How many countries the table lists
+    #[arg(long, value_name = "N", default_value_t = 20)]
+    top: usize,
+
+    /// Also serve the totals as Prometheus metrics on this address
+    /// (e.g. 127.0.0.1:9108)
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum Report {
+    Table,
+    /// One JSON object per report
+    Json,
+    None,
+}
+
+/// One flow record's endpoints and counters
+struct Flow {
+    src: IpAddr,
+    dst: IpAddr,
+    packets: u64,
+    bytes: u64,
+}
+
+/// Information elements cloak reads; v9 and IPFIX share the numbers
+const IN_BYTES: u16 = 1;
+const IN_PKTS: u16 = 2;
+const IPV4_SRC_ADDR: u16 = 8;
+const IPV4_DST_ADDR: u16 = 12;
+const OUT_BYTES: u16 = 23;
+const OUT_PKTS: u16 = 24;
+const IPV6_SRC_ADDR: u16 = 27;
+const IPV6_DST_ADDR: u16 = 28;
+
+/// Field lengths of one template, in record order. `None` for an IPFIX
+/// variable-length field.
+struct Template {
+    fields: Vec<(u16, Option<usize>)>,
+}
+
+/// State kept across datagrams: the templates each exporter announced
+#[derive(Default)]
+struct Decoder {
+    /// Keyed by exporter, source ID / observation domain and template ID
+    templates: HashMap<(IpAddr, u32, u16), Template>,
+}
+
+impl Decoder {
+    fn decode(&mut self, exporter: IpAddr, datagram: &[u8], flows: &mut Vec<Flow>) -> Result<()> {
+        let version = be16(datagram, 0).context("datagram too short")?;
+        match version {
+            5 => decode_v5(datagram, flows),
+            9 => {
+                let domain = be32(datagram, 16).context("truncated v9 header")?;
+                self.decode_sets(exporter, domain, &datagram[20..], (0, 1), flows)
+            }
+            10 => {
+                let length = usize::from(be16(datagram, 2).context("truncated IPFIX header")?);
+                let domain = be32(datagram, 12).context("truncated IPFIX header")?;
+                let end = length.min(datagram.len());
+                if end < 16 {
+                    bail!("truncated IPFIX header");
+                }
+                self.decode_sets(exporter, domain, &datagram[16..end], (2, 3), flows)
+            }
+            other => bail!("unsupported flow export version {}", other),
+        }
+    }
+
+    /// Walk the flowsets (v9) or sets (IPFIX) of one message.
+    /// `(template, options)` are the set IDs of the two template kinds.
+    fn decode_sets(
+        &mut self,
+        exporter: IpAddr,
+        domain: u32,
+        mut sets: &[u8],
+        (template_set, options_set): (u16, u16),
+        flows: &mut Vec<Flow>,
+    ) -> Result<()> {
+        let ipfix = template_set == 2;
+        while sets.len() >= 4 {
+            let id = be16(sets, 0).unwrap_or_default();
+            let length = usize::from(be16(sets, 2).unwrap_or_default());
+            if length < 4 || length > sets.len() {
+                bail!("corrupt set length {}", length);
+            }
+            let body = &sets[4..length];
+            if id == template_set {
+                self.read_templates(exporter, domain, body, ipfix)?;
+            } else if id >= 256 {
+                if let Some(template) = self.templates.get(&(exporter, domain, id)) {
+                    decode_records(template, body, flows);
+                }
+            } else if id != options_set {
+                warning!("ignoring set {} from {}", id, exporter);
+            }
+            sets = &sets[length..];
+        }
+        Ok(())
+    }
+
+    fn read_templates(&mut self, exporter: IpAddr, domain: u32, mut body: &[u8], ipfix: bool) -> Result<()> {
+        while body.len() >= 4 {
+            let id = be16(body, 0).unwrap_or_default();
+            let count = usize::from(be16(body, 2).unwrap_or_default());
+            body = &body[4..];
+            let mut fields = Vec::with_capacity(count);
+            for _ in 0..count {
+                let kind = be16(body, 0).context("truncated template")?;
+                let length = be16(body, 2).context("truncated template")?;
+                body = &body[4..];
+                // IPFIX marks enterprise-specific elements with the top bit
+                // and follows them with the enterprise number
+                let kind = if ipfix && kind & 0x8000 != 0 {
+                    body = body.get(4..).context("truncated template")?;
+                    u16::MAX
+                } else {
+                    kind
+                };
+                let length = if ipfix && length == 0xffff { None } else { Some(usize::from(length)) };
+                fields.push((kind, length));
+            }
+            self.templates.insert((exporter, domain, id), Template { fields });
+        }
+        Ok(())
+    }
+}
+
+fn decode_v5(datagram: &[u8], flows: &mut Vec<Flow>) -> Result<()> {
+    let count = usize::from(be16(datagram, 2).context("truncated v5 header")?);
+    for i in 0..count {
+        let Some(record) = datagram.get(24 + i * 48..24 + (i + 1) * 48) else {
+            bail!("v5 datagram announces {} records but holds fewer", count);
+        };
+        let src = Ipv4Addr::new(record[0], record[1], record[2], record[3]);
+        let dst = Ipv4Addr::new(record[4], record[5], record[6], record[7]);
+        flows.push(Flow {
+            src: IpAddr::V4(src),
+            dst: IpAddr::V4(dst),
+            packets: u64::from(be32(record, 16).unwrap_or_default()),
+            bytes: u64::from(be32(record, 20).unwrap_or_default()),
+        });
+    }
+    Ok(())
+}
+
+/// Decode the data records of one set; padding at the end is ignored
+fn decode_records(template: &Template, mut body: &[u8], flows: &mut Vec<Flow>) {
+    loop {
+        let before = body.len();
+        let mut src = None;
+        let mut dst = None;
+        let (mut packets, mut bytes) = (None, None);
+        let (mut out_packets, mut out_bytes) = (None, None);
+        for &(kind, length) in &template.fields {
+            let length = match length {
+                Some(length) => length,
+                None => match body.first() {
+                    Some(255) => {
+                        let Some(long) = be16(body, 1) else { return };
+                        body = &body[3..];
+                        usize::from(long)
+                    }
+                    Some(&short) => {
+                        body = &body[1..];
+                        usize::from(short)
+                    }
+                    None => return,
+                },
+            };
+            let Some(value) = body.get(..length) else {
+                return;
+            };
+            body = &body[length..];
+            match (kind, value.len()) {
+                (IPV4_SRC_ADDR, 4) => src = Some(IpAddr::from(<[u8; 4]>::try_from(value).expect("4 bytes"))),
+                (IPV4_DST_ADDR, 4) => dst = Some(IpAddr::from(<[u8; 4]>::try_from(value).expect("4 bytes"))),
+                (IPV6_SRC_ADDR, 16) => src = Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(value).expect("16 bytes")))),
+                (IPV6_DST_ADDR, 16) => dst = Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(value).expect("16 bytes")))),
+                (IN_BYTES, _) => bytes = Some(counter(value)),
+                (IN_PKTS, _) => packets = Some(counter(value)),
+                (OUT_BYTES, _) => out_bytes = Some(counter(value)),
+                (OUT_PKTS, _) => out_packets = Some(counter(value)),
+                _ => {}
+            }
+        }
+        if let (Some(src), Some(dst)) = (src, dst) {
+            flows.push(Flow {
+                src,
+                dst,
+                packets: packets.or(out_packets).unwrap_or(0),
+                bytes: bytes.or(out_bytes).unwrap_or(0),
+            });
+        }
+        // A template of zero-length fields would never get anywhere
+        if body.is_empty() || body.len() == before {
+            return;
+        }
+    }
+}
+
+/// Unsigned big-endian counter of 1 to 8 bytes
+fn counter(bytes: &[u8]) -> u64 {
+    bytes.iter().take(8).fold(0, |value, &byte| value << 8 | u64::from(byte))
+}
+
+fn be16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+pub async fn run(args: &CollectArgs) -> Result<()> {
+    let index = if args.map.is_empty() {
+        let map = fetch::cached_countries(&args.cache_dir)?;
+        if map.is_empty() {
+            bail!("no country data in {}; pass --map or fetch a list first", args.cache_dir.display());
+        }
+        info!("Using {} countries from {}", map.len(), args.cache_dir.display());
+        Index::build(&map)
+    } else {
+        Index::load(&args.map)?
+    };
+    let socket = UdpSocket::bind(args.listen).await.with_context(|| format!("listen on {}", args.listen))?;
+    let traffic = Arc::new(Mutex::new(CountryTraffic::default()));
+    if let Some(addr) = args.metrics_addr {
+        let listener = TcpListener::bind(addr).await.with_context(|| format!("listen on {}", addr))?;
+        let shared = traffic.clone();
+        tokio::spawn(metrics::serve(listener, move || render_metrics(&shared.lock().expect("traffic lock"))));
+        info!("Serving Prometheus metrics on http://{}/metrics", addr);
+    }
+    info!("Collecting NetFlow/IPFIX on {}", args.listen);
+
+    let mut decoder = Decoder::default();
+    let mut buf = vec![0u8; 65536];
+    let mut flows = Vec::new();
+    let mut tick = tokio::time::interval(args.report_every);
+    tick.tick().await;
+    let shutdown = daemon::shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, from) = received.context("receive flow export")?;
+                flows.clear();
+                if let Err(e) = decoder.decode(from.ip(), &buf[..len], &mut flows) {
+                    warning!("bad datagram from {}: {:#}", from, e);
+                }
+                let mut traffic = traffic.lock().expect("traffic lock");
+                for flow in &flows {
+                    traffic.record(&index, flow.src, flow.dst, flow.packets, flow.bytes);
+                }
+            }
+            _ = tick.tick() => report(&traffic.lock().expect("traffic lock"), args),
+            _ = &mut shutdown => {
+                report(&traffic.lock().expect("traffic lock"), args);
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn report(traffic: &CountryTraffic, args: &CollectArgs) {
+    match args.report {
+        Report::Table => {
+            println!();
+            traffic.print_table(args.top);
+        }
+        Report::Json => match serde_json::to_string(traffic) {
+            Ok(json) => println!("{}", json),
+            Err(e) => warning!("could not encode the report: {}", e),
+        },
+        Report::None => {}
+    }
+}
+
+/// The totals in the Prometheus text format
+fn render_metrics(traffic: &CountryTraffic) -> String {
+    type Counter = fn(&Traffic) -> u64;
+    let counters: [(&str, &str, Counter); 2] = [
+        ("bytes", "Bytes in flows with an endpoint in the country", |t| t.bytes),
+        ("packets", "Packets in flows with an endpoint in the country", |t| t.packets),
+    ];
+    let mut out = String::new();
+    for (name, help, value) in counters {
+        metrics::header(&mut out, &format!("cloak_country_{}_total", name), "counter", help);
+        for (cc, counts) in &traffic.countries {
+            out.push_str(&format!("cloak_country_{}_total{{country=\"{}\"}} {}\n", name, cc, value(counts)));
+        }
+        let unmatched = format!("Flow {} with no endpoint in a known country", name);
+        metrics::header(&mut out, &format!("cloak_unmatched_{}_total", name), "counter", &unmatched);
+        out.push_str(&format!("cloak_unmatched_{}_total {}\n", name, value(&traffic.unmatched)));
+    }
+    out
+}
@@ -0,0 +1,1173 @@
+//! nftables ruleset generation and loading.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Write},
+    fs,
+    io::Write as _,
+    net::IpAddr,
+    path::Path,
+    process::Stdio,
+};
+
+use anyhow::{bail, Context, Result};
+use ipnetwork::IpNetwork;
+
+use crate::{
+    cidr, privilege, temp,
+    ui::warning,
+    Action, CountryNets, Direction, RuleArgs,
+};
+
+/// Name of the `inet` table holding everything cloak generates
+pub const TABLE: &str = "cloak";
+
+/// Name of the `netdev` table with the hardware-offloaded drops of
+/// `--offload`
+pub const OFFLOAD_TABLE: &str = "cloak_offload";
+
+/// Name of the `bridge` table filtering frames bridged to VMs, with
+/// `--bridge`
+pub const BRIDGE_TABLE: &str = "cloak_bridge";
+
+/// Prefix of the kernel log lines for packets cloak's rules drop
+pub const LOG_PREFIX: &str = "cloak-drop ";
+
+/// Prefix of the kernel log lines for packets `--action mark` rules mark
+const MARK_LOG_PREFIX: &str = "cloak-mark ";
+
+/// Start of the names of the per-country drop counters
+const COUNTER_PREFIX: &str = "drop_";
+
+/// Marks the rule comment carrying the ruleset fingerprint
+const FINGERPRINT_PREFIX: &str = "cloak:";
+
+/// Daily time window, in the firewall's local time, during which the
+/// country rules apply; it may wrap past midnight (`22:00-06:00`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    start: (u8, u8),
+    end: (u8, u8),
+}
+
+/// Parse `HH:MM-HH:MM` for `--schedule`.
+pub fn parse_schedule(text: &str) -> Result<Schedule, String> {
+    let clock = |part: &str| -> Option<(u8, u8)> {
+        let (hour, minute) = part.trim().split_once(':')?;
+        let (hour, minute) = (hour.parse::<u8>().ok()?, minute.parse::<u8>().ok()?);
+        (hour < 24 && minute < 60).then_some((hour, minute))
+    };
+    let invalid = || format!("invalid schedule `{}` (expected e.g. 22:00-06:00)", text);
+    let (start, end) = text.split_once('-').ok_or_else(invalid)?;
+    let schedule = Schedule { start: clock(start).ok_or_else(invalid)?, end: clock(end).ok_or_else(invalid)? };
+    if schedule.start == schedule.end {
+        return Err(format!("schedule `{}` is empty; leave out --schedule to apply the rules all day", text));
+    }
+    Ok(schedule)
+}
+
+/// DSCP code point `--action mark` sets
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dscp(u8);
+
+/// Class names nft knows; `le` (1) is written as its number, as older nft
+/// releases lack the name
+const DSCP_CLASSES: [(&str, u8); 21] = [
+    ("cs0", 0), ("cs1", 8), ("cs2", 16), ("cs3", 24), ("cs4", 32), ("cs5", 40), ("cs6", 48), ("cs7", 56),
+    ("af11", 10), ("af12", 12), ("af13", 14), ("af21", 18), ("af22", 20), ("af23", 22),
+    ("af31", 26), ("af32", 28), ("af33", 30), ("af41", 34), ("af42", 36), ("af43", 38), ("ef", 46),
+];
+
+/// Parse a class name (`cs1`, `af11`, `ef`, `le`) or a code point 0-63
+/// for `--dscp`.
+pub fn parse_dscp(text: &str) -> Result<Dscp, String> {
+    let name = text.to_ascii_lowercase();
+    if name == "le" {
+        return Ok(Dscp(1));
+    }
+    if let Some(&(_, value)) = DSCP_CLASSES.iter().find(|(class, _)| *class == name) {
+        return Ok(Dscp(value));
+    }
+    match text.parse::<u8>() {
+        Ok(value) if value < 64 => Ok(Dscp(value)),
+        _ => Err(format!("invalid DSCP `{}` (expected a class such as le, cs1 or af11, or 0-63)", text)),
+    }
+}
+
+impl fmt::Display for Dscp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match DSCP_CLASSES.iter().find(|(_, value)| *value == self.0) {
+            Some((class, _)) => write!(f, "{}", class),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// tc class `--action mark` sets as the packet priority
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Priority(u16, u16);
+
+/// Parse `MAJOR:MINOR` (hexadecimal, as tc writes them) for `--priority`.
+pub fn parse_priority(text: &str) -> Result<Priority, String> {
+    let invalid = || format!("invalid priority `{}` (expected a tc class such as 1:10)", text);
+    let (major, minor) = text.split_once(':').ok_or_else(invalid)?;
+    let major = u16::from_str_radix(major, 16).map_err(|_| invalid())?;
+    let minor = u16::from_str_radix(minor, 16).map_err(|_| invalid())?;
+    Ok(Priority(major, minor))
+}
+
+/// Network device for `--offload` or `--bridge`; a fixed-size copy of the name, as the
+/// kernel limits them to 15 bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Device {
+    name: [u8; 15],
+    len: u8,
+}
+
+/// Parse an interface name for `--offload` or `--bridge`.
+pub fn parse_device(text: &str) -> Result<Device, String> {
+    let valid = !text.is_empty() && text.len() <= 15 && !text.contains(|c: char| c.is_whitespace() || c == '/' || c == '"');
+    if !valid {
+        return Err(format!("`{}` is not a network device name", text));
+    }
+    let mut name = [0; 15];
+    name[..text.len()].copy_from_slice(text.as_bytes());
+    Ok(Device { name, len: text.len() as u8 })
+}
+
+impl Device {
+    pub fn name(&self) -> &str {
+        std::str::from_utf8(&self.name[..usize::from(self.len)]).expect("parsed from a str")
+    }
+}
+
+/// The statements marking a packet of `family` (`ip` or `ip6`)
+fn mark_statements(rules: RuleArgs, family: &str) -> Result<String> {
+    let mut marks = Vec::new();
+    if let Some(dscp) = rules.dscp {
+        marks.push(format!("{} dscp set {}", family, dscp));
+    }
+    if let Some(Priority(major, minor)) = rules.priority {
+        marks.push(format!("meta priority set {:x}:{:x}", major, minor));
+    }
+    if marks.is_empty() {
+        bail!("--action mark needs --dscp, --priority or both");
+    }
+    Ok(marks.join(" "))
+}
+
+/// Write the ruleset for `map` to `filename` and return its fingerprint.
+/// Peers in `whitelist` are accepted before any country rule applies;
+/// `rules` picks the hooks and an optional time window.
+///
+/// The fingerprint is a hash of the ruleset content and is also embedded as
+/// a comment on the chain's final rule, so the kernel's copy can later be
+/// checked against what cloak generated (see [`live_fingerprint`]).
+pub fn generate_nftables(
+    map: &HashMap<String, CountryNets>,
+    action: Action,
+    whitelist: &[IpNetwork],
+    rules: RuleArgs,
+    filename: &str,
+) -> Result<String> {
+    // The whitelist has to cover the mapped forms too, or those peers
+    // would meet the country rules
+    let mut whitelist = whitelist.to_vec();
+    if rules.mapped_v6 {
+        whitelist.extend(mapped(whitelist.iter()));
+    }
+    let whitelist = &whitelist[..];
+    if rules.sets_only {
+        let sets = render_sets(map, action, whitelist, rules)?;
+        fs::write(filename, &sets).with_context(|| format!("write {}", filename))?;
+        return Ok(hash_hex(sets.as_bytes()));
+    }
+    if let Some(device) = rules.offload {
+        probe_offload(device);
+    }
+    if let Some(port) = rules.bridge {
+        probe_bridge(port);
+    }
+    let fingerprint = hash_hex(render(map, action, whitelist, rules, None)?.as_bytes());
+    let ruleset = render(map, action, whitelist, rules, Some(&fingerprint))?;
+    fs::write(filename, ruleset).with_context(|| format!("write {}", filename))?;
+    Ok(fingerprint)
+}
+
+fn render(
+    map: &HashMap<String, CountryNets>,
+    action: Action,
+    whitelist: &[IpNetwork],
+    rules: RuleArgs,
+    fingerprint: Option<&str>,
+) -> Result<String> {
+    let mut file = String::new();
+
+    // Output must be byte-for-byte stable for unchanged data so the
+    // fingerprint can detect "nothing to do"
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+
+    write_preamble(&mut file)?;
+
+    // Small territories can have no prefixes of a family at all; their
+    // sets and rules are left out rather than matching nothing
+    let (has_ipv4, has_ipv6) = if rules.country_counters {
+        write_country_sets(&mut file, map, rules)?
+    } else {
+        write_combined_sets(&mut file, map, rules)?
+    };
+    let families: Vec<(&str, &str)> =
+        [("ip", "ipv4", has_ipv4), ("ip6", "ipv6", has_ipv6)].into_iter().filter(|(_, _, any)| *any).map(|(family, version, _)| (family, version)).collect();
+
+    let whitelisted = write_whitelist_sets(&mut file, whitelist)?;
+    write_temp_sets(&mut file)?;
+    let comment = fingerprint
+        .map(|f| format!(" comment \"{}{}\"", FINGERPRINT_PREFIX, f))
+        .unwrap_or_default();
+    // nft reads `meta hour` in local time and handles windows spanning
+    // midnight itself
+    let when = rules
+        .schedule
+        .map(|s| format!("meta hour \"{:02}:{:02}\"-\"{:02}:{:02}\" ", s.start.0, s.start.1, s.end.0, s.end.1))
+        .unwrap_or_default();
+    // Named counters replace the anonymous one in the drop rules
+    let mut log = if rules.counters && !rules.country_counters { "counter ".to_string() } else { String::new() };
+    if rules.log {
+        log.push_str(&format!("log prefix \"{}\" ", if action == Action::Mark { MARK_LOG_PREFIX } else { LOG_PREFIX }));
+    }
+    // Marks are for QoS further along, so they go on forwarded traffic too
+    let (ingress, egress) = match action {
+        Action::Mark => ("prerouting", "postrouting"),
+        _ => ("input", "output"),
+    };
+    let mut hooks = Vec::new();
+    if rules.direction != Direction::Out {
+        hooks.push((ingress, "saddr"));
+    }
+    if rules.direction != Direction::In {
+        hooks.push((egress, "daddr"));
+    }
+    // Connections to published container ports are DNATed in prerouting
+    // and forwarded, so the input chain never sees them. Every base chain
+    // on a hook runs, so an accept in netavark's, Docker's or firewalld's
+    // own forward chain does not save a packet this one drops.
+    if rules.containers {
+        hooks.push(("forward", "saddr"));
+    }
+    for (i, (hook, field)) in hooks.into_iter().enumerate() {
+        if i > 0 {
+            writeln!(file, "  }}")?;
+        }
+        open_chain(&mut file, hook, field, whitelisted)?;
+        write_temp_rules(&mut file, field)?;
+        // Only published ports; other forwarded traffic is left to the
+        // container stack. Allow rules only decide on new connections so
+        // containers' own outbound traffic keeps flowing.
+        let scope = match (hook, action) {
+            ("forward", Action::Allow) => "ct state new ct status dnat ",
+            ("forward", _) => "ct status dnat ",
+            _ => "",
+        };
+        match (action, rules.schedule) {
+            (Action::Block, _) if rules.country_counters => {
+                for cc in &codes {
+                    let name = set_name(cc);
+                    for &(family, version) in &families {
+                        if country_nets(&map[*cc], version, rules).is_empty() {
+                            continue;
+                        }
+                        writeln!(
+                            file,
+                            "    {}{}{} {} @country_{}_{} {}counter name \"{}{}_{}\" drop;",
+                            scope, when, family, field, name, version, log, COUNTER_PREFIX, name, version
+                        )?;
+                    }
+                }
+                writeln!(file, "    accept{};", comment)?;
+            }
+            (Action::Block, _) => {
+                for (family, version) in &families {
+                    writeln!(file, "    {}{}{} {} @country_{} {}drop;", scope, when, family, field, version, log)?;
+                }
+                writeln!(file, "    accept{};", comment)?;
+            }
+            (Action::Allow, None) => {
+                for (family, version) in &families {
+                    writeln!(file, "    {}{} {} @country_{} accept;", scope, family, field, version)?;
+                }
+                writeln!(file, "    {}{}drop{};", scope, log, comment)?;
+            }
+            // Outside the window everything is let through
+            (Action::Allow, Some(_)) => {
+                for (family, version) in &families {
+                    writeln!(file, "    {}{} {} @country_{} accept;", scope, family, field, version)?;
+                }
+                writeln!(file, "    {}{}{}drop;", scope, when, log)?;
+                writeln!(file, "    accept{};", comment)?;
+            }
+            (Action::Mark, _) => {
+                for (family, version) in &families {
+                    writeln!(file, "    {}{} {} @country_{} {}{};", when, family, field, version, log, mark_statements(rules, family)?)?;
+                }
+                writeln!(file, "    accept{};", comment)?;
+            }
+        }
+    }
+
+    writeln!(file, "  }}")?;
+    writeln!(file, "}}")?;
+    if let Some(device) = rules.offload {
+        write_offload(&mut file, map, action, whitelist, device, &comment)?;
+    }
+    if let Some(port) = rules.bridge {
+        write_bridge(&mut file, map, action, whitelist, rules, port, (&when, &log, &comment))?;
+    }
+    Ok(file)
+}
+
+/// The `--bridge` table. Frames a bridge passes between its ports, such as
+/// traffic to VMs on a Proxmox or libvirt host, take the bridge family's
+/// forward hook and never the inet input hook, so the policy is repeated
+/// there for frames crossing `port`, the uplink. Traffic between the VMs
+/// does not cross it. Expiring entries (see [`crate::temp`]) only apply in
+/// the inet table. The final rule carries the fingerprint like the inet
+/// table's, for drift detection.
+fn write_bridge(
+    file: &mut String,
+    map: &HashMap<String, CountryNets>,
+    action: Action,
+    whitelist: &[IpNetwork],
+    rules: RuleArgs,
+    port: Device,
+    (when, log, comment): (&str, &str, &str),
+) -> Result<()> {
+    writeln!(file)?;
+    writeln!(file, "table bridge {}", BRIDGE_TABLE)?;
+    writeln!(file, "delete table bridge {}", BRIDGE_TABLE)?;
+    writeln!(file)?;
+    writeln!(file, "table bridge {} {{", BRIDGE_TABLE)?;
+    let (has_ipv4, has_ipv6) = write_combined_sets(file, map, rules)?;
+    let (whitelist_v4, whitelist_v6) = write_whitelist_sets(file, whitelist)?;
+    writeln!(file, "  chain forward {{")?;
+    writeln!(file, "    type filter hook forward priority 0;")?;
+    let mut sides = Vec::new();
+    if rules.direction != Direction::Out {
+        sides.push(("iifname", "saddr"));
+    }
+    if rules.direction != Direction::In {
+        sides.push(("oifname", "daddr"));
+    }
+    for (side, field) in sides {
+        let scope = format!("{} \"{}\" ", side, port.name());
+        let families = [("ip", "ipv4", has_ipv4, whitelist_v4), ("ip6", "ipv6", has_ipv6, whitelist_v6)];
+        for (family, version, _, whitelisted) in families {
+            if whitelisted {
+                writeln!(file, "    {}{} {} @whitelist_{} accept;", scope, family, field, version)?;
+            }
+        }
+        for (family, version, present, _) in families {
+            if !present {
+                continue;
+            }
+            match action {
+                Action::Allow => writeln!(file, "    {}{} {} @country_{} accept;", scope, family, field, version)?,
+                _ => writeln!(file, "    {}{}{} {} @country_{} {}drop;", scope, when, family, field, version, log)?,
+            }
+        }
+        // ARP and other non-IP frames are none of the policy's business
+        if action == Action::Allow {
+            writeln!(file, "    {}{}meta protocol {{ ip, ip6 }} {}drop;", scope, when, log)?;
+        }
+    }
+    writeln!(file, "    accept{};", comment)?;
+    writeln!(file, "  }}")?;
+    writeln!(file, "}}")?;
+    Ok(())
+}
+
+/// Warn when `port` is not a bridge port on this host, where the rules
+/// may well not be meant to run, so this never fails
+fn probe_bridge(port: Device) {
+    let name = port.name();
+    if cfg!(target_os = "linux") && !Path::new("/sys/class/net").join(name).join("brport").exists() {
+        warning!("{} is not a bridge port on this host; --bridge takes the port facing the outside, e.g. eno1 in vmbr0", name);
+    }
+}
+
+/// The `--offload` table: an ingress chain on `device` that the NIC runs
+/// itself. Set lookups cannot be offloaded, so every prefix is a rule of
+/// its own, and expiring entries (see [`crate::temp`]) are not seen there.
+/// The last rule carries the fingerprint `comment`.
+fn write_offload(
+    file: &mut String,
+    map: &HashMap<String, CountryNets>,
+    action: Action,
+    whitelist: &[IpNetwork],
+    device: Device,
+    comment: &str,
+) -> Result<()> {
+    // A --source list can repeat prefixes of the fetched countries; one
+    // rule covers them
+    let all: Vec<IpNetwork> = map.values().flat_map(|nets| nets.ipv4.iter().chain(&nets.ipv6).map(|net| net.0)).collect();
+    writeln!(file)?;
+    writeln!(file, "table netdev {}", OFFLOAD_TABLE)?;
+    writeln!(file, "delete table netdev {}", OFFLOAD_TABLE)?;
+    writeln!(file)?;
+    writeln!(file, "table netdev {} {{", OFFLOAD_TABLE)?;
+    writeln!(file, "  chain ingress {{")?;
+    writeln!(file, "    type filter hook ingress device \"{}\" priority -500; flags offload;", device.name())?;
+    let mut lines: Vec<String> =
+        whitelist.iter().map(|net| format!("{} saddr {} accept", if net.is_ipv4() { "ip" } else { "ip6" }, net)).collect();
+    let verdict = if action == Action::Allow { "accept" } else { "drop" };
+    for net in cidr::aggregate(&all) {
+        lines.push(format!("{} saddr {} {}", if net.is_ipv4() { "ip" } else { "ip6" }, net, verdict));
+    }
+    if action == Action::Allow {
+        lines.push("meta protocol { ip, ip6 } drop".to_string());
+    }
+    // The fingerprint needs a rule to go on
+    if lines.is_empty() {
+        lines.push("accept".to_string());
+    }
+    let last = lines.len() - 1;
+    for (i, line) in lines.iter().enumerate() {
+        writeln!(file, "    {}{};", line, if i == last { comment } else { "" })?;
+    }
+    writeln!(file, "  }}")?;
+    writeln!(file, "}}")?;
+    Ok(())
+}
+
+/// Warn when `device` cannot run the `--offload` chain here: it does not
+/// exist, or its driver has TC offload switched off. The rules may well be
+/// meant for another host, so this never fails.
+pub fn probe_offload(device: Device) {
+    let name = device.name();
+    if !cfg!(target_os = "linux") {
+        return;
+    }
+    if !Path::new("/sys/class/net").join(name).exists() {
+        warning!("there is no device {} on this host; nft refuses --offload rules for a missing device", name);
+        return;
+    }
+    let output = match std::process::Command::new("ethtool").args(["-k", name]).output() {
+        Ok(output) if output.status.success() => output,
+        _ => {
+            warning!("could not check whether {} supports offload (is ethtool installed?)", name);
+            return;
+        }
+    };
+    let features = String::from_utf8_lossy(&output.stdout);
+    let offload = features.lines().find_map(|line| line.trim().strip_prefix("hw-tc-offload:")).map(str::trim);
+    match offload {
+        Some(state) if state.starts_with("on") => {}
+        Some(state) if state.contains("fixed") => {
+            warning!("{} cannot offload filters (hw-tc-offload: {}); nft will refuse the --offload chain", name, state)
+        }
+        _ => warning!("{} has hw-tc-offload off; enable it with: ethtool -K {} hw-tc-offload on", name, name),
+    }
+}
+
+/// The prefixes of one family (`ipv4`, `ipv6`) of a country as they go
+/// into its sets
+fn country_nets(nets: &CountryNets, version: &str, rules: RuleArgs) -> Vec<IpNetwork> {
+    match version {
+        "ipv4" => nets.ipv4.iter().map(|net| net.0).collect(),
+        _ => ipv6_nets(nets, rules),
+    }
+}
+
+/// The two sets holding every country's prefixes, leaving out a family
+/// that has none (nft rejects an empty element list); returns which were
+/// written. Interval sets refuse overlapping elements, which countries of
+/// overlapping groups and `--source` lists repeating fetched prefixes
+/// share, so the prefixes are aggregated first.
+fn write_combined_sets(file: &mut String, map: &HashMap<String, CountryNets>, rules: RuleArgs) -> Result<(bool, bool)> {
+    let mut written = [false; 2];
+    for (i, (version, kind)) in [("ipv4", "ipv4_addr"), ("ipv6", "ipv6_addr")].into_iter().enumerate() {
+        let all: Vec<IpNetwork> = map.values().flat_map(|nets| country_nets(nets, version, rules)).collect();
+        let nets = cidr::aggregate(&all);
+        if nets.is_empty() {
+            continue;
+        }
+        writeln!(file, "  set country_{} {{ type {}; flags interval; elements = {{", version, kind)?;
+        for net in nets {
+            writeln!(file, "    {},", net)?;
+        }
+        writeln!(file, "  }} }}")?;
+        written[i] = true;
+    }
+    Ok((written[0], written[1]))
+}
+
+/// A pair of sets and a pair of named drop counters per country, for
+/// `--country-counters`; a family a country has no prefixes of gets only
+/// its counter. Returns whether any country had each family.
+fn write_country_sets(file: &mut String, map: &HashMap<String, CountryNets>, rules: RuleArgs) -> Result<(bool, bool)> {
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    let mut written = [false; 2];
+    for cc in codes {
+        let name = set_name(cc);
+        for (i, (family, kind)) in [("ipv4", "ipv4_addr"), ("ipv6", "ipv6_addr")].into_iter().enumerate() {
+            writeln!(file, "  counter {}{}_{} {{ packets 0 bytes 0 }}", COUNTER_PREFIX, name, family)?;
+            let nets = country_nets(&map[cc], family, rules);
+            if nets.is_empty() {
+                continue;
+            }
+            writeln!(file, "  set country_{}_{} {{ type {}; flags interval; elements = {{", name, family, kind)?;
+            for net in nets {
+                writeln!(file, "    {},", net)?;
+            }
+            writeln!(file, "  }} }}")?;
+            written[i] = true;
+        }
+    }
+    Ok((written[0], written[1]))
+}
+
+/// `cc` as it appears in set and counter names
+pub fn set_name(cc: &str) -> String {
+    cc.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect()
+}
+
+/// Only the set declarations, one pair per country, with no table around
+/// them, for `include` inside a table the user writes. `geoip_map` adds
+/// verdict maps sending every prefix to the action's verdict.
+fn render_sets(map: &HashMap<String, CountryNets>, action: Action, whitelist: &[IpNetwork], rules: RuleArgs) -> Result<String> {
+    let mut file = String::new();
+    writeln!(file, "# Country sets generated by cloak {}; include this file inside a table", env!("CARGO_PKG_VERSION"))?;
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    for cc in &codes {
+        let name = set_name(cc);
+        let ipv4: Vec<IpNetwork> = map[*cc].ipv4.iter().map(|net| net.0).collect();
+        for (family, kind, nets) in [("ipv4", "ipv4_addr", ipv4), ("ipv6", "ipv6_addr", ipv6_nets(&map[*cc], rules))] {
+            // nft rejects an empty element list, but an empty set is fine
+            if nets.is_empty() {
+                writeln!(file, "set country_{}_{} {{ type {}; flags interval; }}", name, family, kind)?;
+                continue;
+            }
+            writeln!(file, "set country_{}_{} {{ type {}; flags interval; elements = {{", name, family, kind)?;
+            for net in nets {
+                writeln!(file, "  {},", net)?;
+            }
+            writeln!(file, "}} }}")?;
+        }
+    }
+    let mut sets = String::new();
+    write_whitelist_sets(&mut sets, whitelist)?;
+    for line in sets.lines() {
+        writeln!(file, "{}", line.strip_prefix("  ").unwrap_or(line))?;
+    }
+
+    if rules.geoip_map {
+        let verdict = match action {
+            Action::Block => "drop",
+            Action::Allow => "accept",
+            Action::Mark => bail!("--geoip-map needs --action block or allow"),
+        };
+        // Interval maps refuse overlapping keys, which prefixes listed
+        // under two countries would be
+        let all: Vec<IpNetwork> = map.values().flat_map(|nets| nets.ipv4.iter().map(|net| net.0).chain(ipv6_nets(nets, rules))).collect();
+        let all = cidr::aggregate(&all);
+        let (v4, v6): (Vec<IpNetwork>, Vec<IpNetwork>) = all.into_iter().partition(|net| net.is_ipv4());
+        for (family, kind, nets) in [("ipv4", "ipv4_addr", v4), ("ipv6", "ipv6_addr", v6)] {
+            if nets.is_empty() {
+                writeln!(file, "map geoip_{} {{ type {} : verdict; flags interval; }}", family, kind)?;
+                continue;
+            }
+            writeln!(file, "map geoip_{} {{ type {} : verdict; flags interval; elements = {{", family, kind)?;
+            for net in nets {
+                writeln!(file, "  {} : {},", net, verdict)?;
+            }
+            writeln!(file, "}} }}")?;
+        }
+    }
+    Ok(file)
+}
+
+/// The IPv4-mapped IPv6 form of the IPv4 prefixes among `nets`
+fn mapped<'a>(nets: impl IntoIterator<Item = &'a IpNetwork>) -> Vec<IpNetwork> {
+    nets.into_iter()
+        .filter_map(|net| match net {
+            IpNetwork::V4(v4) => IpNetwork::new(IpAddr::V6(v4.network().to_ipv6_mapped()), 96 + v4.prefix()).ok(),
+            IpNetwork::V6(_) => None,
+        })
+        .collect()
+}
+
+/// A country's IPv6 prefixes, followed with `--mapped-v6` by its IPv4
+/// prefixes in mapped form
+fn ipv6_nets(nets: &CountryNets, rules: RuleArgs) -> Vec<IpNetwork> {
+    let mut out: Vec<IpNetwork> = nets.ipv6.iter().map(|net| net.0).collect();
+    if rules.mapped_v6 {
+        out.extend(mapped(nets.ipv4.iter().map(|net| &net.0)));
+    }
+    out
+}
+
+/// One policy of a layered ruleset: traffic in its scope (interface and
+/// destination ports, both optional) is blocked from, or only allowed
+/// from, its prefixes.
+pub struct Layer {
+    pub action: Action,
+    pub interface: Option<String>,
+    pub ports: Vec<u16>,
+    pub ipv4: Vec<IpNetwork>,
+    pub ipv6: Vec<IpNetwork>,
+}
+
+/// A group of interfaces, or of internal subnets on a router, sharing one
+/// ordered list of layers. A zone with neither applies to all input traffic.
+///
+/// Subnet zones filter forwarded traffic: their layers match the remote
+/// end, i.e. the destination of packets leaving the subnet.
+pub struct Zone {
+    pub name: String,
+    pub interfaces: Vec<String>,
+    pub subnets: Vec<IpNetwork>,
+    pub layers: Vec<Layer>,
+}
+
+impl Zone {
+    fn covers_all(&self) -> bool {
+        self.interfaces.is_empty() && self.subnets.is_empty()
+    }
+
+    /// Address matched against the layers' sets
+    fn field(&self) -> &'static str {
+        if self.subnets.is_empty() {
+            "saddr"
+        } else {
+            "daddr"
+        }
+    }
+}
+
+/// Write a ruleset combining several policies and return its fingerprint.
+/// Each zone gets its own chain, entered from the input chain by interface
+/// or from the forward chain by source subnet; within a zone the layers are matched in order and the first one whose
+/// scope and action apply decides. Traffic nothing claims is accepted.
+pub fn generate_layered(zones: &[Zone], filename: &str) -> Result<String> {
+    let fingerprint = hash_hex(render_layered(zones, None)?.as_bytes());
+    let ruleset = render_layered(zones, Some(&fingerprint))?;
+    fs::write(filename, ruleset).with_context(|| format!("write {}", filename))?;
+    Ok(fingerprint)
+}
+
+fn render_layered(zones: &[Zone], fingerprint: Option<&str>) -> Result<String> {
+    let mut file = String::new();
+    write_preamble(&mut file)?;
+    for zone in zones {
+        for (i, layer) in zone.layers.iter().enumerate() {
+            for (family, kind, nets) in [("ipv4", "ipv4_addr", &layer.ipv4), ("ipv6", "ipv6_addr", &layer.ipv6)] {
+                // nft rejects empty element lists, so families without
+                // prefixes get neither a set nor a rule
+                if nets.is_empty() {
+                    continue;
+                }
+                let mut nets = nets.clone();
+                nets.sort_by_key(|net| (net.network(), net.prefix()));
+                nets.dedup();
+                writeln!(
+                    file,
+                    "  set {}_{} {{ type {}; flags interval; elements = {{",
+                    set_prefix(zone, i),
+                    family,
+                    kind
+                )?;
+                for net in &nets {
+                    writeln!(file, "    {},", net)?;
+                }
+                writeln!(file, "  }} }}")?;
+            }
+        }
+    }
+
+    write_temp_sets(&mut file)?;
+
+    // Zone chains come first so the jumps below refer to existing chains
+    for zone in zones.iter().filter(|zone| !zone.covers_all()) {
+        writeln!(file, "  chain zone_{} {{", zone.name)?;
+        write_layer_rules(&mut file, zone)?;
+        writeln!(file, "  }}")?;
+    }
+
+    let routed: Vec<&Zone> = zones.iter().filter(|zone| !zone.subnets.is_empty()).collect();
+    if !routed.is_empty() {
+        open_chain(&mut file, "forward", "saddr", (false, false))?;
+        write_temp_rules(&mut file, "saddr")?;
+        write_temp_rules(&mut file, "daddr")?;
+        for zone in routed {
+            let (v4, v6): (Vec<&IpNetwork>, Vec<&IpNetwork>) = zone.subnets.iter().partition(|net| net.is_ipv4());
+            for (family, nets) in [("ip", v4), ("ip6", v6)] {
+                if nets.is_empty() {
+                    continue;
+                }
+                let nets: Vec<String> = nets.iter().map(|net| net.to_string()).collect();
+                writeln!(file, "    {} saddr {{ {} }} jump zone_{};", family, nets.join(", "), zone.name)?;
+            }
+        }
+        writeln!(file, "  }}")?;
+    }
+
+    open_chain(&mut file, "input", "saddr", (false, false))?;
+    write_temp_rules(&mut file, "saddr")?;
+    for zone in zones.iter().filter(|zone| zone.subnets.is_empty()) {
+        match zone.interfaces.as_slice() {
+            [] => write_layer_rules(&mut file, zone)?,
+            [one] => writeln!(file, "    iifname \"{}\" jump zone_{};", one, zone.name)?,
+            many => {
+                let names: Vec<String> = many.iter().map(|name| format!("\"{}\"", name)).collect();
+                writeln!(file, "    iifname {{ {} }} jump zone_{};", names.join(", "), zone.name)?;
+            }
+        }
+    }
+
+    let comment = fingerprint
+        .map(|f| format!(" comment \"{}{}\"", FINGERPRINT_PREFIX, f))
+        .unwrap_or_default();
+    writeln!(file, "    accept{};", comment)?;
+    writeln!(file, "  }}")?;
+    writeln!(file, "}}")?;
+    Ok(file)
+}
+
+/// `layer0` for the layers of a zone covering everything, `wan_0` for the
+/// first layer of zone `wan`
+fn set_prefix(zone: &Zone, layer: usize) -> String {
+    if zone.covers_all() {
+        format!("layer{}", layer)
+    } else {
+        format!("{}_{}", zone.name, layer)
+    }
+}
+
+fn write_layer_rules(file: &mut String, zone: &Zone) -> Result<()> {
+    for (i, layer) in zone.layers.iter().enumerate() {
+        let mut scope = String::new();
+        if let Some(interface) = &layer.interface {
+            write!(scope, "iifname \"{}\" ", interface)?;
+        }
+        if !layer.ports.is_empty() {
+            let ports: Vec<String> = layer.ports.iter().map(u16::to_string).collect();
+            write!(scope, "meta l4proto {{ tcp, udp }} th dport {{ {} }} ", ports.join(", "))?;
+        }
+        let verdict = match layer.action {
+            Action::Block => "drop",
+            Action::Allow => "accept",
+            Action::Mark => bail!("profile layers cannot mark traffic; use block or allow"),
+        };
+        let set = set_prefix(zone, i);
+        if !layer.ipv4.is_empty() {
+            writeln!(file, "    {}ip {} @{}_ipv4 {};", scope, zone.field(), set, verdict)?;
+        }
+        if !layer.ipv6.is_empty() {
+            writeln!(file, "    {}ip6 {} @{}_ipv6 {};", scope, zone.field(), set, verdict)?;
+        }
+        if layer.action == Action::Allow {
+            writeln!(file, "    {}drop;", scope)?;
+        }
+    }
+    Ok(())
+}
+
+/// A standalone host policy: loopback and established traffic, SSH from
+/// `ssh_from` only, drops for known-bad prefixes, and new connections from
+/// the home prefixes accepted while the rest is rate limited or dropped.
+pub struct Hardened {
+    pub home: Vec<IpNetwork>,
+    pub bad: Vec<IpNetwork>,
+    pub ssh_from: Vec<IpNetwork>,
+    pub ssh_port: u16,
+    /// nft rate (`25/second`) at which other new connections are let in;
+    /// `None` drops them all
+    pub rest_rate: Option<String>,
+}
+
+/// Write the ruleset for `policy` to `filename` and return its fingerprint
+pub fn generate_hardened(policy: &Hardened, filename: &str) -> Result<String> {
+    let fingerprint = hash_hex(render_hardened(policy, None)?.as_bytes());
+    let ruleset = render_hardened(policy, Some(&fingerprint))?;
+    fs::write(filename, ruleset).with_context(|| format!("write {}", filename))?;
+    Ok(fingerprint)
+}
+
+fn render_hardened(policy: &Hardened, fingerprint: Option<&str>) -> Result<String> {
+    let mut file = String::new();
+    write_preamble(&mut file)?;
+    let mut declared = HashSet::new();
+    for (name, nets) in [("ssh", &policy.ssh_from), ("bad", &policy.bad), ("home", &policy.home)] {
+        // Drop lists overlap, and nft refuses overlapping intervals
+        let (v4, v6): (Vec<IpNetwork>, Vec<IpNetwork>) = cidr::aggregate(nets).into_iter().partition(|net| net.is_ipv4());
+        for (family, kind, nets) in [("ipv4", "ipv4_addr", v4), ("ipv6", "ipv6_addr", v6)] {
+            // nft rejects empty element lists
+            if nets.is_empty() {
+                continue;
+            }
+            writeln!(file, "  set {}_{} {{ type {}; flags interval; elements = {{", name, family, kind)?;
+            for net in &nets {
+                writeln!(file, "    {},", net)?;
+            }
+            writeln!(file, "  }} }}")?;
+            declared.insert((name, family));
+        }
+    }
+    write_temp_sets(&mut file)?;
+
+    let matches = |name: &'static str, rest: &str| -> Vec<String> {
+        [("ipv4", "ip"), ("ipv6", "ip6")]
+            .into_iter()
+            .filter(|(family, _)| declared.contains(&(name, *family)))
+            .map(|(family, ip)| format!("    {} saddr @{}_{} {};", ip, name, family, rest))
+            .collect()
+    };
+    open_chain(&mut file, "input", "saddr", (false, false))?;
+    writeln!(file, "    iif \"lo\" accept;")?;
+    writeln!(file, "    ct state established,related accept;")?;
+    writeln!(file, "    ct state invalid drop;")?;
+    // IPv6 stops working without neighbour discovery
+    writeln!(file, "    icmpv6 type {{ nd-neighbor-solicit, nd-neighbor-advert, nd-router-solicit, nd-router-advert }} accept;")?;
+    for rule in matches("ssh", &format!("tcp dport {} accept", policy.ssh_port)) {
+        writeln!(file, "{}", rule)?;
+    }
+    write_temp_rules(&mut file, "saddr")?;
+    for rule in matches("bad", "drop") {
+        writeln!(file, "{}", rule)?;
+    }
+    writeln!(file, "    tcp dport {} drop;", policy.ssh_port)?;
+    for rule in matches("home", "accept") {
+        writeln!(file, "{}", rule)?;
+    }
+    if let Some(rate) = &policy.rest_rate {
+        writeln!(file, "    limit rate {} accept;", rate)?;
+    }
+    let comment = fingerprint
+        .map(|f| format!(" comment \"{}{}\"", FINGERPRINT_PREFIX, f))
+        .unwrap_or_default();
+    writeln!(file, "    drop{};", comment)?;
+    writeln!(file, "  }}")?;
+    writeln!(file, "}}")?;
+    Ok(file)
+}
+
+fn write_preamble(file: &mut String) -> Result<()> {
+    // Declaring then deleting the table makes the file replace any
+    // previously loaded version in one transaction instead of appending
+    writeln!(file, "table inet {}", TABLE)?;
+    writeln!(file, "delete table inet {}", TABLE)?;
+    writeln!(file)?;
+    writeln!(file, "table inet {} {{", TABLE)?;
+    Ok(())
+}
+
+/// Declare the whitelist sets, only for families that have entries, and
+/// return which families got one. The entries are aggregated, since the
+/// `--keep` presets can fall inside a whitelisted network and interval
+/// sets refuse overlapping elements.
+fn write_whitelist_sets(file: &mut String, whitelist: &[IpNetwork]) -> Result<(bool, bool)> {
+    let (allowed_v4, allowed_v6): (Vec<IpNetwork>, Vec<IpNetwork>) = cidr::aggregate(whitelist).into_iter().partition(|net| net.is_ipv4());
+    for (name, kind, nets) in [("whitelist_ipv4", "ipv4_addr", &allowed_v4), ("whitelist_ipv6", "ipv6_addr", &allowed_v6)] {
+        if nets.is_empty() {
+            continue;
+        }
+        writeln!(file, "  set {} {{ type {}; flags interval; elements = {{", name, kind)?;
+        for net in nets {
+            writeln!(file, "    {},", net)?;
+        }
+        writeln!(file, "  }} }}")?;
+    }
+    Ok((!allowed_v4.is_empty(), !allowed_v6.is_empty()))
+}
+
+/// Declare the sets of expiring entries. They are always empty in the file
+/// and refilled from the state directory after each load (see
+/// [`crate::temp`]).
+fn write_temp_sets(file: &mut String) -> Result<()> {
+    for set in temp::SETS {
+        writeln!(file, "  set {}_ipv4 {{ type ipv4_addr; flags interval, timeout; }}", set.name)?;
+        writeln!(file, "  set {}_ipv6 {{ type ipv6_addr; flags interval, timeout; }}", set.name)?;
+    }
+    Ok(())
+}
+
+/// Drop or accept peers (matched on `field`) held in the sets of expiring
+/// entries
+fn write_temp_rules(file: &mut String, field: &str) -> Result<()> {
+    for set in temp::SETS {
+        writeln!(file, "    ip {} @{}_ipv4 {};", field, set.name, set.verdict)?;
+        writeln!(file, "    ip6 {} @{}_ipv6 {};", field, set.name, set.verdict)?;
+    }
+    Ok(())
+}
+
+/// Start a base chain on `hook`, accepting whitelisted peers (matched on
+/// `field`, saddr or daddr) before anything else
+fn open_chain(file: &mut String, hook: &str, field: &str, (whitelist_v4, whitelist_v6): (bool, bool)) -> Result<()> {
+    writeln!(file, "  chain {} {{", hook)?;
+    writeln!(file, "    type filter hook {} priority 0;", hook)?;
+    if whitelist_v4 {
+        writeln!(file, "    ip {} @whitelist_ipv4 accept;", field)?;
+    }
+    if whitelist_v6 {
+        writeln!(file, "    ip6 {} @whitelist_ipv6 accept;", field)?;
+    }
+    Ok(())
+}
+
+/// 64-bit FNV-1a, hex encoded
+pub fn hash_hex(bytes: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// The listing of `table` in the inet family
+pub fn list_table(table: &str) -> Result<String> {
+    list_family_table("inet", table)
+}
+
+/// The listing of `table` in `family`
+pub fn list_family_table(family: &str, table: &str) -> Result<String> {
+    let output = privilege::command("nft")
+        .args(["list", "table", family, table])
+        .stderr(Stdio::null())
+        .output()
+        .context("failed to execute nft command")?;
+    if !output.status.success() {
+        bail!("nft could not list it{}", privilege::hint());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Fingerprint of the ruleset currently loaded in the kernel, or `None` if
+/// cloak's table is missing or no longer has the rules cloak generated
+/// (e.g. after `nft flush ruleset` or a hand-edited chain). The `--offload`
+/// and `--bridge` tables `ruleset` has must be loaded with the same
+/// fingerprint too; pass an empty one to check cloak's table alone.
+pub fn live_fingerprint(ruleset: &str) -> Option<String> {
+    // Flushing the table or its chains takes the fingerprint's rule too
+    let fingerprint = embedded_fingerprint(&list_table(TABLE).ok()?)?;
+    for (family, table) in [("netdev", OFFLOAD_TABLE), ("bridge", BRIDGE_TABLE)] {
+        if !ruleset.contains(&format!("table {} {} {{", family, table)) {
+            continue;
+        }
+        let listing = list_family_table(family, table).ok()?;
+        if embedded_fingerprint(&listing)? != fingerprint {
+            return None;
+        }
+    }
+    Some(fingerprint)
+}
+
+fn embedded_fingerprint(listing: &str) -> Option<String> {
+    let start = listing.find(FINGERPRINT_PREFIX)? + FINGERPRINT_PREFIX.len();
+    let end = listing[start..].find('"')? + start;
+    Some(listing[start..end].to_string())
+}
+
+/// Packets and bytes dropped by the `counter`s in cloak's loaded table,
+/// as generated with `--counters`. `None` if the table cannot be listed.
+pub fn drop_counters() -> Option<(u64, u64)> {
+    let listing = table_listing()?;
+    let mut totals = (0, 0);
+    for line in listing.lines().filter(|line| line.contains(" drop")) {
+        let mut words = line.split_whitespace();
+        while let Some(word) = words.next() {
+            match word {
+                "packets" => totals.0 += words.next().and_then(|n| n.parse::<u64>().ok()).unwrap_or(0),
+                "bytes" => totals.1 += words.next().and_then(|n| n.parse::<u64>().ok()).unwrap_or(0),
+                _ => {}
+            }
+        }
+    }
+    for (_, _, packets, bytes) in named_counters(&listing) {
+        totals.0 += packets;
+        totals.1 += bytes;
+    }
+    Some(totals)
+}
+
+/// Packets and bytes dropped per country and family (`ipv4`, `ipv6`) by
+/// rules written with `--country-counters`; empty for other rules
+pub fn country_counters() -> Option<Vec<(String, String, u64, u64)>> {
+    Some(named_counters(&table_listing()?))
+}
+
+fn table_listing() -> Option<String> {
+    let output = privilege::command("nft")
+        .args(["list", "table", "inet", TABLE])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The `drop_<cc>_<family>` counter objects in a table listing, whose
+/// values nft prints on the line after the name
+fn named_counters(listing: &str) -> Vec<(String, String, u64, u64)> {
+    let mut counters = Vec::new();
+    let mut current = None;
+    for line in listing.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("counter ").and_then(|rest| rest.strip_prefix(COUNTER_PREFIX)) {
+            current = rest.trim_end_matches(['{', ' ']).rsplit_once('_').map(|(cc, family)| (cc.to_string(), family.to_string()));
+            continue;
+        }
+        let Some((cc, family)) = current.take() else { continue };
+        let mut words = line.split_whitespace();
+        let (mut packets, mut bytes) = (0, 0);
+        while let Some(word) = words.next() {
+            match word {
+                "packets" => packets = words.next().and_then(|n| n.parse().ok()).unwrap_or(0),
+                "bytes" => bytes = words.next().and_then(|n| n.parse().ok()).unwrap_or(0),
+                _ => {}
+            }
+        }
+        counters.push((cc, family, packets, bytes));
+    }
+    counters
+}
+
+/// Load a ruleset file with `nft -f`; returns whether nft accepted it.
+pub fn load(path: &str) -> Result<bool> {
+    let status = privilege::command("nft")
+        .arg("-f")
+        .arg(path)
+        .status()
+        .context("failed to execute nft command")?;
+    Ok(status.success())
+}
+
+/// Feed `script` to `nft -f -` as one transaction
+pub fn run_script(script: &str) -> Result<()> {
+    let mut child = privilege::command("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("failed to execute nft command")?;
+    child
+        .stdin
+        .take()
+        .context("nft stdin")?
+        .write_all(script.as_bytes())
+        .context("write to nft")?;
+    let output = child.wait_with_output().context("wait for nft")?;
+    if !output.status.success() {
+        bail!("{}{}", String::from_utf8_lossy(&output.stderr).trim(), privilege::hint());
+    }
+    Ok(())
+}
+
+/// Delete cloak's table; returns `Ok(false)` if nft refused, e.g. because
+/// the table is not loaded.
+pub fn remove() -> Result<bool> {
+    // Only there with --offload and --bridge
+    let _ = privilege::command("nft").args(["delete", "table", "netdev", OFFLOAD_TABLE]).stderr(Stdio::null()).status();
+    let _ = privilege::command("nft").args(["delete", "table", "bridge", BRIDGE_TABLE]).stderr(Stdio::null()).status();
+    let status = privilege::command("nft")
+        .args(["delete", "table", "inet", TABLE])
+        .status()
+        .context("failed to execute nft command")?;
+    Ok(status.success())
+}
+
+/// Check that `ruleset` is a file cloak generated, i.e. it only replaces
+/// cloak's own table, and return its fingerprint. Keeps `cloak apply`
+/// from being a general-purpose way to load arbitrary nft scripts.
+pub fn check_own_ruleset(ruleset: &str) -> Result<String> {
+    let own = [("inet", TABLE), ("netdev", OFFLOAD_TABLE), ("bridge", BRIDGE_TABLE)];
+    // Statements outside any block, however they are indented; `opens`
+    // when the statement starts one
+    let check = |statement: &str, opens: bool, number: usize| -> Result<()> {
+        let words: Vec<&str> = statement.split_whitespace().collect();
+        let allowed = match (words.as_slice(), opens) {
+            ([], false) => true,
+            (["table", family, name], _) | (["delete", "table", family, name], false) => own.contains(&(*family, *name)),
+            _ => false,
+        };
+        if !allowed {
+            bail!("line {}: `{}` touches something other than table inet {}", number + 1, words.join(" "), TABLE);
+        }
+        Ok(())
+    };
+    let mut depth = 0usize;
+    let mut quoted = false;
+    let mut statement = String::new();
+    for (number, line) in ruleset.lines().enumerate() {
+        if line.trim_start().starts_with("include") {
+            bail!("line {}: include directives are not allowed", number + 1);
+        }
+        for c in line.chars() {
+            match c {
+                '"' => quoted = !quoted,
+                _ if quoted => {}
+                '#' => break,
+                '{' => {
+                    if depth == 0 {
+                        check(&statement, true, number)?;
+                        statement.clear();
+                    }
+                    depth += 1;
+                    continue;
+                }
+                '}' => {
+                    depth = depth.checked_sub(1).with_context(|| format!("line {}: unbalanced `}}`", number + 1))?;
+                    continue;
+                }
+                ';' if depth == 0 => {
+                    check(&statement, false, number)?;
+                    statement.clear();
+                    continue;
+                }
+                _ => {}
+            }
+            if depth == 0 {
+                statement.push(c);
+            }
+        }
+        if depth == 0 && !quoted {
+            check(&statement, false, number)?;
+            statement.clear();
+        }
+    }
+    if depth > 0 {
+        bail!("unbalanced `{{`: a block is never closed");
+    }
+    let start = ruleset
+        .find(FINGERPRINT_PREFIX)
+        .context("no cloak fingerprint found; was this file generated by cloak?")?
+        + FINGERPRINT_PREFIX.len();
+    let end = ruleset[start..].find('"').context("malformed cloak fingerprint")? + start;
+    Ok(ruleset[start..end].to_string())
+}
+
+fn applied_hash_path(state_dir: &Path) -> std::path::PathBuf {
+    state_dir.join("last_applied.hash")
+}
+
+/// Hash of the ruleset most recently loaded successfully, if recorded
+pub fn last_applied_hash(state_dir: &Path) -> Option<String> {
+    fs::read_to_string(applied_hash_path(state_dir))
+        .ok()
+        .map(|hash| hash.trim().to_string())
+}
+
+pub fn forget_applied_hash(state_dir: &Path) -> Result<()> {
+    match fs::remove_file(applied_hash_path(state_dir)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("remove {}", applied_hash_path(state_dir).display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn record_applied_hash(state_dir: &Path, hash: &str) -> Result<()> {
+    let path = applied_hash_path(state_dir);
+    fs::write(&path, format!("{}\n", hash)).with_context(|| format!("write {}", path.display()))
+}
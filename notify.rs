@@ -0,0 +1,581 @@
+//! Notifications about refreshes, drift corrections and applied rules.
+//!
+//! Configured under `notify:` in the daemon's config file; any combination
+//! of channels can be enabled:
+//!
+//! ```yaml
+//! notify:
+//!   email:
+//!     from: cloak@router.example.com
+//!     to: [ops@example.com]
+//!     smtp: mail.example.com:25      # or: sendmail: /usr/sbin/sendmail
+//!   slack: { webhook: "https://hooks.slack.com/services/..." }
+//!   discord: { webhook: "https://discord.com/api/webhooks/..." }
+//!   matrix: { homeserver: "https://matrix.org", room: "!abc:matrix.org", token: "..." }
+//!   webhook: { url: "https://example.com/hook" }   # JSON, see `Webhook`
+//!   syslog: { facility: daemon }   # RFC 5424 with structured data to /dev/log
+//!   journald: {}                   # native protocol, CLOAK_* fields
+//! ```
+//!
+//! The SMTP client speaks plain SMTP to a relay, without TLS or
+//! authentication; for those, hand the message to a local MTA (sendmail,
+//! msmtp, ...) with `sendmail:` instead.
+
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    process::Stdio,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{fetch, naming, ui::warning, yaml};
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Discord rejects messages longer than this
+const DISCORD_LIMIT: usize = 2000;
+
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// A channel notifications can be delivered through
+pub trait Notifier: Send + Sync {
+    /// Short name used in error messages
+    fn name(&self) -> &'static str;
+
+    fn send<'a>(&'a self, event: &'a Event) -> SendFuture<'a>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Refreshed,
+    RefreshFailed,
+    DriftCorrected,
+    RestoreFailed,
+    Applied,
+    ApplyFailed,
+}
+
+impl EventKind {
+    /// Stable identifier for log filters and SIEM rules
+    pub fn id(self) -> &'static str {
+        match self {
+            EventKind::Refreshed => "refreshed",
+            EventKind::RefreshFailed => "refresh_failed",
+            EventKind::DriftCorrected => "drift_corrected",
+            EventKind::RestoreFailed => "restore_failed",
+            EventKind::Applied => "applied",
+            EventKind::ApplyFailed => "apply_failed",
+        }
+    }
+
+    /// syslog severity: notice for policy changes, warning for drift,
+    /// error for failures
+    fn severity(self) -> u8 {
+        match self {
+            EventKind::Refreshed | EventKind::Applied => 5,
+            EventKind::DriftCorrected => 4,
+            EventKind::RefreshFailed | EventKind::RestoreFailed | EventKind::ApplyFailed => 3,
+        }
+    }
+}
+
+/// Something worth telling people about, with machine-readable details
+pub struct Event {
+    pub kind: EventKind,
+    pub subject: String,
+    pub body: String,
+    /// Extra structured fields (lowercase names, e.g. `fingerprint`)
+    pub fields: Vec<(&'static str, String)>,
+}
+
+impl Event {
+    pub fn new(kind: EventKind, subject: impl Into<String>, body: impl Into<String>) -> Self {
+        Event { kind, subject: subject.into(), body: body.into(), fields: Vec::new() }
+    }
+
+    pub fn field(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.fields.push((name, value.to_string()));
+        self
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    pub email: Option<EmailConfig>,
+    pub slack: Option<SlackConfig>,
+    pub discord: Option<DiscordConfig>,
+    pub matrix: Option<MatrixConfig>,
+    pub webhook: Option<WebhookConfig>,
+    pub syslog: Option<SyslogConfig>,
+    pub journald: Option<JournaldConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    pub from: String,
+    pub to: Vec<String>,
+    /// `host:port` of an SMTP relay
+    pub smtp: Option<String>,
+    /// sendmail-compatible program reading the message on stdin
+    pub sendmail: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SlackConfig {
+    /// Incoming-webhook URL
+    pub webhook: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DiscordConfig {
+    pub webhook: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MatrixConfig {
+    pub homeserver: String,
+    /// Room ID (`!abc:example.org`) the bot account has joined
+    pub room: String,
+    /// Access token of the sending account
+    pub token: String,
+}
+
+/// Anything else: the event is POSTed as JSON
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SyslogConfig {
+    #[serde(default = "default_syslog_socket")]
+    pub socket: PathBuf,
+    /// `daemon`, `auth`, `user` or `local0` to `local7`
+    #[serde(default = "default_facility")]
+    pub facility: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct JournaldConfig {
+    #[serde(default = "default_journal_socket")]
+    pub socket: PathBuf,
+}
+
+fn default_syslog_socket() -> PathBuf {
+    PathBuf::from("/dev/log")
+}
+
+fn default_facility() -> String {
+    "daemon".to_string()
+}
+
+fn default_journal_socket() -> PathBuf {
+    PathBuf::from("/run/systemd/journal/socket")
+}
+
+fn facility_code(name: &str) -> Option<u8> {
+    match name {
+        "user" => Some(1),
+        "daemon" => Some(3),
+        "auth" => Some(4),
+        _ => name.strip_prefix("local")?.parse::<u8>().ok().filter(|n| *n <= 7).map(|n| 16 + n),
+    }
+}
+
+impl NotifyConfig {
+    /// Read only the `notify:` section of a daemon config file
+    pub fn from_config_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("read config {}", path.display()))?;
+        let doc = yaml::parse(&text).with_context(|| format!("parse {}", path.display()))?;
+        let section = doc.get("notify").cloned().unwrap_or(Value::Null);
+        let config: NotifyConfig = match section {
+            Value::Null => NotifyConfig::default(),
+            section => serde_json::from_value(section).with_context(|| format!("invalid notify section in {}", path.display()))?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check settings that can be checked without sending anything
+    pub fn validate(&self) -> Result<()> {
+        if let Some(email) = &self.email {
+            if email.to.is_empty() {
+                bail!("notify.email: `to` needs at least one address");
+            }
+            if email.smtp.is_some() == email.sendmail.is_some() {
+                bail!("notify.email: set exactly one of `smtp` or `sendmail`");
+            }
+        }
+        let urls = [
+            self.slack.as_ref().map(|c| ("slack", &c.webhook)),
+            self.discord.as_ref().map(|c| ("discord", &c.webhook)),
+            self.matrix.as_ref().map(|c| ("matrix", &c.homeserver)),
+            self.webhook.as_ref().map(|c| ("webhook", &c.url)),
+        ];
+        for (name, url) in urls.into_iter().flatten() {
+            reqwest::Url::parse(url).with_context(|| format!("notify.{}: invalid URL `{}`", name, url))?;
+        }
+        if let Some(syslog) = &self.syslog {
+            if facility_code(&syslog.facility).is_none() {
+                bail!("notify.syslog: unknown facility `{}`", syslog.facility);
+            }
+        }
+        Ok(())
+    }
+
+    fn notifiers(&self) -> Result<Vec<Box<dyn Notifier>>> {
+        let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+        if let Some(email) = &self.email {
+            notifiers.push(Box::new(email.clone()));
+        }
+        if let Some(syslog) = &self.syslog {
+            notifiers.push(Box::new(syslog.clone()));
+        }
+        if let Some(journald) = &self.journald {
+            notifiers.push(Box::new(journald.clone()));
+        }
+        let webhooks = self.slack.is_some() || self.discord.is_some() || self.matrix.is_some() || self.webhook.is_some();
+        if !webhooks {
+            return Ok(notifiers);
+        }
+        let client = fetch::client_builder()?.timeout(WEBHOOK_TIMEOUT).build()?;
+        if let Some(slack) = &self.slack {
+            notifiers.push(Box::new(Slack { client: client.clone(), config: slack.clone() }));
+        }
+        if let Some(discord) = &self.discord {
+            notifiers.push(Box::new(Discord { client: client.clone(), config: discord.clone() }));
+        }
+        if let Some(matrix) = &self.matrix {
+            notifiers.push(Box::new(Matrix { client: client.clone(), config: matrix.clone() }));
+        }
+        if let Some(webhook) = &self.webhook {
+            notifiers.push(Box::new(Webhook { client, config: webhook.clone() }));
+        }
+        Ok(notifiers)
+    }
+}
+
+/// Deliver a notification through every configured channel. Failures are
+/// reported but never interrupt the caller.
+pub async fn send(config: &NotifyConfig, event: &Event) {
+    let notifiers = match config.notifiers() {
+        Ok(notifiers) => notifiers,
+        Err(e) => {
+            warning!("could not set up notifications: {:#}", e);
+            return;
+        }
+    };
+    for notifier in &notifiers {
+        if let Err(e) = notifier.send(event).await {
+            warning!("could not send {} notification: {:#}", notifier.name(), e);
+        }
+    }
+}
+
+impl Notifier for EmailConfig {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    fn send<'a>(&'a self, event: &'a Event) -> SendFuture<'a> {
+        Box::pin(send_email(self, &event.subject, &event.body))
+    }
+}
+
+struct Slack {
+    client: reqwest::Client,
+    config: SlackConfig,
+}
+
+impl Notifier for Slack {
+    fn name(&self) -> &'static str {
+        "slack"
+    }
+
+    fn send<'a>(&'a self, event: &'a Event) -> SendFuture<'a> {
+        let payload = json!({ "text": format!("*{}*\n{}", event.subject, event.body) });
+        Box::pin(post(&self.client, &self.config.webhook, payload))
+    }
+}
+
+struct Discord {
+    client: reqwest::Client,
+    config: DiscordConfig,
+}
+
+impl Notifier for Discord {
+    fn name(&self) -> &'static str {
+        "discord"
+    }
+
+    fn send<'a>(&'a self, event: &'a Event) -> SendFuture<'a> {
+        let mut content = format!("**{}**\n{}", event.subject, event.body);
+        if content.chars().count() > DISCORD_LIMIT {
+            content = content.chars().take(DISCORD_LIMIT - 1).collect::<String>() + "…";
+        }
+        Box::pin(post(&self.client, &self.config.webhook, json!({ "content": content })))
+    }
+}
+
+struct Matrix {
+    client: reqwest::Client,
+    config: MatrixConfig,
+}
+
+impl Notifier for Matrix {
+    fn name(&self) -> &'static str {
+        "matrix"
+    }
+
+    fn send<'a>(&'a self, event: &'a Event) -> SendFuture<'a> {
+        Box::pin(async move {
+            let mut url = reqwest::Url::parse(&self.config.homeserver)?;
+            // A fresh transaction ID per event; Matrix de-duplicates retries on it
+            let txn = format!("cloak-{}", SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos());
+            url.path_segments_mut()
+                .map_err(|_| anyhow::anyhow!("homeserver URL cannot have a path"))?
+                .pop_if_empty()
+                .extend(["_matrix", "client", "v3", "rooms", &self.config.room, "send", "m.room.message", &txn]);
+            let payload = json!({ "msgtype": "m.text", "body": format!("{}\n{}", event.subject, event.body) });
+            let response = self
+                .client
+                .put(url)
+                .bearer_auth(&self.config.token)
+                .json(&payload)
+                .send()
+                .await?;
+            response.error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+struct Webhook {
+    client: reqwest::Client,
+    config: WebhookConfig,
+}
+
+impl Notifier for Webhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    /// `{"event": "refreshed", "subject": ..., "body": ..., "fields": {...}}`
+    fn send<'a>(&'a self, event: &'a Event) -> SendFuture<'a> {
+        let fields: serde_json::Map<String, Value> =
+            event.fields.iter().map(|(k, v)| (k.to_string(), Value::String(v.clone()))).collect();
+        let payload = json!({
+            "event": event.kind.id(),
+            "subject": event.subject,
+            "body": event.body,
+            "fields": fields,
+        });
+        Box::pin(post(&self.client, &self.config.url, payload))
+    }
+}
+
+impl Notifier for SyslogConfig {
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+
+    /// RFC 5424 with the fields as structured data. Timestamp and host are
+    /// left to the local syslog daemon. 32473 is the documentation
+    /// enterprise number (RFC 5612), as cloak has none of its own.
+    fn send<'a>(&'a self, event: &'a Event) -> SendFuture<'a> {
+        let facility = facility_code(&self.facility).unwrap_or(3);
+        let mut params = format!("event=\"{}\"", event.kind.id());
+        for (name, value) in &event.fields {
+            let escaped = value.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]");
+            params.push_str(&format!(" {}=\"{}\"", name, escaped));
+        }
+        let message = format!(
+            "<{}>1 - - cloak {} {} [cloak@32473 {}] {}",
+            u16::from(facility) * 8 + u16::from(event.kind.severity()),
+            std::process::id(),
+            event.kind.id(),
+            params,
+            event.subject
+        );
+        Box::pin(async move { send_datagram(&self.socket, message.as_bytes()) })
+    }
+}
+
+impl Notifier for JournaldConfig {
+    fn name(&self) -> &'static str {
+        "journald"
+    }
+
+    /// Native journal protocol; fields become `CLOAK_<NAME>`
+    fn send<'a>(&'a self, event: &'a Event) -> SendFuture<'a> {
+        let mut datagram = Vec::new();
+        let mut field = |name: &str, value: &str| {
+            if value.contains('\n') {
+                // Multi-line values use the length-prefixed binary form
+                datagram.extend_from_slice(name.as_bytes());
+                datagram.push(b'\n');
+                datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+                datagram.extend_from_slice(value.as_bytes());
+                datagram.push(b'\n');
+            } else {
+                datagram.extend_from_slice(format!("{}={}\n", name, value).as_bytes());
+            }
+        };
+        field("MESSAGE", &event.subject);
+        field("PRIORITY", &event.kind.severity().to_string());
+        field("SYSLOG_IDENTIFIER", "cloak");
+        field("CLOAK_EVENT", event.kind.id());
+        field("CLOAK_DETAILS", event.body.trim_end());
+        for (name, value) in &event.fields {
+            field(&format!("CLOAK_{}", name.to_ascii_uppercase()), value);
+        }
+        Box::pin(async move { send_datagram(&self.socket, &datagram) })
+    }
+}
+
+#[cfg(unix)]
+fn send_datagram(socket: &Path, data: &[u8]) -> Result<()> {
+    let sender = std::os::unix::net::UnixDatagram::unbound()?;
+    sender.send_to(data, socket).with_context(|| format!("send to {}", socket.display()))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_datagram(socket: &Path, _data: &[u8]) -> Result<()> {
+    bail!("{}: local log sockets need a Unix system", socket.display())
+}
+
+async fn post(client: &reqwest::Client, url: &str, payload: Value) -> Result<()> {
+    client.post(url).json(&payload).send().await?.error_for_status()?;
+    Ok(())
+}
+
+async fn send_email(email: &EmailConfig, subject: &str, body: &str) -> Result<()> {
+    let message = format_message(email, subject, body);
+    if let Some(program) = &email.sendmail {
+        let mut child = tokio::process::Command::new(program)
+            .arg("-t")
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("run {}", program.display()))?;
+        let mut stdin = child.stdin.take().context("sendmail stdin")?;
+        stdin.write_all(message.as_bytes()).await?;
+        drop(stdin);
+        let status = child.wait().await?;
+        if !status.success() {
+            bail!("{} exited with {}", program.display(), status);
+        }
+        return Ok(());
+    }
+    let relay = email.smtp.as_deref().context("no smtp relay configured")?;
+    tokio::time::timeout(SMTP_TIMEOUT, smtp_send(relay, email, &message))
+        .await
+        .with_context(|| format!("SMTP session with {} timed out", relay))?
+}
+
+async fn smtp_send(relay: &str, email: &EmailConfig, message: &str) -> Result<()> {
+    let stream = TcpStream::connect(relay).await.with_context(|| format!("connect to {}", relay))?;
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+
+    expect(&mut read, 220).await?;
+    command(&mut write, &mut read, "EHLO localhost", 250).await?;
+    command(&mut write, &mut read, &format!("MAIL FROM:<{}>", email.from), 250).await?;
+    for to in &email.to {
+        command(&mut write, &mut read, &format!("RCPT TO:<{}>", to), 250).await?;
+    }
+    command(&mut write, &mut read, "DATA", 354).await?;
+    // Dot-stuffing: a line starting with "." would otherwise end the data
+    let mut data = String::new();
+    for line in message.lines() {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    data.push_str(".\r\n");
+    write.write_all(data.as_bytes()).await?;
+    expect(&mut read, 250).await.context("SMTP message")?;
+    let _ = write.write_all(b"QUIT\r\n").await;
+    Ok(())
+}
+
+async fn command<W, R>(write: &mut W, read: &mut R, line: &str, code: u16) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncBufReadExt + Unpin,
+{
+    write.write_all(line.as_bytes()).await?;
+    write.write_all(b"\r\n").await?;
+    let verb = line.split(' ').next().unwrap_or_default();
+    expect(read, code).await.with_context(|| format!("SMTP {}", verb))
+}
+
+/// Read a (possibly multi-line) reply and check its status code
+async fn expect<R: AsyncBufReadExt + Unpin>(read: &mut R, code: u16) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        if read.read_line(&mut line).await? == 0 {
+            bail!("connection closed by server");
+        }
+        let status: u16 = line.get(..3).and_then(|c| c.parse().ok()).context("malformed SMTP reply")?;
+        // "250-..." continues, "250 ..." ends the reply
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        if status != code {
+            bail!("server replied {}", line.trim_end());
+        }
+        return Ok(());
+    }
+}
+
+fn format_message(email: &EmailConfig, subject: &str, body: &str) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+        email.from,
+        email.to.join(", "),
+        subject,
+        rfc2822_date(now),
+        body.replace('\n', "\r\n")
+    )
+}
+
+/// `Tue, 14 Oct 2025 08:00:00 +0000`
+fn rfc2822_date(unix: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let days = (unix / 86_400) as i64;
+    let secs = unix % 86_400;
+    let (year, month, day) = naming::civil_date(unix);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+        DAYS[(days.rem_euclid(7)) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
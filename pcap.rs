@@ -0,0 +1,242 @@
+//! Reading packet captures: classic pcap and pcapng, as written by tcpdump
+//! and Wireshark. Only what traffic statistics need is decoded, i.e. the
+//! IP addresses and length of each packet.
+
+use std::{
+    io::{ErrorKind, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use anyhow::{bail, Context, Result};
+
+/// Link types cloak can find the IP header in
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+/// Larger records than this mean a corrupt file rather than a real frame
+const MAX_FRAME: usize = 1 << 20;
+
+const PCAPNG_SECTION: u32 = 0x0A0D_0D0A;
+const PCAPNG_INTERFACE: u32 = 1;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+
+/// The endpoints and on-the-wire length of one captured packet
+pub struct Packet {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub len: u32,
+}
+
+enum Format {
+    /// Classic pcap with its one link type
+    Pcap { linktype: u32 },
+    /// pcapng, with the link type of each interface seen so far
+    Pcapng { linktypes: Vec<u32> },
+}
+
+pub struct Reader<R> {
+    input: R,
+    format: Format,
+    big_endian: bool,
+    /// Packets without an IPv4 or IPv6 header (ARP, truncated, ...)
+    pub skipped: u64,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut input: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic).context("read capture header")?;
+        let (format, big_endian) = match magic {
+            [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] => (Format::Pcap { linktype: 0 }, false),
+            [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] => (Format::Pcap { linktype: 0 }, true),
+            [0x0a, 0x0d, 0x0d, 0x0a] => (Format::Pcapng { linktypes: Vec::new() }, false),
+            _ => bail!("not a pcap or pcapng file"),
+        };
+        let mut reader = Reader { input, format, big_endian, skipped: 0 };
+        match reader.format {
+            Format::Pcap { .. } => {
+                // Version, timezone, accuracy and snap length precede the link type
+                let header = reader.bytes(20).context("read pcap header")?;
+                let linktype = reader.u32(&header[16..20]) & 0x0fff_ffff;
+                reader.format = Format::Pcap { linktype };
+            }
+            Format::Pcapng { .. } => reader.section_header()?,
+        }
+        Ok(reader)
+    }
+
+    /// The next IP packet, or `None` at the end of the capture
+    pub fn next_packet(&mut self) -> Result<Option<Packet>> {
+        loop {
+            let Some((linktype, data, len)) = self.next_frame()? else {
+                return Ok(None);
+            };
+            match addresses(linktype, &data) {
+                Some((src, dst)) => return Ok(Some(Packet { src, dst, len })),
+                None => self.skipped += 1,
+            }
+        }
+    }
+
+    /// Link type, captured bytes and original length of the next frame
+    fn next_frame(&mut self) -> Result<Option<(u32, Vec<u8>, u32)>> {
+        if let Format::Pcap { linktype } = self.format {
+            let Some(header) = self.bytes_or_eof(16)? else {
+                return Ok(None);
+            };
+            let captured = self.u32(&header[8..12]) as usize;
+            let len = self.u32(&header[12..16]);
+            if captured > MAX_FRAME {
+                bail!("corrupt pcap record of {} bytes", captured);
+            }
+            let data = self.bytes(captured).context("read packet")?;
+            return Ok(Some((linktype, data, len)));
+        }
+        loop {
+            let Some(header) = self.bytes_or_eof(8)? else {
+                return Ok(None);
+            };
+            let kind = self.u32(&header[0..4]);
+            if kind == PCAPNG_SECTION {
+                // A new section may switch byte order; re-read its header
+                self.section_body(&header)?;
+                continue;
+            }
+            let total = self.u32(&header[4..8]) as usize;
+            if total < 12 || !total.is_multiple_of(4) || total > MAX_FRAME {
+                bail!("corrupt pcapng block length {}", total);
+            }
+            let body = self.bytes(total - 8).context("read pcapng block")?;
+            let body = &body[..total - 12];
+            match kind {
+                PCAPNG_INTERFACE if body.len() >= 2 => {
+                    let raw = [body[0], body[1]];
+                    let linktype = if self.big_endian { u16::from_be_bytes(raw) } else { u16::from_le_bytes(raw) };
+                    if let Format::Pcapng { linktypes } = &mut self.format {
+                        linktypes.push(u32::from(linktype));
+                    }
+                }
+                PCAPNG_ENHANCED_PACKET if body.len() >= 20 => {
+                    let linktype = self.interface_linktype(self.u32(&body[0..4]) as usize)?;
+                    let captured = (self.u32(&body[12..16]) as usize).min(body.len() - 20);
+                    let len = self.u32(&body[16..20]);
+                    return Ok(Some((linktype, body[20..20 + captured].to_vec(), len)));
+                }
+                PCAPNG_SIMPLE_PACKET if body.len() >= 4 => {
+                    let linktype = self.interface_linktype(0)?;
+                    let len = self.u32(&body[0..4]);
+                    return Ok(Some((linktype, body[4..].to_vec(), len)));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn interface_linktype(&self, interface: usize) -> Result<u32> {
+        match &self.format {
+            Format::Pcapng { linktypes } => linktypes.get(interface).copied(),
+            Format::Pcap { linktype } => Some(*linktype),
+        }
+        .with_context(|| format!("packet on undeclared interface {}", interface))
+    }
+
+    /// The rest of the first section header, whose type was the magic
+    fn section_header(&mut self) -> Result<()> {
+        let length = self.bytes(4).context("read pcapng header")?;
+        let mut header = PCAPNG_SECTION.to_le_bytes().to_vec();
+        header.extend(length);
+        self.section_body(&header)
+    }
+
+    fn section_body(&mut self, header: &[u8]) -> Result<()> {
+        let rest = self.bytes(4).context("read pcapng section")?;
+        self.big_endian = match rest.as_slice() {
+            [0x1a, 0x2b, 0x3c, 0x4d] => true,
+            [0x4d, 0x3c, 0x2b, 0x1a] => false,
+            _ => bail!("corrupt pcapng section header"),
+        };
+        let total = self.u32(&header[4..8]) as usize;
+        if total < 12 {
+            bail!("corrupt pcapng section length {}", total);
+        }
+        self.bytes(total - 12).context("read pcapng section")?;
+        // Interface numbers restart with every section
+        self.format = Format::Pcapng { linktypes: Vec::new() };
+        Ok(())
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; n];
+        self.input.read_exact(&mut buf).context("capture ends in the middle of a record")?;
+        Ok(buf)
+    }
+
+    /// `n` bytes, or `None` if the capture ends right here
+    fn bytes_or_eof(&mut self, n: usize) -> Result<Option<Vec<u8>>> {
+        let mut buf = vec![0; n];
+        let mut filled = 0;
+        while filled < n {
+            match self.input.read(&mut buf[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => bail!("capture ends in the middle of a record"),
+                Ok(read) => filled += read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e).context("read capture"),
+            }
+        }
+        Ok(Some(buf))
+    }
+}
+
+/// Source and destination of the IP packet inside a frame of `linktype`
+fn addresses(linktype: u32, frame: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    let ip = match linktype {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ethertype = u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?]);
+            // 802.1Q and 802.1ad tags
+            while matches!(ethertype, 0x8100 | 0x88a8) {
+                offset += 4;
+                ethertype = u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?]);
+            }
+            match ethertype {
+                0x0800 | 0x86dd => frame.get(offset + 2..)?,
+                _ => return None,
+            }
+        }
+        // Some platforms number raw IP 12 or 14 instead
+        LINKTYPE_RAW | 12 | 14 => frame,
+        // Address family in host byte order; the IP version nibble tells
+        // the families apart without knowing the capturing host
+        LINKTYPE_NULL => frame.get(4..)?,
+        LINKTYPE_LINUX_SLL => frame.get(16..)?,
+        LINKTYPE_LINUX_SLL2 => frame.get(20..)?,
+        _ => return None,
+    };
+    match ip.first()? >> 4 {
+        4 if ip.len() >= 20 => {
+            let src = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+            let dst = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+            Some((IpAddr::V4(src), IpAddr::V4(dst)))
+        }
+        6 if ip.len() >= 40 => {
+            let src: [u8; 16] = ip[8..24].try_into().ok()?;
+            let dst: [u8; 16] = ip[24..40].try_into().ok()?;
+            Some((IpAddr::V6(Ipv6Addr::from(src)), IpAddr::V6(Ipv6Addr::from(dst))))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,237 @@
+//! A drop-in copy of the loaded rules for the system's nftables service,
+//! so `systemctl restart nftables` (or a reboot) brings them back instead
+//! of wiping them.
+//!
+//! `/etc/nftables.conf` usually starts with `flush ruleset` and then
+//! includes the files of a directory such as `/etc/nftables.d`. Once
+//! `--persist-dir` or `cloak persist` has put `cloak.nft` there, every
+//! later load by apply, run or the daemon rewrites it, and `cloak remove`
+//! deletes it again. The state directory remembers where it went.
+//!
+//! On systems without the nftables service, `cloak persist --systemd`
+//! keeps the copy in the state directory instead and installs a oneshot
+//! unit that loads it early in boot, before the network comes up.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    attach, nft, privilege,
+    ui::{info, success, warning},
+};
+
+/// Name of the drop-in inside the directory
+const FILE: &str = "cloak.nft";
+
+/// Configuration the nftables service loads at boot
+const SERVICE_CONFIG: &str = "/etc/nftables.conf";
+
+/// Name of the unit `--systemd` installs
+const UNIT: &str = "cloak-restore.service";
+
+#[derive(clap::Args, Debug)]
+pub struct PersistArgs {
+    /// Directory the nftables service includes the files of
+    #[arg(long, value_name = "DIR", default_value = "/etc/nftables.d", conflicts_with = "systemd")]
+    dir: PathBuf,
+
+    /// Install a oneshot systemd unit that loads the rules at boot instead
+    /// of relying on the nftables service
+    #[arg(long)]
+    systemd: bool,
+
+    /// Where the unit goes
+    #[arg(long, value_name = "DIR", default_value = "/etc/systemd/system", requires = "systemd")]
+    unit_dir: PathBuf,
+}
+
+fn record_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("persisted")
+}
+
+fn unit_record_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("persisted_unit")
+}
+
+/// The drop-in currently kept up to date, if any
+pub fn installed(state_dir: &Path) -> Option<PathBuf> {
+    let path = fs::read_to_string(record_path(state_dir)).ok()?;
+    Some(PathBuf::from(path.trim()))
+}
+
+/// Start keeping a copy of `rules` in `dir` and write it now
+pub fn install(rules: &Path, dir: &Path, state_dir: &Path) -> Result<PathBuf> {
+    let dropin = keep(rules, dir, state_dir)?;
+    // Without an include the service never reads the drop-in
+    if !included(dir) {
+        warning!("{} does not include {}; add this line to it:", SERVICE_CONFIG, dir.display());
+        info!("   {}", include_line(dir));
+    }
+    Ok(dropin)
+}
+
+/// Write the copy of `rules` in `dir` and remember it for later loads
+fn keep(rules: &Path, dir: &Path, state_dir: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+    let dropin = dir.join(FILE);
+    write(rules, &dropin)?;
+    let record = record_path(state_dir);
+    fs::write(&record, format!("{}\n", dropin.display())).with_context(|| format!("write {}", record.display()))?;
+    Ok(dropin)
+}
+
+fn included(dir: &Path) -> bool {
+    fs::read_to_string(SERVICE_CONFIG)
+        .map(|config| config.lines().any(|line| line.trim_start().starts_with("include") && line.contains(&*dir.to_string_lossy())))
+        .unwrap_or(false)
+}
+
+fn include_line(dir: &Path) -> String {
+    format!("include \"{}/*.nft\"", dir.display())
+}
+
+/// Rewrite the drop-in, if there is one, with the rules just loaded
+pub fn update(rules: &Path, state_dir: &Path) -> Result<()> {
+    match installed(state_dir) {
+        Some(dropin) => write(rules, &dropin),
+        None => Ok(()),
+    }
+}
+
+/// Delete the drop-in and stop keeping it, returning where it was
+pub fn uninstall(state_dir: &Path) -> Result<Option<PathBuf>> {
+    let Some(dropin) = installed(state_dir) else { return Ok(None) };
+    match fs::remove_file(&dropin) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("remove {}", dropin.display()));
+        }
+        _ => {}
+    }
+    let record = record_path(state_dir);
+    fs::remove_file(&record).with_context(|| format!("remove {}", record.display()))?;
+    remove_unit(state_dir)?;
+    Ok(Some(dropin))
+}
+
+/// Copy `rules` to `dropin` by renaming a temporary file over it, so the
+/// service never reads half a ruleset
+fn write(rules: &Path, dropin: &Path) -> Result<()> {
+    let ruleset = fs::read_to_string(rules).with_context(|| format!("read {}", rules.display()))?;
+    let text = format!("# Written by cloak from {}; edits are overwritten on the next load\n{}", rules.display(), ruleset);
+    let partial = dropin.with_extension("nft.partial");
+    fs::write(&partial, text).with_context(|| format!("write {}", partial.display()))?;
+    fs::rename(&partial, dropin).with_context(|| format!("replace {}", dropin.display()))
+}
+
+/// `cloak persist`: keep the rules loaded now across reboots
+pub fn run(args: &PersistArgs, state_dir: &Path) -> Result<()> {
+    let (table, prefix) = attach::location(state_dir);
+    if !prefix.is_empty() {
+        bail!("the rules are attached to table inet {}; persist that table with the rest of its own rules", table);
+    }
+    let listing = nft::list_table(nft::TABLE).with_context(|| format!("table inet {} is not loaded; apply the rules first", nft::TABLE))?;
+    // The listing alone would add to a table the boot already has
+    let loaded = state_dir.join("loaded.nft");
+    let mut ruleset = format!("table inet {table}\ndelete table inet {table}\n\n{}", listing, table = nft::TABLE);
+    for (family, table) in [("netdev", nft::OFFLOAD_TABLE), ("bridge", nft::BRIDGE_TABLE)] {
+        if let Ok(listing) = nft::list_family_table(family, table) {
+            ruleset.push_str(&format!("\ntable {family} {table}\ndelete table {family} {table}\n\n{}", listing, family = family, table = table));
+        }
+    }
+    fs::write(&loaded, ruleset).with_context(|| format!("write {}", loaded.display()))?;
+    // Switching between the service and the unit leaves nothing behind
+    uninstall(state_dir)?;
+
+    if !args.systemd {
+        if !included(&args.dir) {
+            add_include(&args.dir)?;
+        }
+        let dropin = install(&loaded, &args.dir, state_dir)?;
+        success!("Saved the rules to {} for the nftables service.", dropin.display());
+        if !systemctl(&["is-enabled", "--quiet", "nftables.service"]) {
+            warning!("the nftables service is not enabled, so nothing loads {} at boot; run `systemctl enable nftables` or use --systemd", dropin.display());
+        }
+        return Ok(());
+    }
+
+    // The unit runs before anything could change the working directory
+    let dir = fs::canonicalize(state_dir).with_context(|| format!("resolve {}", state_dir.display()))?;
+    let dropin = keep(&loaded, &dir, state_dir)?;
+    let unit = args.unit_dir.join(UNIT);
+    fs::write(&unit, unit_file(&dropin)).with_context(|| format!("write {}", unit.display()))?;
+    let record = unit_record_path(state_dir);
+    fs::write(&record, format!("{}\n", unit.display())).with_context(|| format!("write {}", record.display()))?;
+    success!("Saved the rules to {} and installed {} to load them at boot.", dropin.display(), unit.display());
+    if systemctl(&["daemon-reload"]) && systemctl(&["enable", UNIT]) {
+        success!("Enabled {}.", UNIT);
+    } else {
+        warning!("could not enable the unit; run `systemctl daemon-reload && systemctl enable {}`", UNIT);
+    }
+    Ok(())
+}
+
+/// Append the include of `dir` to the nftables service's configuration
+fn add_include(dir: &Path) -> Result<()> {
+    let mut config = fs::OpenOptions::new()
+        .append(true)
+        .open(SERVICE_CONFIG)
+        .with_context(|| format!("open {}; is the nftables service installed? (or use --systemd)", SERVICE_CONFIG))?;
+    writeln!(config, "\n# Added by cloak persist\n{}", include_line(dir)).with_context(|| format!("write {}", SERVICE_CONFIG))?;
+    info!("Added `{}` to {}.", include_line(dir), SERVICE_CONFIG);
+    Ok(())
+}
+
+/// A oneshot unit loading `rules` once the file systems are mounted and
+/// before the network is configured
+fn unit_file(rules: &Path) -> String {
+    format!(
+        "# Written by cloak persist\n\
+         [Unit]\n\
+         Description=Load cloak's nftables rules\n\
+         DefaultDependencies=no\n\
+         After=local-fs.target\n\
+         Wants=network-pre.target\n\
+         Before=network-pre.target network-online.target shutdown.target\n\
+         Conflicts=shutdown.target\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         RemainAfterExit=yes\n\
+         ExecStart={} -f {}\n\
+         \n\
+         [Install]\n\
+         WantedBy=sysinit.target\n",
+        privilege::tool_path("nft").display(),
+        rules.display()
+    )
+}
+
+fn systemctl(args: &[&str]) -> bool {
+    Command::new("systemctl")
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Disable and delete the unit `--systemd` installed, if any
+fn remove_unit(state_dir: &Path) -> Result<()> {
+    let record = unit_record_path(state_dir);
+    let Ok(unit) = fs::read_to_string(&record) else { return Ok(()) };
+    let unit = PathBuf::from(unit.trim());
+    systemctl(&["disable", UNIT]);
+    match fs::remove_file(&unit) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e).with_context(|| format!("remove {}", unit.display())),
+        _ => {}
+    }
+    fs::remove_file(&record).with_context(|| format!("remove {}", record.display()))?;
+    info!("Removed {}.", unit.display());
+    Ok(())
+}
@@ -0,0 +1,49 @@
+//! pf anchor generation for macOS and the BSDs.
+
+use std::{collections::HashMap, fmt::Write, fs};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{Action, CountryNets, Direction};
+
+/// Anchor name the generated rules are meant to be loaded into
+pub const ANCHOR: &str = "cloak";
+
+/// Write a pf anchor for `map` to `filename`. Load it with
+/// `pfctl -a cloak -f <file>`; the main ruleset needs an `anchor "cloak"`
+/// line for the rules to be evaluated.
+pub fn generate_pf(map: &HashMap<String, CountryNets>, action: Action, direction: Direction, filename: &str) -> Result<()> {
+    let mut file = String::new();
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+
+    writeln!(file, "# Generated by cloak. Load with: pfctl -a {} -f {}", ANCHOR, filename)?;
+    writeln!(file, "table <cloak_countries> persist {{")?;
+    for cc in &codes {
+        for ip in map[*cc].ipv4.iter().chain(&map[*cc].ipv6) {
+            writeln!(file, "  {}", ip.0)?;
+        }
+    }
+    writeln!(file, "}}")?;
+
+    let mut sides = Vec::new();
+    if direction != Direction::Out {
+        sides.push(("in", "from"));
+    }
+    if direction != Direction::In {
+        sides.push(("out", "to"));
+    }
+    for (way, peer) in sides {
+        match action {
+            Action::Block => {
+                writeln!(file, "block drop {} quick {} <cloak_countries>", way, peer)?;
+            }
+            Action::Allow => {
+                writeln!(file, "pass {} quick {} <cloak_countries>", way, peer)?;
+                writeln!(file, "block drop {} quick all", way)?;
+            }
+            Action::Mark => bail!("pf rules cannot mark traffic"),
+        }
+    }
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
@@ -0,0 +1,657 @@
+//! The impact report printed before a ruleset is loaded: how much of the
+//! internet it shuts out, whether the addresses this host is managed from
+//! can still open connections, and which rules already loaded it leaves
+//! with nothing to match.
+//!
+//! Reachability is worked out by following the input chain for a new TCP
+//! connection. Rules matching on anything this cannot know (interfaces,
+//! other protocols) are taken not to match, so the verdict is the one for
+//! the common case rather than a proof. `cloak simulate` follows the same
+//! model with the interface and destination filled in.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    process::Stdio,
+};
+
+use anyhow::Result;
+use ipnetwork::IpNetwork;
+
+use crate::{
+    cidr::{self, Exclusions},
+    compare,
+    lint::{self, Stmt},
+    nft, privilege,
+    ui::{self, info, warning},
+};
+
+/// How many rules made moot are listed by name
+const MAX_LISTED: usize = 5;
+
+/// `value_parser` for `--reachable`: `ADDR` (SSH port 22) or `ADDR:PORT`
+pub fn parse_reachable(text: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = text.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let addr: IpAddr = text.parse().map_err(|_| format!("expected ADDR or ADDR:PORT, e.g. 203.0.113.7 or [2001:db8::7]:2222, not `{}`", text))?;
+    Ok(SocketAddr::new(addr, 22))
+}
+
+/// What happens to a new connection
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Fate {
+    Accepted,
+    RateLimited,
+    Dropped,
+}
+
+impl Fate {
+    fn describe(self) -> &'static str {
+        match self {
+            Fate::Accepted => "accepted",
+            Fate::RateLimited => "rate limited",
+            Fate::Dropped => "dropped",
+        }
+    }
+}
+
+/// A table's sets and chains, as far as they decide what is accepted
+#[derive(Default)]
+struct Tables {
+    sets: HashMap<String, Vec<IpNetwork>>,
+    chains: HashMap<String, Vec<Vec<String>>>,
+    /// The chain on the input hook
+    input: Option<String>,
+    /// The chain on the forward hook
+    forward: Option<String>,
+}
+
+/// The first packet of a new connection, followed through the chains.
+/// What is not known (the destination address, the interface) matches
+/// no rule that asks for it.
+pub struct Packet<'a> {
+    pub from: IpAddr,
+    pub to: Option<IpAddr>,
+    pub port: u16,
+    /// `tcp` or `udp`
+    pub protocol: &'a str,
+    pub interface: Option<&'a str>,
+}
+
+impl Packet<'_> {
+    fn tcp(from: IpAddr, port: u16) -> Packet<'static> {
+        Packet { from, to: None, port, protocol: "tcp", interface: None }
+    }
+}
+
+impl Tables {
+    fn read<'a>(body: impl IntoIterator<Item = &'a Stmt>) -> Tables {
+        let mut tables = Tables::default();
+        for stmt in body {
+            let (Some(kind), Some(name), Some(inner)) = (stmt.words.first(), stmt.words.get(1), &stmt.body) else {
+                continue;
+            };
+            match kind.as_str() {
+                // Sets with timeouts are filled at runtime; at load time
+                // they are empty
+                "set" => {
+                    let dynamic = inner.iter().any(|part| part.words[0] == "flags" && part.words.iter().any(|w| w == "timeout"));
+                    let elements = inner.iter().find(|part| part.words[0] == "elements");
+                    let nets = match elements {
+                        Some(part) if !dynamic => compare::prefixes(&part.elements),
+                        _ => Vec::new(),
+                    };
+                    tables.sets.insert(name.clone(), nets);
+                }
+                "chain" => {
+                    for (hook, chain) in [("input", &mut tables.input), ("forward", &mut tables.forward)] {
+                        if inner.iter().any(|part| part.words.starts_with(&["type".into(), "filter".into(), "hook".into(), hook.into()])) {
+                            *chain = Some(name.clone());
+                        }
+                    }
+                    tables.chains.insert(name.clone(), inner.iter().map(|rule| rule.words.clone()).collect());
+                }
+                _ => {}
+            }
+        }
+        tables
+    }
+
+    /// What the input chain does with a new TCP connection from `from` to
+    /// `port`
+    fn fate(&self, from: IpAddr, port: u16) -> Fate {
+        self.hook_fate(self.input.as_deref(), &Packet::tcp(from, port), &mut Vec::new())
+    }
+
+    /// What the base chain `hook` does with `packet`, noting each rule
+    /// that decided something in `path` as `chain: rule`
+    fn hook_fate(&self, hook: Option<&str>, packet: &Packet, path: &mut Vec<String>) -> Fate {
+        let Some(hook) = hook else { return Fate::Accepted };
+        if let Some(fate) = self.chain_fate(hook, packet, 0, path) {
+            return fate;
+        }
+        let policy_drop = self.chains[hook].iter().any(|rule| rule.iter().map(String::as_str).eq(["policy", "drop"]));
+        path.push(format!("{}: policy {}", hook, if policy_drop { "drop" } else { "accept" }));
+        if policy_drop {
+            Fate::Dropped
+        } else {
+            Fate::Accepted
+        }
+    }
+
+    fn chain_fate(&self, chain: &str, packet: &Packet, depth: usize, path: &mut Vec<String>) -> Option<Fate> {
+        let rules = self.chains.get(chain)?;
+        if depth > 16 {
+            return None;
+        }
+        for words in rules {
+            if matches!(words[0].as_str(), "type" | "policy" | "comment" | "devices") {
+                continue;
+            }
+            let (matches, verdict, target) = split_rule(words);
+            let Some(limited) = self.rule_matches(&matches, packet) else { continue };
+            if verdict.is_some() {
+                path.push(format!("{}: {}", chain, words.join(" ")));
+            }
+            match verdict {
+                Some("accept") if limited => return Some(Fate::RateLimited),
+                Some("accept") => return Some(Fate::Accepted),
+                Some("drop" | "reject") => return Some(Fate::Dropped),
+                Some("return") => return None,
+                Some("jump") => {
+                    if let Some(fate) = target.and_then(|target| self.chain_fate(target, packet, depth + 1, path)) {
+                        return Some(fate);
+                    }
+                }
+                Some("goto") => return target.and_then(|target| self.chain_fate(target, packet, depth + 1, path)),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// `Some(rate limited)` when `packet` matches every statement of a
+    /// rule's `matches`
+    fn rule_matches(&self, matches: &[String], packet: &Packet) -> Option<bool> {
+        let mut limited = false;
+        let mut words = matches.iter().map(String::as_str);
+        while let Some(word) = words.next() {
+            match (word, words.next()) {
+                ("ip" | "ip6", Some(field @ ("saddr" | "daddr"))) => {
+                    let value = words.next()?;
+                    let addr = if field == "saddr" { packet.from } else { packet.to? };
+                    if (word == "ip") != addr.is_ipv4() || !self.value_nets(value).iter().any(|net| net.contains(addr)) {
+                        return None;
+                    }
+                }
+                ("tcp" | "udp" | "th", Some("dport")) => {
+                    if word != "th" && word != packet.protocol || !ports(words.next()?).any(|(low, high)| (low..=high).contains(&packet.port)) {
+                        return None;
+                    }
+                }
+                ("meta", Some("l4proto")) => {
+                    if !literal(words.next()?).any(|protocol| protocol == packet.protocol) {
+                        return None;
+                    }
+                }
+                ("iifname" | "iif", Some(value)) => {
+                    let interface = packet.interface?;
+                    if !literal(value).any(|name| name.trim_matches('"') == interface) {
+                        return None;
+                    }
+                }
+                ("ct", Some("state")) => {
+                    if !words.next()?.contains("new") {
+                        return None;
+                    }
+                }
+                // Assume the window is open
+                ("meta", Some("hour")) => {
+                    words.next();
+                }
+                // `limit rate 25/second` or `limit rate 25 / second`
+                ("limit", Some("rate")) => {
+                    match words.next()? {
+                        "over" => return None,
+                        count if !count.contains('/') => {
+                            words.next();
+                            words.next();
+                        }
+                        _ => {}
+                    }
+                    limited = true;
+                }
+                ("burst", Some(_)) => {
+                    words.next();
+                }
+                _ => return None,
+            }
+        }
+        Some(limited)
+    }
+
+    /// The prefixes of a set reference or a literal
+    fn value_nets(&self, value: &str) -> Vec<IpNetwork> {
+        if let Some(set) = value.strip_prefix('@') {
+            return self.sets.get(set).cloned().unwrap_or_default();
+        }
+        let elements: Vec<(String, usize)> = literal(value).map(|element| (element.to_string(), 0)).collect();
+        compare::prefixes(&elements)
+    }
+
+    /// The space of the sets that rules accept from and drop
+    fn set_space(&self) -> (Vec<IpNetwork>, Vec<IpNetwork>) {
+        let (mut accepted, mut dropped) = (Vec::new(), Vec::new());
+        for rules in self.chains.values() {
+            for words in rules {
+                let (matches, verdict, _) = split_rule(words);
+                let into = match verdict {
+                    Some("accept") => &mut accepted,
+                    Some("drop" | "reject") => &mut dropped,
+                    _ => continue,
+                };
+                for window in matches.windows(2) {
+                    if matches!(window[0].as_str(), "saddr") {
+                        into.extend(self.value_nets(&window[1]));
+                    }
+                }
+            }
+        }
+        (cidr::aggregate(&accepted), cidr::aggregate(&dropped))
+    }
+}
+
+/// The rules of a ruleset as far as new inbound connections go, for asking
+/// what loaded rules do with particular addresses
+pub struct Model(Tables);
+
+impl Model {
+    pub fn new(ruleset: &str) -> Model {
+        Model(Tables::read(table_body(&lint::parse_nft(ruleset))))
+    }
+
+    /// Whether a chain is on the input hook at all
+    pub fn hooked(&self) -> bool {
+        self.0.input.is_some()
+    }
+
+    /// What happens to a new TCP connection from `from` to `port`:
+    /// accepted, rate limited or dropped
+    pub fn fate(&self, from: IpAddr, port: u16) -> &'static str {
+        self.0.fate(from, port).describe()
+    }
+
+    /// Whether `set` holds `addr`
+    pub fn in_set(&self, set: &str, addr: IpAddr) -> bool {
+        self.0.sets.get(set).is_some_and(|nets| nets.iter().any(|net| net.contains(addr)))
+    }
+
+    /// Whether a chain is on the forward hook
+    pub fn forwards(&self) -> bool {
+        self.0.forward.is_some()
+    }
+
+    /// What the input chain (or the forward chain) does with `packet`, and
+    /// the rules that led there
+    pub fn trace(&self, packet: &Packet, forward: bool) -> (&'static str, Vec<String>) {
+        let hook = if forward { &self.0.forward } else { &self.0.input };
+        let mut path = Vec::new();
+        let fate = self.0.hook_fate(hook.as_deref(), packet, &mut path);
+        (fate.describe(), path)
+    }
+}
+
+/// The statements of the last inet table in a parsed ruleset, or of the
+/// last table if none is inet; the netdev and bridge tables of
+/// `--offload` and `--bridge` come after cloak's own
+fn table_body(stmts: &[Stmt]) -> &[Stmt] {
+    let tables: Vec<&Stmt> = stmts.iter().filter(|s| s.words.first().is_some_and(|w| w == "table") && s.body.is_some()).collect();
+    tables
+        .iter()
+        .rev()
+        .find(|s| s.words.get(1).is_some_and(|family| family == "inet"))
+        .or(tables.last())
+        .and_then(|s| s.body.as_deref())
+        .unwrap_or_default()
+}
+
+/// A rule's matching part, its verdict and the chain a jump goes to
+fn split_rule(words: &[String]) -> (Vec<String>, Option<&str>, Option<&str>) {
+    let at = words
+        .iter()
+        .position(|w| matches!(w.as_str(), "accept" | "drop" | "reject" | "return" | "goto" | "jump" | "queue" | "continue"));
+    match at {
+        Some(at) => (lint::nft_matches(&words[..at]), Some(words[at].as_str()), words.get(at + 1).map(String::as_str)),
+        None => (lint::nft_matches(words), None, None),
+    }
+}
+
+/// The elements of `{ a, b }`, or the value itself
+fn literal(value: &str) -> impl Iterator<Item = &str> {
+    let inner = value.strip_prefix("{ ").and_then(|v| v.strip_suffix(" }")).unwrap_or(value);
+    inner.split(", ").filter(|element| !element.is_empty())
+}
+
+/// Port ranges of a `dport` value
+fn ports(value: &str) -> impl Iterator<Item = (u16, u16)> + '_ {
+    literal(value).filter_map(|element| match element.split_once('-') {
+        Some((low, high)) => Some((low.parse().ok()?, high.parse().ok()?)),
+        None => element.parse().ok().map(|port| (port, port)),
+    })
+}
+
+/// Share of the IPv4 space and of the IPv6 unicast space (2000::/3) in
+/// `nets`, in percent
+fn shares(nets: &[IpNetwork]) -> (f64, f64) {
+    let unicast = Exclusions::new(&["2000::/3".parse::<IpNetwork>().expect("valid prefix")]);
+    let (mut v4, mut v6) = (0f64, 0f64);
+    for net in nets {
+        let (start, end) = cidr::bounds(net);
+        let size = (end - start) as f64 + 1.0;
+        if net.is_ipv4() {
+            v4 += size;
+        } else if unicast.covers(net) {
+            v6 += size;
+        }
+    }
+    (v4 / 2f64.powi(32) * 100.0, v6 / 2f64.powi(125) * 100.0)
+}
+
+/// A management address to check and where it came from
+struct Peer {
+    addr: SocketAddr,
+    origin: &'static str,
+}
+
+/// The report for one ruleset
+pub struct Report {
+    prefixes: usize,
+    sets: usize,
+    /// What happens to new connections from addresses in none of the sets
+    default: Fate,
+    accepted: Vec<IpNetwork>,
+    dropped: Vec<IpNetwork>,
+    peers: Vec<(Peer, Fate)>,
+    /// Accepting rules of other tables that cannot see their traffic any
+    /// more, as `table chain: rule`
+    moot: Vec<String>,
+    /// Container stacks and firewalls forwarding connections in past the
+    /// input chain, when the ruleset has no forward chain of its own
+    forwarders: Vec<&'static str>,
+    /// Bridges of this host with their physical ports, when the ruleset
+    /// has no bridge table for them
+    bridges: Vec<(String, Vec<String>)>,
+}
+
+/// Work out the report for `ruleset`, checking the current SSH session,
+/// other established SSH connections and `reachable`
+pub fn assess(ruleset: &str, reachable: &[SocketAddr]) -> Report {
+    let stmts = lint::parse_nft(ruleset);
+    let tables = Tables::read(table_body(&stmts));
+    let bridged = stmts.iter().any(|s| s.words.first().is_some_and(|w| w == "table") && s.words.get(1).is_some_and(|family| family == "bridge"));
+    let prefixes = tables.sets.values().map(Vec::len).sum();
+    let sets = tables.sets.values().filter(|nets| !nets.is_empty()).count();
+    // Documentation addresses are in no country's list
+    let default = tables.fate(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 443);
+    let (accepted, mut dropped) = tables.set_space();
+    // Accepted earlier, e.g. whitelisted, beats dropped later
+    let accepted_space = Exclusions::new(&accepted);
+    dropped = dropped.iter().flat_map(|net| {
+        let mut left = Vec::new();
+        accepted_space.subtract(net, &mut left);
+        left
+    }).collect();
+
+    let peers: Vec<(Peer, Fate)> = management_peers(reachable)
+        .into_iter()
+        .map(|peer| {
+            let fate = tables.fate(peer.addr.ip(), peer.addr.port());
+            (peer, fate)
+        })
+        .collect();
+
+    let cut_off = |net: &IpNetwork| match default {
+        Fate::Dropped => !accepted_space.overlaps(net),
+        _ => Exclusions::new(&dropped).covers(net),
+    };
+    let live = live_ruleset();
+    let moot = live_moot_rules(&live, &cut_off);
+    let forwarders = if tables.forward.is_none() { forwarding_stacks(&live) } else { Vec::new() };
+    let bridges = if bridged { Vec::new() } else { host_bridges() };
+    Report { prefixes, sets, default, accepted, dropped, peers, moot, forwarders, bridges }
+}
+
+impl Report {
+    /// Whether loading needs a typed confirmation: the ruleset only allows
+    /// some countries, or it would cut off a management address
+    pub fn risky(&self) -> bool {
+        self.allow_mode() || self.locks_out()
+    }
+
+    fn allow_mode(&self) -> bool {
+        self.default == Fate::Dropped
+    }
+
+    fn locks_out(&self) -> bool {
+        self.peers.iter().any(|(_, fate)| *fate == Fate::Dropped)
+    }
+
+    pub fn print(&self, file: &str) {
+        info!("Before loading {}:", file);
+        info!("   {} prefixes in {} sets", self.prefixes, self.sets);
+        if self.allow_mode() {
+            let (v4, v6) = shares(&self.accepted);
+            info!(
+                "   only accepts new connections from {} prefixes ({:.2}% of IPv4, {:.2}% of IPv6 unicast); the other {:.2}% of IPv4 is dropped",
+                self.accepted.len(),
+                v4,
+                v6,
+                100.0 - v4
+            );
+        } else {
+            let (v4, v6) = shares(&self.dropped);
+            info!(
+                "   drops new connections from {} prefixes ({:.2}% of IPv4, {:.2}% of IPv6 unicast); other new connections are {}",
+                self.dropped.len(),
+                v4,
+                v6,
+                self.default.describe()
+            );
+        }
+        if self.peers.is_empty() {
+            info!("   no SSH session or --reachable address to check");
+        }
+        for (peer, fate) in &self.peers {
+            if *fate == Fate::Dropped {
+                warning!("{} ({}) could not connect any more: new connections from it are dropped", peer.addr, peer.origin);
+            } else {
+                info!("   {} ({}) stays reachable: new connections from it are {}", peer.addr, peer.origin, fate.describe());
+            }
+        }
+        if !self.forwarders.is_empty() {
+            warning!(
+                "{} forward connections (published container ports, port forwards) past the input chain, where these rules do not see them; generate them with --containers",
+                self.forwarders.join(", ")
+            );
+        }
+        for (bridge, ports) in &self.bridges {
+            warning!(
+                "bridge {} passes traffic between {} and its VMs without the input chain, where these rules do not see it; generate them with --bridge {}",
+                bridge,
+                ports.join(", "),
+                ports[0]
+            );
+        }
+        if !self.moot.is_empty() {
+            warning!("{} accepting rules already loaded would no longer see the traffic they accept:", self.moot.len());
+            for rule in self.moot.iter().take(MAX_LISTED) {
+                warning!("   {}", rule);
+            }
+            if self.moot.len() > MAX_LISTED {
+                warning!("   ... and {} more", self.moot.len() - MAX_LISTED);
+            }
+        }
+    }
+
+    /// Ask for the typed confirmation [`Report::risky`] rulesets need
+    pub fn confirm(&self) -> std::io::Result<bool> {
+        let (question, word) = match (self.allow_mode(), self.locks_out()) {
+            (true, true) => ("These rules only allow some countries and cut off a management address.", "allow"),
+            (true, false) => ("These rules drop every country they do not allow.", "allow"),
+            (false, _) => ("These rules cut off a management address.", "cut off"),
+        };
+        ui::confirm_typed(question, word)
+    }
+}
+
+/// The current SSH client, peers of other established connections to the
+/// SSH port and the `--reachable` addresses
+fn management_peers(reachable: &[SocketAddr]) -> Vec<Peer> {
+    let mut peers = Vec::new();
+    let mut ssh_port = 22;
+    // `client_ip client_port server_ip server_port`
+    if let Ok(connection) = env::var("SSH_CONNECTION") {
+        let fields: Vec<&str> = connection.split_whitespace().collect();
+        if let [client, _, _, port] = fields.as_slice() {
+            ssh_port = port.parse().unwrap_or(22);
+            if let Ok(client) = client.parse::<IpAddr>() {
+                peers.push(Peer { addr: SocketAddr::new(client.to_canonical(), ssh_port), origin: "this session" });
+            }
+        }
+    }
+    for peer in established_peers(ssh_port) {
+        if !peers.iter().any(|known| known.addr.ip() == peer) {
+            peers.push(Peer { addr: SocketAddr::new(peer, ssh_port), origin: "connected over SSH" });
+        }
+    }
+    peers.extend(reachable.iter().map(|&addr| Peer { addr, origin: "--reachable" }));
+    peers
+}
+
+/// Remote addresses of established TCP connections to local `port`, from
+/// /proc/net/tcp and tcp6
+fn established_peers(port: u16) -> Vec<IpAddr> {
+    let mut peers = Vec::new();
+    for file in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let Ok(text) = fs::read_to_string(file) else { continue };
+        for line in text.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // `sl local_address rem_address st ...`, state 01 is ESTABLISHED
+            let [_, local, remote, "01", ..] = fields.as_slice() else { continue };
+            let local_port = local.rsplit_once(':').and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+            if local_port != Some(port) {
+                continue;
+            }
+            if let Some(addr) = remote.rsplit_once(':').and_then(|(addr, _)| proc_addr(addr)) {
+                let addr = addr.to_canonical();
+                if !peers.contains(&addr) && !addr.is_loopback() {
+                    peers.push(addr);
+                }
+            }
+        }
+    }
+    peers
+}
+
+/// An address as /proc/net/tcp prints it: 32-bit words in hex, each in
+/// host byte order
+fn proc_addr(hex: &str) -> Option<IpAddr> {
+    let words: Vec<u32> = (0..hex.len() / 8).map(|i| u32::from_str_radix(hex.get(i * 8..i * 8 + 8)?, 16).ok()).collect::<Option<_>>()?;
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_ne_bytes()).collect();
+    match bytes.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))),
+        16 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?))),
+        _ => None,
+    }
+}
+
+/// Bridges with physical ports, each with those ports; VMs and
+/// containers on the other ports are reached without the input hook
+fn host_bridges() -> Vec<(String, Vec<String>)> {
+    let sys = Path::new("/sys/class/net");
+    let Ok(entries) = fs::read_dir(sys) else { return Vec::new() };
+    let mut bridges = Vec::new();
+    for entry in entries.flatten() {
+        let Ok(ports) = fs::read_dir(entry.path().join("brif")) else { continue };
+        let mut physical: Vec<String> = ports
+            .flatten()
+            .map(|port| port.file_name().to_string_lossy().into_owned())
+            .filter(|port| sys.join(port).join("device").exists())
+            .collect();
+        if physical.is_empty() {
+            continue;
+        }
+        physical.sort();
+        bridges.push((entry.file_name().to_string_lossy().into_owned(), physical));
+    }
+    bridges.sort();
+    bridges
+}
+
+/// The statements of the loaded ruleset, none when it cannot be listed
+fn live_ruleset() -> Vec<Stmt> {
+    match privilege::command("nft").args(["list", "ruleset"]).stderr(Stdio::null()).output() {
+        Ok(output) if output.status.success() => lint::parse_nft(&String::from_utf8_lossy(&output.stdout)),
+        _ => Vec::new(),
+    }
+}
+
+/// Container stacks and firewalls in the loaded ruleset that DNAT
+/// connections to containers or other hosts, which then take the forward
+/// hook instead of input
+fn forwarding_stacks(live: &[Stmt]) -> Vec<&'static str> {
+    let mut stacks = Vec::new();
+    for table in live {
+        let (Some(name), Some(body)) = (table.words.get(2), &table.body) else { continue };
+        let chains: Vec<&Stmt> = body.iter().filter(|stmt| stmt.words[0] == "chain").collect();
+        let has_chain = |prefix: &str| chains.iter().any(|chain| chain.words.get(1).is_some_and(|n| n.starts_with(prefix)));
+        let dnats = chains.iter().flat_map(|chain| chain.body.iter().flatten()).any(|rule| rule.words.iter().any(|w| w == "dnat"));
+        let stack = match name.as_str() {
+            "netavark" => Some("Podman (netavark)"),
+            "firewalld" if dnats => Some("firewalld"),
+            _ if has_chain("NETAVARK") => Some("Podman (netavark)"),
+            _ if has_chain("DOCKER") => Some("Docker"),
+            _ => None,
+        };
+        if let Some(stack) = stack.filter(|stack| !stacks.contains(stack)) {
+            stacks.push(stack);
+        }
+    }
+    stacks
+}
+
+/// Accepting rules on the input hook in other tables of `live` whose
+/// source prefixes all fall in the space `cut_off` reports the new rules
+/// drop
+fn live_moot_rules(live: &[Stmt], cut_off: &dyn Fn(&IpNetwork) -> bool) -> Vec<String> {
+    let mut moot = Vec::new();
+    for table in live {
+        let (Some(family), Some(name), Some(body)) = (table.words.get(1), table.words.get(2), &table.body) else {
+            continue;
+        };
+        if table.words[0] != "table" || (family == "inet" && name == nft::TABLE) {
+            continue;
+        }
+        // Attached rules live in the target table under cloak_ names
+        let tables = Tables::read(body.iter().filter(|stmt| !stmt.words.get(1).is_some_and(|n| n.starts_with("cloak_"))));
+        let Some(input) = &tables.input else { continue };
+        for words in &tables.chains[input] {
+            let (matches, verdict, _) = split_rule(words);
+            if verdict != Some("accept") {
+                continue;
+            }
+            let nets: Vec<IpNetwork> = matches
+                .windows(2)
+                .filter(|window| window[0] == "saddr")
+                .flat_map(|window| tables.value_nets(&window[1]))
+                .collect();
+            if !nets.is_empty() && nets.iter().all(cut_off) {
+                moot.push(format!("{} {} {}: {}", family, name, input, words.join(" ")));
+            }
+        }
+    }
+    moot
+}
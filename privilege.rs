@@ -0,0 +1,161 @@
+//! Running the privileged tools (`nft`, `conntrack`).
+//!
+//! If cloak already has CAP_NET_ADMIN (running as root, or granted it as a
+//! file capability with `setcap cap_net_admin+ep cloak`) the tools are run
+//! directly. Otherwise they go through the escalation command given with
+//! `--escalate` (e.g. `sudo`, `doas`). Nothing is assumed: hard-coding sudo
+//! breaks systemd services and containers that don't have it.
+//!
+//! Capabilities from the permitted set are not inherited across exec by
+//! default, so CAP_NET_ADMIN is raised into the ambient set of the tool's
+//! process alone, between fork and exec; nothing else cloak starts gets it.
+//! The tools are found in [`TOOL_DIRS`], never through `$PATH`, which
+//! would let anyone run their own `nft` with a setcap'd cloak's privileges.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+/// Where the privileged tools are looked for, in order
+const TOOL_DIRS: &[&str] = &["/usr/sbin", "/sbin", "/usr/bin", "/bin"];
+
+static ESCALATE: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Set the escalation command (split on whitespace, e.g. "sudo -n") used
+/// when cloak lacks the privileges itself.
+pub fn init(escalate: Option<&str>) {
+    let words = escalate.map(|cmd| cmd.split_whitespace().map(str::to_string).collect()).unwrap_or_default();
+    let _ = ESCALATE.set(words);
+}
+
+/// Build a command running `program` with the privileges it needs.
+pub fn command(program: &str) -> Command {
+    let program = tool_path(program);
+    match ESCALATE.get().filter(|words| !words.is_empty()) {
+        Some(words) if !has_net_admin() => {
+            let mut command = Command::new(&words[0]);
+            command.args(&words[1..]).arg(program);
+            command
+        }
+        _ => {
+            let mut command = Command::new(program);
+            pass_net_admin(&mut command);
+            command
+        }
+    }
+}
+
+/// The absolute path of `program` in [`TOOL_DIRS`]; in the first of them
+/// if it is in none, so running it fails rather than finds another one
+pub fn tool_path(program: &str) -> PathBuf {
+    TOOL_DIRS
+        .iter()
+        .map(|dir| Path::new(dir).join(program))
+        .find(|candidate| candidate.is_file())
+        .unwrap_or_else(|| Path::new(TOOL_DIRS[0]).join(program))
+}
+
+/// Appended to errors from privileged tools
+pub fn hint() -> &'static str {
+    if has_net_admin() || ESCALATE.get().is_some_and(|words| !words.is_empty()) {
+        ""
+    } else {
+        " (needs root or CAP_NET_ADMIN; or pass --escalate sudo)"
+    }
+}
+
+/// Whether the effective capability set includes CAP_NET_ADMIN
+pub fn has_net_admin() -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        linux::has_net_admin()
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        false
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    const CAP_NET_ADMIN: u32 = 12;
+    const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+    #[repr(C)]
+    struct CapHeader {
+        version: u32,
+        pid: libc::c_int,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct CapData {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    /// CapEff from /proc, which also covers root without CAP_NET_ADMIN
+    /// (e.g. in an unprivileged container)
+    pub fn has_net_admin() -> bool {
+        std::fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| {
+                let hex = status.lines().find_map(|line| line.strip_prefix("CapEff:"))?;
+                u64::from_str_radix(hex.trim(), 16).ok()
+            })
+            .is_some_and(|caps| caps & (1 << CAP_NET_ADMIN) != 0)
+    }
+
+    /// Raise CAP_NET_ADMIN into the ambient set of the calling process.
+    /// Runs between fork and exec, so only makes raw system calls.
+    pub fn raise_ambient() {
+        let mut header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+        let mut data = [CapData::default(); 2];
+        // SAFETY: header and data match the kernel's v3 capability ABI
+        // (one header, two data words) and outlive the calls.
+        unsafe {
+            if libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) != 0 {
+                return;
+            }
+            let bit = 1u32 << CAP_NET_ADMIN;
+            if data[0].permitted & bit == 0 {
+                return;
+            }
+            // Ambient capabilities must also be permitted and inheritable
+            data[0].inheritable |= bit;
+            if libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) != 0 {
+                return;
+            }
+            libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong,
+                CAP_NET_ADMIN as libc::c_ulong,
+                0 as libc::c_ulong,
+                0 as libc::c_ulong,
+            );
+        }
+    }
+}
+
+/// Let `command`'s program inherit CAP_NET_ADMIN if cloak holds it. Best
+/// effort: without the capability, or as root, nothing changes.
+fn pass_net_admin(command: &mut Command) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: the hook runs in the forked child before exec and only
+        // makes the capget, capset and prctl system calls, which are
+        // async-signal-safe; it allocates nothing and takes no locks
+        unsafe {
+            command.pre_exec(|| {
+                linux::raise_ambient();
+                Ok(())
+            });
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = command;
+}
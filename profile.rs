@@ -0,0 +1,257 @@
+//! Named profiles: several policies layered into one ruleset.
+//!
+//! Profiles live in the `profiles:` section of a config file. Each is an
+//! ordered list of layers and the first layer that claims a packet decides:
+//!
+//! ```yaml
+//! profiles:
+//!   edge:
+//!     - { list: brics, action: block, interface: wan0 }
+//!     - { countries: [ir, kp], action: block }
+//!     - { sources: [173.245.48.0/20, 2606:4700::/32], action: allow, ports: [443] }
+//! ```
+//!
+//! A layer's scope is its interface and destination ports, when given. A
+//! block layer drops its sources within that scope; an allow layer accepts
+//! its sources and drops the rest of the scope. Traffic outside every scope
+//! is accepted.
+//!
+//! Instead of one list for all traffic, a profile can split interfaces into
+//! zones, each with its own layers and its own chain:
+//!
+//! ```yaml
+//! profiles:
+//!   site:
+//!     zones:
+//!       wan: { interfaces: [eth0, ppp0], layers: [{ list: brics, action: block }] }
+//!       vpn: { interfaces: [wg0], layers: [{ countries: [de, nl], action: allow }] }
+//! ```
+//!
+//! An interface may belong to one zone only; interfaces in no zone are not
+//! filtered.
+//!
+//! On a router, a zone can list internal `subnets` instead of interfaces.
+//! Its layers then apply to forwarded traffic leaving those subnets and
+//! match where it is going, so each network gets its own policy:
+//!
+//! ```yaml
+//! profiles:
+//!   router:
+//!     zones:
+//!       guest: { subnets: [192.168.20.0/24], layers: [{ list: eu, action: allow }] }
+//!       lan: { subnets: [192.168.1.0/24, "fd00:1::/64"], layers: [{ list: sanctioned, action: block }] }
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use ipnetwork::IpNetwork;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    groups::{self, Group},
+    nft::{Layer, Zone},
+    yaml, Action, CountryNets, SerIpNet,
+};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayerConfig {
+    list: Option<String>,
+    #[serde(default)]
+    countries: Vec<String>,
+    #[serde(default)]
+    sources: Vec<SerIpNet>,
+    action: Action,
+    interface: Option<String>,
+    #[serde(default)]
+    ports: Vec<u16>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ZonesConfig {
+    zones: BTreeMap<String, ZoneConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ZoneConfig {
+    #[serde(default)]
+    interfaces: Vec<String>,
+    #[serde(default)]
+    subnets: Vec<SerIpNet>,
+    layers: Vec<LayerConfig>,
+}
+
+struct ProfileZone {
+    name: String,
+    interfaces: Vec<String>,
+    subnets: Vec<IpNetwork>,
+    layers: Vec<ProfileLayer>,
+}
+
+struct ProfileLayer {
+    group: Option<Group>,
+    sources: Vec<IpNetwork>,
+    action: Action,
+    interface: Option<String>,
+    ports: Vec<u16>,
+}
+
+pub struct Profile {
+    pub name: String,
+    zones: Vec<ProfileZone>,
+}
+
+impl Profile {
+    /// Read profile `name` from the `profiles:` section of `path`
+    pub fn load(path: &Path, name: &str, file_groups: &HashMap<String, Vec<String>>) -> Result<Profile> {
+        let text = fs::read_to_string(path).with_context(|| format!("read config {}", path.display()))?;
+        let doc = yaml::parse(&text).with_context(|| format!("parse {}", path.display()))?;
+        let Some(Value::Object(profiles)) = doc.get("profiles") else {
+            bail!("{} has no `profiles:` section", path.display());
+        };
+        let Some(section) = profiles.get(name) else {
+            let known: Vec<&str> = profiles.keys().map(String::as_str).collect();
+            bail!("{} defines no profile `{}` (defined: {})", path.display(), name, known.join(", "));
+        };
+        let context = || format!("{}: profile `{}`", path.display(), name);
+        let zones = if section.is_array() {
+            let layers = serde_json::from_value(section.clone()).with_context(context)?;
+            let layers = build_layers(layers, file_groups).with_context(context)?;
+            vec![ProfileZone { name: String::new(), interfaces: Vec::new(), subnets: Vec::new(), layers }]
+        } else {
+            let config: ZonesConfig = serde_json::from_value(section.clone()).with_context(context)?;
+            build_zones(config, file_groups).with_context(context)?
+        };
+        Ok(Profile { name: name.to_string(), zones })
+    }
+
+    /// Every country some layer needs, each once
+    pub fn countries(&self) -> Vec<(String, String)> {
+        let mut countries: Vec<(String, String)> = Vec::new();
+        let layers = self.zones.iter().flat_map(|zone| &zone.layers);
+        for group in layers.filter_map(|layer| layer.group.as_ref()) {
+            for country in &group.countries {
+                if !countries.iter().any(|(cc, _)| *cc == country.0) {
+                    countries.push(country.clone());
+                }
+            }
+        }
+        countries
+    }
+
+    /// The profile's zones, with their countries filled in from `map`
+    pub fn zones(&self, map: &HashMap<String, CountryNets>) -> Vec<Zone> {
+        self.zones
+            .iter()
+            .map(|zone| Zone {
+                name: zone.name.clone(),
+                interfaces: zone.interfaces.clone(),
+                subnets: zone.subnets.clone(),
+                layers: zone.layers.iter().map(|layer| layer.resolve(map)).collect(),
+            })
+            .collect()
+    }
+}
+
+impl ProfileLayer {
+    fn resolve(&self, map: &HashMap<String, CountryNets>) -> Layer {
+        let mut nets = self.sources.clone();
+        for (cc, _) in self.group.iter().flat_map(|group| &group.countries) {
+            if let Some(country) = map.get(cc) {
+                nets.extend(country.ipv4.iter().chain(&country.ipv6).map(|net| net.0));
+            }
+        }
+        let (ipv4, ipv6) = nets.into_iter().partition(IpNetwork::is_ipv4);
+        Layer {
+            action: self.action,
+            interface: self.interface.clone(),
+            ports: self.ports.clone(),
+            ipv4,
+            ipv6,
+        }
+    }
+}
+
+fn build_zones(config: ZonesConfig, file_groups: &HashMap<String, Vec<String>>) -> Result<Vec<ProfileZone>> {
+    if config.zones.is_empty() {
+        bail!("`zones` is empty");
+    }
+    let mut zones: Vec<ProfileZone> = Vec::new();
+    for (name, zone) in config.zones {
+        let valid = name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_');
+        if !valid || !name.starts_with(|c: char| c.is_ascii_lowercase()) {
+            bail!("zone name `{}` must be lowercase letters, digits and `_`", name);
+        }
+        if zone.interfaces.is_empty() == zone.subnets.is_empty() {
+            bail!("zone `{}` needs either `interfaces` or `subnets`", name);
+        }
+        for interface in &zone.interfaces {
+            check_interface(interface).with_context(|| format!("zone `{}`", name))?;
+            if let Some(other) = zones.iter().find(|z| z.interfaces.contains(interface)) {
+                bail!("interface {} is in both zone `{}` and zone `{}`", interface, other.name, name);
+            }
+        }
+        let subnets: Vec<IpNetwork> = zone.subnets.into_iter().map(|net| net.0).collect();
+        for subnet in &subnets {
+            let overlapping = |z: &&ProfileZone| z.subnets.iter().any(|net| net.contains(subnet.network()) || subnet.contains(net.network()));
+            if let Some(other) = zones.iter().find(overlapping) {
+                bail!("subnet {} of zone `{}` overlaps zone `{}`", subnet, name, other.name);
+            }
+        }
+        let layers = build_layers(zone.layers, file_groups).with_context(|| format!("zone `{}`", name))?;
+        zones.push(ProfileZone { name, interfaces: zone.interfaces, subnets, layers });
+    }
+    Ok(zones)
+}
+
+fn build_layers(configs: Vec<LayerConfig>, file_groups: &HashMap<String, Vec<String>>) -> Result<Vec<ProfileLayer>> {
+    if configs.is_empty() {
+        bail!("no layers");
+    }
+    configs
+        .into_iter()
+        .enumerate()
+        .map(|(i, config)| build_layer(config, file_groups).with_context(|| format!("layer {}", i + 1)))
+        .collect()
+}
+
+fn build_layer(config: LayerConfig, file_groups: &HashMap<String, Vec<String>>) -> Result<ProfileLayer> {
+    let group = match (config.list, config.countries.is_empty()) {
+        (Some(list), true) => Some(groups::resolve(&list, file_groups)?),
+        (None, false) => Some(groups::from_codes("custom", &config.countries)?),
+        (None, true) => None,
+        (Some(_), false) => bail!("set at most one of `list` or `countries`"),
+    };
+    if group.is_none() && config.sources.is_empty() {
+        bail!("needs a `list`, `countries` or `sources`");
+    }
+    if let Some(interface) = &config.interface {
+        check_interface(interface)?;
+    }
+    if config.ports.contains(&0) {
+        bail!("port 0 cannot be matched");
+    }
+    Ok(ProfileLayer {
+        group,
+        sources: config.sources.into_iter().map(|net| net.0).collect(),
+        action: config.action,
+        interface: config.interface,
+        ports: config.ports,
+    })
+}
+
+fn check_interface(name: &str) -> Result<()> {
+    let valid = name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.*".contains(&b));
+    if name.is_empty() || name.len() > 15 || !valid {
+        bail!("`{}` is not an interface name", name);
+    }
+    Ok(())
+}
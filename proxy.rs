@@ -0,0 +1,85 @@
+//! Reverse-proxy snippets, for setups where a proxy rather than the
+//! kernel firewall is the place to filter: Traefik `ipAllowList`
+//! middlewares and Caddy `remote_ip` matchers.
+//!
+//! Traefik can only allow listed ranges, so it takes allow lists alone.
+
+use std::{collections::HashMap, fmt::Write, fs};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{Action, CountryNets};
+
+/// Caddy reads arbitrarily long lines, but editors and diffs do not
+const PREFIXES_PER_LINE: usize = 20;
+
+/// Every prefix of `map`, IPv4 first, in country order
+fn prefixes(map: &HashMap<String, CountryNets>) -> Vec<String> {
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    let ipv4 = codes.iter().flat_map(|cc| &map[*cc].ipv4);
+    ipv4.chain(codes.iter().flat_map(|cc| &map[*cc].ipv6)).map(|net| net.0.to_string()).collect()
+}
+
+/// `name` as a config identifier (letters, digits and `_`)
+fn identifier(name: &str) -> String {
+    format!("cloak_{}", name.replace(|c: char| !c.is_ascii_alphanumeric(), "_"))
+}
+
+/// Write a Traefik middleware for `map` to `filename`, as dynamic
+/// configuration in YAML or (with `toml`) TOML for the file provider
+pub fn generate_traefik(map: &HashMap<String, CountryNets>, action: Action, toml: bool, name: &str, filename: &str) -> Result<()> {
+    if action != Action::Allow {
+        bail!("Traefik middlewares can only allow ranges; use --action allow or --format caddy");
+    }
+    let middleware = identifier(name).replace('_', "-");
+    let mut file = String::new();
+    writeln!(file, "# Generated by cloak. Load with the file provider and add {}@file to a router's", middleware)?;
+    writeln!(file, "# middlewares; Traefik v2 calls ipAllowList ipWhiteList.")?;
+    if toml {
+        writeln!(file, "[http.middlewares.{}.ipAllowList]", middleware)?;
+        writeln!(file, "  sourceRange = [")?;
+        for net in prefixes(map) {
+            writeln!(file, "    \"{}\",", net)?;
+        }
+        writeln!(file, "  ]")?;
+    } else {
+        writeln!(file, "http:")?;
+        writeln!(file, "  middlewares:")?;
+        writeln!(file, "    {}:", middleware)?;
+        writeln!(file, "      ipAllowList:")?;
+        writeln!(file, "        sourceRange:")?;
+        for net in prefixes(map) {
+            writeln!(file, "          - \"{}\"", net)?;
+        }
+    }
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
+
+/// Write a Caddyfile snippet for `map` to `filename` that aborts requests
+/// from the prefixes (block) or from everywhere else (allow)
+pub fn generate_caddy(map: &HashMap<String, CountryNets>, action: Action, name: &str, filename: &str) -> Result<()> {
+    let snippet = identifier(name);
+    let nets = prefixes(map);
+    let mut file = String::new();
+    writeln!(file, "# Generated by cloak. Add `import {}` at the top of the Caddyfile,", filename)?;
+    writeln!(file, "# then `import {}` in each site block to filter.", snippet)?;
+    writeln!(file, "({}) {{", snippet)?;
+    writeln!(file, "\t@{} {{", snippet)?;
+    let indent = if action == Action::Allow {
+        writeln!(file, "\t\tnot {{")?;
+        "\t\t\t"
+    } else {
+        "\t\t"
+    };
+    for chunk in nets.chunks(PREFIXES_PER_LINE) {
+        writeln!(file, "{}remote_ip {}", indent, chunk.join(" "))?;
+    }
+    if action == Action::Allow {
+        writeln!(file, "\t\t}}")?;
+    }
+    writeln!(file, "\t}}")?;
+    writeln!(file, "\tabort @{}", snippet)?;
+    writeln!(file, "}}")?;
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
@@ -0,0 +1,65 @@
+//! RDAP lookups of who holds an address, for `cloak lookup --whois`.
+//!
+//! Queries go to rdap.org, which redirects to the registry responsible for
+//! the address.
+
+use std::net::IpAddr;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+const IP_URL: &str = "https://rdap.org/ip/";
+
+/// Holder and abuse contact of the network an address belongs to
+pub struct Holder {
+    /// Organization registered for the network, else the network's name
+    pub name: Option<String>,
+    pub abuse_email: Option<String>,
+}
+
+pub async fn holder(client: &reqwest::Client, addr: IpAddr) -> Result<Holder> {
+    let url = format!("{}{}", IP_URL, addr);
+    let network: Value = client
+        .get(&url)
+        .header("Accept", "application/rdap+json")
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("RDAP query for {}", addr))?
+        .json()
+        .await
+        .with_context(|| format!("parse the RDAP answer for {}", addr))?;
+
+    let entities = entities(&network);
+    let with_role = |role: &str| {
+        entities.iter().copied().find(|entity| {
+            entity["roles"].as_array().is_some_and(|roles| roles.iter().any(|r| r.as_str() == Some(role)))
+        })
+    };
+    let name = with_role("registrant")
+        .and_then(|entity| vcard(entity, "fn"))
+        .or_else(|| network["name"].as_str().map(str::to_string));
+    let abuse_email = with_role("abuse").and_then(|entity| vcard(entity, "email"));
+    Ok(Holder { name, abuse_email })
+}
+
+/// Every entity of `object`, including the ones nested in other entities
+/// (registries often put the abuse contact inside the registrant)
+fn entities(object: &Value) -> Vec<&Value> {
+    let mut found = Vec::new();
+    for entity in object["entities"].as_array().into_iter().flatten() {
+        found.push(entity);
+        found.extend(entities(entity));
+    }
+    found
+}
+
+/// First `property` of an entity's jCard, e.g. `fn` or `email`
+fn vcard(entity: &Value, property: &str) -> Option<String> {
+    entity["vcardArray"][1]
+        .as_array()?
+        .iter()
+        .find(|field| field[0].as_str() == Some(property))
+        .and_then(|field| field[3].as_str())
+        .map(str::to_string)
+}
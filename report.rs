@@ -0,0 +1,236 @@
+//! `cloak report`: which countries a policy allows and blocks and how many
+//! prefixes each one has, for reviewing a policy with people who do not
+//! read rule files.
+//!
+//! `--geojson` writes one feature per country, keyed by its ISO 3166-1
+//! alpha-2 code. cloak carries no country shapes, so the geometries are
+//! null: join the file on `iso_a2` with a world layer (e.g. Natural Earth's
+//! admin-0 countries) in QGIS, Mapshaper or kepler.gl to draw the map.
+//! `--html` writes a page with no external resources that shows every
+//! country as a tile coloured by what the policy does with it.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use crate::{
+    iso3166::{self, COUNTRIES},
+    read_maps,
+    ui::{info, success},
+    unix_now, Action, CountryNets,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct ReportArgs {
+    /// JSON maps the policy is generated from (nested layout)
+    #[arg(required = true)]
+    maps: Vec<PathBuf>,
+
+    /// What the rules do with the countries in the maps
+    #[arg(long, value_enum, default_value_t = Action::Block)]
+    action: Action,
+
+    /// Write a GeoJSON FeatureCollection with one feature per country
+    #[arg(long, value_name = "FILE")]
+    geojson: Option<PathBuf>,
+
+    /// Write a self-contained HTML page of the policy
+    #[arg(long, value_name = "FILE")]
+    html: Option<PathBuf>,
+}
+
+/// What the policy does with one country or source
+struct Entry {
+    code: String,
+    name: Option<&'static str>,
+    status: &'static str,
+    /// Prefix and IPv4 address counts, for entries in the maps
+    nets: Option<(usize, usize, u64)>,
+}
+
+pub fn run(args: &ReportArgs) -> Result<()> {
+    let map = read_maps(&args.maps)?;
+    let entries = entries(&map, args.action);
+    let listed = entries.iter().filter(|entry| entry.nets.is_some()).count();
+
+    if args.geojson.is_none() && args.html.is_none() {
+        print_table(&entries, args.action);
+        return Ok(());
+    }
+    if let Some(path) = &args.geojson {
+        let sources = entries.iter().filter(|entry| entry.name.is_none()).count();
+        fs::write(path, serde_json::to_string_pretty(&geojson(&entries, args.action))?)
+            .with_context(|| format!("write {}", path.display()))?;
+        success!("Wrote {} ({} countries, {} in the maps).", path.display(), entries.len() - sources, listed - sources);
+        if sources > 0 {
+            info!("{} entries that are not countries (--source labels) are left out of the GeoJSON.", sources);
+        }
+    }
+    if let Some(path) = &args.html {
+        write_html(path, &entries, args.action, &args.maps)?;
+        success!("Wrote {}.", path.display());
+    }
+    Ok(())
+}
+
+/// Every country, then any other labels in the maps, with what the policy
+/// does with them
+fn entries(map: &HashMap<String, CountryNets>, action: Action) -> Vec<Entry> {
+    let (listed, unlisted) = match action {
+        Action::Allow => ("allowed", "blocked"),
+        Action::Block => ("blocked", "allowed"),
+        Action::Mark => ("marked", "unmarked"),
+    };
+    let counts = |nets: &CountryNets| {
+        let addresses = nets.ipv4.iter().map(|net| 1u64 << (32 - u32::from(net.0.prefix()))).sum();
+        (nets.ipv4.len(), nets.ipv6.len(), addresses)
+    };
+    let mut entries: Vec<Entry> = COUNTRIES
+        .iter()
+        .map(|&(code, name)| {
+            let nets = map.get(code).map(counts);
+            Entry { code: code.to_string(), name: Some(name), status: if nets.is_some() { listed } else { unlisted }, nets }
+        })
+        .collect();
+    let mut others: Vec<(&String, &CountryNets)> = map.iter().filter(|(code, _)| iso3166::name(code).is_none()).collect();
+    others.sort_by(|a, b| a.0.cmp(b.0));
+    for (code, nets) in others {
+        entries.push(Entry { code: code.clone(), name: None, status: listed, nets: Some(counts(nets)) });
+    }
+    entries
+}
+
+fn print_table(entries: &[Entry], action: Action) {
+    let listed: Vec<&Entry> = entries.iter().filter(|entry| entry.nets.is_some()).collect();
+    let countries = entries.iter().filter(|entry| entry.name.is_some()).count();
+    info!(
+        "The policy {}s {} of {} countries; everything else is {}.",
+        action,
+        listed.iter().filter(|entry| entry.name.is_some()).count(),
+        countries,
+        entries.iter().find(|entry| entry.nets.is_none()).map_or("left alone", |entry| entry.status)
+    );
+    println!("{:<10} {:<32} {:>10} {:>10} {:>16}", "Code", "Name", "IPv4", "IPv6", "IPv4 addresses");
+    for entry in listed {
+        let (v4, v6, addresses) = entry.nets.unwrap_or_default();
+        println!("{:<10} {:<32} {:>10} {:>10} {:>16}", entry.code.to_uppercase(), entry.name.unwrap_or(""), v4, v6, addresses);
+    }
+}
+
+fn geojson(entries: &[Entry], action: Action) -> Value {
+    let features: Vec<Value> = entries
+        .iter()
+        .filter_map(|entry| {
+            let name = entry.name?;
+            let (v4, v6, addresses) = match entry.nets {
+                Some((v4, v6, addresses)) => (json!(v4), json!(v6), json!(addresses)),
+                None => (Value::Null, Value::Null, Value::Null),
+            };
+            Some(json!({
+                "type": "Feature",
+                "id": entry.code.to_uppercase(),
+                "geometry": null,
+                "properties": {
+                    "iso_a2": entry.code.to_uppercase(),
+                    "name": name,
+                    "status": entry.status,
+                    "listed": entry.nets.is_some(),
+                    "ipv4_prefixes": v4,
+                    "ipv6_prefixes": v6,
+                    "ipv4_addresses": addresses,
+                },
+            }))
+        })
+        .collect();
+    json!({
+        "type": "FeatureCollection",
+        "name": format!("cloak {} policy", action),
+        "features": features,
+    })
+}
+
+fn write_html(path: &Path, entries: &[Entry], action: Action, maps: &[PathBuf]) -> Result<()> {
+    let listed = entries.iter().filter(|entry| entry.nets.is_some()).count();
+    // Tiles of listed countries get darker with more addresses, on a log
+    // scale so small countries still show
+    let most = entries.iter().filter_map(|entry| entry.nets).map(|(_, _, addresses)| addresses).max().unwrap_or(1).max(2);
+    let title = format!("cloak {} policy: {} of {} entries", action, listed, entries.len());
+    let sources: Vec<String> = maps.iter().map(|map| escape(&map.display().to_string())).collect();
+
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n\
+         body {{ font-family: system-ui, sans-serif; margin: 2em; color: #222; }}\n\
+         .grid {{ display: grid; grid-template-columns: repeat(auto-fill, minmax(7.5em, 1fr)); gap: 4px; }}\n\
+         .tile {{ padding: 0.4em; border-radius: 3px; font-size: 0.8em; min-height: 3.2em; }}\n\
+         .tile b {{ display: block; font-size: 1.2em; }}\n\
+         .blocked {{ background: rgb(200, 40, 40); color: #fff; }}\n\
+         .allowed, .unmarked {{ background: #dfeedd; }}\n\
+         .marked {{ background: rgb(220, 140, 20); color: #fff; }}\n\
+         .listed.allowed {{ background: rgb(40, 140, 60); color: #fff; }}\n\
+         .legend span {{ display: inline-block; padding: 0.2em 0.6em; margin-right: 0.5em; border-radius: 3px; }}\n\
+         table {{ border-collapse: collapse; margin-top: 2em; }}\n\
+         td, th {{ padding: 0.2em 0.8em; text-align: right; border-bottom: 1px solid #ddd; }}\n\
+         td:nth-child(-n+3), th:nth-child(-n+3) {{ text-align: left; }}\n\
+         </style>\n</head>\n<body>\n<h1>{}</h1>\n<p>From {} at {} (Unix time). Hover a tile for its prefix counts.</p>\n",
+        escape(&title),
+        escape(&title),
+        sources.join(", "),
+        unix_now()
+    );
+    let _ = writeln!(html, "<p class=\"legend\">");
+    let mut statuses: Vec<(&str, bool)> = entries.iter().map(|entry| (entry.status, entry.nets.is_some())).collect();
+    statuses.sort();
+    statuses.dedup();
+    for (status, is_listed) in statuses {
+        let _ = write!(html, "<span class=\"tile {}{}\">{}</span>", status, if is_listed { " listed" } else { "" }, status);
+    }
+    let _ = writeln!(html, "</p>\n<div class=\"grid\">");
+    for entry in entries {
+        let name = escape(entry.name.unwrap_or(&entry.code));
+        let (class, style, hover) = match entry.nets {
+            Some((v4, v6, addresses)) => {
+                let shade = 0.45 + 0.55 * (addresses.max(1) as f64).ln() / (most as f64).ln();
+                (" listed", format!(" style=\"opacity: {:.2}\"", shade), format!("{}: {} IPv4 and {} IPv6 prefixes, {} IPv4 addresses", name, v4, v6, addresses))
+            }
+            None => ("", String::new(), format!("{}: not in the maps", name)),
+        };
+        let _ = writeln!(
+            html,
+            "<div class=\"tile {}{}\"{} title=\"{}\"><b>{}</b>{}</div>",
+            entry.status,
+            class,
+            style,
+            hover,
+            escape(&entry.code.to_uppercase()),
+            name
+        );
+    }
+    let _ = writeln!(html, "</div>\n<table>\n<tr><th>Code</th><th>Name</th><th>Status</th><th>IPv4</th><th>IPv6</th><th>IPv4 addresses</th></tr>");
+    for entry in entries.iter().filter(|entry| entry.nets.is_some()) {
+        let (v4, v6, addresses) = entry.nets.unwrap_or_default();
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&entry.code.to_uppercase()),
+            escape(entry.name.unwrap_or("")),
+            entry.status,
+            v4,
+            v6,
+            addresses
+        );
+    }
+    let _ = writeln!(html, "</table>\n</body>\n</html>");
+    fs::write(path, html).with_context(|| format!("write {}", path.display()))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -0,0 +1,69 @@
+//! Deny lists for home DNS filters that check the addresses in answers:
+//! Unbound's `response-ip` (respip module), Blocky denylists and AdGuard
+//! Home filter lists. A name resolving into a listed prefix then fails to
+//! resolve, as with `--format rpz`.
+//!
+//! Blocky and AdGuard Home only deny, so they take block lists alone.
+
+use std::{collections::HashMap, fmt::Write, fs};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{Action, CountryNets};
+
+/// Every prefix of `map` in country order, each group headed by its
+/// country code
+fn by_country(map: &HashMap<String, CountryNets>) -> Vec<(String, Vec<String>)> {
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    codes
+        .into_iter()
+        .map(|cc| (cc.to_uppercase(), map[cc].ipv4.iter().chain(&map[cc].ipv6).map(|net| net.0.to_string()).collect()))
+        .collect()
+}
+
+/// Write an Unbound configuration snippet for `map` to `filename`. The
+/// most specific `response-ip` wins, so allow lists let their prefixes
+/// through a catch-all for each family.
+pub fn generate_unbound(map: &HashMap<String, CountryNets>, action: Action, filename: &str) -> Result<()> {
+    let mut file = String::new();
+    writeln!(file, "# Generated by cloak. Include from unbound.conf (include: \"{}\") with", filename)?;
+    writeln!(file, "# the respip module enabled: module-config: \"respip validator iterator\"")?;
+    writeln!(file, "server:")?;
+    let verdict = if action == Action::Allow {
+        writeln!(file, "    response-ip: 0.0.0.0/0 always_nxdomain")?;
+        writeln!(file, "    response-ip: ::/0 always_nxdomain")?;
+        "always_transparent"
+    } else {
+        "always_nxdomain"
+    };
+    for (cc, nets) in by_country(map) {
+        writeln!(file, "    # {}", cc)?;
+        for net in nets {
+            writeln!(file, "    response-ip: {} {}", net, verdict)?;
+        }
+    }
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
+
+/// Write a Blocky denylist or (with `adguard`) an AdGuard Home filter list
+/// for `map` to `filename`: one prefix per line
+pub fn generate_list(map: &HashMap<String, CountryNets>, action: Action, adguard: bool, filename: &str) -> Result<()> {
+    let (product, comment) = if adguard { ("AdGuard Home", "!") } else { ("Blocky", "#") };
+    if action != Action::Block {
+        bail!("{} lists can only deny; use --action block or --format unbound", product);
+    }
+    let mut file = String::new();
+    if adguard {
+        writeln!(file, "! Generated by cloak. Add as a custom filter list under Filters > DNS blocklists.")?;
+    } else {
+        writeln!(file, "# Generated by cloak. Add to a group under blocking.denylists in Blocky's config.")?;
+    }
+    for (cc, nets) in by_country(map) {
+        writeln!(file, "{} {}", comment, cc)?;
+        for net in nets {
+            writeln!(file, "{}", net)?;
+        }
+    }
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
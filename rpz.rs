@@ -0,0 +1,90 @@
+//! DNS response policy zones with `rpz-ip` triggers, for resolvers (BIND,
+//! Knot Resolver, Unbound with RPZ) to refuse answers pointing into the
+//! prefixes: the packet filter's rules applied one step earlier, when a
+//! name is resolved.
+//!
+//! A trigger is the prefix written backwards under `rpz-ip`:
+//! `24.0.2.0.192.rpz-ip` for 192.0.2.0/24, and with `zz` standing for
+//! `::` in IPv6, `32.zz.db8.2001.rpz-ip` for 2001:db8::/32. The most
+//! specific trigger wins, which is how allow lists pass their prefixes
+//! through two catch-all halves of each family.
+
+use std::{collections::HashMap, fmt::Write, fs, net::Ipv6Addr};
+
+use anyhow::{Context, Result};
+use ipnetwork::IpNetwork;
+
+use crate::{Action, CountryNets};
+
+/// Answer with NXDOMAIN
+const REFUSE: &str = "CNAME .";
+
+/// Let the answer through unchanged
+const PASS: &str = "CNAME rpz-passthru.";
+
+/// Write a response policy zone for `map` to `filename`
+pub fn generate_rpz(map: &HashMap<String, CountryNets>, action: Action, name: &str, filename: &str) -> Result<()> {
+    let zone = format!("{}.cloak.rpz", name.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "-"));
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+
+    let mut file = String::new();
+    writeln!(file, "; Generated by cloak. Load as zone {} and list it in response-policy, e.g. for BIND:", zone)?;
+    writeln!(file, ";   zone \"{}\" {{ type primary; file \"{}\"; }};", zone, filename)?;
+    writeln!(file, ";   options {{ response-policy {{ zone \"{}\"; }}; }};", zone)?;
+    writeln!(file, "$TTL 300")?;
+    writeln!(file, "@ SOA localhost. root.localhost. 1 3600 600 86400 300")?;
+    writeln!(file, "@ NS localhost.")?;
+    let trigger = if action == Action::Allow {
+        for half in ["0.0.0.0/1", "128.0.0.0/1", "::/1", "8000::/1"] {
+            writeln!(file, "{} {}", owner(&half.parse()?), REFUSE)?;
+        }
+        PASS
+    } else {
+        REFUSE
+    };
+    for cc in codes {
+        writeln!(file, "; {}", cc.to_uppercase())?;
+        for net in map[cc].ipv4.iter().chain(&map[cc].ipv6) {
+            writeln!(file, "{} {}", owner(&net.0), trigger)?;
+        }
+    }
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
+
+/// The `rpz-ip` owner name of `net`, relative to the zone
+fn owner(net: &IpNetwork) -> String {
+    match net {
+        IpNetwork::V4(n) => {
+            let octets: Vec<String> = n.network().octets().iter().rev().map(u8::to_string).collect();
+            format!("{}.{}.rpz-ip", n.prefix(), octets.join("."))
+        }
+        IpNetwork::V6(n) => format!("{}.{}.rpz-ip", n.prefix(), ipv6_labels(n.network())),
+    }
+}
+
+/// The groups of `addr` in reverse order, the longest run of two or more
+/// zero groups replaced by `zz`
+fn ipv6_labels(addr: Ipv6Addr) -> String {
+    let groups = addr.segments();
+    let (mut best, mut run) = ((0, 0), (0, 0));
+    for (i, &group) in groups.iter().enumerate() {
+        run = if group == 0 { (if run.1 == 0 { i } else { run.0 }, run.1 + 1) } else { (0, 0) };
+        if run.1 > best.1 {
+            best = run;
+        }
+    }
+    let mut labels: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < groups.len() {
+        if best.1 >= 2 && i == best.0 {
+            labels.push("zz".to_string());
+            i += best.1;
+        } else {
+            labels.push(format!("{:x}", groups[i]));
+            i += 1;
+        }
+    }
+    labels.reverse();
+    labels.join(".")
+}
@@ -0,0 +1,174 @@
+//! `cloak selftest`: whether the loaded rules actually do what their map
+//! says, to catch a policy that loaded fine but stops nothing (a missing
+//! hook, emptied sets, a whitelist covering everything).
+//!
+//! Addresses sampled from the map are looked up in the kernel's sets with
+//! `nft get element`, and the verdicts of the loaded input chain are
+//! worked out for them and for an address in no country's list, the same
+//! way as in the pre-flight report. `--probe` also opens real connections.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use ipnetwork::IpNetwork;
+use tokio::net::TcpStream;
+
+use crate::{
+    attach, cidr,
+    exit::{Code, WithCode},
+    nft,
+    preflight::{self, Model},
+    privilege, read_map,
+    ui::{info, success, warning},
+    Action, CountryNets,
+};
+
+/// Give up on a `--probe` connection after this long
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(clap::Args, Debug)]
+pub struct SelftestArgs {
+    /// JSON map the loaded rules were generated from (nested layout)
+    map: PathBuf,
+
+    /// What the loaded rules do with the countries in the map
+    #[arg(long, value_enum, default_value_t = Action::Block)]
+    action: Action,
+
+    /// How many addresses from the map to check
+    #[arg(long, value_name = "N", default_value_t = 20)]
+    samples: usize,
+
+    /// Also connect to this address, which the policy allows, and fail if
+    /// the connection does not open (ADDR for port 22, or ADDR:PORT;
+    /// repeatable)
+    #[arg(long, value_name = "ADDR", value_parser = preflight::parse_reachable)]
+    probe: Vec<SocketAddr>,
+}
+
+/// Counts the checks and their failures
+#[derive(Default)]
+struct Checks {
+    run: usize,
+    failed: usize,
+}
+
+impl Checks {
+    fn pass(&mut self, message: String) {
+        self.run += 1;
+        info!("ok    {}", message);
+    }
+
+    fn fail(&mut self, message: String) {
+        self.run += 1;
+        self.failed += 1;
+        warning!("FAIL  {}", message);
+    }
+
+    fn check(&mut self, passed: bool, message: String) {
+        if passed {
+            self.pass(message)
+        } else {
+            self.fail(message)
+        }
+    }
+}
+
+pub async fn run(args: &SelftestArgs, state_dir: &Path) -> Result<()> {
+    let map = read_map(&args.map)?;
+    let (table, prefix) = attach::location(state_dir);
+    let listing = nft::list_table(&table).with_context(|| format!("table inet {} is not loaded; apply the rules first", table)).code(Code::Validation)?;
+    let mut checks = Checks::default();
+    checks.pass(format!("table inet {} is loaded", table));
+
+    // Attached rules live in a chain the user's own rules jump to, which
+    // the pre-flight model does not follow
+    let model = Model::new(&listing);
+    let modelled = prefix.is_empty() && args.action != Action::Mark;
+    if modelled {
+        checks.check(model.hooked(), "a chain of the table is on the input hook".to_string());
+    }
+
+    for (cc, addr) in samples(&map, args.samples) {
+        let family = if addr.is_ipv4() { "ipv4" } else { "ipv6" };
+        let own = format!("{}country_{}_{}", prefix, nft::set_name(&cc), family);
+        let set = if listing.contains(&format!("set {} {{", own)) { own } else { format!("{}country_{}", prefix, family) };
+        checks.check(in_kernel_set(&table, &set, addr), format!("{} ({}) is in set {}", addr, cc.to_uppercase(), set));
+        if !modelled {
+            continue;
+        }
+        let fate = model.fate(addr, 443);
+        let whitelisted = model.in_set(&format!("whitelist_{}", family), addr);
+        let expected = match (args.action, whitelisted) {
+            (_, true) => "accepted",
+            (Action::Allow, false) => "accepted",
+            _ => "dropped",
+        };
+        checks.check(fate == expected, format!("new connections from {} are {} (expected {})", addr, fate, expected));
+    }
+    if modelled {
+        // Documentation addresses are in no country's list
+        let outside = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let (fate, expected) = (model.fate(outside, 443), if args.action == Action::Allow { "dropped" } else { "accepted" });
+        checks.check(fate == expected, format!("new connections from the unlisted {} are {} (expected {})", outside, fate, expected));
+    } else if !prefix.is_empty() {
+        info!("The rules are attached to another table; only set contents are checked.");
+    }
+
+    for &addr in &args.probe {
+        match tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(Ok(_)) => checks.pass(format!("connected to {}", addr)),
+            Ok(Err(e)) => checks.fail(format!("could not connect to {}: {}", addr, e)),
+            Err(_) => checks.fail(format!("no answer from {} within {:?}", addr, PROBE_TIMEOUT)),
+        }
+    }
+
+    if checks.failed > 0 {
+        return Err(anyhow!("{} of {} checks failed", checks.failed, checks.run)).code(Code::Validation);
+    }
+    success!("All {} checks passed.", checks.run);
+    Ok(())
+}
+
+/// Whether the kernel finds `addr` in `set`
+fn in_kernel_set(table: &str, set: &str, addr: IpAddr) -> bool {
+    privilege::command("nft")
+        .args(["get", "element", "inet", table, set])
+        .arg(format!("{{ {} }}", addr))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Up to `count` addresses spread evenly over the map's prefixes, each in
+/// the middle of its prefix
+fn samples(map: &HashMap<String, CountryNets>, count: usize) -> Vec<(String, IpAddr)> {
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    let all: Vec<(&String, IpNetwork)> =
+        codes.into_iter().flat_map(|cc| map[cc].ipv4.iter().chain(&map[cc].ipv6).map(move |net| (cc, net.0))).collect();
+    if all.is_empty() || count == 0 {
+        return Vec::new();
+    }
+    let step = (all.len() / count).max(1);
+    all.iter()
+        .step_by(step)
+        .take(count)
+        .map(|(cc, net)| {
+            let (start, end) = cidr::bounds(net);
+            let middle = start + (end - start) / 2;
+            let addr = match net {
+                IpNetwork::V4(_) => IpAddr::V4(Ipv4Addr::from(middle as u32)),
+                IpNetwork::V6(_) => IpAddr::V6(Ipv6Addr::from(middle)),
+            };
+            ((*cc).clone(), addr)
+        })
+        .collect()
+}
@@ -0,0 +1,111 @@
+//! `cloak simulate`: follow one packet through a ruleset in userspace and
+//! show the rules that decide its fate, for reasoning about layered and
+//! multi-zone policies without a lab host to send it from.
+//!
+//! The packet is the first one of a new connection and goes through the
+//! same model as the pre-flight report, with the interface and the
+//! destination known. Rules matching on anything else are taken not to
+//! match.
+
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+
+use crate::{
+    attach, nft,
+    preflight::{Model, Packet},
+    ui::{info, warning},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Debug)]
+enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SimulateArgs {
+    /// Rule file to follow the packet through; the loaded rules if left out
+    rules: Option<PathBuf>,
+
+    /// Source address of the packet
+    #[arg(long, value_name = "ADDR")]
+    from: IpAddr,
+
+    /// Destination, `:PORT` or `ADDR:PORT` (e.g. :22, 10.0.0.5:443 or
+    /// [2001:db8::5]:443)
+    #[arg(long, value_name = "[ADDR]:PORT", value_parser = parse_target)]
+    to: (Option<IpAddr>, u16),
+
+    /// Interface the packet arrives on
+    #[arg(long, value_name = "NAME")]
+    iface: Option<String>,
+
+    /// Protocol of the packet
+    #[arg(long, value_enum, default_value_t = Protocol::Tcp)]
+    proto: Protocol,
+
+    /// Follow the forward chain, for traffic routed through this host,
+    /// instead of the input chain
+    #[arg(long)]
+    forward: bool,
+}
+
+/// `value_parser` for `--to`
+fn parse_target(text: &str) -> Result<(Option<IpAddr>, u16), String> {
+    if let Some(port) = text.strip_prefix(':') {
+        return port.parse().map(|port| (None, port)).map_err(|_| format!("invalid port `{}`", port));
+    }
+    let addr: SocketAddr = text.parse().map_err(|_| format!("expected :PORT or ADDR:PORT, e.g. :22 or 10.0.0.5:443, not `{}`", text))?;
+    Ok((Some(addr.ip()), addr.port()))
+}
+
+pub fn run(args: &SimulateArgs, state_dir: &Path) -> Result<()> {
+    let (to, port) = args.to;
+    if to.is_some_and(|to| to.is_ipv4() != args.from.is_ipv4()) {
+        bail!("--from and --to must both be IPv4 or both IPv6");
+    }
+    let (ruleset, origin) = match &args.rules {
+        Some(path) => (fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?, path.display().to_string()),
+        None => {
+            let (table, prefix) = attach::location(state_dir);
+            if !prefix.is_empty() {
+                bail!("the rules are attached to table inet {}, whose own chains are not followed; pass the rule file instead", table);
+            }
+            let listing = nft::list_table(&table).with_context(|| format!("table inet {} is not loaded; pass a rule file", table))?;
+            (listing, format!("the loaded table inet {}", table))
+        }
+    };
+
+    let model = Model::new(&ruleset);
+    let (hook, present) = if args.forward { ("forward", model.forwards()) } else { ("input", model.hooked()) };
+    if !present {
+        warning!("{} has no chain on the {} hook, so nothing in it filters this packet", origin, hook);
+    }
+    let protocol = match args.proto {
+        Protocol::Tcp => "tcp",
+        Protocol::Udp => "udp",
+    };
+    let packet = Packet { from: args.from, to, port, protocol, interface: args.iface.as_deref() };
+    let (fate, path) = model.trace(&packet, args.forward);
+
+    let destination = to.map_or(format!(":{}", port), |to| SocketAddr::new(to, port).to_string());
+    let interface = args.iface.as_deref().map_or(String::new(), |name| format!(" on {}", name));
+    info!("New {} connection from {} to {}{}, {} hook of {}:", protocol.to_uppercase(), args.from, destination, interface, hook, origin);
+    for step in &path {
+        println!("  {}", step);
+    }
+    info!("Verdict: {}", fate);
+    if to.is_none() && ruleset.contains(" daddr ") {
+        info!("Rules matching a destination address did not match; give one with --to ADDR:PORT.");
+    }
+    if args.iface.is_none() && (ruleset.contains("iifname ") || ruleset.contains("iif ")) {
+        info!("Rules matching an interface did not match; give one with --iface.");
+    }
+    Ok(())
+}
@@ -0,0 +1,94 @@
+//! Persistent state shared between runs (lock file, last applied ruleset).
+
+use std::{
+    env,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::ui::info;
+
+/// `$XDG_STATE_HOME/cloak`, `~/.local/state/cloak`, or `/var/lib/cloak`
+/// when neither is set (e.g. under systemd). On Windows,
+/// `%LOCALAPPDATA%\cloak`.
+pub fn default_state_dir() -> PathBuf {
+    #[cfg(windows)]
+    if let Some(dir) = env::var_os("LOCALAPPDATA").filter(|d| !d.is_empty()) {
+        return PathBuf::from(dir).join("cloak");
+    }
+    if let Some(dir) = env::var_os("XDG_STATE_HOME").filter(|d| !d.is_empty()) {
+        return PathBuf::from(dir).join("cloak");
+    }
+    if let Some(home) = env::var_os("HOME").filter(|d| !d.is_empty()) {
+        return PathBuf::from(home).join(".local/state/cloak");
+    }
+    PathBuf::from("/var/lib/cloak")
+}
+
+/// `$XDG_CACHE_HOME/cloak`, `~/.cache/cloak`, or `/var/cache/cloak`
+/// (`%LOCALAPPDATA%\cloak\cache` on Windows)
+pub fn default_cache_dir() -> PathBuf {
+    #[cfg(windows)]
+    if let Some(dir) = env::var_os("LOCALAPPDATA").filter(|d| !d.is_empty()) {
+        return PathBuf::from(dir).join("cloak").join("cache");
+    }
+    if let Some(dir) = env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()) {
+        return PathBuf::from(dir).join("cloak");
+    }
+    if let Some(home) = env::var_os("HOME").filter(|d| !d.is_empty()) {
+        return PathBuf::from(home).join(".cache/cloak");
+    }
+    PathBuf::from("/var/cache/cloak")
+}
+
+/// Exclusive run lock, released when dropped.
+pub struct RunLock {
+    _file: File,
+}
+
+impl RunLock {
+    /// Take the lock in `state_dir`. With `wait` the call blocks until a
+    /// concurrent run finishes; otherwise it fails immediately.
+    pub fn acquire(state_dir: &Path, wait: bool) -> Result<Self> {
+        fs::create_dir_all(state_dir)
+            .with_context(|| format!("create state directory {}", state_dir.display()))?;
+        let path = state_dir.join("cloak.lock");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("open lock file {}", path.display()))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                let holder = holder.trim();
+                let holder = if holder.is_empty() { "another process".to_string() } else { format!("pid {}", holder) };
+                if !wait {
+                    bail!(
+                        "another cloak run ({}) holds {}; retry later or pass --wait",
+                        holder,
+                        path.display()
+                    );
+                }
+                info!("Waiting for another cloak run ({}) to finish...", holder);
+                file.lock().with_context(|| format!("lock {}", path.display()))?;
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("lock {}", path.display()));
+            }
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", std::process::id())?;
+        Ok(RunLock { _file: file })
+    }
+}
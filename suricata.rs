@@ -0,0 +1,78 @@
+//! Suricata rules matching the prefixes through IP reputation (`iprep`),
+//! which scales to country-sized lists where address groups in the rules
+//! themselves would not.
+//!
+//! Next to the rules go the two files Suricata's reputation support reads:
+//! a categories file naming one category for the group and a reputation
+//! list giving every prefix that category.
+
+use std::{collections::HashMap, fmt::Write, fs, path::Path};
+
+use anyhow::{Context, Result};
+
+use crate::{Action, CountryNets, Direction};
+
+/// First signature id of the generated rules
+const SID_BASE: u32 = 4_190_000;
+
+/// Category id of the group in the categories file
+const CATEGORY_ID: u8 = 1;
+
+/// Reputation score given to each prefix (Suricata allows 0-127)
+const SCORE: u8 = 127;
+
+/// Write Suricata rules for `map` to `filename`, plus `<stem>.categories`
+/// and `<stem>.list` for the reputation data they match. With `alert` the
+/// rules only alert, for IDS deployments.
+pub fn generate_suricata(
+    map: &HashMap<String, CountryNets>,
+    action: Action,
+    direction: Direction,
+    alert: bool,
+    name: &str,
+    filename: &str,
+) -> Result<()> {
+    let category = format!("cloak_{}", name.replace(|c: char| !c.is_ascii_alphanumeric(), "_"));
+    let categories_file = Path::new(filename).with_extension("categories");
+    let list_file = Path::new(filename).with_extension("list");
+
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    let mut list = String::new();
+    for net in codes.iter().flat_map(|cc| map[*cc].ipv4.iter().chain(&map[*cc].ipv6)) {
+        writeln!(list, "{},{},{}", net.0, CATEGORY_ID, SCORE)?;
+    }
+    let categories = format!("{},{},Prefixes of cloak's {} list\n", CATEGORY_ID, category, name);
+
+    let verdict = if alert { "alert" } else { "drop" };
+    let mut file = String::new();
+    writeln!(file, "# Generated by cloak. In suricata.yaml, add this file to rule-files and set:")?;
+    writeln!(file, "#   reputation-categories-file: {}", categories_file.display())?;
+    writeln!(file, "#   reputation-files:")?;
+    writeln!(file, "#     - {}", list_file.display())?;
+    writeln!(file, "# Several lists need distinct category ids and signature ids.")?;
+    let mut sides = Vec::new();
+    if direction != Direction::Out {
+        sides.push(("src", "$EXTERNAL_NET any -> $HOME_NET any", "from"));
+    }
+    if direction != Direction::In {
+        sides.push(("dst", "$HOME_NET any -> $EXTERNAL_NET any", "to"));
+    }
+    let mut sid = SID_BASE;
+    for (side, flow, word) in sides {
+        let matched = format!("iprep:{},{},>,0;", side, category);
+        match action {
+            Action::Allow => {
+                // pass rules are evaluated before drop and alert rules
+                writeln!(file, "pass ip {} (msg:\"cloak: traffic {} the {} list\"; {} sid:{}; rev:1;)", flow, word, name, matched, sid)?;
+                writeln!(file, "{} ip {} (msg:\"cloak: traffic {} outside the {} list\"; sid:{}; rev:1;)", verdict, flow, word, name, sid + 1)?;
+            }
+            _ => writeln!(file, "{} ip {} (msg:\"cloak: traffic {} the {} list\"; {} sid:{}; rev:1;)", verdict, flow, word, name, matched, sid)?,
+        }
+        sid += 2;
+    }
+
+    fs::write(&list_file, list).with_context(|| format!("write {}", list_file.display()))?;
+    fs::write(&categories_file, categories).with_context(|| format!("write {}", categories_file.display()))?;
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
@@ -0,0 +1,186 @@
+//! Policy and generated rules kept in a git repository, so their history
+//! is the repository's log and other hosts can pull the same policy.
+//!
+//! Shared policy files (config, group definitions, whitelists) live at the
+//! top of the repository, each host's generated files under
+//! `hosts/<hostname>/`. Every change is one commit whose message ends in
+//! `Key: value` trailers describing it, pushed right away. git itself does
+//! the work, with whatever credentials (SSH agent, deploy key, helper) it
+//! is set up with.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    naming, nft,
+    ui::{info, success},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct SyncArgs {
+    /// Repository to keep the policy and rules in (e.g.
+    /// git@git.example.com:ops/cloak.git)
+    #[arg(long, value_name = "URL")]
+    repo: String,
+
+    /// Branch to commit to
+    #[arg(long, default_value = "main")]
+    branch: String,
+
+    /// Generated files (rules, JSON maps, bundles) to commit under
+    /// hosts/<hostname>/
+    files: Vec<PathBuf>,
+
+    /// Policy files shared by every host (config, group definitions,
+    /// whitelists) to commit at the top of the repository
+    #[arg(long, value_name = "FILE")]
+    shared: Vec<PathBuf>,
+
+    /// Only bring the local checkout up to date, committing nothing; point
+    /// `--config` or `--group-file` into it to use the shared policy
+    #[arg(long, conflicts_with_all = ["files", "shared"])]
+    pull: bool,
+}
+
+/// A local checkout of the sync repository
+pub struct Repo {
+    dir: PathBuf,
+    branch: String,
+}
+
+pub fn run(args: &SyncArgs, state_dir: &Path) -> Result<()> {
+    let repo = Repo::open(&args.repo, &args.branch, state_dir)?;
+    if args.pull {
+        success!("{} is up to date with {} {}.", repo.dir.display(), args.repo, args.branch);
+        return Ok(());
+    }
+    if args.files.is_empty() && args.shared.is_empty() {
+        bail!("nothing to sync; name generated files or --shared policy files");
+    }
+    let host = naming::hostname();
+    let mut stored = Vec::new();
+    for file in &args.shared {
+        stored.push(repo.store(file, Path::new(""))?);
+    }
+    for file in &args.files {
+        stored.push(repo.store(file, &Path::new("hosts").join(&host))?);
+    }
+    let mut trailers = vec![("Host", host.clone()), ("Command", "sync".to_string())];
+    trailers.extend(fingerprints(&args.files));
+    let subject = format!("cloak: sync {} files from {}", stored.len(), host);
+    match repo.commit(&subject, &trailers, &stored)? {
+        Some(commit) => success!("Committed {} files to {} {} ({}).", stored.len(), args.repo, args.branch, commit),
+        None => info!("Nothing changed; {} {} already has these files.", args.repo, args.branch),
+    }
+    Ok(())
+}
+
+/// `Fingerprint` trailers for the cloak rule files among `files`
+fn fingerprints(files: &[PathBuf]) -> Vec<(&'static str, String)> {
+    files
+        .iter()
+        .filter_map(|file| {
+            let text = fs::read_to_string(file).ok()?;
+            let fingerprint = nft::check_own_ruleset(&text).ok()?;
+            Some(("Fingerprint", format!("{} {}", fingerprint, file.file_name()?.to_string_lossy())))
+        })
+        .collect()
+}
+
+impl Repo {
+    /// Clone `url` into the state directory, or bring the existing checkout
+    /// up to date, with `branch` checked out as it is on the remote
+    pub fn open(url: &str, branch: &str, state_dir: &Path) -> Result<Repo> {
+        let dir = state_dir.join("sync");
+        if !dir.join(".git").exists() {
+            fs::create_dir_all(state_dir).with_context(|| format!("create {}", state_dir.display()))?;
+            git(state_dir, &["clone", "--quiet", url, "sync"]).with_context(|| format!("clone {}", url))?;
+        } else {
+            let origin = git(&dir, &["remote", "get-url", "origin"])?;
+            if origin.trim() != url {
+                bail!("{} is a checkout of {}, not {}; remove it to switch repositories", dir.display(), origin.trim(), url);
+            }
+            git(&dir, &["fetch", "--quiet", "origin"]).with_context(|| format!("fetch {}", url))?;
+        }
+        let repo = Repo { dir, branch: branch.to_string() };
+        repo.reset()?;
+        Ok(repo)
+    }
+
+    /// Check out the branch as the remote has it; a new branch (or an
+    /// empty repository) starts from nothing
+    fn reset(&self) -> Result<()> {
+        let remote = format!("origin/{}", self.branch);
+        git(&self.dir, &["checkout", "--quiet", "-B", &self.branch])?;
+        if git(&self.dir, &["rev-parse", "--verify", "--quiet", &remote]).is_ok() {
+            git(&self.dir, &["reset", "--quiet", "--hard", &remote])?;
+        }
+        Ok(())
+    }
+
+    /// Copy `file` into `subdir` of the checkout and return its path there
+    pub fn store(&self, file: &Path, subdir: &Path) -> Result<String> {
+        let name = file.file_name().with_context(|| format!("{} is not a file", file.display()))?;
+        let relative = subdir.join(name);
+        let dest = self.dir.join(&relative);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("create {}", parent.display()))?;
+        }
+        fs::copy(file, &dest).with_context(|| format!("copy {} to {}", file.display(), dest.display()))?;
+        Ok(relative.to_string_lossy().into_owned())
+    }
+
+    /// Commit `paths` with a message of `subject` and `trailers` and push
+    /// it. Returns the short commit id, or `None` when nothing changed.
+    /// A push beaten by another host's is retried once on top of it.
+    pub fn commit(&self, subject: &str, trailers: &[(&str, String)], paths: &[String]) -> Result<Option<String>> {
+        let mut add = vec!["add", "--"];
+        add.extend(paths.iter().map(String::as_str));
+        git(&self.dir, &add)?;
+        if git(&self.dir, &["diff", "--cached", "--quiet"]).is_ok() {
+            return Ok(None);
+        }
+        let mut message = format!("{}\n\n", subject);
+        for (key, value) in trailers {
+            message.push_str(&format!("{}: {}\n", key, value));
+        }
+        message.push_str(&format!("Files: {}\nCloak-Version: {}\n", paths.join(", "), env!("CARGO_PKG_VERSION")));
+        let mut commit = Command::new("git");
+        commit.arg("-C").arg(&self.dir);
+        // Hosts rarely have a git identity of their own
+        if git(&self.dir, &["config", "user.email"]).is_err() {
+            let host = naming::hostname();
+            commit.args(["-c", "user.name=cloak", "-c"]).arg(format!("user.email=cloak@{}", host));
+        }
+        commit.args(["commit", "--quiet", "-m", &message]);
+        output(commit, "git commit")?;
+
+        let refspec = format!("HEAD:{}", self.branch);
+        if git(&self.dir, &["push", "--quiet", "origin", &refspec]).is_err() {
+            git(&self.dir, &["pull", "--quiet", "--rebase", "origin", &self.branch])?;
+            git(&self.dir, &["push", "--quiet", "origin", &refspec]).context("push the commit")?;
+        }
+        Ok(Some(git(&self.dir, &["rev-parse", "--short", "HEAD"])?.trim().to_string()))
+    }
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let mut command = Command::new("git");
+    command.arg("-C").arg(dir).args(args);
+    output(command, &format!("git {}", args[0]))
+}
+
+/// Run `command`, returning its stdout
+fn output(mut command: Command, what: &str) -> Result<String> {
+    let output = command.output().context("failed to execute git (is it installed?)")?;
+    if !output.status.success() {
+        let message = if output.stderr.is_empty() { &output.stdout } else { &output.stderr };
+        bail!("{} failed: {}", what, String::from_utf8_lossy(message).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
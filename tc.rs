@@ -0,0 +1,59 @@
+//! Shell scripts installing tc flower filters that drop the prefixes on
+//! one device, which NICs and switch ASICs with flower offload enforce in
+//! hardware where nftables offload is not available.
+//!
+//! The filters sit on a clsact qdisc at priorities of their own, so the
+//! script can replace an earlier run's filters without touching others.
+
+use std::{collections::HashMap, fmt::Write, fs};
+
+use anyhow::{Context, Result};
+
+use crate::{Action, CountryNets, Direction};
+
+/// Priority of the IPv4 filters; IPv4 catch-all, IPv6 and IPv6 catch-all
+/// follow it, since tc keeps one protocol per priority
+const PREF: u32 = 4900;
+
+/// Write a script for `map` to `filename` that takes the device as its
+/// argument
+pub fn generate_tc(map: &HashMap<String, CountryNets>, action: Action, direction: Direction, filename: &str) -> Result<()> {
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    let ipv4: Vec<String> = codes.iter().flat_map(|cc| &map[*cc].ipv4).map(|net| net.0.to_string()).collect();
+    let ipv6: Vec<String> = codes.iter().flat_map(|cc| &map[*cc].ipv6).map(|net| net.0.to_string()).collect();
+    let mut hooks = Vec::new();
+    if direction != Direction::Out {
+        hooks.push(("ingress", "src_ip"));
+    }
+    if direction != Direction::In {
+        hooks.push(("egress", "dst_ip"));
+    }
+
+    let mut file = String::new();
+    writeln!(file, "#!/bin/sh")?;
+    writeln!(file, "# Generated by cloak. Run as root: sh {} DEVICE", filename)?;
+    writeln!(file, "# Check offload with: tc -s filter show dev DEVICE ingress (in_hw)")?;
+    writeln!(file, "set -e")?;
+    writeln!(file, "DEV=${{1:?usage: sh {} DEVICE}}", filename)?;
+    writeln!(file, "tc qdisc add dev \"$DEV\" clsact 2>/dev/null || true")?;
+    for (hook, _) in &hooks {
+        for pref in PREF..PREF + 4 {
+            writeln!(file, "tc filter del dev \"$DEV\" {} pref {} 2>/dev/null || true", hook, pref)?;
+        }
+    }
+    writeln!(file, "tc -batch - <<EOF")?;
+    let verdict = if action == Action::Allow { "pass" } else { "drop" };
+    for (hook, field) in &hooks {
+        for (pref, protocol, nets) in [(PREF, "ip", &ipv4), (PREF + 2, "ipv6", &ipv6)] {
+            for net in nets {
+                writeln!(file, "filter add dev $DEV {} pref {} protocol {} flower {} {} action {}", hook, pref, protocol, field, net, verdict)?;
+            }
+            if action == Action::Allow {
+                writeln!(file, "filter add dev $DEV {} pref {} protocol {} flower action drop", hook, pref + 1, protocol)?;
+            }
+        }
+    }
+    writeln!(file, "EOF")?;
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
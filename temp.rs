@@ -0,0 +1,152 @@
+//! Sets of expiring entries maintained at runtime.
+//!
+//! Every generated ruleset has a pair of sets with per-element timeouts for
+//! each [`DynamicSet`]: `temp_` for `cloak block-temp`, `crowdsec_` for
+//! the CrowdSec bouncer and `travel_` for `cloak travel allow`. The kernel
+//! drops entries when they expire, but loading a ruleset recreates the sets
+//! empty, so each entry is also recorded in the state directory with its
+//! expiry and put back after every load until it runs out.
+
+use std::{
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use ipnetwork::IpNetwork;
+
+use crate::{attach, nft, ui::warning, unix_now};
+
+/// A pair of timeout sets and the state file recording their entries
+pub struct DynamicSet {
+    /// Base name of the sets, one per family
+    pub name: &'static str,
+    /// What the rules do with peers in the sets
+    pub verdict: &'static str,
+    record: &'static str,
+}
+
+pub const TEMP: DynamicSet = DynamicSet { name: "temp", verdict: "drop", record: "temp_blocks" };
+pub const CROWDSEC: DynamicSet = DynamicSet { name: "crowdsec", verdict: "drop", record: "crowdsec_decisions" };
+pub const TRAVEL: DynamicSet = DynamicSet { name: "travel", verdict: "accept", record: "travel_allows" };
+
+/// Every dynamic set, in the order their rules are written: blocks first,
+/// so a travel allowance does not lift them
+pub const SETS: [&DynamicSet; 3] = [&TEMP, &CROWDSEC, &TRAVEL];
+
+/// A prefix in a dynamic set and the Unix time it leaves it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub net: IpNetwork,
+    pub expires: u64,
+}
+
+impl Block {
+    /// Block `net` from now for `duration`
+    pub fn lasting(net: IpNetwork, duration: Duration) -> Block {
+        // Host bits would make nft reject the element
+        let net = IpNetwork::new(net.network(), net.prefix()).expect("prefix of an existing network");
+        Block { net, expires: unix_now() + duration.as_secs() }
+    }
+}
+
+impl DynamicSet {
+    fn record_path(&self, state_dir: &Path) -> PathBuf {
+        state_dir.join(self.record)
+    }
+
+    /// Blocks that have not expired yet; unreadable lines are skipped
+    pub fn read(&self, state_dir: &Path) -> Vec<Block> {
+        let now = unix_now();
+        let text = fs::read_to_string(self.record_path(state_dir)).unwrap_or_default();
+        text.lines()
+            .filter_map(|line| {
+                let (net, expires) = line.split_once(' ')?;
+                Some(Block { net: net.parse().ok()?, expires: expires.trim().parse().ok()? })
+            })
+            .filter(|block| block.expires > now)
+            .collect()
+    }
+
+    /// Add `blocks`, extending those already there when the new one lasts
+    /// longer
+    pub fn add(&self, blocks: &[Block], state_dir: &Path) -> Result<()> {
+        let mut all = self.read(state_dir);
+        for block in blocks {
+            match all.iter_mut().find(|known| known.net == block.net) {
+                Some(known) => known.expires = known.expires.max(block.expires),
+                None => all.push(*block),
+            }
+        }
+        self.replace(&all, state_dir)
+    }
+
+    /// Make `blocks` the complete contents of the sets and the record
+    pub fn replace(&self, blocks: &[Block], state_dir: &Path) -> Result<()> {
+        self.write(blocks, state_dir)?;
+        self.load(blocks, state_dir)
+    }
+
+    fn write(&self, blocks: &[Block], state_dir: &Path) -> Result<()> {
+        let path = self.record_path(state_dir);
+        let mut text = String::new();
+        for block in blocks {
+            writeln!(text, "{} {}", block.net, block.expires)?;
+        }
+        fs::write(&path, text).with_context(|| format!("write {}", path.display()))
+    }
+
+    /// Replace the live sets' contents with `blocks` in one transaction
+    fn load(&self, blocks: &[Block], state_dir: &Path) -> Result<()> {
+        let (table, prefix) = attach::location(state_dir);
+        let now = unix_now();
+        let mut script = String::new();
+        for (family, v6) in [("ipv4", false), ("ipv6", true)] {
+            let set = format!("{}{}_{}", prefix, self.name, family);
+            writeln!(script, "flush set inet {} {}", table, set)?;
+            let elements: Vec<String> = elements(blocks, v6)
+                .into_iter()
+                .map(|(net, expires)| format!("{} timeout {}s", net, expires.saturating_sub(now).max(1)))
+                .collect();
+            if !elements.is_empty() {
+                writeln!(script, "add element inet {} {} {{ {} }}", table, set, elements.join(", "))?;
+            }
+        }
+        nft::run_script(&script).with_context(|| format!("update the {} sets in table inet {}", self.name, table))
+    }
+}
+
+/// The set elements for one family's blocks. Interval sets reject
+/// overlapping elements, so a prefix inside another is left out and the
+/// outer one lasts until the later of the two expiries.
+fn elements(blocks: &[Block], v6: bool) -> Vec<(IpNetwork, u64)> {
+    let mut family: Vec<&Block> = blocks.iter().filter(|block| block.net.is_ipv6() == v6).collect();
+    family.sort_by_key(|block| (block.net.network(), block.net.prefix()));
+    let mut kept: Vec<(IpNetwork, u64)> = Vec::new();
+    for block in family {
+        // Sorted by start address, so only the last kept prefix can
+        // contain this one
+        match kept.last_mut() {
+            Some((net, expires)) if net.contains(block.net.network()) => *expires = (*expires).max(block.expires),
+            _ => kept.push((block.net, block.expires)),
+        }
+    }
+    kept
+}
+
+/// Put the recorded blocks that are still running back into the sets of a
+/// freshly loaded ruleset. Failures only warn, as the rules themselves
+/// are in place.
+pub fn restore(state_dir: &Path) {
+    for set in SETS {
+        let blocks = set.read(state_dir);
+        if blocks.is_empty() {
+            continue;
+        }
+        if let Err(e) = set.replace(&blocks, state_dir) {
+            warning!("could not restore the {} blocks: {:#}", set.name, e);
+        }
+    }
+}
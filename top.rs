@@ -0,0 +1,195 @@
+//! `cloak top`: drops per country and port, refreshed every second, for
+//! watching an attack while tuning the policy.
+//!
+//! Countries come from the named counters of rules generated with
+//! `--country-counters`, which count every drop; without them, and for
+//! ports in any case, from the log lines of rules generated with `--log`.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, IsTerminal},
+    path::PathBuf,
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::{
+    analyze::format_bytes,
+    daemon, fetch, groups,
+    index::Index,
+    logs, nft, parse_duration, state,
+    ui::warning,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct TopArgs {
+    /// How often to redraw
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    interval: Duration,
+
+    /// How many countries and ports to list
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    top: usize,
+
+    /// Syslog file to follow (e.g. /var/log/kern.log) instead of the journal
+    #[arg(long, value_name = "FILE")]
+    file: Option<PathBuf>,
+
+    /// JSON maps, or one index from `cloak compile`, to classify logged
+    /// drops with (default: every country in the download cache)
+    #[arg(long, value_name = "FILE")]
+    map: Vec<PathBuf>,
+
+    /// Where earlier fetches left their downloads
+    #[arg(long, value_name = "DIR", default_value_os_t = state::default_cache_dir())]
+    cache_dir: PathBuf,
+}
+
+/// Packets and bytes in the last interval and since the start
+#[derive(Clone, Copy, Default)]
+struct Rate {
+    packets: u64,
+    bytes: u64,
+    total: u64,
+}
+
+impl Rate {
+    fn add(&mut self, packets: u64, bytes: u64) {
+        self.packets += packets;
+        self.bytes += bytes;
+        self.total += packets;
+    }
+}
+
+/// What the current interval has seen
+#[derive(Default)]
+struct Window {
+    countries: BTreeMap<String, Rate>,
+    ports: BTreeMap<(String, u16), Rate>,
+    logged: u64,
+}
+
+impl Window {
+    /// Start the next interval, keeping the totals
+    fn reset(&mut self) {
+        for rate in self.countries.values_mut().chain(self.ports.values_mut()) {
+            rate.packets = 0;
+            rate.bytes = 0;
+        }
+    }
+}
+
+pub async fn run(args: &TopArgs) -> Result<()> {
+    // Logged drops are classified by address, so that needs country data
+    let index = if args.map.is_empty() {
+        let map = fetch::cached_countries(&args.cache_dir)?;
+        (!map.is_empty()).then(|| Index::build(&map))
+    } else {
+        Some(Index::load(&args.map)?)
+    };
+
+    let mut reader = match logs::log_command(args.file.as_deref(), true).stdout(Stdio::piped()).stderr(Stdio::null()).kill_on_drop(true).spawn() {
+        Ok(child) => Some(child),
+        Err(e) => {
+            warning!("cannot follow the kernel log ({}); only counters are shown", e);
+            None
+        }
+    };
+    let mut lines = reader.as_mut().and_then(|child| child.stdout.take()).map(|stdout| BufReader::new(stdout).lines());
+    let mut counters = nft::country_counters().unwrap_or_default();
+    if lines.is_none() && counters.is_empty() {
+        bail!("nothing to watch: no --country-counters rules are loaded and the kernel log cannot be read");
+    }
+
+    let mut window = Window::default();
+    let mut drawn = Instant::now();
+    let mut tick = tokio::time::interval(args.interval);
+    tick.tick().await;
+    let shutdown = daemon::shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            line = async { lines.as_mut().expect("guarded by the condition").next_line().await }, if lines.is_some() => {
+                match line.context("read the kernel log")? {
+                    Some(line) => record_line(&mut window, index.as_ref(), counters.is_empty(), &line),
+                    None => lines = None,
+                }
+            }
+            _ = tick.tick() => {
+                // Counter deltas since the last draw
+                let now = nft::country_counters().unwrap_or_default();
+                for (cc, family, packets, bytes) in &now {
+                    let before = counters.iter().find(|(c, f, _, _)| c == cc && f == family);
+                    let (old_packets, old_bytes) = before.map_or((*packets, *bytes), |(_, _, p, b)| (*p, *b));
+                    // A reload starts the counters over
+                    let (packets, bytes) = if *packets < old_packets { (*packets, *bytes) } else { (packets - old_packets, bytes.saturating_sub(old_bytes)) };
+                    window.countries.entry(cc.clone()).or_default().add(packets, bytes);
+                }
+                counters = now;
+                draw(&window, args, drawn.elapsed(), !counters.is_empty(), index.is_some());
+                drawn = Instant::now();
+                window.reset();
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+    if let Some(mut child) = reader {
+        let _ = child.kill().await;
+    }
+    Ok(())
+}
+
+/// Count a logged drop; its country only when no counters give them
+fn record_line(window: &mut Window, index: Option<&Index>, by_country: bool, line: &str) {
+    let Some(drop) = logs::parse_drop(line) else { return };
+    window.logged += 1;
+    if let Some(port) = drop.port {
+        window.ports.entry(port).or_default().add(1, drop.len);
+    }
+    if let Some(cc) = index.filter(|_| by_country).and_then(|index| index.lookup(drop.src).or_else(|| index.lookup(drop.dst))) {
+        window.countries.entry(cc.to_string()).or_default().add(1, drop.len);
+    }
+}
+
+fn draw(window: &Window, args: &TopArgs, elapsed: Duration, counted: bool, classified: bool) {
+    let secs = elapsed.as_secs_f64().max(0.001);
+    if io::stdout().is_terminal() {
+        // Home and clear, like top
+        print!("\x1b[H\x1b[2J");
+    } else {
+        println!();
+    }
+    let source = match (counted, classified) {
+        (true, _) => "countries from --country-counters, ports from --log",
+        (false, true) => "countries and ports from --log",
+        (false, false) => "ports from --log (no country data to classify drops with)",
+    };
+    println!("cloak top: drops per second over {:.1?}, {} (Ctrl-C to quit)", elapsed, source);
+    println!();
+    println!("{:<4} {:<24} {:>10} {:>12} {:>12}", "CC", "Country", "Drops/s", "Bytes/s", "Total");
+    let mut countries: Vec<(&String, &Rate)> = window.countries.iter().collect();
+    countries.sort_by(|a, b| b.1.packets.cmp(&a.1.packets).then(b.1.total.cmp(&a.1.total)).then(a.0.cmp(b.0)));
+    for (cc, rate) in countries.into_iter().take(args.top) {
+        println!(
+            "{:<4} {:<24} {:>10.1} {:>12} {:>12}",
+            cc.to_uppercase(),
+            groups::country_name(cc).unwrap_or(""),
+            rate.packets as f64 / secs,
+            format_bytes((rate.bytes as f64 / secs) as u64),
+            rate.total
+        );
+    }
+    println!();
+    println!("{:<12} {:>10} {:>12}", "Port", "Drops/s", "Total");
+    let mut ports: Vec<(&(String, u16), &Rate)> = window.ports.iter().collect();
+    ports.sort_by(|a, b| b.1.packets.cmp(&a.1.packets).then(b.1.total.cmp(&a.1.total)).then(a.0.cmp(b.0)));
+    for ((proto, port), rate) in ports.into_iter().take(args.top) {
+        println!("{:<12} {:>10.1} {:>12}", format!("{}/{}", port, proto.to_lowercase()), rate.packets as f64 / secs, rate.total);
+    }
+    if window.logged == 0 && window.ports.is_empty() {
+        println!("(no drops logged yet; ports need rules generated with --log)");
+    }
+}
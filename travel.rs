@@ -0,0 +1,93 @@
+//! `cloak travel`: let a country or a few addresses in for a limited time,
+//! so logging in from a trip does not mean widening the policy for good.
+//!
+//! Allowances live in the `travel_` timeout sets of the loaded rules (see
+//! [`crate::temp`]); the kernel removes each one when its time is up, and
+//! they are put back after every apply until then.
+
+use std::{path::{Path, PathBuf}, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use clap::Subcommand;
+
+use crate::{
+    fetch, format_duration, parse_duration,
+    temp::{self, Block},
+    target_nets,
+    ui::{info, success, Summary},
+    unix_now,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct TravelArgs {
+    #[command(subcommand)]
+    command: TravelCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum TravelCommand {
+    /// Accept two-letter country codes, addresses or prefixes until the
+    /// time runs out
+    Allow {
+        /// Country codes, addresses or prefixes (CIDR) to accept
+        #[arg(required = true)]
+        targets: Vec<String>,
+
+        /// How long to accept them (e.g. 12h, 14d, 2w)
+        #[arg(long = "for", value_name = "DURATION", value_parser = parse_duration)]
+        duration: Duration,
+
+        /// JSON map to take country prefixes from instead of downloading
+        /// them
+        #[arg(long, value_name = "FILE")]
+        map: Option<PathBuf>,
+
+        #[command(flatten)]
+        http: fetch::HttpArgs,
+    },
+
+    /// Show the allowances still running
+    Status,
+
+    /// End every allowance now
+    End,
+}
+
+pub async fn run(args: &TravelArgs, state_dir: &Path, summary: &mut Summary) -> Result<()> {
+    match &args.command {
+        TravelCommand::Allow { targets, duration, map, http } => {
+            if !cfg!(target_os = "linux") {
+                bail!("travel allowances are only supported on Linux");
+            }
+            if duration.is_zero() {
+                bail!("--for must be longer than 0s");
+            }
+            summary.action = Some("allow".to_string());
+            let nets = target_nets(targets, map.as_deref(), http, summary).await?;
+            let allows: Vec<Block> = nets.iter().map(|&net| Block::lasting(net, *duration)).collect();
+            temp::TRAVEL
+                .add(&allows, state_dir)
+                .context("rules applied by an older cloak have no travel sets; generate and apply them again first")?;
+            success!("Accepting {} prefixes of {} for {}.", nets.len(), targets.join(", "), format_duration(*duration));
+            info!("To end it early, run: cloak travel end");
+            summary.print_human();
+        }
+        TravelCommand::Status => {
+            let allows = temp::TRAVEL.read(state_dir);
+            match allows.iter().map(|allow| allow.expires).max() {
+                Some(last) => info!(
+                    "{} prefixes accepted, the last of them for another {}.",
+                    allows.len(),
+                    format_duration(Duration::from_secs(last.saturating_sub(unix_now())))
+                ),
+                None => info!("No travel allowances are running."),
+            }
+        }
+        TravelCommand::End => {
+            let count = temp::TRAVEL.read(state_dir).len();
+            temp::TRAVEL.replace(&[], state_dir)?;
+            success!("Ended {} travel allowances.", count);
+        }
+    }
+    Ok(())
+}
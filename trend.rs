@@ -0,0 +1,127 @@
+//! Prefix counts per country over time, recorded by every daemon refresh,
+//! and `cloak trend` to chart them.
+//!
+//! A provider that suddenly publishes half a country, or twice as much,
+//! shows up here long before anyone wonders why traffic got through. The
+//! history is a tab-separated file in the state directory with one line
+//! per country and refresh (`time cc ipv4 ipv6`); changes are worked out
+//! when it is read.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{iso3166, naming, parse_duration, ui::FamilyCounts, unix_now};
+
+/// Name of the history inside the state directory
+const FILE: &str = "trend.tsv";
+
+#[derive(clap::Args, Debug)]
+pub struct TrendArgs {
+    /// Country to chart (code or name)
+    #[arg(value_parser = iso3166::resolve)]
+    country: &'static str,
+
+    /// Only refreshes within this long (e.g. 30d)
+    #[arg(long, value_parser = parse_duration)]
+    since: Option<Duration>,
+
+    /// Mark refreshes whose count changed by more than this percentage
+    #[arg(long, value_name = "PERCENT", default_value_t = 10.0)]
+    threshold: f64,
+
+    /// Width of the bars
+    #[arg(long, value_name = "COLUMNS", default_value_t = 40)]
+    width: usize,
+}
+
+fn path(state_dir: &Path) -> PathBuf {
+    state_dir.join(FILE)
+}
+
+/// Append the counts of one refresh to the history
+pub fn record(state_dir: &Path, counts: &BTreeMap<String, FamilyCounts>) -> Result<()> {
+    let path = path(state_dir);
+    let now = unix_now();
+    let mut lines = String::new();
+    for (cc, count) in counts {
+        lines.push_str(&format!("{}\t{}\t{}\t{}\n", now, cc, count.ipv4, count.ipv6));
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(&path).with_context(|| format!("open {}", path.display()))?;
+    file.write_all(lines.as_bytes()).with_context(|| format!("write {}", path.display()))
+}
+
+/// The recorded refreshes of `country` since `since` (Unix time), oldest
+/// first
+fn history(state_dir: &Path, country: &str, since: u64) -> Result<Vec<(u64, FamilyCounts)>> {
+    let path = path(state_dir);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!("no history in {}; it is recorded by `cloak daemon` refreshes", state_dir.display())
+        }
+        Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+    };
+    let mut points = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [time, cc, ipv4, ipv6] = fields[..] else {
+            bail!("{}:{}: expected 4 tab-separated fields", path.display(), number + 1);
+        };
+        if cc != country {
+            continue;
+        }
+        let parse = |field: &str| field.parse().with_context(|| format!("{}:{}: invalid number `{}`", path.display(), number + 1, field));
+        let time: u64 = parse(time)?;
+        if time >= since {
+            points.push((time, FamilyCounts { ipv4: parse(ipv4)? as usize, ipv6: parse(ipv6)? as usize }));
+        }
+    }
+    Ok(points)
+}
+
+pub fn run(args: &TrendArgs, state_dir: &Path) -> Result<()> {
+    let since = args.since.map_or(0, |since| unix_now().saturating_sub(since.as_secs()));
+    let points = history(state_dir, args.country, since)?;
+    let name = iso3166::name(args.country).unwrap_or(args.country);
+    if points.is_empty() {
+        bail!("no refreshes of {} ({}) recorded", name, args.country.to_uppercase());
+    }
+
+    println!("{} ({}): prefixes per refresh, ! marks a change over {}%", name, args.country.to_uppercase(), args.threshold);
+    println!("{:<17} {:>8} {:>8} {:>8} {:>8}  IPv4", "Time (UTC)", "IPv4", "change", "IPv6", "change");
+    let most = points.iter().map(|(_, counts)| counts.ipv4).max().unwrap_or(0).max(1);
+    let mut previous: Option<FamilyCounts> = None;
+    for (time, counts) in &points {
+        let (year, month, day) = naming::civil_date(*time);
+        let stamp = format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, time % 86400 / 3600, time % 3600 / 60);
+        let change = |before: usize, now: usize| now as i64 - before as i64;
+        let (v4, v6) = previous.map_or((0, 0), |before| (change(before.ipv4, counts.ipv4), change(before.ipv6, counts.ipv6)));
+        let shown = |delta: i64| if previous.is_some() { format!("{:+}", delta) } else { String::new() };
+        // Relative to the previous count, so a country appearing from
+        // nothing is not flagged
+        let unusual = previous.is_some_and(|before| {
+            let jump = |before: usize, delta: i64| before > 0 && delta.unsigned_abs() as f64 * 100.0 / before as f64 > args.threshold;
+            jump(before.ipv4, v4) || jump(before.ipv6, v6)
+        });
+        let bar = "#".repeat((counts.ipv4 * args.width).div_ceil(most));
+        println!(
+            "{:<17} {:>8} {:>8} {:>8} {:>8}  {}{}",
+            stamp,
+            counts.ipv4,
+            shown(v4),
+            counts.ipv6,
+            shown(v6),
+            bar,
+            if unusual { " !" } else { "" }
+        );
+        previous = Some(*counts);
+    }
+    Ok(())
+}
@@ -0,0 +1,189 @@
+//! Console output: leveled, optionally colored messages for people and a
+//! machine-readable run summary for wrapper scripts.
+//!
+//! Progress goes to stdout unless `--summary-json` is given, in which case
+//! everything human-oriented moves to stderr so stdout carries only JSON.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, IsTerminal, Write},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+static COLOR: AtomicU8 = AtomicU8::new(ColorChoice::Auto as u8);
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Level {
+    Info,
+    Success,
+    Warn,
+    Error,
+}
+
+pub fn init(quiet: bool, color: ColorChoice, summary_json: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+    TO_STDERR.store(summary_json, Ordering::Relaxed);
+    COLOR.store(color as u8, Ordering::Relaxed);
+}
+
+pub fn emit(level: Level, args: fmt::Arguments) {
+    if QUIET.load(Ordering::Relaxed) && level != Level::Error {
+        return;
+    }
+    let (prefix, ansi) = match level {
+        Level::Info => ("", None),
+        Level::Success => ("", Some("32")),
+        Level::Warn => ("warning: ", Some("33")),
+        Level::Error => ("error: ", Some("1;31")),
+    };
+    let stderr = TO_STDERR.load(Ordering::Relaxed) || matches!(level, Level::Warn | Level::Error);
+    let line = match ansi {
+        Some(code) if use_color(stderr) => format!("\x1b[{}m{}{}\x1b[0m", code, prefix, args),
+        _ => format!("{}{}", prefix, args),
+    };
+    if stderr {
+        let _ = writeln!(io::stderr(), "{}", line);
+    } else {
+        let _ = writeln!(io::stdout(), "{}", line);
+    }
+}
+
+/// Ask a yes/no question. Shown even with `-q`, since the run is waiting on
+/// the answer. Builds without the `interactive` feature never ask and
+/// answer no.
+pub fn confirm(question: &str) -> io::Result<bool> {
+    Ok(ask(question, "[y/N]")?.is_some_and(|answer| answer.eq_ignore_ascii_case("y")))
+}
+
+/// Ask for `word` to be typed out before doing something hard to undo;
+/// anything else is no. Like [`confirm`], never asks without the
+/// `interactive` feature.
+pub fn confirm_typed(question: &str, word: &str) -> io::Result<bool> {
+    Ok(ask(question, &format!("Type `{}` to continue:", word))?.as_deref() == Some(word))
+}
+
+fn ask(question: &str, prompt: &str) -> io::Result<Option<String>> {
+    if !cfg!(feature = "interactive") {
+        emit(Level::Warn, format_args!("{} Not asking in this build; pass --yes to confirm.", question));
+        return Ok(None);
+    }
+    let line = if use_color(TO_STDERR.load(Ordering::Relaxed)) {
+        format!("\x1b[1m{}\x1b[0m {}", question, prompt)
+    } else {
+        format!("{} {}", question, prompt)
+    };
+    if TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(Some(input.trim().to_string()))
+}
+
+fn use_color(stderr: bool) -> bool {
+    if !cfg!(feature = "interactive") {
+        return false;
+    }
+    match COLOR.load(Ordering::Relaxed) {
+        c if c == ColorChoice::Always as u8 => true,
+        c if c == ColorChoice::Never as u8 => false,
+        _ => {
+            std::env::var_os("NO_COLOR").is_none()
+                && if stderr { io::stderr().is_terminal() } else { io::stdout().is_terminal() }
+        }
+    }
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::ui::emit($crate::ui::Level::Info, format_args!($($arg)*)) };
+}
+macro_rules! success {
+    ($($arg:tt)*) => { $crate::ui::emit($crate::ui::Level::Success, format_args!($($arg)*)) };
+}
+macro_rules! warning {
+    ($($arg:tt)*) => { $crate::ui::emit($crate::ui::Level::Warn, format_args!($($arg)*)) };
+}
+pub(crate) use {info, success, warning};
+
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct FamilyCounts {
+    pub ipv4: usize,
+    pub ipv6: usize,
+}
+
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadResult {
+    #[default]
+    NotAttempted,
+    Declined,
+    UpToDate,
+    Loaded,
+    Failed,
+}
+
+/// Final outcome of a run, printed as JSON with `--summary-json`
+#[derive(Serialize, Debug, Default)]
+pub struct Summary {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub list: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    pub countries: BTreeMap<String, FamilyCounts>,
+    pub files_written: Vec<String>,
+    pub load: LoadResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conntrack_peers_flushed: Option<usize>,
+}
+
+impl Summary {
+    pub fn new(command: &str) -> Self {
+        Summary { command: command.to_string(), ..Default::default() }
+    }
+
+    pub fn wrote(&mut self, path: &str) {
+        info!("Wrote {}", path);
+        self.files_written.push(path.to_string());
+    }
+
+    /// One-paragraph recap for people at the end of a run
+    pub fn print_human(&self) {
+        let totals = self.countries.values().fold(FamilyCounts::default(), |acc, c| FamilyCounts {
+            ipv4: acc.ipv4 + c.ipv4,
+            ipv6: acc.ipv6 + c.ipv6,
+        });
+        success!(
+            "Done: {} countries, {} IPv4 and {} IPv6 prefixes, {} files written",
+            self.countries.len(),
+            totals.ipv4,
+            totals.ipv6,
+            self.files_written.len()
+        );
+    }
+
+    pub fn print_json(&self) {
+        match serde_json::to_string_pretty(self) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("error: failed to serialize run summary: {}", e),
+        }
+    }
+}
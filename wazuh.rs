@@ -0,0 +1,33 @@
+//! Wazuh (and OSSEC) CDB lists of prefix -> country, for rules that
+//! check a decoded address with `lookup="address_match_key"`.
+//!
+//! CDB list files have no comments, so how to use the list is left to
+//! the load hint, e.g. in a rule:
+//!
+//! ```text
+//! <list field="srcip" lookup="address_match_key">etc/lists/brics_block</list>
+//! ```
+
+use std::{collections::HashMap, fmt::Write, fs};
+
+use anyhow::{Context, Result};
+
+use crate::CountryNets;
+
+/// Write a CDB list for `map` to `filename`, one `<cidr>:<CC>` line per
+/// prefix. IPv6 keys are quoted, since they contain the `:` separator.
+pub fn generate_wazuh(map: &HashMap<String, CountryNets>, filename: &str) -> Result<()> {
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    let mut file = String::new();
+    for cc in codes {
+        let value = cc.to_uppercase();
+        for net in &map[cc].ipv4 {
+            writeln!(file, "{}:{}", net.0, value)?;
+        }
+        for net in &map[cc].ipv6 {
+            writeln!(file, "\"{}\":{}", net.0, value)?;
+        }
+    }
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
@@ -0,0 +1,77 @@
+//! Windows Defender Firewall script generation.
+
+use std::{collections::HashMap, fmt::Write, fs};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{Action, CountryNets, Direction};
+
+/// Rule group used to find (and replace) everything cloak created
+pub const GROUP: &str = "cloak";
+
+/// Windows rejects rules with very long address lists, so prefixes are
+/// spread over several rules of this size.
+const ADDRESSES_PER_RULE: usize = 1000;
+
+/// Write a PowerShell script for `map` to `filename`. Running it (elevated)
+/// removes rules from any earlier run and creates the new ones.
+pub fn generate_powershell(
+    map: &HashMap<String, CountryNets>,
+    action: Action,
+    direction: Direction,
+    name: &str,
+    filename: &str,
+) -> Result<()> {
+    let mut file = String::new();
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+    let nets: Vec<String> = codes
+        .iter()
+        .flat_map(|cc| map[*cc].ipv4.iter().chain(&map[*cc].ipv6))
+        .map(|ip| ip.0.to_string())
+        .collect();
+
+    writeln!(file, "# Generated by cloak. Run from an elevated PowerShell:")?;
+    writeln!(file, "#   powershell -ExecutionPolicy Bypass -File {}", filename)?;
+    if action == Action::Allow {
+        writeln!(file, "# Traffic not matched by an allow rule is blocked by the")?;
+        writeln!(file, "# default profile policy; other allow rules still apply.")?;
+    }
+    if action == Action::Allow && direction != Direction::In {
+        writeln!(file, "# Outbound allow rules only restrict anything if the profile's")?;
+        writeln!(file, "# default outbound action is set to Block.")?;
+    }
+    writeln!(file, "$ErrorActionPreference = 'Stop'")?;
+    writeln!(file, "Remove-NetFirewallRule -Group '{}' -ErrorAction SilentlyContinue", GROUP)?;
+
+    let verdict = match action {
+        Action::Block => "Block",
+        Action::Allow => "Allow",
+        Action::Mark => bail!("Windows Firewall rules cannot mark traffic"),
+    };
+    let mut sides = Vec::new();
+    if direction != Direction::Out {
+        sides.push(("Inbound", ""));
+    }
+    if direction != Direction::In {
+        sides.push(("Outbound", " out"));
+    }
+    for (way, label) in sides {
+        for (i, chunk) in nets.chunks(ADDRESSES_PER_RULE).enumerate() {
+            let addresses: Vec<String> = chunk.iter().map(|net| format!("'{}'", net)).collect();
+            writeln!(
+                file,
+                "New-NetFirewallRule -DisplayName 'cloak {} {}{} {}' -Group '{}' -Direction {} -Action {} -RemoteAddress @({}) | Out-Null",
+                name,
+                action,
+                label,
+                i + 1,
+                GROUP,
+                way,
+                verdict,
+                addresses.join(",")
+            )?;
+        }
+    }
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}
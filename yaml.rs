@@ -0,0 +1,306 @@
+//! Minimal YAML reader for cloak's own configuration files.
+//!
+//! Supports the subset people actually write by hand: block mappings and
+//! sequences nested by indentation, single-line flow collections
+//! (`[a, b]`, `{k: v}`), quoted and plain scalars, and `#` comments.
+//! Scalars follow the YAML 1.2 core schema, so `no` (Norway) stays a string.
+//! Anchors, tags, multi-document streams and block scalars are rejected or
+//! read as plain text.
+
+use anyhow::{bail, Result};
+use serde_json::{Map, Number, Value};
+
+struct Line {
+    number: usize,
+    indent: usize,
+    text: String,
+}
+
+/// Parse a YAML document into a JSON value tree.
+pub fn parse(input: &str) -> Result<Value> {
+    let mut lines = Vec::new();
+    for (i, raw) in input.lines().enumerate() {
+        let content = strip_comment(raw);
+        if content.trim().is_empty() || content.trim() == "---" {
+            continue;
+        }
+        let indent = content.len() - content.trim_start().len();
+        if content[..indent].contains('\t') {
+            bail!("line {}: tabs are not allowed for indentation", i + 1);
+        }
+        lines.push(Line {
+            number: i + 1,
+            indent,
+            text: content.trim().to_string(),
+        });
+    }
+    if lines.is_empty() {
+        return Ok(Value::Null);
+    }
+    let mut idx = 0;
+    let indent = lines[0].indent;
+    let value = parse_block(&mut lines, &mut idx, indent)?;
+    if idx < lines.len() {
+        bail!("line {}: unexpected indentation", lines[idx].number);
+    }
+    Ok(value)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '#') if prev.is_whitespace() => return &line[..i],
+            _ => {}
+        }
+        prev = c;
+    }
+    line
+}
+
+fn is_seq_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+fn parse_block(lines: &mut [Line], idx: &mut usize, indent: usize) -> Result<Value> {
+    if is_seq_item(&lines[*idx].text) {
+        parse_seq(lines, idx, indent)
+    } else {
+        parse_map(lines, idx, indent)
+    }
+}
+
+fn parse_seq(lines: &mut [Line], idx: &mut usize, indent: usize) -> Result<Value> {
+    let mut items = Vec::new();
+    while *idx < lines.len() && lines[*idx].indent == indent && is_seq_item(&lines[*idx].text) {
+        let rest = lines[*idx].text[1..].trim_start().to_string();
+        if rest.is_empty() {
+            *idx += 1;
+            items.push(parse_child(lines, idx, indent)?);
+        } else if split_key(&rest).is_some() {
+            // "- key: value" opens a mapping whose keys line up with `key`
+            let offset = lines[*idx].text.len() - rest.len();
+            lines[*idx].indent = indent + offset;
+            lines[*idx].text = rest;
+            let child = lines[*idx].indent;
+            items.push(parse_map(lines, idx, child)?);
+        } else {
+            items.push(parse_scalar(&rest, lines[*idx].number)?);
+            *idx += 1;
+        }
+    }
+    Ok(Value::Array(items))
+}
+
+fn parse_map(lines: &mut [Line], idx: &mut usize, indent: usize) -> Result<Value> {
+    let mut map = Map::new();
+    while *idx < lines.len() && lines[*idx].indent == indent {
+        let number = lines[*idx].number;
+        if is_seq_item(&lines[*idx].text) {
+            bail!("line {}: sequence item where a mapping key was expected", number);
+        }
+        let Some((key, value)) = split_key(&lines[*idx].text) else {
+            bail!("line {}: expected `key: value`", number);
+        };
+        let key = unquote(&key);
+        let value = value.to_string();
+        if map.contains_key(&key) {
+            bail!("line {}: duplicate key `{}`", number, key);
+        }
+        *idx += 1;
+        let parsed = if value.is_empty() {
+            // A sequence may sit at the same indentation as its parent key
+            if *idx < lines.len() && lines[*idx].indent == indent && is_seq_item(&lines[*idx].text) {
+                parse_seq(lines, idx, indent)?
+            } else {
+                parse_child(lines, idx, indent)?
+            }
+        } else {
+            parse_scalar(&value, number)?
+        };
+        map.insert(key, parsed);
+    }
+    if *idx < lines.len() && lines[*idx].indent > indent {
+        bail!("line {}: unexpected indentation", lines[*idx].number);
+    }
+    Ok(Value::Object(map))
+}
+
+fn parse_child(lines: &mut [Line], idx: &mut usize, indent: usize) -> Result<Value> {
+    if *idx < lines.len() && lines[*idx].indent > indent {
+        let child = lines[*idx].indent;
+        parse_block(lines, idx, child)
+    } else {
+        Ok(Value::Null)
+    }
+}
+
+/// Split `key: value` on the first unquoted `: ` (or trailing `:`).
+fn split_key(text: &str) -> Option<(String, &str)> {
+    if text.starts_with('[') || text.starts_with('{') {
+        return None;
+    }
+    let mut quote = None;
+    let bytes = text.as_bytes();
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') if i == 0 => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, ':') if i + 1 == text.len() || bytes[i + 1] == b' ' => {
+                return Some((text[..i].trim().to_string(), text[i + 1..].trim()));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn unquote(text: &str) -> String {
+    let t = text.trim();
+    if t.len() >= 2 && ((t.starts_with('"') && t.ends_with('"')) || (t.starts_with('\'') && t.ends_with('\''))) {
+        t[1..t.len() - 1].to_string()
+    } else {
+        t.to_string()
+    }
+}
+
+fn parse_scalar(text: &str, number: usize) -> Result<Value> {
+    let text = text.trim();
+    if text.starts_with('[') || text.starts_with('{') {
+        let mut chars = text.char_indices().peekable();
+        let value = parse_flow(text, &mut chars, number)?;
+        if let Some((i, _)) = chars.find(|(_, c)| !c.is_whitespace()) {
+            bail!("line {}: trailing characters `{}`", number, &text[i..]);
+        }
+        return Ok(value);
+    }
+    if text.starts_with('&') || text.starts_with('*') || text.starts_with('!') {
+        bail!("line {}: anchors, aliases and tags are not supported", number);
+    }
+    if text.starts_with('"') || text.starts_with('\'') {
+        let quote = text.chars().next().unwrap();
+        if text.len() < 2 || !text.ends_with(quote) {
+            bail!("line {}: unterminated string", number);
+        }
+        return Ok(Value::String(unescape(&text[1..text.len() - 1], quote)));
+    }
+    Ok(plain_scalar(text))
+}
+
+fn plain_scalar(text: &str) -> Value {
+    match text {
+        "true" | "True" | "TRUE" => return Value::Bool(true),
+        "false" | "False" | "FALSE" => return Value::Bool(false),
+        "null" | "Null" | "NULL" | "~" => return Value::Null,
+        _ => {}
+    }
+    if let Ok(n) = text.parse::<i64>() {
+        return Value::Number(n.into());
+    }
+    if text.contains('.') {
+        if let Some(n) = text.parse::<f64>().ok().and_then(Number::from_f64) {
+            return Value::Number(n);
+        }
+    }
+    Value::String(text.to_string())
+}
+
+fn unescape(text: &str, quote: char) -> String {
+    if quote == '\'' {
+        return text.replace("''", "'");
+    }
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::CharIndices<'a>>;
+
+fn skip_ws(chars: &mut Chars) {
+    while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+}
+
+fn parse_flow(text: &str, chars: &mut Chars, number: usize) -> Result<Value> {
+    skip_ws(chars);
+    match chars.peek().copied() {
+        Some((_, '[')) => {
+            chars.next();
+            let mut items = Vec::new();
+            loop {
+                skip_ws(chars);
+                if chars.next_if(|(_, c)| *c == ']').is_some() {
+                    return Ok(Value::Array(items));
+                }
+                items.push(parse_flow(text, chars, number)?);
+                skip_ws(chars);
+                match chars.next() {
+                    Some((_, ',')) => continue,
+                    Some((_, ']')) => return Ok(Value::Array(items)),
+                    _ => bail!("line {}: expected `,` or `]` in flow sequence", number),
+                }
+            }
+        }
+        Some((_, '{')) => {
+            chars.next();
+            let mut map = Map::new();
+            loop {
+                skip_ws(chars);
+                if chars.next_if(|(_, c)| *c == '}').is_some() {
+                    return Ok(Value::Object(map));
+                }
+                let key = flow_token(text, chars, &[':', ',', '}']);
+                if chars.next_if(|(_, c)| *c == ':').is_none() {
+                    bail!("line {}: expected `:` in flow mapping", number);
+                }
+                let value = parse_flow(text, chars, number)?;
+                map.insert(unquote(&key), value);
+                skip_ws(chars);
+                match chars.next() {
+                    Some((_, ',')) => continue,
+                    Some((_, '}')) => return Ok(Value::Object(map)),
+                    _ => bail!("line {}: expected `,` or `}}` in flow mapping", number),
+                }
+            }
+        }
+        Some(_) => {
+            let token = flow_token(text, chars, &[',', ']', '}']);
+            parse_scalar(&token, number)
+        }
+        None => bail!("line {}: unterminated flow collection", number),
+    }
+}
+
+/// Read a scalar inside a flow collection up to one of `stops`.
+fn flow_token(text: &str, chars: &mut Chars, stops: &[char]) -> String {
+    skip_ws(chars);
+    let start = chars.peek().map(|(i, _)| *i).unwrap_or(text.len());
+    let mut end = start;
+    let mut quote = None;
+    while let Some(&(i, c)) = chars.peek() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if stops.contains(&c) => break,
+            None => {}
+        }
+        end = i + c.len_utf8();
+        chars.next();
+    }
+    text[start..end].trim().to_string()
+}
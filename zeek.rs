@@ -0,0 +1,35 @@
+//! Zeek Intelligence Framework files, so sensors tag the connections of
+//! the selected countries in `intel.log` for detection pipelines to use.
+
+use std::{collections::HashMap, fmt::Write, fs};
+
+use anyhow::{Context, Result};
+use crate::{iso3166, Action, CountryNets};
+
+/// Write a Zeek intel file for `map` to `filename`: one `Intel::SUBNET`
+/// row per prefix, or `Intel::ADDR` for single addresses, described by
+/// country and list
+pub fn generate_zeek(map: &HashMap<String, CountryNets>, action: Action, name: &str, filename: &str) -> Result<()> {
+    let verdict = match action {
+        Action::Allow => "allow",
+        _ => "block",
+    };
+    let mut codes: Vec<&String> = map.keys().collect();
+    codes.sort();
+
+    let mut file = String::new();
+    writeln!(file, "# Generated by cloak. Load with: redef Intel::read_files += {{ \"{}\" }};", filename)?;
+    writeln!(file, "#fields\tindicator\tindicator_type\tmeta.source\tmeta.desc")?;
+    for cc in codes {
+        let country = iso3166::name(cc).unwrap_or(cc);
+        let desc = format!("{} ({}), cloak {} list ({})", country, cc.to_uppercase(), name, verdict);
+        for net in map[cc].ipv4.iter().chain(&map[cc].ipv6) {
+            if net.0.prefix() == if net.0.is_ipv4() { 32 } else { 128 } {
+                writeln!(file, "{}\tIntel::ADDR\tcloak\t{}", net.0.ip(), desc)?;
+            } else {
+                writeln!(file, "{}\tIntel::SUBNET\tcloak\t{}", net.0, desc)?;
+            }
+        }
+    }
+    fs::write(filename, file).with_context(|| format!("write {}", filename))
+}